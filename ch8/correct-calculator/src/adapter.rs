@@ -1,13 +1,14 @@
 // adapter.rs - Adapter pattern implementation
 
 use crate::config::AngleMode;
+use crate::error::CalcError;
 
 // Science operations interface
 pub trait ScientificOperations: Send + Sync {
     fn sin(&self, angle: f64) -> f64;
     fn cos(&self, angle: f64) -> f64;
     fn tan(&self, angle: f64) -> f64;
-    fn log(&self, value: f64, base: f64) -> Result<f64, String>;
+    fn log(&self, value: f64, base: f64) -> Result<f64, CalcError>;
 }
 
 // Standard implementation using Rust's math functions
@@ -37,12 +38,12 @@ impl ScientificOperations for StandardScientificOperations {
         }
     }
     
-    fn log(&self, value: f64, base: f64) -> Result<f64, String> {
+    fn log(&self, value: f64, base: f64) -> Result<f64, CalcError> {
         if value <= 0.0 {
-            return Err("Cannot take logarithm of non-positive number".to_string());
+            return Err(log_domain_error("log", value));
         }
         if base <= 0.0 || base == 1.0 {
-            return Err("Invalid logarithm base".to_string());
+            return Err(log_domain_error("log base", base));
         }
         
         Ok(value.log(base))
@@ -83,11 +84,23 @@ impl ScientificOperations for ExternalLibraryAdapter {
         }
     }
     
-    fn log(&self, value: f64, base: f64) -> Result<f64, String> {
-        if value <= 0.0 || base <= 0.0 || base == 1.0 {
-            return Err("Invalid logarithm arguments".to_string());
+    fn log(&self, value: f64, base: f64) -> Result<f64, CalcError> {
+        if value <= 0.0 {
+            return Err(log_domain_error("log", value));
+        }
+        if base <= 0.0 || base == 1.0 {
+            return Err(log_domain_error("log base", base));
         }
         
         Ok(value.log(base))
     }
 }
+
+// Shared by both implementations so the messages stay identical
+fn log_domain_error(function: &str, argument: f64) -> CalcError {
+    CalcError::DomainError {
+        function: function.to_string(),
+        argument,
+        span: None,
+    }
+}
@@ -0,0 +1,418 @@
+// bigint.rs - Arbitrary-size integers for the exact and high-precision number types
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem;
+use std::ops::{Add, Sub, Mul, Neg};
+
+// Limbs are base 10^9, so printing and scaling by powers of ten stay simple
+const BASE: u64 = 1_000_000_000;
+const LIMB_DIGITS: usize = 9;
+
+// Sign and magnitude, with the least significant limb first. Zero has no
+// limbs and is never negative, so equal values are equal structurally
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        Self { negative: false, limbs: Vec::new() }
+    }
+    
+    fn from_limbs(negative: bool, mut limbs: Vec<u32>) -> Self {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        Self { negative: negative && !limbs.is_empty(), limbs }
+    }
+    
+    // Optional `-` then decimal digits
+    pub fn parse(text: &str) -> Option<Self> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        
+        let limbs = digits.as_bytes()
+            .rchunks(LIMB_DIGITS)
+            .map(|chunk| chunk.iter().fold(0, |limb, digit| limb * 10 + u32::from(digit - b'0')))
+            .collect();
+        Some(Self::from_limbs(negative, limbs))
+    }
+    
+    pub fn pow10(exponent: usize) -> Self {
+        let mut limbs = vec![0; exponent / LIMB_DIGITS];
+        limbs.push(10u32.pow((exponent % LIMB_DIGITS) as u32));
+        Self::from_limbs(false, limbs)
+    }
+    
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+    
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+    
+    pub fn is_even(&self) -> bool {
+        self.limbs.first().is_none_or(|limb| limb % 2 == 0)
+    }
+    
+    pub fn abs(&self) -> Self {
+        Self { negative: false, limbs: self.limbs.clone() }
+    }
+    
+    // Decimal digits in the magnitude; zero has none
+    pub fn digits(&self) -> usize {
+        match self.limbs.last() {
+            Some(top) => (self.limbs.len() - 1) * LIMB_DIGITS + top.to_string().len(),
+            None => 0,
+        }
+    }
+    
+    // Nearest f64, rounded once by the standard library's parser
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+    
+    // Quotient rounded towards zero, and the remainder, which has the sign of
+    // `self`. Panics when `divisor` is zero, as integer division does
+    pub fn div_rem(&self, divisor: &BigInt) -> (BigInt, BigInt) {
+        assert!(!divisor.is_zero(), "BigInt division by zero");
+        let (quotient, remainder) = div_rem_magnitude(&self.limbs, &divisor.limbs);
+        (
+            Self::from_limbs(self.negative != divisor.negative, quotient),
+            Self::from_limbs(self.negative, remainder),
+        )
+    }
+    
+    pub fn pow(&self, mut exponent: u32) -> Self {
+        let (mut result, mut square) = (BigInt::from(1), self.clone());
+        while exponent > 0 {
+            if exponent % 2 == 1 {
+                result = &result * &square;
+            }
+            square = &square * &square;
+            exponent /= 2;
+        }
+        result
+    }
+    
+    // Largest integer whose square is at most `self`, by Newton's method from
+    // above; None for negative numbers
+    pub fn sqrt(&self) -> Option<Self> {
+        if self.negative {
+            return None;
+        }
+        if self.is_zero() {
+            return Some(Self::zero());
+        }
+        
+        let two = BigInt::from(2);
+        let mut root = BigInt::pow10(self.digits().div_ceil(2));
+        loop {
+            let next = (&root + &self.div_rem(&root).0).div_rem(&two).0;
+            if next >= root {
+                return Some(root);
+            }
+            root = next;
+        }
+    }
+}
+
+// Greatest common divisor, which is never negative. Binary (Stein's)
+// algorithm: only subtraction and halving, each linear in the limbs, where
+// Euclid's would run a long division at every step
+pub fn gcd(a: &BigInt, b: &BigInt) -> BigInt {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    if a.is_zero() || b.is_zero() {
+        return if a.is_zero() { b } else { a };
+    }
+    
+    // Powers of two both share come back at the end; the rest are dropped
+    let twos = strip_twos(&mut a).min(strip_twos(&mut b));
+    
+    // Both odd, so their difference is even and its twos can go
+    loop {
+        match a.cmp(&b) {
+            Ordering::Equal => break,
+            Ordering::Less => mem::swap(&mut a, &mut b),
+            Ordering::Greater => {},
+        }
+        a = &a - &b;
+        strip_twos(&mut a);
+    }
+    &a * &BigInt::from(2).pow(twos)
+}
+
+// Divides a non-zero value by two until it is odd, giving how many times
+fn strip_twos(value: &mut BigInt) -> u32 {
+    let mut twos = 0;
+    loop {
+        // BASE is a multiple of 2^9, so the lowest limb holds up to nine of the twos
+        let shift = value.limbs[0].trailing_zeros().min(9);
+        if shift == 0 {
+            return twos;
+        }
+        *value = BigInt::from_limbs(value.negative, div_small(&value.limbs, 1 << shift).0);
+        twos += shift;
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let mut magnitude = value.unsigned_abs();
+        let mut limbs = Vec::new();
+        while magnitude > 0 {
+            limbs.push((magnitude % BASE) as u32);
+            magnitude /= BASE;
+        }
+        Self::from_limbs(value < 0, limbs)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare(&self.limbs, &other.limbs),
+            (true, true) => compare(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+    
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_limbs(self.negative, add_magnitude(&self.limbs, &other.limbs));
+        }
+        // Opposite signs: the larger magnitude decides the sign
+        match compare(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::from_limbs(other.negative, sub_magnitude(&other.limbs, &self.limbs)),
+            _ => BigInt::from_limbs(self.negative, sub_magnitude(&self.limbs, &other.limbs)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+    
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+    
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut limbs = vec![0u64; self.limbs.len() + other.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let total = limbs[i + j] + u64::from(a) * u64::from(b) + carry;
+                limbs[i + j] = total % BASE;
+                carry = total / BASE;
+            }
+            limbs[i + other.limbs.len()] += carry;
+        }
+        BigInt::from_limbs(self.negative != other.negative, limbs.into_iter().map(|limb| limb as u32).collect())
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+    
+    fn neg(self) -> BigInt {
+        BigInt::from_limbs(!self.negative, self.limbs.clone())
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some((top, rest)) = self.limbs.split_last() else {
+            return write!(f, "0");
+        };
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", top)?;
+        for limb in rest.iter().rev() {
+            write!(f, "{:09}", limb)?;
+        }
+        Ok(())
+    }
+}
+
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut limbs = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0;
+    for (i, &limb) in long.iter().enumerate() {
+        let total = u64::from(limb) + u64::from(short.get(i).copied().unwrap_or(0)) + carry;
+        limbs.push((total % BASE) as u32);
+        carry = total / BASE;
+    }
+    limbs.push(carry as u32);
+    limbs
+}
+
+// a - b, where a >= b
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut limbs = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &limb) in a.iter().enumerate() {
+        let mut difference = i64::from(limb) - i64::from(b.get(i).copied().unwrap_or(0)) - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += BASE as i64;
+            borrow = 1;
+        }
+        limbs.push(difference as u32);
+    }
+    limbs
+}
+
+fn mul_small(a: &[u32], factor: u64) -> Vec<u32> {
+    let mut limbs = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0;
+    for &limb in a {
+        let total = u64::from(limb) * factor + carry;
+        limbs.push((total % BASE) as u32);
+        carry = total / BASE;
+    }
+    limbs.push(carry as u32);
+    limbs
+}
+
+fn div_small(a: &[u32], divisor: u64) -> (Vec<u32>, u64) {
+    let mut quotient = vec![0; a.len()];
+    let mut remainder = 0;
+    for (i, &limb) in a.iter().enumerate().rev() {
+        let current = remainder * BASE + u64::from(limb);
+        quotient[i] = (current / divisor) as u32;
+        remainder = current % divisor;
+    }
+    (quotient, remainder)
+}
+
+// Long division (Knuth's algorithm D): each quotient limb is guessed from the
+// leading limbs, then corrected
+fn div_rem_magnitude(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if compare(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    if let [divisor] = b {
+        let (quotient, remainder) = div_small(a, u64::from(*divisor));
+        return (quotient, vec![remainder as u32]);
+    }
+    
+    // Scaling so the divisor's top limb is at least BASE / 2 keeps every guess
+    // at most 2 too large
+    let scale = BASE / (u64::from(b[b.len() - 1]) + 1);
+    let mut u = mul_small(a, scale);
+    let v = mul_small(b, scale);
+    let n = b.len();
+    let m = a.len() - n;
+    u.resize(a.len() + 1, 0);
+    let (top, next) = (u64::from(v[n - 1]), u64::from(v[n - 2]));
+    
+    let mut quotient = vec![0; m + 1];
+    for j in (0..=m).rev() {
+        let leading = u64::from(u[j + n]) * BASE + u64::from(u[j + n - 1]);
+        let (mut guess, mut rest) = (leading / top, leading % top);
+        while guess >= BASE || guess * next > rest * BASE + u64::from(u[j + n - 2]) {
+            guess -= 1;
+            rest += top;
+            if rest >= BASE {
+                break;
+            }
+        }
+        
+        // u -= guess * v, shifted by j limbs
+        let (mut carry, mut borrow) = (0, 0);
+        for i in 0..=n {
+            let product = guess * u64::from(v.get(i).copied().unwrap_or(0)) + carry;
+            carry = product / BASE;
+            let mut difference = i64::from(u[i + j]) - (product % BASE) as i64 - borrow;
+            borrow = 0;
+            if difference < 0 {
+                difference += BASE as i64;
+                borrow = 1;
+            }
+            u[i + j] = difference as u32;
+        }
+        
+        // The guess was one too large: add v back
+        if borrow > 0 {
+            guess -= 1;
+            let mut carry = 0;
+            for i in 0..=n {
+                let total = u64::from(u[i + j]) + u64::from(v.get(i).copied().unwrap_or(0)) + carry;
+                u[i + j] = (total % BASE) as u32;
+                carry = total / BASE;
+            }
+        }
+        quotient[j] = guess as u32;
+    }
+    
+    let (remainder, _) = div_small(&u[..n], scale);
+    (quotient, remainder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Rng;
+    
+    // Euclid's algorithm, which `gcd` replaced
+    fn euclid(a: &BigInt, b: &BigInt) -> BigInt {
+        let (mut a, mut b) = (a.abs(), b.abs());
+        while !b.is_zero() {
+            let remainder = a.div_rem(&b).1;
+            a = b;
+            b = remainder;
+        }
+        a
+    }
+    
+    #[test]
+    fn binary_gcd_matches_euclid() {
+        let mut rng = Rng::new(7);
+        let mut random = |limbs: usize| {
+            let digits: String = (0..limbs * LIMB_DIGITS).map(|_| char::from(b'0' + rng.below(10) as u8)).collect();
+            BigInt::parse(&digits).unwrap()
+        };
+        
+        for limbs in 1..12 {
+            let common = random(limbs);
+            let (a, b) = (&random(limbs) * &common, &random(limbs + 1) * &common);
+            assert_eq!(gcd(&a, &b), euclid(&a, &b), "gcd({}, {})", a, b);
+        }
+        
+        let power = BigInt::from(2).pow(100);
+        assert_eq!(gcd(&power, &(&power * &BigInt::from(-3))), power);
+        assert_eq!(gcd(&BigInt::from(-12), &BigInt::zero()), BigInt::from(12));
+        assert_eq!(gcd(&BigInt::zero(), &BigInt::zero()), BigInt::zero());
+        assert_eq!(gcd(&BigInt::from(1_000_000_000), &BigInt::from(512 * 3)), BigInt::from(512));
+    }
+}
@@ -1,66 +1,171 @@
 // bridge.rs - Bridge pattern implementation
 
 use std::collections::HashMap;
-use crate::expression::Expression;
+use crate::expression::{Expr, UserFunction};
+use crate::error::CalcError;
+use crate::config::DEFAULT_RECURSION_DEPTH;
+use crate::formatter::NumberFormatter;
+use crate::interval::{Interval, IntervalContext};
+use crate::numeric::{Numeric, NumericContext, Value};
 
 // Display interface (abstraction)
 pub trait Display {
-    fn show_result(&self, result: f64);
+    fn show_result(&self, result: &Value);
     fn show_error(&self, error: &str);
-    fn show_expression(&self, expression: &dyn Expression);
+    fn show_expression(&self, expression: &Expr);
     fn show_message(&self, message: &str);
+    
+    // How this display writes numbers, for messages built by its callers
+    fn format_value(&self, value: &Value) -> String {
+        NumberFormatter::new().format_value(value)
+    }
 }
 
 // Concrete display implementation
-pub struct ConsoleDisplay;
+pub struct ConsoleDisplay {
+    formatter: NumberFormatter,
+}
+
+impl ConsoleDisplay {
+    pub fn new() -> Self {
+        Self { formatter: NumberFormatter::new() }
+    }
+    
+    pub fn with_formatter(mut self, formatter: NumberFormatter) -> Self {
+        self.formatter = formatter;
+        self
+    }
+}
 
 impl Display for ConsoleDisplay {
-    fn show_result(&self, result: f64) {
-        println!("Result: {}", result);
+    fn show_result(&self, result: &Value) {
+        println!("Result: {}", self.formatter.format_value(result));
     }
     
     fn show_error(&self, error: &str) {
         println!("Error: {}", error);
     }
     
-    fn show_expression(&self, expression: &dyn Expression) {
-        println!("Expression: {}", expression.to_string());
+    fn show_expression(&self, expression: &Expr) {
+        println!("Expression: {}", expression);
     }
     
     fn show_message(&self, message: &str) {
         println!("{}", message);
     }
+    
+    fn format_value(&self, value: &Value) -> String {
+        self.formatter.format_value(value)
+    }
 }
 
 // Evaluator (abstraction)
-pub struct Evaluator {
-    strategy: Box<dyn EvaluationStrategy>,
+pub struct Evaluator<V = f64> {
+    strategy: Box<dyn EvaluationStrategy<Value = V>>,
 }
 
-// Implementor for evaluation
+// Implementor for evaluation. `Value` is what variables hold and results are:
+// plain numbers, or something else such as ranges
 pub trait EvaluationStrategy {
-    fn evaluate(&self, expression: &dyn Expression, variables: &HashMap<String, f64>) -> Result<f64, String>;
+    type Value;
+    
+    fn evaluate(&self, expression: &Expr, variables: &HashMap<String, Self::Value>) -> Result<Self::Value, CalcError>;
 }
 
 // Concrete implementor
 pub struct StandardEvaluator;
 
 impl EvaluationStrategy for StandardEvaluator {
-    fn evaluate(&self, expression: &dyn Expression, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    type Value = f64;
+    
+    fn evaluate(&self, expression: &Expr, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
         expression.evaluate(variables)
     }
 }
 
-impl Evaluator {
-    pub fn new(strategy: Box<dyn EvaluationStrategy>) -> Self {
+// Concrete implementor over ranges: every variable is an interval, and so is
+// the result, which holds every value the expression takes over them
+pub struct IntervalEvaluator {
+    functions: HashMap<String, UserFunction>,
+    max_depth: usize,
+}
+
+impl IntervalEvaluator {
+    pub fn new() -> Self {
+        Self {
+            functions: HashMap::new(),
+            max_depth: DEFAULT_RECURSION_DEPTH,
+        }
+    }
+    
+    pub fn with_functions(mut self, functions: HashMap<String, UserFunction>) -> Self {
+        self.functions = functions;
+        self
+    }
+    
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl EvaluationStrategy for IntervalEvaluator {
+    type Value = Interval;
+    
+    fn evaluate(&self, expression: &Expr, variables: &HashMap<String, Interval>) -> Result<Interval, CalcError> {
+        IntervalContext::new(variables, &self.functions)
+            .with_max_depth(self.max_depth)
+            .evaluate(expression)
+    }
+}
+
+// Concrete implementor for any number type, such as exact fractions
+pub struct NumericEvaluator<N: Numeric> {
+    functions: HashMap<String, UserFunction>,
+    settings: N::Settings,
+    max_depth: usize,
+}
+
+impl<N: Numeric> NumericEvaluator<N> {
+    pub fn new(settings: N::Settings) -> Self {
+        Self {
+            functions: HashMap::new(),
+            settings,
+            max_depth: DEFAULT_RECURSION_DEPTH,
+        }
+    }
+    
+    pub fn with_functions(mut self, functions: HashMap<String, UserFunction>) -> Self {
+        self.functions = functions;
+        self
+    }
+    
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl<N: Numeric> EvaluationStrategy for NumericEvaluator<N> {
+    type Value = N;
+    
+    fn evaluate(&self, expression: &Expr, variables: &HashMap<String, N>) -> Result<N, CalcError> {
+        NumericContext::new(variables, &self.functions, self.settings)
+            .with_max_depth(self.max_depth)
+            .evaluate(expression)
+    }
+}
+
+impl<V> Evaluator<V> {
+    pub fn new(strategy: Box<dyn EvaluationStrategy<Value = V>>) -> Self {
         Self { strategy }
     }
     
-    pub fn evaluate(&self, expression: &dyn Expression, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    pub fn evaluate(&self, expression: &Expr, variables: &HashMap<String, V>) -> Result<V, CalcError> {
         self.strategy.evaluate(expression, variables)
     }
     
-    pub fn change_strategy(&mut self, strategy: Box<dyn EvaluationStrategy>) {
+    pub fn change_strategy(&mut self, strategy: Box<dyn EvaluationStrategy<Value = V>>) {
         self.strategy = strategy;
     }
 }
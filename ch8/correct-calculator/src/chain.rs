@@ -1,12 +1,17 @@
 // chain.rs - Chain of Responsibility pattern implementation
 
-use crate::command::{Command, CommandProcessor, EvaluateCommand, SetVariableCommand, ClearVariablesCommand};
-use crate::parser::ExpressionParser;
+use crate::command::{CommandProcessor, EvaluateCommand, SetVariableCommand, ClearVariablesCommand, DefineFunctionCommand};
+use crate::parser::{ExpressionParser, split_assignment};
+use crate::error::CalcError;
+use crate::constants::{self, check_assignable};
+use crate::formatter::NumberFormatter;
+use crate::numeric::Value;
 
 // Handler interface
 pub trait InputHandler {
-    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<f64>, String>;
-    fn set_next(&mut self, next: Box<dyn InputHandler>) -> &mut Self;
+    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<Value>, CalcError>;
+    // Only on concrete handlers, since it returns `Self`; the chain is built before boxing
+    fn set_next(&mut self, next: Box<dyn InputHandler>) -> &mut Self where Self: Sized;
 }
 
 // Base implementation for chaining
@@ -21,11 +26,11 @@ impl BaseHandler {
 }
 
 impl InputHandler for BaseHandler {
-    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<f64>, String> {
+    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<Value>, CalcError> {
         if let Some(next) = &self.next {
             next.handle(input, processor)
         } else {
-            Err(format!("No handler found for input: {}", input))
+            Err(CalcError::UnknownCommand { name: input.trim().to_string() })
         }
     }
     
@@ -47,10 +52,10 @@ impl CommandHandler {
 }
 
 impl InputHandler for CommandHandler {
-    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<f64>, String> {
+    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<Value>, CalcError> {
         let trimmed = input.trim();
-        if trimmed.starts_with("/") {
-            match &trimmed[1..] {
+        if let Some(command) = trimmed.strip_prefix('/') {
+            match command {
                 "undo" => {
                     processor.undo()?;
                     Ok(None)
@@ -69,12 +74,27 @@ impl InputHandler for CommandHandler {
                     let command = Box::new(ClearVariablesCommand::new());
                     processor.execute(command)
                 },
+                "functions" => {
+                    for function in processor.get_calculator().functions.values() {
+                        println!("{} = {}", function.signature(), function.body);
+                    }
+                    Ok(None)
+                },
+                "constants" => {
+                    let formatter = NumberFormatter::new();
+                    for constant in constants::all() {
+                        println!("{} = {} ({})", constant.name, formatter.format(constant.value), constant.description);
+                    }
+                    Ok(None)
+                },
                 "help" => {
                     println!("Calculator commands:");
                     println!("  /undo - Undo last operation");
                     println!("  /redo - Redo last undone operation");
                     println!("  /history - Show command history");
                     println!("  /clear - Clear all variables");
+                    println!("  /functions - List user-defined functions");
+                    println!("  /constants - List built-in constants");
                     println!("  /help - Show this help");
                     println!("  /exit - Exit the calculator");
                     Ok(None)
@@ -92,7 +112,7 @@ impl InputHandler for CommandHandler {
     }
 }
 
-// Handles variable assignments (x=5)
+// Handles variable assignments (x=5) and function definitions (f(x) = x^2)
 pub struct VariableAssignmentHandler {
     base: BaseHandler,
     parser: ExpressionParser,
@@ -108,24 +128,32 @@ impl VariableAssignmentHandler {
 }
 
 impl InputHandler for VariableAssignmentHandler {
-    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<f64>, String> {
+    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<Value>, CalcError> {
         let trimmed = input.trim();
-        if let Some((name, value_str)) = trimmed.split_once('=') {
+        if let Some((name, value_str)) = split_assignment(trimmed) {
             let name = name.trim();
             let value_str = value_str.trim();
             
+            // A parameter list on the left makes this a function definition
+            if name.contains('(') {
+                let function = self.parser.parse_function_definition(trimmed)?;
+                let define_command = Box::new(DefineFunctionCommand::new(function));
+                return processor.execute(define_command);
+            }
+            
             // Check if the name is valid
             if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
-                return Err(format!("Invalid variable name: {}", name));
+                return Err(CalcError::InvalidName { name: name.to_string() });
             }
+            check_assignable(name)?;
             
             // Try to evaluate the right side expression
             let expr = self.parser.parse(value_str)?;
             let calculator = processor.get_calculator();
-            let value = expr.evaluate(&calculator.variables)?;
+            let value = Value::Float(expr.evaluate_in(&calculator.context(&calculator.float_variables()))?);
             
             // Set the variable
-            let set_command = Box::new(SetVariableCommand::new(name.to_string(), value));
+            let set_command = Box::new(SetVariableCommand::new(name.to_string(), value.clone()));
             processor.execute(set_command)?;
             
             Ok(Some(value))
@@ -156,7 +184,7 @@ impl ExpressionHandler {
 }
 
 impl InputHandler for ExpressionHandler {
-    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<f64>, String> {
+    fn handle(&self, input: &str, processor: &mut CommandProcessor) -> Result<Option<Value>, CalcError> {
         let trimmed = input.trim();
         
         // Parse the expression
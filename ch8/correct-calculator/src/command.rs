@@ -1,27 +1,34 @@
 // command.rs - Command pattern implementation
 
 use std::collections::HashMap;
-use crate::expression::Expression;
+use crate::expression::{Expr, EvaluationContext, UserFunction};
+use crate::error::CalcError;
+use crate::config::get_global_config;
+use crate::numeric::Value;
 
 // Command interface
 pub trait Command {
-    fn execute(&self, calculator: &mut Calculator) -> Result<Option<f64>, String>;
-    fn undo(&self, calculator: &mut Calculator) -> Result<(), String>;
+    // Takes `&mut self` so a command can remember what it replaced, for undo
+    fn execute(&mut self, calculator: &mut Calculator) -> Result<Option<Value>, CalcError>;
+    fn undo(&self, calculator: &mut Calculator) -> Result<(), CalcError>;
     fn description(&self) -> String;
 }
 
-// Calculator struct for command context
+// Calculator struct for command context. Variables keep the number type
+// they were worked out in
 pub struct Calculator {
-    pub variables: HashMap<String, f64>,
+    pub variables: HashMap<String, Value>,
     pub history: Vec<Calculation>,
-    pub last_result: Option<f64>,
+    pub last_result: Option<Value>,
+    pub functions: HashMap<String, UserFunction>,
+    pub max_recursion_depth: usize,
 }
 
-// Represents a complete calculation
+// Represents a complete calculation, with the result in the number type it was worked out in
 #[derive(Debug, Clone)]
 pub struct Calculation {
     pub expression: String,
-    pub result: f64,
+    pub result: Value,
     pub timestamp: std::time::SystemTime,
 }
 
@@ -31,29 +38,50 @@ impl Calculator {
             variables: HashMap::new(),
             history: Vec::new(),
             last_result: None,
+            functions: HashMap::new(),
+            max_recursion_depth: get_global_config().max_recursion_depth,
         }
     }
-
-    pub fn set_variable(&mut self, name: &str, value: f64) {
+    
+    pub fn set_variable(&mut self, name: &str, value: Value) {
         self.variables.insert(name.to_string(), value);
     }
-
-    pub fn get_variable(&self, name: &str) -> Option<f64> {
-        self.variables.get(name).copied()
+    
+    pub fn get_variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
     }
-
+    
+    // The variables as f64s, for evaluating in float
+    pub fn float_variables(&self) -> HashMap<String, f64> {
+        float_variables(&self.variables)
+    }
+    
     pub fn clear_variable(&mut self, name: &str) {
         self.variables.remove(name);
     }
-
-    pub fn set_last_result(&mut self, result: f64) {
-        self.last_result = Some(result);
+    
+    pub fn define_function(&mut self, function: UserFunction) {
+        self.functions.insert(function.name.clone(), function);
     }
-
-    pub fn store_calculation(&mut self, expression: String, result: f64) {
+    
+    pub fn get_function(&self, name: &str) -> Option<&UserFunction> {
+        self.functions.get(name)
+    }
+    
+    pub fn remove_function(&mut self, name: &str) {
+        self.functions.remove(name);
+    }
+    
+    // Lets expressions call the functions defined so far, over `variables`
+    pub fn context<'a>(&'a self, variables: &'a HashMap<String, f64>) -> EvaluationContext<'a> {
+        EvaluationContext::new(variables, &self.functions, ())
+            .with_max_depth(self.max_recursion_depth)
+    }
+    
+    pub fn store_calculation(&mut self, expression: String, result: Value) {
         let calculation = Calculation {
             expression,
-            result,
+            result: result.clone(),
             timestamp: std::time::SystemTime::now(),
         };
         self.history.push(calculation);
@@ -64,12 +92,12 @@ impl Calculator {
 // Concrete command for evaluating expressions
 pub struct EvaluateCommand {
     expression: String,
-    expr_tree: Box<dyn Expression>,
-    previous_result: Option<f64>,
+    expr_tree: Expr,
+    previous_result: Option<Value>,
 }
 
 impl EvaluateCommand {
-    pub fn new(expression: String, expr_tree: Box<dyn Expression>) -> Self {
+    pub fn new(expression: String, expr_tree: Expr) -> Self {
         Self {
             expression,
             expr_tree,
@@ -79,23 +107,24 @@ impl EvaluateCommand {
 }
 
 impl Command for EvaluateCommand {
-    fn execute(&self, calculator: &mut Calculator) -> Result<Option<f64>, String> {
-        self.previous_result = calculator.last_result;
+    fn execute(&mut self, calculator: &mut Calculator) -> Result<Option<Value>, CalcError> {
+        self.previous_result = calculator.last_result.clone();
         
-        let result = self.expr_tree.evaluate(&calculator.variables)?;
-        calculator.store_calculation(self.expression.clone(), result);
+        let variables = calculator.float_variables();
+        let result = Value::Float(self.expr_tree.evaluate_in(&calculator.context(&variables))?);
+        calculator.store_calculation(self.expression.clone(), result.clone());
         
         Ok(Some(result))
     }
     
-    fn undo(&self, calculator: &mut Calculator) -> Result<(), String> {
+    fn undo(&self, calculator: &mut Calculator) -> Result<(), CalcError> {
         // Remove the last entry from history
         if !calculator.history.is_empty() {
             calculator.history.pop();
         }
         
         // Restore previous result
-        calculator.last_result = self.previous_result;
+        calculator.last_result = self.previous_result.clone();
         
         Ok(())
     }
@@ -108,12 +137,12 @@ impl Command for EvaluateCommand {
 // Command for setting variables
 pub struct SetVariableCommand {
     name: String,
-    value: f64,
-    previous_value: Option<f64>,
+    value: Value,
+    previous_value: Option<Value>,
 }
 
 impl SetVariableCommand {
-    pub fn new(name: String, value: f64) -> Self {
+    pub fn new(name: String, value: Value) -> Self {
         Self {
             name,
             value,
@@ -123,16 +152,16 @@ impl SetVariableCommand {
 }
 
 impl Command for SetVariableCommand {
-    fn execute(&self, calculator: &mut Calculator) -> Result<Option<f64>, String> {
-        self.previous_value = calculator.get_variable(&self.name);
-        calculator.set_variable(&self.name, self.value);
+    fn execute(&mut self, calculator: &mut Calculator) -> Result<Option<Value>, CalcError> {
+        self.previous_value = calculator.get_variable(&self.name).cloned();
+        calculator.set_variable(&self.name, self.value.clone());
         Ok(None)
     }
     
-    fn undo(&self, calculator: &mut Calculator) -> Result<(), String> {
-        match self.previous_value {
+    fn undo(&self, calculator: &mut Calculator) -> Result<(), CalcError> {
+        match &self.previous_value {
             Some(value) => {
-                calculator.set_variable(&self.name, value);
+                calculator.set_variable(&self.name, value.clone());
                 Ok(())
             },
             None => {
@@ -147,9 +176,45 @@ impl Command for SetVariableCommand {
     }
 }
 
+// Command for defining functions such as `f(x) = x^2 + 1`
+pub struct DefineFunctionCommand {
+    function: UserFunction,
+    previous_definition: Option<UserFunction>,
+}
+
+impl DefineFunctionCommand {
+    pub fn new(function: UserFunction) -> Self {
+        Self {
+            function,
+            previous_definition: None,
+        }
+    }
+}
+
+impl Command for DefineFunctionCommand {
+    fn execute(&mut self, calculator: &mut Calculator) -> Result<Option<Value>, CalcError> {
+        self.previous_definition = calculator.get_function(&self.function.name).cloned();
+        calculator.define_function(self.function.clone());
+        Ok(None)
+    }
+    
+    fn undo(&self, calculator: &mut Calculator) -> Result<(), CalcError> {
+        // Bring back the definition this one replaced, if any
+        match &self.previous_definition {
+            Some(function) => calculator.define_function(function.clone()),
+            None => calculator.remove_function(&self.function.name),
+        }
+        Ok(())
+    }
+    
+    fn description(&self) -> String {
+        format!("Define: {} = {}", self.function.signature(), self.function.body)
+    }
+}
+
 // Clear all variables command
 pub struct ClearVariablesCommand {
-    previous_variables: Option<HashMap<String, f64>>,
+    previous_variables: Option<HashMap<String, Value>>,
 }
 
 impl ClearVariablesCommand {
@@ -161,18 +226,18 @@ impl ClearVariablesCommand {
 }
 
 impl Command for ClearVariablesCommand {
-    fn execute(&self, calculator: &mut Calculator) -> Result<Option<f64>, String> {
+    fn execute(&mut self, calculator: &mut Calculator) -> Result<Option<Value>, CalcError> {
         self.previous_variables = Some(calculator.variables.clone());
         calculator.variables.clear();
         Ok(None)
     }
     
-    fn undo(&self, calculator: &mut Calculator) -> Result<(), String> {
+    fn undo(&self, calculator: &mut Calculator) -> Result<(), CalcError> {
         if let Some(vars) = &self.previous_variables {
             calculator.variables = vars.clone();
             Ok(())
         } else {
-            Err("No previous variables state saved".to_string())
+            Err(CalcError::NothingToUndo)
         }
     }
     
@@ -181,6 +246,11 @@ impl Command for ClearVariablesCommand {
     }
 }
 
+// Each value's f64, NaN for a complex number that isn't real
+pub fn float_variables(variables: &HashMap<String, Value>) -> HashMap<String, f64> {
+    variables.iter().map(|(name, value)| (name.clone(), value.to_f64())).collect()
+}
+
 // Command processor that handles and tracks commands
 pub struct CommandProcessor {
     calculator: Calculator,
@@ -197,30 +267,30 @@ impl CommandProcessor {
         }
     }
     
-    pub fn execute(&mut self, command: Box<dyn Command>) -> Result<Option<f64>, String> {
+    pub fn execute(&mut self, mut command: Box<dyn Command>) -> Result<Option<Value>, CalcError> {
         let result = command.execute(&mut self.calculator)?;
         self.history.push(command);
         self.undo_stack.clear(); // Clear redo stack after new command
         Ok(result)
     }
     
-    pub fn undo(&mut self) -> Result<(), String> {
+    pub fn undo(&mut self) -> Result<(), CalcError> {
         if let Some(command) = self.history.pop() {
             command.undo(&mut self.calculator)?;
             self.undo_stack.push(command);
             Ok(())
         } else {
-            Err("Nothing to undo".to_string())
+            Err(CalcError::NothingToUndo)
         }
     }
     
-    pub fn redo(&mut self) -> Result<(), String> {
-        if let Some(command) = self.undo_stack.pop() {
+    pub fn redo(&mut self) -> Result<(), CalcError> {
+        if let Some(mut command) = self.undo_stack.pop() {
             command.execute(&mut self.calculator)?;
             self.history.push(command);
             Ok(())
         } else {
-            Err("Nothing to redo".to_string())
+            Err(CalcError::NothingToRedo)
         }
     }
     
@@ -239,3 +309,52 @@ impl CommandProcessor {
         &mut self.calculator
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ExpressionParser;
+    
+    fn define(processor: &mut CommandProcessor, definition: &str) {
+        let function = ExpressionParser::new().parse_function_definition(definition).unwrap();
+        processor.execute(Box::new(DefineFunctionCommand::new(function))).unwrap();
+    }
+    
+    fn call(processor: &CommandProcessor, input: &str) -> Result<f64, CalcError> {
+        let calculator = processor.get_calculator();
+        ExpressionParser::new().parse(input)?.evaluate_in(&calculator.context(&calculator.float_variables()))
+    }
+    
+    #[test]
+    fn defined_functions_can_be_called() {
+        let mut processor = CommandProcessor::new();
+        define(&mut processor, "f(x) = x^2 + 1");
+        define(&mut processor, "g(a, b) = f(a) * b");
+        assert_eq!(call(&processor, "g(2, 3)"), Ok(15.0));
+        assert!(matches!(call(&processor, "f(1, 2)"), Err(CalcError::WrongArgumentCount { .. })));
+    }
+    
+    #[test]
+    fn undoing_a_redefinition_brings_back_the_old_body() {
+        let mut processor = CommandProcessor::new();
+        define(&mut processor, "f(x) = x + 1");
+        define(&mut processor, "f(x) = x * 10");
+        assert_eq!(call(&processor, "f(2)"), Ok(20.0));
+        
+        processor.undo().unwrap();
+        assert_eq!(call(&processor, "f(2)"), Ok(3.0));
+        processor.redo().unwrap();
+        assert_eq!(call(&processor, "f(2)"), Ok(20.0));
+        
+        processor.undo().unwrap();
+        processor.undo().unwrap();
+        assert!(matches!(call(&processor, "f(2)"), Err(CalcError::UnknownFunction { .. })));
+    }
+    
+    #[test]
+    fn runaway_recursion_is_stopped() {
+        let mut processor = CommandProcessor::new();
+        define(&mut processor, "f(x) = f(x + 1)");
+        assert!(matches!(call(&processor, "f(0)"), Err(CalcError::RecursionLimit { .. })));
+    }
+}
@@ -0,0 +1,288 @@
+// complex.rs - Complex numbers, for square roots and logarithms of negative numbers
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+use crate::numeric::{Numeric, Value};
+
+// The name of √-1 in complex mode
+pub const IMAGINARY_UNIT: &str = "i";
+
+// How complex results are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComplexForm {
+    Rectangular, // 3 + 4i
+    Polar,       // 5 ∠ 0.9272952180016122, with the angle in radians
+}
+
+#[derive(Debug, Clone)]
+pub struct Complex {
+    re: f64,
+    im: f64,
+    form: ComplexForm,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64, form: ComplexForm) -> Self {
+        Self { re, im, form }
+    }
+    
+    fn with_parts(&self, re: f64, im: f64) -> Self {
+        Complex::new(re, im, self.form)
+    }
+    
+    fn real(&self, value: f64) -> Self {
+        self.with_parts(value, 0.0)
+    }
+    
+    pub fn is_real(&self) -> bool {
+        self.im == 0.0
+    }
+    
+    pub fn modulus(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+    
+    // In (-π, π]. Negating a real number leaves an imaginary part of -0, which
+    // would give -π for a negative number, so -0 counts as 0 here
+    pub fn argument(&self) -> f64 {
+        let im = if self.im == 0.0 { 0.0 } else { self.im };
+        im.atan2(self.re)
+    }
+    
+    fn finite(self) -> Option<Self> {
+        (self.re.is_finite() && self.im.is_finite()).then_some(self)
+    }
+}
+
+impl Numeric for Complex {
+    type Settings = ComplexForm;
+    
+    const NAME: &'static str = "complex";
+    const COMPLEX: bool = true;
+    
+    fn from_f64(value: f64, form: ComplexForm) -> Option<Self> {
+        Some(Complex::new(value, 0.0, form))
+    }
+    
+    fn from_i64(value: i64, form: ComplexForm) -> Self {
+        Complex::new(value as f64, 0.0, form)
+    }
+    
+    // NaN when there is an imaginary part
+    fn to_f64(&self) -> f64 {
+        if self.is_real() { self.re } else { f64::NAN }
+    }
+    
+    fn settings(&self) -> ComplexForm {
+        self.form
+    }
+    
+    fn into_value(self) -> Value {
+        Value::Complex(self)
+    }
+    
+    fn from_value(value: &Value, form: ComplexForm) -> Option<Self> {
+        match value {
+            Value::Complex(value) => Some(Complex { form, ..value.clone() }),
+            _ => None,
+        }
+    }
+    
+    fn named(name: &str, form: ComplexForm) -> Option<Self> {
+        (name == IMAGINARY_UNIT).then_some(Complex::new(0.0, 1.0, form))
+    }
+    
+    fn is_zero(&self) -> bool {
+        self.re == 0.0 && self.im == 0.0
+    }
+    
+    fn floor(&self) -> Self {
+        self.with_parts(self.re.floor(), self.im.floor())
+    }
+    
+    fn trunc(&self) -> Self {
+        self.with_parts(self.re.trunc(), self.im.trunc())
+    }
+    
+    // The root with a non-negative real part, as for real numbers
+    fn sqrt(&self) -> Option<Self> {
+        if self.is_real() && self.re >= 0.0 {
+            return self.real(self.re.sqrt()).finite();
+        }
+        let modulus = self.modulus();
+        let re = ((modulus + self.re) / 2.0).sqrt();
+        let im = ((modulus - self.re) / 2.0).sqrt();
+        self.with_parts(re, if self.im < 0.0 { -im } else { im }).finite()
+    }
+    
+    fn exp(&self) -> Option<Self> {
+        let scale = self.re.exp();
+        if self.is_real() {
+            return self.real(scale).finite();
+        }
+        self.with_parts(scale * self.im.cos(), scale * self.im.sin()).finite()
+    }
+    
+    // The principal logarithm, with an imaginary part in (-π, π]
+    fn ln(&self) -> Option<Self> {
+        self.with_parts(self.modulus().ln(), self.argument()).finite()
+    }
+    
+    fn sin(&self) -> Option<Self> {
+        self.with_parts(self.re.sin() * self.im.cosh(), self.re.cos() * self.im.sinh()).finite()
+    }
+    
+    fn cos(&self) -> Option<Self> {
+        self.with_parts(self.re.cos() * self.im.cosh(), -(self.re.sin() * self.im.sinh())).finite()
+    }
+    
+    fn re(&self) -> Self {
+        self.real(self.re)
+    }
+    
+    fn im(&self) -> Self {
+        self.real(self.im)
+    }
+    
+    fn abs(&self) -> Self {
+        self.real(self.modulus())
+    }
+    
+    fn arg(&self) -> Option<Self> {
+        Some(self.real(self.argument()))
+    }
+    
+    fn conj(&self) -> Self {
+        self.with_parts(self.re, -self.im)
+    }
+}
+
+impl PartialEq for Complex {
+    fn eq(&self, other: &Self) -> bool {
+        self.re == other.re && self.im == other.im
+    }
+}
+
+impl PartialOrd for Complex {
+    // Only real numbers are ordered
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.is_real() && other.is_real() {
+            self.re.partial_cmp(&other.re)
+        } else {
+            None
+        }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    
+    fn add(self, other: Complex) -> Complex {
+        self.with_parts(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    
+    fn sub(self, other: Complex) -> Complex {
+        self.with_parts(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    
+    fn mul(self, other: Complex) -> Complex {
+        // Real operands stay real, so ∞ * 2 doesn't pick up a NaN from ∞ * 0
+        if self.is_real() && other.is_real() {
+            return self.real(self.re * other.re);
+        }
+        self.with_parts(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    
+    // Callers check for a zero divisor first
+    fn div(self, other: Complex) -> Complex {
+        if other.is_real() {
+            return self.with_parts(self.re / other.re, self.im / other.re);
+        }
+        let scale = other.re * other.re + other.im * other.im;
+        self.with_parts(
+            (self.re * other.re + self.im * other.im) / scale,
+            (self.im * other.re - self.re * other.im) / scale,
+        )
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+    
+    fn neg(self) -> Complex {
+        self.with_parts(-self.re, -self.im)
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.form == ComplexForm::Polar {
+            return write!(f, "{} ∠ {}", self.modulus(), self.argument());
+        }
+        
+        let imaginary = match self.im.abs() {
+            1.0 => IMAGINARY_UNIT.to_string(),
+            magnitude => format!("{}{}", magnitude, IMAGINARY_UNIT),
+        };
+        match (self.re, self.im) {
+            (_, 0.0) => write!(f, "{}", self.re),
+            (0.0, im) if im < 0.0 => write!(f, "-{}", imaginary),
+            (0.0, _) => write!(f, "{}", imaginary),
+            (re, im) if im < 0.0 => write!(f, "{} - {}", re, imaginary),
+            (re, _) => write!(f, "{} + {}", re, imaginary),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::evaluate_in;
+    
+    #[test]
+    fn complex_results() {
+        let cases = [
+            ("sqrt(-4)", "2i"),
+            ("i^2", "-1"),
+            ("(1+2i)*(3-i)", "5 + 5i"),
+            ("1/i", "-i"),
+            ("log(-1, e)", "3.141592653589793i"),
+            ("(-8)^(1/3)", "1 + 1.732050807568877i"), // The principal root, not -2
+            ("i^i", "0.20787957635076193"),
+            ("re(3+4i)", "3"),
+            ("im(3+4i)", "4"),
+            ("abs(3+4i)", "5"),
+            ("arg(-1)", "3.141592653589793"),
+            ("conj(3+4i)", "3 - 4i"),
+            ("sqrt(4) + 1", "3"),
+        ];
+        
+        for (input, expected) in cases {
+            assert_eq!(evaluate_in::<Complex>(input, ComplexForm::Rectangular).unwrap().to_string(), expected, "{}", input);
+        }
+        assert_eq!(evaluate_in::<Complex>("3+4i", ComplexForm::Polar).unwrap().to_string(), "5 ∠ 0.9272952180016122");
+        assert_eq!(evaluate_in::<Complex>("-4", ComplexForm::Polar).unwrap().to_string(), "4 ∠ 3.141592653589793");
+        
+        // Only real numbers are ordered
+        for input in ["i < 1", "max(i, 2)", "1 / 0", "log(0, 10)"] {
+            assert!(evaluate_in::<Complex>(input, ComplexForm::Rectangular).is_err(), "{} should fail", input);
+        }
+        assert_eq!(evaluate_in::<Complex>("2 < 3", ComplexForm::Rectangular).unwrap().to_string(), "1");
+    }
+}
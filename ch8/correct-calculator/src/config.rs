@@ -1,7 +1,10 @@
 // config.rs - Configuration (alternative to Singleton from Chapter 6)
 
-use std::sync::OnceLock;
-use crate::token::NumberFormat;
+use std::sync::{OnceLock, RwLock, PoisonError};
+use crate::token::{NumberFormat, ModuloConvention};
+use crate::error::CalcError;
+use crate::numeric::NumericBackend;
+use crate::formatter::MAX_SIGNIFICANT_DIGITS;
 
 #[derive(Debug, Clone, Copy)]
 pub enum AngleMode {
@@ -9,11 +12,42 @@ pub enum AngleMode {
     Radians,
 }
 
+// Character between the integer and fractional parts of a number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecimalMark {
+    Dot,   // 1,234.5
+    Comma, // 1.234,5; function arguments are then separated by `;`
+}
+
+impl DecimalMark {
+    pub fn symbol(&self) -> char {
+        match self {
+            DecimalMark::Dot => '.',
+            DecimalMark::Comma => ',',
+        }
+    }
+    
+    // Thousands separator that goes with this mark
+    pub fn group_separator(&self) -> char {
+        match self {
+            DecimalMark::Dot => ',',
+            DecimalMark::Comma => '.',
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CalculatorConfig {
-    pub precision: u32,
+    pub precision: u32, // Significant digits in displayed results
     pub angle_mode: AngleMode,
     pub notation: NumberFormat,
+    pub max_recursion_depth: usize, // Nesting limit for user-defined function calls
+    pub implicit_multiplication: bool, // Read `2x` as `2 * x`; strict users can turn this off
+    pub modulo_convention: ModuloConvention,
+    pub allow_constant_shadowing: bool, // Let `pi = 3` hide the built-in constant
+    pub decimal_mark: DecimalMark,
+    pub thousands_separator: bool, // Group displayed digits, as in 1,234,567
+    pub numeric: NumericBackend, // Number type expressions are evaluated in
 }
 
 impl Default for CalculatorConfig {
@@ -22,6 +56,14 @@ impl Default for CalculatorConfig {
             precision: 10,
             angle_mode: AngleMode::Radians,
             notation: NumberFormat::Decimal,
+            max_recursion_depth: DEFAULT_RECURSION_DEPTH,
+            implicit_multiplication: true,
+            // Floored, so that a == (a // b) * b + a % b
+            modulo_convention: ModuloConvention::Floored,
+            allow_constant_shadowing: false,
+            decimal_mark: DecimalMark::Dot,
+            thousands_separator: false,
+            numeric: NumericBackend::Float,
         }
     }
 }
@@ -33,33 +75,136 @@ impl CalculatorConfig {
             precision: 15,
             angle_mode: AngleMode::Radians,
             notation: NumberFormat::Scientific,
+            max_recursion_depth: DEFAULT_RECURSION_DEPTH,
+            implicit_multiplication: true,
+            modulo_convention: ModuloConvention::Floored,
+            allow_constant_shadowing: false,
+            decimal_mark: DecimalMark::Dot,
+            thousands_separator: false,
+            numeric: NumericBackend::Float,
         }
     }
-
+    
     pub fn engineering() -> Self {
         Self {
             notation: NumberFormat::Engineering,
             ..Default::default()
         }
     }
+    
+    // Changes one setting by name, as in `/set implicit_multiplication off`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), CalcError> {
+        match name {
+            "implicit_multiplication" => self.implicit_multiplication = switch(name, value)?,
+            "allow_constant_shadowing" => self.allow_constant_shadowing = switch(name, value)?,
+            "thousands_separator" => self.thousands_separator = switch(name, value)?,
+            "decimal_mark" => {
+                self.decimal_mark = match value {
+                    "dot" => DecimalMark::Dot,
+                    "comma" => DecimalMark::Comma,
+                    _ => return Err(CalcError::usage("/set decimal_mark [dot|comma]")),
+                };
+            },
+            "precision" => {
+                self.precision = match value.parse() {
+                    Ok(precision @ 1..=MAX_SIGNIFICANT_DIGITS) => precision,
+                    _ => return Err(CalcError::usage(format!("/set precision [1-{}]", MAX_SIGNIFICANT_DIGITS))),
+                };
+            },
+            "notation" => {
+                self.notation = match NOTATIONS.iter().find(|(notation, _)| *notation == value) {
+                    Some((_, format)) => *format,
+                    None => {
+                        let names: Vec<_> = NOTATIONS.iter().map(|(notation, _)| *notation).collect();
+                        return Err(CalcError::usage(format!("/set notation [{}]", names.join("|"))));
+                    },
+                };
+            },
+            "modulo_convention" => {
+                self.modulo_convention = match value {
+                    "truncated" => ModuloConvention::Truncated,
+                    "floored" => ModuloConvention::Floored,
+                    "euclidean" => ModuloConvention::Euclidean,
+                    _ => return Err(CalcError::usage("/set modulo_convention [truncated|floored|euclidean]")),
+                };
+            },
+            _ => return Err(CalcError::UnknownSetting { name: name.to_string() }),
+        }
+        Ok(())
+    }
+    
+    // The settings `set` can change, with their current values
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("precision", self.precision.to_string()),
+            ("notation", notation_name(self.notation).to_string()),
+            ("implicit_multiplication", on_off(self.implicit_multiplication)),
+            ("modulo_convention", format!("{:?}", self.modulo_convention).to_lowercase()),
+            ("allow_constant_shadowing", on_off(self.allow_constant_shadowing)),
+            ("decimal_mark", format!("{:?}", self.decimal_mark).to_lowercase()),
+            ("thousands_separator", on_off(self.thousands_separator)),
+        ]
+    }
+}
+
+fn switch(name: &str, value: &str) -> Result<bool, CalcError> {
+    match value {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(CalcError::usage(format!("/set {} [on|off]", name))),
+    }
+}
+
+// Names `/set notation` accepts. An SI prefix is picked to suit each result
+const NOTATIONS: &[(&str, NumberFormat)] = &[
+    ("decimal", NumberFormat::Decimal),
+    ("scientific", NumberFormat::Scientific),
+    ("engineering", NumberFormat::Engineering),
+    ("grouped", NumberFormat::Grouped),
+    ("hexadecimal", NumberFormat::Hexadecimal),
+    ("binary", NumberFormat::Binary),
+    ("octal", NumberFormat::Octal),
+    ("si", NumberFormat::SiPrefix('k')),
+];
+
+fn notation_name(notation: NumberFormat) -> &'static str {
+    match notation {
+        NumberFormat::SiPrefix(_) => "si",
+        other => NOTATIONS.iter().find(|(_, format)| *format == other).map_or("decimal", |(name, _)| name),
+    }
+}
+
+fn on_off(enabled: bool) -> String {
+    if enabled { "on" } else { "off" }.to_string()
 }
 
 // Constants
 pub const DEFAULT_PRECISION: u32 = 10;
 pub const MAX_PRECISION: u32 = 100;
+pub const DEFAULT_RECURSION_DEPTH: usize = 100;
 
-// If we need a global configuration (alternative to Singleton)
-static CONFIG: OnceLock<CalculatorConfig> = OnceLock::new();
+// If we need a global configuration (alternative to Singleton).
+// Behind a lock, so `/set` can change it while the REPL runs
+static CONFIG: OnceLock<RwLock<CalculatorConfig>> = OnceLock::new();
 
-pub fn get_global_config() -> &'static CalculatorConfig {
+fn global_config() -> &'static RwLock<CalculatorConfig> {
     CONFIG.get_or_init(|| {
         // In a real application, this might load from a file or environment
-        CalculatorConfig::default()
+        RwLock::new(CalculatorConfig::default())
     })
 }
 
+// A copy of the current settings; later changes aren't seen through it
+pub fn get_global_config() -> CalculatorConfig {
+    global_config().read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+pub fn update_global_config<T>(update: impl FnOnce(&mut CalculatorConfig) -> T) -> T {
+    update(&mut global_config().write().unwrap_or_else(PoisonError::into_inner))
+}
+
 // Thread-safe calculator with shared config
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 pub struct CalculatorPool {
     shared_config: Arc<CalculatorConfig>,
@@ -74,8 +219,73 @@ impl CalculatorPool {
             _calculators: Vec::new(),
         }
     }
-
+    
     pub fn get_config(&self) -> Arc<CalculatorConfig> {
         Arc::clone(&self.shared_config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn settings_change_by_name() {
+        let mut config = CalculatorConfig::default();
+        config.set("implicit_multiplication", "off").unwrap();
+        assert!(!config.implicit_multiplication);
+        assert!(config.settings().contains(&("implicit_multiplication", "off".to_string())));
+    }
+    
+    #[test]
+    fn modulo_convention_is_settable() {
+        let mut config = CalculatorConfig::default();
+        config.set("modulo_convention", "truncated").unwrap();
+        assert_eq!(config.modulo_convention, ModuloConvention::Truncated);
+        assert!(config.settings().contains(&("modulo_convention", "truncated".to_string())));
+        assert!(config.set("modulo_convention", "rounded").is_err());
+    }
+    
+    #[test]
+    fn precision_and_notation_are_settable() {
+        let mut config = CalculatorConfig::default();
+        config.set("precision", "4").unwrap();
+        config.set("notation", "si").unwrap();
+        assert_eq!(config.precision, 4);
+        assert!(matches!(config.notation, NumberFormat::SiPrefix(_)));
+        assert!(config.settings().contains(&("notation", "si".to_string())));
+        
+        config.set("notation", "hexadecimal").unwrap();
+        assert_eq!(config.notation, NumberFormat::Hexadecimal);
+        assert!(config.set("precision", "0").is_err());
+        assert!(config.set("precision", "18").is_err());
+        assert!(config.set("notation", "roman").is_err());
+        assert_eq!(config.precision, 4);
+    }
+    
+    #[test]
+    fn constant_shadowing_is_settable() {
+        let mut config = CalculatorConfig::default();
+        config.set("allow_constant_shadowing", "on").unwrap();
+        assert!(config.allow_constant_shadowing);
+    }
+    
+    #[test]
+    fn locale_is_settable() {
+        let mut config = CalculatorConfig::default();
+        config.set("decimal_mark", "comma").unwrap();
+        config.set("thousands_separator", "on").unwrap();
+        assert_eq!(config.decimal_mark, DecimalMark::Comma);
+        assert!(config.thousands_separator);
+        assert!(config.settings().contains(&("decimal_mark", "comma".to_string())));
+        assert!(config.set("decimal_mark", "space").is_err());
+    }
+    
+    #[test]
+    fn unknown_settings_and_values_are_errors() {
+        let mut config = CalculatorConfig::default();
+        assert!(matches!(config.set("colour", "on"), Err(CalcError::UnknownSetting { .. })));
+        assert!(matches!(config.set("implicit_multiplication", "maybe"), Err(CalcError::Usage { .. })));
+        assert!(config.implicit_multiplication);
+    }
+}
@@ -0,0 +1,97 @@
+// constants.rs - Read-only table of built-in constants
+
+use std::collections::HashMap;
+use crate::error::CalcError;
+use crate::config::get_global_config;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Constant {
+    pub name: &'static str,
+    pub value: f64,
+    pub description: &'static str,
+}
+
+impl Constant {
+    const fn new(name: &'static str, value: f64, description: &'static str) -> Self {
+        Self { name, value, description }
+    }
+}
+
+pub const MATH_CONSTANTS: &[Constant] = &[
+    Constant::new("pi", std::f64::consts::PI, "ratio of a circle's circumference to its diameter"),
+    Constant::new("e", std::f64::consts::E, "base of the natural logarithm"),
+    Constant::new("tau", std::f64::consts::TAU, "2 pi, one full turn in radians"),
+    Constant::new("phi", 1.618_033_988_749_895, "golden ratio"),
+];
+
+// CODATA 2018 values in SI units; all but G are exact by definition since the 2019 SI
+pub const PHYSICAL_CONSTANTS: &[Constant] = &[
+    Constant::new("c", 299_792_458.0, "speed of light in vacuum (m/s)"),
+    Constant::new("G", 6.674_30e-11, "Newtonian constant of gravitation (m^3/(kg s^2))"),
+    Constant::new("h", 6.626_070_15e-34, "Planck constant (J s)"),
+    Constant::new("k_B", 1.380_649e-23, "Boltzmann constant (J/K)"),
+    Constant::new("N_A", 6.022_140_76e23, "Avogadro constant (1/mol)"),
+];
+
+// Every constant, math first
+pub fn all() -> impl Iterator<Item = &'static Constant> {
+    MATH_CONSTANTS.iter().chain(PHYSICAL_CONSTANTS.iter())
+}
+
+pub fn lookup(name: &str) -> Option<&'static Constant> {
+    all().find(|constant| constant.name == name)
+}
+
+// Value of `name` as an expression sees it. Constants take priority, unless the
+// configuration allows variables to shadow them
+pub fn resolve(name: &str, variables: &HashMap<String, f64>) -> Option<f64> {
+    resolve_as(name, variables, |constant| constant.value)
+}
+
+// The same lookup for variables holding other kinds of value, such as intervals;
+// `from_constant` turns a constant into one
+pub fn resolve_as<T: Clone>(name: &str, variables: &HashMap<String, T>, from_constant: impl Fn(&Constant) -> T) -> Option<T> {
+    let variable = variables.get(name).cloned();
+    
+    match lookup(name) {
+        Some(_) if get_global_config().allow_constant_shadowing && variable.is_some() => variable,
+        Some(constant) => Some(from_constant(constant)),
+        None => variable,
+    }
+}
+
+// Rejects assignments, and parameter names, that would hide a constant
+pub fn check_assignable(name: &str) -> Result<(), CalcError> {
+    if lookup(name).is_some() && !get_global_config().allow_constant_shadowing {
+        Err(CalcError::ReadOnlyConstant { name: name.to_string() })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn constants_are_looked_up_by_name() {
+        assert_eq!(lookup("tau").map(|constant| constant.value), Some(std::f64::consts::TAU));
+        assert_eq!(lookup("k_B").map(|constant| constant.value), Some(1.380_649e-23));
+        assert!(lookup("Pi").is_none());
+        assert_eq!(all().count(), MATH_CONSTANTS.len() + PHYSICAL_CONSTANTS.len());
+    }
+    
+    #[test]
+    fn constants_win_over_variables_by_default() {
+        let variables = HashMap::from([("pi".to_string(), 3.0), ("x".to_string(), 2.0)]);
+        assert_eq!(resolve("pi", &variables), Some(std::f64::consts::PI));
+        assert_eq!(resolve("x", &variables), Some(2.0));
+        assert_eq!(resolve("y", &variables), None);
+    }
+    
+    #[test]
+    fn constants_are_read_only_by_default() {
+        assert!(matches!(check_assignable("e"), Err(CalcError::ReadOnlyConstant { .. })));
+        assert_eq!(check_assignable("energy"), Ok(()));
+    }
+}
@@ -0,0 +1,235 @@
+// cse.rs - Common-subexpression elimination: expressions hash-consed into a DAG
+// where every distinct subexpression is one node, evaluated at most once
+
+use std::collections::{HashMap, HashSet};
+use crate::expression::{Expr, Callee, EvaluationContext, is_true, enter_user_function, run_user_function};
+use crate::token::{Operator, PostfixOperator, Function};
+use crate::error::CalcError;
+use crate::iterator::find_variable_nodes;
+
+// Index of a node in its `Dag`
+pub type NodeId = usize;
+
+// One distinct subexpression: the node with its operands blanked out to 0,
+// and the ids of those operands in `Expr::children` order
+struct Node {
+    shape: Expr,
+    children: Vec<NodeId>,
+}
+
+// Equal subtrees, wherever they appear, are the same node. Operands always
+// come before the nodes that use them
+pub struct Dag {
+    nodes: Vec<Node>,
+    root: NodeId,
+}
+
+impl Dag {
+    pub fn new(expr: &Expr) -> Self {
+        let mut dag = Dag { nodes: Vec::new(), root: 0 };
+        dag.root = dag.intern(expr, &mut HashMap::new());
+        dag
+    }
+    
+    // Hash-consing: a node is looked up by its shape and its operands' ids, so
+    // equal subtrees get equal ids without ever comparing them whole
+    fn intern(&mut self, expr: &Expr, ids: &mut HashMap<(Expr, Vec<NodeId>), NodeId>) -> NodeId {
+        // A custom node may use its operands however it likes, so it is only shared whole
+        let key = match expr {
+            Expr::Custom(_) => (expr.clone(), Vec::new()),
+            _ => {
+                let children = expr.children().map(|child| self.intern(child, ids)).collect();
+                (expr.map_children(|_| Expr::number(0.0)), children)
+            },
+        };
+        if let Some(&id) = ids.get(&key) {
+            return id;
+        }
+        
+        // The first occurrence is kept, so errors point where the tree's would
+        let id = self.nodes.len();
+        self.nodes.push(Node { shape: key.0.clone(), children: key.1.clone() });
+        ids.insert(key, id);
+        id
+    }
+    
+    // Distinct subexpressions
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+    
+    pub fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
+        self.evaluate_in(&EvaluationContext::new(variables, &HashMap::new(), ()))
+    }
+    
+    // Same result as `Expr::evaluate_in`, but a node's value is reused once known.
+    // Nodes are evaluated lazily, so `&&`, `||` and `if` still skip what they skip
+    pub fn evaluate_in(&self, context: &EvaluationContext) -> Result<f64, CalcError> {
+        // Only values are kept, since the first error ends the evaluation
+        let mut values = vec![None; self.nodes.len()];
+        self.value(self.root, context, &mut values)
+    }
+    
+    fn value(&self, id: NodeId, context: &EvaluationContext, values: &mut [Option<f64>]) -> Result<f64, CalcError> {
+        if let Some(value) = values[id] {
+            return Ok(value);
+        }
+        let value = self.compute(id, context, values)?;
+        values[id] = Some(value);
+        Ok(value)
+    }
+    
+    fn compute(&self, id: NodeId, context: &EvaluationContext, values: &mut [Option<f64>]) -> Result<f64, CalcError> {
+        let node = &self.nodes[id];
+        let mut operand = |index: usize| self.value(node.children[index], context, values);
+        
+        match &node.shape {
+            Expr::Binary { operator, span, .. } => {
+                let left_val = operand(0)?;
+                
+                // `&&` and `||` short-circuit, so the right side may be an error when unused
+                match operator {
+                    Operator::And if !is_true(left_val) => return Ok(0.0),
+                    Operator::Or if is_true(left_val) => return Ok(1.0),
+                    _ => {},
+                }
+                
+                let right_val = operand(1)?;
+                let percent = matches!(self.nodes[node.children[1]].shape, Expr::Postfix { operator: PostfixOperator::Percent, .. });
+                operator.apply(left_val, right_val, percent, *span)
+            },
+            Expr::Unary { operator, .. } => Ok(operator.apply(operand(0)?)),
+            Expr::Postfix { operator, span, .. } => operator.apply(operand(0)?, *span),
+            // Only the chosen branch of `if` is evaluated
+            Expr::Call { function: Callee::Builtin(Function::If), .. } if node.children.len() == 3 => {
+                let condition = operand(0)?;
+                operand(if is_true(condition) { 1 } else { 2 })
+            },
+            Expr::Call { function: Callee::Builtin(function), span, .. } => {
+                let args = (0..node.children.len()).map(operand).collect::<Result<Vec<f64>, CalcError>>()?;
+                function.apply(&args, *span)
+            },
+            Expr::Call { function: Callee::User(name), span, .. } => {
+                let function = enter_user_function(name, node.children.len(), *span, context)?;
+                let args = (0..node.children.len()).map(operand).collect::<Result<Vec<f64>, CalcError>>()?;
+                run_user_function(function, args, *span, context)
+            },
+            // Numbers, variables, errors and custom nodes evaluate as they are
+            _ => node.shape.evaluate_in(context),
+        }
+    }
+    
+    // Every node used more than once, other than a number or variable, becomes a
+    // temporary such as `t1 = a + b`. Temporaries come before those using them;
+    // the expression is returned rewritten in terms of them
+    pub fn temporaries(&self) -> (Vec<(String, Expr)>, Expr) {
+        let mut uses = vec![0; self.nodes.len()];
+        for node in &self.nodes {
+            for &child in &node.children {
+                uses[child] += 1;
+            }
+        }
+        
+        // Names already in the expression are skipped
+        let taken: HashSet<&str> = self.nodes.iter()
+            .flat_map(|node| find_variable_nodes(&node.shape))
+            .filter_map(|node| match node {
+                Expr::Variable { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let mut names = (1..).map(|n| format!("t{}", n)).filter(|name| !taken.contains(name.as_str()));
+        
+        let mut temporaries = Vec::new();
+        let mut named = vec![None; self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            if uses[id] < 2 || matches!(node.shape, Expr::Number { .. } | Expr::Variable { .. }) {
+                continue;
+            }
+            let name = names.next().unwrap_or_default();
+            temporaries.push((name.clone(), self.rebuild(id, &named)));
+            named[id] = Some(name);
+        }
+        (temporaries, self.rebuild(self.root, &named))
+    }
+    
+    // The subtree at `id`, with named nodes below it written as their names
+    fn rebuild(&self, id: NodeId, named: &[Option<String>]) -> Expr {
+        let node = &self.nodes[id];
+        if node.children.is_empty() {
+            return node.shape.clone();
+        }
+        let mut children = node.children.iter();
+        node.shape.map_children(|_| {
+            let child = *children.next().expect("one id per operand");
+            match &named[child] {
+                Some(name) => Expr::variable(name.clone()),
+                None => self.rebuild(child, named),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ExpressionParser;
+    use crate::expression::UserFunction;
+    use crate::test_support::{check_expressions, mismatch, random_expressions, variables};
+    
+    #[test]
+    fn shared_evaluation_matches_the_tree() {
+        let variables = variables();
+        
+        // Each sample also appears three times over, so it is shared for certain
+        let inputs = random_expressions().flat_map(|input| [format!("({0}) * ({0}) + sin({0})", input), input]);
+        check_expressions(inputs, |expr| mismatch("shared", &expr.evaluate(&variables), &Dag::new(expr).evaluate(&variables)));
+    }
+    
+    #[test]
+    fn repeated_subexpressions_become_temporaries() {
+        let parser = ExpressionParser::new();
+        let cases = [
+            ("(a+b)*(a+b) + sin(a+b)", vec!["t1 = a + b"], "t1 * t1 + sin(t1)"),
+            ("(t1 + 1) * (t1 + 1)", vec!["t2 = t1 + 1"], "t2 * t2"),
+            ("sqrt(x^2 + 1) / (x^2 + 1)", vec!["t1 = x ^ 2 + 1"], "sqrt(t1) / t1"),
+            ("f(x*y) - f(x*y)^2", vec!["t1 = f(x * y)"], "t1 - t1 ^ 2"),
+            ("(a*b + c) * (a*b + c) + a*b", vec!["t1 = a * b", "t2 = t1 + c"], "t2 * t2 + t1"),
+            ("x + x + 1", vec![], "x + x + 1"),
+        ];
+        
+        for (input, expected, rewritten) in cases {
+            let dag = Dag::new(&parser.parse(input).unwrap());
+            let (temporaries, expr) = dag.temporaries();
+            let temporaries: Vec<String> = temporaries.iter().map(|(name, value)| format!("{} = {}", name, value)).collect();
+            assert_eq!(temporaries, expected, "temporaries of {}", input);
+            assert_eq!(expr.to_string(), rewritten, "{} rewritten", input);
+        }
+    }
+    
+    #[test]
+    fn shared_user_functions_match_the_tree() {
+        let parser = ExpressionParser::new();
+        let variables = variables();
+        let functions: HashMap<String, UserFunction> = [
+            "f(x) = x^2 + 1",
+            "fact(n) = if(n <= 1, 1, n * fact(n - 1))",
+            "r(n) = r(n - 1)",
+            "h(t) = t * y",
+            "k(y) = h(2)",
+            "bad(t) = t / 0 + missing",
+        ]
+        .iter()
+        .map(|definition| parser.parse_function_definition(definition).unwrap())
+        .map(|function| (function.name.clone(), function))
+        .collect();
+        
+        let inputs = ["f(x) / f(x) + fact(f(x))", "k(5) + h(1) * k(5)", "r(1)", "0 && r(1)", "f(1, 2)", "bad(x) + bad(x)"];
+        for input in inputs {
+            let expr = parser.parse(input).unwrap();
+            let context = EvaluationContext::new(&variables, &functions, ()).with_max_depth(20);
+            let (tree, shared) = (expr.evaluate_in(&context), Dag::new(&expr).evaluate_in(&context));
+            assert_eq!(mismatch("shared", &tree, &shared), None, "`{}`", input);
+        }
+    }
+}
@@ -0,0 +1,583 @@
+// decimal.rs - Decimal floating point with a chosen number of significant
+// digits, for results more precise than an f64's 17
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+use crate::bigint::BigInt;
+use crate::constants::Constant;
+use crate::numeric::{Numeric, Value, decimal_parts, multiply_out};
+
+// Extra digits carried inside functions, so that their results are right to
+// the last requested digit in all but rare cases
+const GUARD_DIGITS: u32 = 10;
+
+// sin and cos give up on arguments with more digits than this before the
+// decimal point, since reducing them by 2π needs as many more digits of π
+const MAX_TRIG_MAGNITUDE: i64 = 1_000;
+
+// exp gives up on arguments larger than this, whose results' exponents
+// would not fit
+const MAX_EXP_ARGUMENT: f64 = 1e17;
+
+// Largest exponent a product or quotient may have, well inside an i64 so
+// that rounding and comparing values near it cannot overflow
+const MAX_EXPONENT: i64 = i64::MAX / 4;
+
+// mantissa × 10^exponent, rounded half to even to `precision` significant
+// digits. Trailing zeros are stripped, so equal values are equal structurally
+#[derive(Debug, Clone)]
+pub struct Decimal {
+    mantissa: BigInt,
+    exponent: i64,
+    precision: u32,
+}
+
+impl Decimal {
+    pub fn new(mut mantissa: BigInt, mut exponent: i64, precision: u32) -> Self {
+        let excess = mantissa.digits() as i64 - precision as i64;
+        if excess > 0 {
+            let scale = BigInt::pow10(excess as usize);
+            let (quotient, remainder) = mantissa.div_rem(&scale);
+            let twice = &remainder.abs() * &BigInt::from(2);
+            let away = match twice.cmp(&scale) {
+                Ordering::Greater => true,
+                Ordering::Equal => !quotient.is_even(),
+                Ordering::Less => false,
+            };
+            let unit = BigInt::from(if mantissa.is_negative() { -1 } else { 1 });
+            mantissa = if away { &quotient + &unit } else { quotient };
+            exponent += excess;
+        }
+        if mantissa.is_zero() {
+            return Self::zero(precision);
+        }
+        
+        let ten = BigInt::from(10);
+        loop {
+            let (quotient, remainder) = mantissa.div_rem(&ten);
+            if !remainder.is_zero() {
+                break;
+            }
+            mantissa = quotient;
+            exponent += 1;
+        }
+        Self { mantissa, exponent, precision }
+    }
+    
+    pub fn zero(precision: u32) -> Self {
+        Self { mantissa: BigInt::zero(), exponent: 0, precision }
+    }
+    
+    fn int(value: i64, precision: u32) -> Self {
+        Self::new(BigInt::from(value), 0, precision)
+    }
+    
+    // The same value rounded to another number of digits
+    pub fn with_precision(&self, precision: u32) -> Self {
+        Self::new(self.mantissa.clone(), self.exponent, precision)
+    }
+    
+    pub fn is_negative(&self) -> bool {
+        self.mantissa.is_negative()
+    }
+    
+    pub fn abs(&self) -> Self {
+        Self { mantissa: self.mantissa.abs(), ..self.clone() }
+    }
+    
+    // Power of ten of the leading digit, as in scientific notation
+    fn adjusted(&self) -> i64 {
+        self.exponent + self.mantissa.digits() as i64 - 1
+    }
+    
+    // sin and cos together
+    fn sin_cos(&self) -> Option<(Decimal, Decimal)> {
+        const HALVINGS: u32 = 4;
+        
+        let magnitude = self.adjusted().max(0);
+        if magnitude > MAX_TRIG_MAGNITUDE {
+            return None;
+        }
+        let precision = self.precision + GUARD_DIGITS;
+        
+        // x - 2πk needs π to as many more digits as x has before its decimal point
+        let reducing = precision + magnitude as u32;
+        let tau = pi(reducing) * Decimal::int(2, reducing);
+        let x = self.with_precision(reducing);
+        let half = Decimal::new(BigInt::from(5), -1, reducing);
+        let turns = (x.clone() / tau.clone() + half).floor();
+        let reduced = (x - tau * turns).with_precision(precision);
+        
+        // The series run on a sixteenth of the angle, then doubling undoes that
+        let angle = reduced / Decimal::int(1 << HALVINGS, precision);
+        let square = angle.clone() * angle.clone();
+        let (mut sin, mut cos) = (angle.clone(), Decimal::int(1, precision));
+        let (mut sin_term, mut cos_term) = (angle, Decimal::int(1, precision));
+        for n in 1.. {
+            sin_term = -(sin_term * square.clone()) / Decimal::int((2 * n) * (2 * n + 1), precision);
+            cos_term = -(cos_term * square.clone()) / Decimal::int((2 * n - 1) * (2 * n), precision);
+            if negligible(&sin_term, &sin, precision) && negligible(&cos_term, &cos, precision) {
+                break;
+            }
+            sin = sin + sin_term.clone();
+            cos = cos + cos_term.clone();
+        }
+        for _ in 0..HALVINGS {
+            let two = Decimal::int(2, precision);
+            (sin, cos) = (two.clone() * sin.clone() * cos.clone(), Decimal::int(1, precision) - two * sin.clone() * sin);
+        }
+        Some((sin.with_precision(self.precision), cos.with_precision(self.precision)))
+    }
+}
+
+// Whether adding `term` to `sum` can no longer change it at `precision` digits
+fn negligible(term: &Decimal, sum: &Decimal, precision: u32) -> bool {
+    term.is_zero() || (!sum.is_zero() && term.adjusted() < sum.adjusted() - precision as i64 - 1)
+}
+
+// π by Machin's formula, π = 16 atan(1/5) - 4 atan(1/239)
+fn pi(precision: u32) -> Decimal {
+    let working = precision + 2;
+    let one = Decimal::int(1, working);
+    let fifth = atan_series(one.clone() / Decimal::int(5, working), working);
+    let small = atan_series(one / Decimal::int(239, working), working);
+    (fifth * Decimal::int(16, working) - small * Decimal::int(4, working)).with_precision(precision)
+}
+
+// atan z = z - z^3/3 + z^5/5 - ..., for |z| well below 1
+fn atan_series(z: Decimal, precision: u32) -> Decimal {
+    let square = z.clone() * z.clone();
+    let (mut sum, mut power) = (z.clone(), z);
+    for k in 1.. {
+        power = -(power * square.clone());
+        let term = power.clone() / Decimal::int(2 * k + 1, precision);
+        if negligible(&term, &sum, precision) {
+            break;
+        }
+        sum = sum + term;
+    }
+    sum
+}
+
+// atan z, through atan z = ±π/2 - atan(1/z) when |z| > 1. Halving the angle
+// a few times first makes the series converge quickly
+fn atan(z: Decimal, precision: u32) -> Decimal {
+    const HALVINGS: u32 = 4;
+    
+    let one = Decimal::int(1, precision);
+    if z.abs() > one {
+        let half_pi = pi(precision) / Decimal::int(2, precision);
+        let inner = atan(one / z.clone(), precision);
+        return if z.is_negative() { -half_pi - inner } else { half_pi - inner };
+    }
+    
+    // tan(a / 2) = tan a / (1 + sqrt(1 + tan² a))
+    let mut z = z;
+    for _ in 0..HALVINGS {
+        let root = (one.clone() + z.clone() * z.clone()).sqrt().expect("1 + z² is positive");
+        z = z / (one.clone() + root);
+    }
+    atan_series(z, precision) * Decimal::int(1 << HALVINGS, precision)
+}
+
+// ln y for y near 1. Square roots first bring y within 1% of 1, where
+// ln y = 2 atanh((y - 1) / (y + 1)) converges quickly
+fn ln_reduced(y: Decimal, precision: u32) -> Decimal {
+    let precision = precision + 3; // Each root doubles the error in the result
+    let one = Decimal::int(1, precision);
+    let close = Decimal::new(BigInt::from(1), -2, precision);
+    let mut y = y.with_precision(precision);
+    let mut roots = 0;
+    while (y.clone() - one.clone()).abs() > close {
+        y = y.sqrt().expect("y is positive");
+        roots += 1;
+    }
+    
+    let z = (y.clone() - one.clone()) / (y + one);
+    let square = z.clone() * z.clone();
+    let (mut sum, mut power) = (z.clone(), z);
+    for k in 1.. {
+        power = power * square.clone();
+        let term = power.clone() / Decimal::int(2 * k + 1, precision);
+        if negligible(&term, &sum, precision) {
+            break;
+        }
+        sum = sum + term;
+    }
+    sum * Decimal::int(2 << roots, precision)
+}
+
+impl Numeric for Decimal {
+    type Settings = u32; // Significant digits
+    
+    const NAME: &'static str = "decimal";
+    
+    fn from_f64(value: f64, precision: u32) -> Option<Self> {
+        let (mantissa, exponent) = decimal_parts(value)?;
+        Some(Decimal::new(mantissa, exponent, precision))
+    }
+    
+    fn from_i64(value: i64, precision: u32) -> Self {
+        Decimal::int(value, precision)
+    }
+    
+    fn to_f64(&self) -> f64 {
+        format!("{}e{}", self.mantissa, self.exponent).parse().unwrap_or(f64::NAN)
+    }
+    
+    fn settings(&self) -> u32 {
+        self.precision
+    }
+    
+    fn into_value(self) -> Value {
+        Value::Decimal(self)
+    }
+    
+    fn from_value(value: &Value, precision: u32) -> Option<Self> {
+        match value {
+            Value::Decimal(value) => Some(value.with_precision(precision)),
+            _ => None,
+        }
+    }
+    
+    fn is_zero(&self) -> bool {
+        self.mantissa.is_zero()
+    }
+    
+    fn floor(&self) -> Self {
+        let truncated = self.trunc();
+        if truncated > *self {
+            truncated - Decimal::int(1, self.precision)
+        } else {
+            truncated
+        }
+    }
+    
+    fn trunc(&self) -> Self {
+        if self.exponent >= 0 {
+            self.clone()
+        } else if self.adjusted() < 0 {
+            Decimal::zero(self.precision)
+        } else {
+            let (whole, _) = self.mantissa.div_rem(&BigInt::pow10(self.exponent.unsigned_abs() as usize));
+            Decimal::new(whole, 0, self.precision)
+        }
+    }
+    
+    // Exactly rounded: the integer square root of the mantissa, scaled to an
+    // even exponent and twice the digits needed, is exact but for a remainder
+    fn sqrt(&self) -> Option<Self> {
+        if self.is_negative() {
+            return None;
+        }
+        if self.is_zero() {
+            return Some(self.clone());
+        }
+        
+        let mut shift = (2 * (self.precision as i64 + 2) - self.mantissa.digits() as i64).max(0);
+        if (self.exponent - shift) % 2 != 0 {
+            shift += 1;
+        }
+        let scaled = &self.mantissa * &BigInt::pow10(shift as usize);
+        let root = scaled.sqrt()?;
+        let exponent = (self.exponent - shift) / 2;
+        if &root * &root == scaled {
+            Some(Decimal::new(root, exponent, self.precision))
+        } else {
+            // A final 1 stands for the rest, so the result rounds the right way
+            let root = &(&root * &BigInt::from(10)) + &BigInt::from(1);
+            Some(Decimal::new(root, exponent - 1, self.precision))
+        }
+    }
+    
+    // exp x = exp(x / 2^k)^(2^k), with k large enough that the series for
+    // exp(x / 2^k) converges quickly. Each squaring doubles the error, so
+    // digits are added to make up for it
+    fn exp(&self) -> Option<Self> {
+        let x = self.to_f64();
+        if x.abs() > MAX_EXP_ARGUMENT {
+            return None;
+        }
+        let halvings = (x.abs() * 1000.0).log2().ceil().max(0.0) as u32;
+        let precision = self.precision + GUARD_DIGITS + halvings / 3 + 1;
+        
+        let scale = Decimal::new(BigInt::from(2).pow(halvings), 0, precision);
+        let reduced = self.with_precision(precision) / scale;
+        let (mut sum, mut term) = (Decimal::int(1, precision), Decimal::int(1, precision));
+        for n in 1.. {
+            term = term * reduced.clone() / Decimal::int(n, precision);
+            if negligible(&term, &sum, precision) {
+                break;
+            }
+            sum = sum + term.clone();
+        }
+        for _ in 0..halvings {
+            sum = sum.clone() * sum;
+        }
+        Some(sum.with_precision(self.precision))
+    }
+    
+    // x = y × 10^n, with y between 0.5 and 5 so that ln y and n ln 10 don't
+    // cancel, then ln x = ln y + n ln 10
+    fn ln(&self) -> Option<Self> {
+        if self.is_negative() || self.is_zero() {
+            return None;
+        }
+        let mut n = self.adjusted();
+        if self.mantissa.to_string().starts_with(['5', '6', '7', '8', '9']) {
+            n += 1;
+        }
+        let precision = self.precision + GUARD_DIGITS + n.unsigned_abs().to_string().len() as u32;
+        
+        let y = Decimal { exponent: self.exponent - n, precision, mantissa: self.mantissa.clone() };
+        let mut result = ln_reduced(y, precision);
+        if n != 0 {
+            result = result + ln_reduced(Decimal::int(10, precision), precision) * Decimal::int(n, precision);
+        }
+        Some(result.with_precision(self.precision))
+    }
+    
+    // Both logarithms with guard digits, so the quotient is rounded only once
+    fn log(&self, base: &Self) -> Option<Self> {
+        let working = self.precision.max(base.precision) + GUARD_DIGITS;
+        let quotient = self.with_precision(working).ln()? / base.with_precision(working).ln()?;
+        Some(quotient.with_precision(self.precision.max(base.precision)))
+    }
+    
+    fn sin(&self) -> Option<Self> {
+        self.sin_cos().map(|(sin, _)| sin)
+    }
+    
+    fn cos(&self) -> Option<Self> {
+        self.sin_cos().map(|(_, cos)| cos)
+    }
+    
+    fn atan2(&self, x: &Self) -> Option<Self> {
+        let precision = self.precision.max(x.precision);
+        let working = precision + GUARD_DIGITS;
+        let (y, x) = (self.with_precision(working), x.with_precision(working));
+        
+        let half_pi = || pi(working) / Decimal::int(2, working);
+        let angle = if x.is_zero() {
+            match y.mantissa.cmp(&BigInt::zero()) {
+                Ordering::Greater => half_pi(),
+                Ordering::Less => -half_pi(),
+                Ordering::Equal => Decimal::zero(working),
+            }
+        } else {
+            let angle = atan(y.clone() / x.clone(), working);
+            match (x.is_negative(), y.is_negative()) {
+                (true, true) => angle - pi(working),
+                (true, false) => angle + pi(working),
+                (false, _) => angle,
+            }
+        };
+        Some(angle.with_precision(precision))
+    }
+    
+    fn checked_mul(self, other: Decimal) -> Option<Decimal> {
+        let precision = self.precision.max(other.precision);
+        let exponent = self.exponent.checked_add(other.exponent).filter(|exponent| exponent.abs() <= MAX_EXPONENT)?;
+        Some(Decimal::new(&self.mantissa * &other.mantissa, exponent, precision))
+    }
+    
+    // Panics when dividing by zero, which callers check for first
+    fn checked_div(self, other: Decimal) -> Option<Decimal> {
+        let precision = self.precision.max(other.precision);
+        let shift = (precision as i64 + 2 + other.mantissa.digits() as i64 - self.mantissa.digits() as i64).max(0);
+        let exponent = self.exponent.checked_sub(other.exponent).filter(|exponent| exponent.abs() <= MAX_EXPONENT)? - shift;
+        let scaled = &self.mantissa * &BigInt::pow10(shift as usize);
+        let (quotient, remainder) = scaled.div_rem(&other.mantissa);
+        
+        Some(if remainder.is_zero() {
+            Decimal::new(quotient, exponent, precision)
+        } else {
+            // A final 1 stands for the remainder, so the result rounds the right way
+            let unit = BigInt::from(if quotient.is_negative() { -1 } else { 1 });
+            Decimal::new(&(&quotient * &BigInt::from(10)) + &unit, exponent - 1, precision)
+        })
+    }
+    
+    // With guard digits, so the result is rounded once rather than at every product
+    fn powi(&self, exponent: u32) -> Option<Self> {
+        multiply_out(self.with_precision(self.precision + GUARD_DIGITS), exponent).map(|power| power.with_precision(self.precision))
+    }
+    
+    fn factorial(n: u32, precision: u32) -> Self {
+        let working = precision + GUARD_DIGITS;
+        (1..=i64::from(n))
+            .fold(Decimal::int(1, working), |product, k| product * Decimal::int(k, working))
+            .with_precision(precision)
+    }
+    
+    // Mathematical constants are worked out to the full precision
+    fn constant(constant: &Constant, precision: u32) -> Self {
+        let working = precision + GUARD_DIGITS;
+        let value = match constant.name {
+            "pi" => pi(working),
+            "tau" => pi(working) * Decimal::int(2, working),
+            "e" => Decimal::int(1, working).exp().expect("e is finite"),
+            "phi" => {
+                let root = Decimal::int(5, working).sqrt().expect("5 is positive");
+                (Decimal::int(1, working) + root) / Decimal::int(2, working)
+            },
+            _ => return Decimal::from_f64(constant.value, precision).expect("constants are finite"),
+        };
+        value.with_precision(precision)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.mantissa == other.mantissa && self.exponent == other.exponent
+    }
+}
+
+impl PartialOrd for Decimal {
+    // Rounding never turns a non-zero difference into zero, so its sign decides
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let difference = self.clone() - other.clone();
+        Some(difference.mantissa.cmp(&BigInt::zero()))
+    }
+}
+
+impl Add for Decimal {
+    type Output = Decimal;
+    
+    fn add(self, other: Decimal) -> Decimal {
+        let precision = self.precision.max(other.precision);
+        if self.is_zero() {
+            return other.with_precision(precision);
+        }
+        if other.is_zero() {
+            return self.with_precision(precision);
+        }
+        
+        // An operand far below the other's last digit can only affect how the
+        // sum rounds, which any value that small of the same sign does alike
+        let (large, small) = if self.adjusted() >= other.adjusted() { (self, other) } else { (other, self) };
+        let below = large.adjusted() - precision as i64 - 3;
+        let small = if small.adjusted() < below {
+            let unit = BigInt::from(if small.is_negative() { -1 } else { 1 });
+            Decimal { mantissa: unit, exponent: below - 1, precision }
+        } else {
+            small
+        };
+        
+        let exponent = large.exponent.min(small.exponent);
+        let scaled = |value: &Decimal| &value.mantissa * &BigInt::pow10((value.exponent - exponent) as usize);
+        Decimal::new(&scaled(&large) + &scaled(&small), exponent, precision)
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+    
+    fn sub(self, other: Decimal) -> Decimal {
+        self + -other
+    }
+}
+
+impl Mul for Decimal {
+    type Output = Decimal;
+    
+    // Panics when the exponent is out of range, which `checked_mul` reports instead
+    fn mul(self, other: Decimal) -> Decimal {
+        self.checked_mul(other).expect("Decimal exponent out of range")
+    }
+}
+
+impl Div for Decimal {
+    type Output = Decimal;
+    
+    // Panics when dividing by zero, which callers check for first, or when the
+    // exponent is out of range, which `checked_div` reports instead
+    fn div(self, other: Decimal) -> Decimal {
+        self.checked_div(other).expect("Decimal exponent out of range")
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+    
+    fn neg(self) -> Decimal {
+        Decimal { mantissa: -&self.mantissa, ..self }
+    }
+}
+
+impl fmt::Display for Decimal {
+    // Positional unless that would need more digits than the precision, or
+    // many leading zeros; otherwise scientific, as in 1.5e-12
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let sign = if self.is_negative() { "-" } else { "" };
+        let digits = self.mantissa.abs().to_string();
+        let point = self.adjusted() + 1; // Digits before the decimal point
+        
+        if point > self.precision as i64 || point < -5 {
+            let (first, rest) = digits.split_at(1);
+            let rest = if rest.is_empty() { String::new() } else { format!(".{}", rest) };
+            write!(f, "{}{}{}e{}", sign, first, rest, point - 1)
+        } else if point <= 0 {
+            write!(f, "{}0.{}{}", sign, "0".repeat(point.unsigned_abs() as usize), digits)
+        } else if point as usize >= digits.len() {
+            write!(f, "{}{}{}", sign, digits, "0".repeat(point as usize - digits.len()))
+        } else {
+            let (whole, fraction) = digits.split_at(point as usize);
+            write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::evaluate_in;
+    use crate::error::CalcError;
+    
+    #[test]
+    fn decimal_results_honour_the_precision() {
+        // Expected digits from Python's decimal module and mpmath
+        let cases = [
+            ("1/3", "0.33333333333333333333333333333333333333333333333333"),
+            ("sqrt(2)", "1.4142135623730950488016887242096980785696718753769"),
+            ("pi", "3.1415926535897932384626433832795028841971693993751"),
+            ("e", "2.7182818284590452353602874713526624977572470937"),
+            ("log(2, e)", "0.69314718055994530941723212145817656807550013436024"), // Of e rounded to 50 digits
+            ("log(2, 10)", "0.30102999566398119521373889472449302676818988146211"),
+            ("log(8, 2)", "3"),
+            ("e^0.5", "1.6487212707001281468486507878141635716537761007101"),
+            ("sin(1)", "0.84147098480789650665250232163029899962256306079837"),
+            ("atan2(1, 1)", "0.78539816339744830961566084581987572104929234984378"),
+            ("0.1 + 0.2", "0.3"),
+            ("2^10000 / 2^9999", "2"),
+            ("1000!", "4.0238726007709377354370243392300398571937486421071e2567"),
+            ("1e-30", "1e-30"),
+        ];
+        
+        for (input, expected) in cases {
+            assert_eq!(evaluate_in::<Decimal>(input, 50).unwrap().to_string(), expected, "{}", input);
+        }
+        assert_eq!(evaluate_in::<Decimal>("1/3", 5).unwrap().to_string(), "0.33333");
+        assert_eq!(evaluate_in::<Decimal>("2/3", 5).unwrap().to_string(), "0.66667");
+        assert_eq!(evaluate_in::<Decimal>("log(8, 2)", 30).unwrap().to_string(), "3");
+        
+        // The same errors as in f64
+        for input in ["1 / 0", "sqrt(-1)", "log(0, 10)", "tan(pi / 2)", "(-8)^(1/3)", "x"] {
+            assert!(evaluate_in::<Decimal>(input, 50).is_err(), "{} should fail", input);
+        }
+    }
+    
+    #[test]
+    fn exponents_out_of_range_are_errors() {
+        let huge = "((((10^10000)^10000)^10000)^10000)"; // 1e10000000000000000
+        assert_eq!(evaluate_in::<Decimal>(&format!("{}^200", huge), 50).unwrap().to_string(), "1e2000000000000000000");
+        for input in [format!("({})^10000", huge), format!("{h}^200 * {h}^100", h = huge), format!("{h}^-200 / {h}^100", h = huge)] {
+            let result = evaluate_in::<Decimal>(&input, 50);
+            assert!(matches!(result, Err(CalcError::Unsupported { .. })), "{} gave {:?}", input, result);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// differential.rs - Differential tests: every parser must read an expression the same way
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use crate::parser::ExpressionParser;
+use crate::template::{ExpressionEvaluator, RecursiveDescentEvaluator};
+use crate::strategy::{EvaluationStrategy, RecursiveDescentStrategy, ShuntingYardStrategy, CustomSymbolTokenizer};
+use crate::operators::OperatorTable;
+use crate::token::Associativity;
+use crate::expression::Expr;
+use crate::test_support::{CASES, MAX_DEPTH, Rng, Sample, variables};
+use crate::error::CalcError;
+
+// What one parser made of an input. Parse errors all count as the same
+// outcome, since each parser words them its own way
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Rejected,
+    Parsed { tree: Expr, value: Result<String, String> },
+    Panicked,
+}
+
+fn outcome(parse: impl FnOnce() -> Result<Expr, CalcError>) -> Outcome {
+    let variables = variables();
+    
+    match panic::catch_unwind(AssertUnwindSafe(parse)) {
+        Ok(Ok(expression)) => Outcome::Parsed {
+            // Debug formatting, so NaN compares equal to NaN
+            value: expression.evaluate(&variables).map(|value| format!("{:?}", value)).map_err(|error| error.to_string()),
+            tree: expression,
+        },
+        Ok(Err(_)) => Outcome::Rejected,
+        Err(_) => Outcome::Panicked,
+    }
+}
+
+// Every parser's reading of `input`, with the operators in `operators`
+fn outcomes(input: &str, operators: &OperatorTable) -> Vec<(&'static str, Outcome)> {
+    let tokenizer = || Box::new(CustomSymbolTokenizer::new(operators));
+    vec![
+        ("ExpressionParser", outcome(|| ExpressionParser::new().with_operators(operators.clone()).parse(input))),
+        ("template::RecursiveDescentEvaluator", outcome(|| RecursiveDescentEvaluator::new().with_operators(operators.clone()).parse_input(input))),
+        ("strategy::RecursiveDescentStrategy", outcome(|| RecursiveDescentStrategy::new(tokenizer()).with_operators(operators.clone()).parse(input))),
+        ("strategy::ShuntingYardStrategy", outcome(|| ShuntingYardStrategy::new(tokenizer()).with_operators(operators.clone()).parse(input))),
+    ]
+}
+
+fn disagrees(input: &str, operators: &OperatorTable) -> bool {
+    let outcomes = outcomes(input, operators);
+    outcomes.iter().any(|(_, outcome)| *outcome != outcomes[0].1)
+}
+
+// Keeps taking the first simpler variation that still fails, until none does
+fn shrink(sample: Sample, fails: impl Fn(&Sample) -> bool) -> Sample {
+    let mut current = sample;
+    'search: loop {
+        for candidate in current.simpler() {
+            if fails(&candidate) {
+                current = candidate;
+                continue 'search;
+            }
+        }
+        return current;
+    }
+}
+
+fn report(input: &str, operators: &OperatorTable) -> String {
+    let mut lines = vec![format!("parsers disagree on `{}`:", input)];
+    for (name, outcome) in outcomes(input, operators) {
+        let outcome = match outcome {
+            Outcome::Parsed { tree, value } => format!("Parsed `{}` = {:?}", tree, value),
+            other => format!("{:?}", other),
+        };
+        lines.push(format!("  {:<36} {}", name, outcome));
+    }
+    lines.join("\n")
+}
+
+#[test]
+fn parsers_agree_on_random_expressions() {
+    // Quiet the panic hook while parsers that panic are being shrunk
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let operators = OperatorTable::new();
+    let failure = (1..=CASES).find_map(|seed| {
+        let sample = Sample::generate(&mut Rng::new(seed), MAX_DEPTH);
+        if !disagrees(&sample.to_string(), &operators) {
+            return None;
+        }
+        let minimal = shrink(sample, |candidate| disagrees(&candidate.to_string(), &operators));
+        Some(format!("seed {}: {}", seed, report(&minimal.to_string(), &operators)))
+    });
+    panic::set_hook(hook);
+    
+    if let Some(failure) = failure {
+        panic!("{}", failure);
+    }
+}
+
+#[test]
+fn parsers_agree_on_edge_cases() {
+    let inputs = [
+        "-2^2", "2^3^2", "2**-1", "1/2x", "2x^2", "-x!", "3!!", "10 % 3", "200 + 10% - 5", "10 % -3",
+        "!0 && 1 || 0", "1 < 2 == 1", "max(1, 2, 3)", "if(x > 1, 2, 1/0)", "clamp(-x, 0, 1)",
+        "2(3)(4)", "2sqrt(4)", "1_000 + 0xFF", "4.7k * 2",
+        "", "1 +", "(1", "1)", "()", "max()", "sin 5", "1 2", "f(1, 2", ",", "1, 2", "sin(1, 2)", "*1",
+    ];
+    
+    let operators = OperatorTable::new();
+    let failures: Vec<String> = inputs
+        .iter()
+        .filter(|input| disagrees(input, &operators))
+        .map(|input| report(input, &operators))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn parsers_agree_on_registered_operators() {
+    let mut operators = OperatorTable::new();
+    operators.register_infix("∥", 6, Associativity::Left, |a, b| Ok(a * b / (a + b))).unwrap();
+    operators.register_infix("±", 5, Associativity::Right, |a, b| Ok(a + b.abs())).unwrap();
+    operators.register_prefix("±", 8, |value| Ok(value.abs())).unwrap();
+    operators.register_prefix("√", 3, |value| Ok(value.sqrt())).unwrap();
+    operators.register_postfix("‰", 10, |value| Ok(value / 1000.0)).unwrap();
+    
+    let inputs = [
+        "6 ∥ 3", "1 + 6∥3 * 2", "6 ∥ 3 ∥ 2", "1 ± 2 ± 3", "1 - 2 ± 3 + 4", "±-2", "-±2^2", "2 ± ±3",
+        "√16 + 9", "2 * √16 + 9", "5‰ ± 1", "3!‰", "max(6 ∥ 3, ±-1)", "x ∥ (y ± z)",
+        "∥ 3", "6 ∥", "3 ‰ 2", "±", "√",
+    ];
+    
+    let failures: Vec<String> = inputs
+        .iter()
+        .filter(|input| disagrees(input, &operators))
+        .map(|input| report(input, &operators))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    
+    // The registered operators were really parsed, not rejected by every parser
+    assert_eq!(ExpressionParser::new().with_operators(operators).parse("1 ± -2 ∥ 4").unwrap().evaluate(&HashMap::new()), Ok(5.0));
+}
+
+#[test]
+fn shrinking_finds_the_smallest_failing_part() {
+    let sample = Sample::Binary(
+        Box::new(Sample::Call("max", vec![Sample::Variable("x"), Sample::Number("7")])),
+        "+",
+        Box::new(Sample::Group(Box::new(Sample::Binary(
+            Box::new(Sample::Number("2.5")),
+            "/",
+            Box::new(Sample::Variable("y")),
+        )))),
+    );
+    
+    let minimal = shrink(sample, |candidate| candidate.to_string().contains('/'));
+    assert_eq!(minimal.to_string(), "1 / 1");
+}
@@ -0,0 +1,282 @@
+// error.rs - Structured error type shared by every calculator module
+
+use std::fmt;
+use crate::token::{Span, Arity};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalcError {
+    // Evaluation errors
+    DivisionByZero { span: Option<Span> },
+    DomainError { function: String, argument: f64, span: Option<Span> },
+    UndefinedVariable { name: String, suggestion: Option<String>, span: Option<Span> },
+    UnknownFunction { name: String, suggestion: Option<String>, span: Option<Span> },
+    WrongArgumentCount { function: String, expected: Arity, found: usize, span: Option<Span> },
+    RecursionLimit { function: String, limit: usize, span: Option<Span> },
+    NotDifferentiable { what: String, span: Option<Span> },
+    Unsupported { what: String, mode: String, span: Option<Span> }, // Something an evaluation mode cannot handle
+    
+    // Parse errors
+    Syntax { span: Option<Span>, expected: String },
+    EmptyExpression,
+    InvalidNumber { text: String, base: String },
+    InvalidName { name: String },
+    ReadOnlyConstant { name: String },
+    
+    // Command and state errors
+    NothingToUndo,
+    NothingToRedo,
+    UnknownState { name: String },
+    UnknownMode { name: String },
+    UnknownBase { name: String },
+    UnknownCommand { name: String },
+    UnknownSetting { name: String },
+    Usage { usage: String },
+    Internal { message: String },
+}
+
+impl CalcError {
+    // Factory method for syntax errors found at a known location
+    pub fn syntax(span: Span, expected: impl Into<String>) -> Self {
+        CalcError::Syntax {
+            span: Some(span),
+            expected: expected.into(),
+        }
+    }
+    
+    pub fn usage(usage: impl Into<String>) -> Self {
+        CalcError::Usage { usage: usage.into() }
+    }
+    
+    pub fn internal(message: impl Into<String>) -> Self {
+        CalcError::Internal { message: message.into() }
+    }
+    
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CalcError::DivisionByZero { span }
+            | CalcError::DomainError { span, .. }
+            | CalcError::UndefinedVariable { span, .. }
+            | CalcError::UnknownFunction { span, .. }
+            | CalcError::WrongArgumentCount { span, .. }
+            | CalcError::RecursionLimit { span, .. }
+            | CalcError::NotDifferentiable { span, .. }
+            | CalcError::Unsupported { span, .. }
+            | CalcError::Syntax { span, .. } => *span,
+            _ => None,
+        }
+    }
+    
+    fn span_mut(&mut self) -> Option<&mut Option<Span>> {
+        match self {
+            CalcError::DivisionByZero { span }
+            | CalcError::DomainError { span, .. }
+            | CalcError::UndefinedVariable { span, .. }
+            | CalcError::UnknownFunction { span, .. }
+            | CalcError::WrongArgumentCount { span, .. }
+            | CalcError::RecursionLimit { span, .. }
+            | CalcError::NotDifferentiable { span, .. }
+            | CalcError::Unsupported { span, .. }
+            | CalcError::Syntax { span, .. } => Some(span),
+            _ => None,
+        }
+    }
+    
+    // Attaches a location if the error doesn't have one yet
+    pub fn with_span(mut self, new_span: Span) -> Self {
+        if let Some(span @ None) = self.span_mut() {
+            *span = Some(new_span);
+        }
+        self
+    }
+    
+    // Replaces the location, e.g. to point an error from a function body at the call
+    pub fn relocated(mut self, new_span: Option<Span>) -> Self {
+        if let Some(span) = self.span_mut() {
+            *span = new_span;
+        }
+        self
+    }
+    
+    // Spans from a sub-parse are relative to the substring; move them into the full line
+    pub fn shifted(mut self, offset: usize) -> Self {
+        if let Some(Some(span)) = self.span_mut() {
+            *span = span.shifted(offset);
+        }
+        self
+    }
+    
+    // Message followed by the source line with a `^~~~` underline, e.g.
+    //
+    //   Division by zero
+    //     1 + 2 / (x - x)
+    //         ^~~~~~~~~~~
+    pub fn render(&self, source: &str) -> String {
+        let span = match self.span() {
+            Some(span) if span.start <= source.len() => span,
+            _ => return self.to_string(),
+        };
+        
+        let end = span.end.clamp(span.start, source.len());
+        let column = source[..span.start].chars().count();
+        let width = source[span.start..end].chars().count().max(1);
+        
+        format!(
+            "{}\n  {}\n  {}^{}",
+            self,
+            source,
+            " ".repeat(column),
+            "~".repeat(width - 1),
+        )
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalcError::DivisionByZero { .. } => write!(f, "Division by zero"),
+            CalcError::DomainError { function, argument, .. } => {
+                write!(f, "{} is undefined for {}", function, argument)
+            },
+            CalcError::UndefinedVariable { name, suggestion, .. } => {
+                write!(f, "Undefined variable: {}", name)?;
+                write_suggestion(f, suggestion)
+            },
+            CalcError::UnknownFunction { name, suggestion, .. } => {
+                write!(f, "Unknown function: {}", name)?;
+                write_suggestion(f, suggestion)
+            },
+            CalcError::WrongArgumentCount { function, expected, found, .. } => {
+                write!(f, "{} expects {}, got {}", function, expected, found)
+            },
+            CalcError::RecursionLimit { function, limit, .. } => {
+                write!(f, "{} exceeded the recursion limit of {} calls", function, limit)
+            },
+            CalcError::NotDifferentiable { what, .. } => write!(f, "Cannot differentiate {}", what),
+            CalcError::Unsupported { what, mode, .. } => write!(f, "{} is not supported in {} mode", what, mode),
+            CalcError::Syntax { expected, .. } => write!(f, "Syntax error: expected {}", expected),
+            CalcError::EmptyExpression => write!(f, "Empty expression"),
+            CalcError::InvalidNumber { text, base } => write!(f, "Invalid {} number: {}", base, text),
+            CalcError::InvalidName { name } => write!(f, "Invalid name: {}", name),
+            CalcError::ReadOnlyConstant { name } => write!(f, "{} is a built-in constant and cannot be reassigned", name),
+            CalcError::NothingToUndo => write!(f, "Nothing to undo"),
+            CalcError::NothingToRedo => write!(f, "Nothing to redo"),
+            CalcError::UnknownState { name } => write!(f, "No saved state named '{}'", name),
+            CalcError::UnknownMode { name } => write!(f, "Unknown mode: {}", name),
+            CalcError::UnknownBase { name } => write!(f, "Unknown base: {}", name),
+            CalcError::UnknownCommand { name } => write!(f, "Unknown command: {}", name),
+            CalcError::UnknownSetting { name } => write!(f, "Unknown setting: {}", name),
+            CalcError::Usage { usage } => write!(f, "Usage: {}", usage),
+            CalcError::Internal { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+fn write_suggestion(f: &mut fmt::Formatter<'_>, suggestion: &Option<String>) -> fmt::Result {
+    match suggestion {
+        Some(candidate) => write!(f, " (did you mean `{}`?)", candidate),
+        None => Ok(()),
+    }
+}
+
+impl std::error::Error for CalcError {}
+
+// Closest candidate by edit distance, if it is close enough to be a likely typo
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    // One edit per three characters, so single letters never "correct" to each other
+    let max_distance = (name.chars().count() + 1) / 3;
+    
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+// Levenshtein distance between two strings
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::parser::ExpressionParser;
+    
+    #[test]
+    fn render_underlines_the_span() {
+        let source = "1 + 2 / (x - x)";
+        let error = CalcError::DivisionByZero { span: Some(Span::new(4, 15)) };
+        assert_eq!(error.render(source), format!("Division by zero\n  {}\n      ^{}", source, "~".repeat(10)));
+    }
+    
+    #[test]
+    fn render_counts_characters_not_bytes() {
+        let error = CalcError::syntax(Span::new(5, 7), "an operand");
+        assert_eq!(error.render("π + ×"), "Syntax error: expected an operand\n  π + ×\n      ^");
+    }
+    
+    #[test]
+    fn render_without_a_span_is_the_message() {
+        assert_eq!(CalcError::EmptyExpression.render("   "), "Empty expression");
+        let outside = CalcError::syntax(Span::new(10, 11), "')'");
+        assert_eq!(outside.render("(1"), outside.to_string());
+    }
+    
+    #[test]
+    fn evaluation_errors_point_at_the_failing_node() {
+        let source = "1 + 2 / (x - x)";
+        let expr = ExpressionParser::new().parse(source).unwrap();
+        let variables = HashMap::from([("x".to_string(), 1.0)]);
+        let error = expr.evaluate(&variables).unwrap_err();
+        assert_eq!(error.span(), Some(Span::new(4, 15)));
+    }
+    
+    #[test]
+    fn shifted_moves_the_span_into_the_full_line() {
+        let error = CalcError::syntax(Span::new(0, 1), "an operand").shifted(4);
+        assert_eq!(error.span(), Some(Span::new(4, 5)));
+        assert_eq!(CalcError::DivisionByZero { span: None }.with_span(Span::new(2, 3)).span(), Some(Span::new(2, 3)));
+    }
+    
+    #[test]
+    fn messages_name_what_went_wrong() {
+        let error = CalcError::DomainError { function: "sqrt".to_string(), argument: -1.0, span: None };
+        assert_eq!(error.to_string(), "sqrt is undefined for -1");
+        let error = CalcError::UndefinedVariable { name: "pj".to_string(), suggestion: Some("pi".to_string()), span: None };
+        assert_eq!(error.to_string(), "Undefined variable: pj (did you mean `pi`?)");
+        assert_eq!(CalcError::usage("/mode [name]").to_string(), "Usage: /mode [name]");
+    }
+    
+    #[test]
+    fn errors_are_matched_by_variant() {
+        let expr = ExpressionParser::new().parse("sqrt(0 - 1) + y").unwrap();
+        let variables = HashMap::from([("y".to_string(), 1.0)]);
+        assert!(matches!(expr.evaluate(&variables), Err(CalcError::DomainError { argument, .. }) if argument == -1.0));
+        let expr = ExpressionParser::new().parse("y + 1").unwrap();
+        assert!(matches!(expr.evaluate(&HashMap::new()), Err(CalcError::UndefinedVariable { name, .. }) if name == "y"));
+        assert!(matches!(ExpressionParser::new().parse(""), Err(CalcError::EmptyExpression)));
+    }
+    
+    #[test]
+    fn suggestions_are_close_typos() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(suggest("sine", ["sin", "cos", "tan"]), Some("sin".to_string()));
+        assert_eq!(suggest("x", ["y", "z"]), None);
+        assert_eq!(suggest("sin", ["sin"]), None);
+    }
+}
@@ -2,25 +2,148 @@
 
 use std::collections::HashMap;
 use std::any::Any;
-use crate::token::{Operator, Function};
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::Arc;
+use crate::token::{Operator, UnaryOperator, PostfixOperator, Function, Span, Associativity, Arity, Fixity, Number, NumberFormat};
+use crate::error::{CalcError, suggest};
+use crate::config::get_global_config;
+use crate::numeric::NumericContext;
+use crate::constants;
 
-// Expression trait defining common behavior
+// Expression tree. Every node the parsers build is one of these variants, so
+// passes over the tree are plain `match`es; anything else goes in `Custom`
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number {
+        value: f64,
+        format: NumberFormat, // How the literal was written, so it prints the same way
+        span: Option<Span>,
+    },
+    Variable {
+        name: String,
+        span: Option<Span>,
+    },
+    Binary {
+        operator: Operator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        span: Option<Span>,
+    },
+    // Prefix operators such as negation
+    Unary {
+        operator: UnaryOperator,
+        operand: Box<Expr>,
+        span: Option<Span>,
+    },
+    // Postfix operators: factorial and percent
+    Postfix {
+        operator: PostfixOperator,
+        operand: Box<Expr>,
+        span: Option<Span>,
+    },
+    Call {
+        function: Callee,
+        arguments: Vec<Expr>,
+        span: Option<Span>,
+    },
+    // Stands in for input that failed to parse, so error-recovering parsing can
+    // still return a tree. Whatever parsed around the problem is kept in `parts`,
+    // where validation can still look for further mistakes
+    Error {
+        error: CalcError,
+        parts: Vec<Expr>,
+        span: Option<Span>,
+    },
+    // A node defined outside this enum, e.g. an operator registered at runtime
+    Custom(Box<dyn Expression>),
+}
+
+// What a call node calls
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Callee {
+    Builtin(Function),
+    // A function defined in the REPL; it is looked up by name only when
+    // evaluated, so a body may call itself or functions defined after it
+    User(String),
+}
+
+// Extension point for nodes the `Expr` enum doesn't cover. Such a node sits
+// in `Expr::Custom` and exposes its operands through `children`
 pub trait Expression {
-    fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, String>;
+    fn evaluate_in(&self, context: &EvaluationContext) -> Result<f64, CalcError>;
     fn to_string(&self) -> String;
     
-    // For debugging and visualization
+    // For parenthesising when printed
     fn precedence(&self) -> u8 {
-        0 // Leaf nodes have lowest precedence by default
+        u8::MAX // Leaf nodes never need parentheses
+    }
+    
+    // Location in the source text, if the node came from the parser
+    fn span(&self) -> Option<Span> {
+        None
+    }
+    
+    // Subexpressions, left to right, so traversals can reach inside the node
+    fn children(&self) -> &[Expr] {
+        &[]
+    }
+    
+    // The same node over new children, for passes that rewrite the tree
+    fn with_children(&self, children: Vec<Expr>) -> Box<dyn Expression>;
+    
+    // The node's value given its children's values, if it depends on nothing
+    // else; constant folding and validation use it
+    fn fold(&self, _values: &[f64]) -> Option<Result<f64, CalcError>> {
+        None
     }
     
-    // Allow downcasting for visitor pattern
+    // So an embedder can get its own node type back
     fn as_any(&self) -> &dyn Any;
     
     // Default implementation for cloning
     fn clone_box(&self) -> Box<dyn Expression>;
 }
 
+// What an evaluation can look up besides the expression itself. Evaluating
+// in f64 is the generic evaluation, so every number type follows the same rules
+pub type EvaluationContext<'a> = NumericContext<'a, f64>;
+
+// A context user functions can be called in, whatever values its variables
+// hold. `enter_user_function` and `run_user_function` do the rest for all of them
+pub trait CallContext {
+    type Value: Clone;
+    
+    fn functions(&self) -> &HashMap<String, UserFunction>;
+    fn variables(&self) -> &HashMap<String, Self::Value>;
+    // User function calls in progress, and how many may be
+    fn depth(&self) -> (usize, usize);
+    // Evaluates `body` in a context like this one, with `scope` for its
+    // variables and one more call in progress
+    fn evaluate_nested(&self, scope: &HashMap<String, Self::Value>, body: &Expr) -> Result<Self::Value, CalcError>;
+}
+
+// A function defined in the REPL, e.g. `f(x, y) = x^2 + y`
+#[derive(Debug, Clone)]
+pub struct UserFunction {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Expr,
+}
+
+impl UserFunction {
+    pub fn new(name: impl Into<String>, parameters: Vec<String>, body: Expr) -> Self {
+        Self { name: name.into(), parameters, body }
+    }
+    
+    // The left-hand side of the definition, e.g. `f(x, y)`
+    pub fn signature(&self) -> String {
+        format!("{}({})", self.name, self.parameters.join(", "))
+    }
+}
+
 // Extension to allow cloning of trait objects
 impl Clone for Box<dyn Expression> {
     fn clone(&self) -> Self {
@@ -28,200 +151,590 @@ impl Clone for Box<dyn Expression> {
     }
 }
 
-// Leaf node for number values
-#[derive(Debug, Clone)]
-pub struct NumberExpression {
-    pub value: f64,
-}
-
-impl NumberExpression {
-    pub fn new(value: f64) -> Self {
-        Self { value }
+impl fmt::Debug for dyn Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Custom({})", self.to_string())
     }
 }
 
-impl Expression for NumberExpression {
-    fn evaluate(&self, _variables: &HashMap<String, f64>) -> Result<f64, String> {
-        Ok(self.value)
+impl Expr {
+    pub fn number(value: f64) -> Self {
+        Expr::Number { value, format: NumberFormat::Decimal, span: None }
     }
     
-    fn to_string(&self) -> String {
-        format!("{}", self.value)
+    pub fn variable(name: impl Into<String>) -> Self {
+        Expr::Variable { name: name.into(), span: None }
     }
     
-    fn as_any(&self) -> &dyn Any {
-        self
+    pub fn binary(left: Expr, right: Expr, operator: Operator) -> Self {
+        // The operation covers everything from its left operand to its right one
+        let span = merge_spans(left.span(), right.span());
+        
+        // Implicit multiplication only binds differently while parsing; once
+        // the tree is built it is an ordinary product and prints with `*`
+        let operator = match operator {
+            Operator::ImplicitMultiply => Operator::Multiply,
+            operator => operator,
+        };
+        
+        Expr::Binary { operator, left: Box::new(left), right: Box::new(right), span }
     }
     
-    fn clone_box(&self) -> Box<dyn Expression> {
-        Box::new(self.clone())
+    pub fn unary(operator: UnaryOperator, operand: Expr) -> Self {
+        let span = operand.span();
+        Expr::Unary { operator, operand: Box::new(operand), span }
     }
-}
-
-// Leaf node for variables
-#[derive(Debug, Clone)]
-pub struct VariableExpression {
-    pub name: String,
-}
-
-impl VariableExpression {
-    pub fn new(name: impl Into<String>) -> Self {
-        Self { name: name.into() }
+    
+    pub fn postfix(operator: PostfixOperator, operand: Expr) -> Self {
+        let span = operand.span();
+        Expr::Postfix { operator, operand: Box::new(operand), span }
     }
-}
-
-impl Expression for VariableExpression {
-    fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, String> {
-        variables
-            .get(&self.name)
-            .copied()
-            .ok_or_else(|| format!("Undefined variable: {}", self.name))
+    
+    pub fn user_call(name: impl Into<String>, arguments: Vec<Expr>) -> Self {
+        Expr::Call { function: Callee::User(name.into()), arguments, span: None }
     }
     
-    fn to_string(&self) -> String {
-        self.name.clone()
+    pub fn error(error: CalcError, parts: Vec<Expr>) -> Self {
+        let span = error.span();
+        Expr::Error { error, parts, span }
     }
     
-    fn as_any(&self) -> &dyn Any {
+    // Overrides the derived span, e.g. to cover an operator symbol or parentheses.
+    // A custom node keeps the span it was built with
+    pub fn with_span(mut self, new_span: Option<Span>) -> Self {
+        match &mut self {
+            Expr::Number { span, .. }
+            | Expr::Variable { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Postfix { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::Error { span, .. } => *span = new_span,
+            Expr::Custom(_) => {},
+        }
         self
     }
     
-    fn clone_box(&self) -> Box<dyn Expression> {
-        Box::new(self.clone())
+    // The value of a number node
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Expr::Number { value, .. } => Some(*value),
+            _ => None,
+        }
+    }
+    
+    // Location in the source text, if the node came from the parser
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expr::Number { span, .. }
+            | Expr::Variable { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Postfix { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::Error { span, .. } => *span,
+            Expr::Custom(node) => node.span(),
+        }
+    }
+    
+    pub fn precedence(&self) -> u8 {
+        match self {
+            // A negative literal prints with a leading `-`, so it groups like negation
+            Expr::Number { value, .. } if value.is_sign_negative() => UnaryOperator::Negate.precedence(),
+            Expr::Binary { operator, .. } => operator.precedence(),
+            Expr::Unary { operator, .. } => operator.precedence(),
+            Expr::Postfix { operator, .. } => operator.precedence(),
+            Expr::Custom(node) => node.precedence(),
+            // Leaves and calls are self-delimiting
+            _ => u8::MAX,
+        }
+    }
+    
+    // Direct subexpressions, left to right
+    pub fn children(&self) -> impl DoubleEndedIterator<Item = &Expr> {
+        let (first, second, rest): (Option<&Expr>, Option<&Expr>, &[Expr]) = match self {
+            Expr::Binary { left, right, .. } => (Some(left), Some(right), &[]),
+            Expr::Unary { operand, .. } | Expr::Postfix { operand, .. } => (Some(operand), None, &[]),
+            Expr::Call { arguments, .. } => (None, None, arguments),
+            Expr::Error { parts, .. } => (None, None, parts),
+            Expr::Custom(node) => (None, None, node.children()),
+            Expr::Number { .. } | Expr::Variable { .. } => (None, None, &[]),
+        };
+        first.into_iter().chain(second).chain(rest)
+    }
+    
+    // The same node over children `f` rebuilt, for passes that rewrite the tree
+    pub fn try_map_children<E>(&self, mut f: impl FnMut(&Expr) -> Result<Expr, E>) -> Result<Expr, E> {
+        Ok(match self {
+            Expr::Number { .. } | Expr::Variable { .. } => self.clone(),
+            Expr::Binary { operator, left, right, span } => Expr::Binary {
+                operator: operator.clone(),
+                left: Box::new(f(left)?),
+                right: Box::new(f(right)?),
+                span: *span,
+            },
+            Expr::Unary { operator, operand, span } => Expr::Unary {
+                operator: operator.clone(),
+                operand: Box::new(f(operand)?),
+                span: *span,
+            },
+            Expr::Postfix { operator, operand, span } => Expr::Postfix {
+                operator: operator.clone(),
+                operand: Box::new(f(operand)?),
+                span: *span,
+            },
+            Expr::Call { function, arguments, span } => Expr::Call {
+                function: function.clone(),
+                arguments: arguments.iter().map(f).collect::<Result<_, E>>()?,
+                span: *span,
+            },
+            Expr::Error { error, parts, span } => Expr::Error {
+                error: error.clone(),
+                parts: parts.iter().map(f).collect::<Result<_, E>>()?,
+                span: *span,
+            },
+            Expr::Custom(node) => Expr::Custom(node.with_children(node.children().iter().map(f).collect::<Result<_, E>>()?)),
+        })
+    }
+    
+    pub fn map_children(&self, mut f: impl FnMut(&Expr) -> Expr) -> Expr {
+        match self.try_map_children(|child| Ok::<Expr, Infallible>(f(child))) {
+            Ok(expr) => expr,
+            Err(never) => match never {},
+        }
+    }
+    
+    pub fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
+        // Without a context no user functions are defined
+        self.evaluate_in(&EvaluationContext::new(variables, &HashMap::new(), ()))
+    }
+    
+    // Evaluation that can also call user-defined functions
+    pub fn evaluate_in(&self, context: &EvaluationContext) -> Result<f64, CalcError> {
+        context.evaluate(self)
     }
 }
 
-// Composite node for binary operations
-#[derive(Debug, Clone)]
-pub struct BinaryOperation {
-    pub left: Box<dyn Expression>,
-    pub right: Box<dyn Expression>,
-    pub operator: Operator,
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number { value, format, .. } => write!(f, "{}", Number::with_format(*value, *format).format()),
+            Expr::Variable { name, .. } => write!(f, "{}", name),
+            Expr::Binary { operator, left, right, .. } => {
+                // An operand with the same precedence only needs parentheses on the
+                // side the operator doesn't group towards: a - (b - c), (a ^ b) ^ c
+                let precedence = operator.precedence();
+                let associativity = operator.associativity();
+                let left_parens = left.precedence() < precedence
+                    || (left.precedence() == precedence && associativity == Associativity::Right);
+                
+                // Parenthesised, so a sign after `%` never reads as a percentage: 10 % (-3)
+                let right_str = right.to_string();
+                let right_parens = right.precedence() < precedence
+                    || (right.precedence() == precedence && associativity == Associativity::Left)
+                    || (*operator == Operator::Modulo && right_str.starts_with(['-', '+', '!']));
+                
+                write!(f, "{} {} {}", parenthesised(left, left_parens), operator.symbol(), parenthesised(right, right_parens))
+            },
+            Expr::Unary { operator, operand, .. } => {
+                write!(f, "{}{}", operator.symbol(), parenthesised(operand, operand.precedence() < operator.precedence()))
+            },
+            Expr::Postfix { operator, operand, .. } => {
+                write!(f, "{}{}", parenthesised(operand, operand.precedence() < operator.precedence()), operator.symbol())
+            },
+            Expr::Call { function, arguments, .. } => {
+                let name = match function {
+                    Callee::Builtin(function) => function.name(),
+                    Callee::User(name) => name,
+                };
+                let args: Vec<String> = arguments.iter().map(|argument| argument.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            },
+            // `?` marks the spot where something was missing
+            Expr::Error { parts, .. } if parts.is_empty() => write!(f, "?"),
+            Expr::Error { parts, .. } => {
+                let parts: Vec<String> = parts.iter().map(|part| part.to_string()).collect();
+                write!(f, "{}", parts.join(" "))
+            },
+            Expr::Custom(node) => write!(f, "{}", node.to_string()),
+        }
+    }
 }
 
-impl BinaryOperation {
-    pub fn new(
-        left: Box<dyn Expression>,
-        right: Box<dyn Expression>,
-        operator: Operator,
-    ) -> Self {
-        Self { left, right, operator }
+// Structural equality: spans and number formats are ignored, so `x + 1`
+// equals itself wherever it was written. Numbers compare bit for bit, which
+// keeps `Eq` and `Hash` consistent even for NaN
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Expr::Number { value: a, .. }, Expr::Number { value: b, .. }) => a.to_bits() == b.to_bits(),
+            (Expr::Variable { name: a, .. }, Expr::Variable { name: b, .. }) => a == b,
+            (
+                Expr::Binary { operator: op_a, left: left_a, right: right_a, .. },
+                Expr::Binary { operator: op_b, left: left_b, right: right_b, .. },
+            ) => op_a == op_b && left_a == left_b && right_a == right_b,
+            (Expr::Unary { operator: op_a, operand: a, .. }, Expr::Unary { operator: op_b, operand: b, .. }) => op_a == op_b && a == b,
+            (Expr::Postfix { operator: op_a, operand: a, .. }, Expr::Postfix { operator: op_b, operand: b, .. }) => op_a == op_b && a == b,
+            (Expr::Call { function: f_a, arguments: a, .. }, Expr::Call { function: f_b, arguments: b, .. }) => f_a == f_b && a == b,
+            (Expr::Error { error: e_a, parts: a, .. }, Expr::Error { error: e_b, parts: b, .. }) => e_a.to_string() == e_b.to_string() && a == b,
+            (Expr::Custom(a), Expr::Custom(b)) => a.to_string() == b.to_string(),
+            _ => false,
+        }
     }
-    
-    fn operator_symbol(&self) -> &'static str {
-        match self.operator {
-            Operator::Add => "+",
-            Operator::Subtract => "-",
-            Operator::Multiply => "*",
-            Operator::Divide => "/",
-            Operator::Power => "^",
+}
+
+impl Eq for Expr {}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Expr::Number { value, .. } => value.to_bits().hash(state),
+            Expr::Variable { name, .. } => name.hash(state),
+            Expr::Binary { operator, left, right, .. } => {
+                operator.hash(state);
+                left.hash(state);
+                right.hash(state);
+            },
+            Expr::Unary { operator, operand, .. } => {
+                operator.hash(state);
+                operand.hash(state);
+            },
+            Expr::Postfix { operator, operand, .. } => {
+                operator.hash(state);
+                operand.hash(state);
+            },
+            Expr::Call { function, arguments, .. } => {
+                function.hash(state);
+                arguments.hash(state);
+            },
+            Expr::Error { error, parts, .. } => {
+                error.to_string().hash(state);
+                parts.hash(state);
+            },
+            Expr::Custom(node) => node.to_string().hash(state),
         }
     }
 }
 
-impl Expression for BinaryOperation {
-    fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, String> {
-        let left_val = self.left.evaluate(variables)?;
-        let right_val = self.right.evaluate(variables)?;
+// Applies a binary operator to evaluated operands; also used to fold constants.
+// `right` is the right operand's node, since a percentage there means a share
+// of the left operand, as on a desk calculator: 200 + 10% is 200 + 20
+pub fn apply_binary(operator: &Operator, left_val: f64, right: &Expr, right_val: f64, span: Option<Span>) -> Result<f64, CalcError> {
+    let percent = matches!(right, Expr::Postfix { operator: PostfixOperator::Percent, .. });
+    operator.apply(left_val, right_val, percent, span)
+}
+
+impl Operator {
+    // `percent` says whether the right operand was written as a percentage
+    pub fn apply(&self, left_val: f64, right_val: f64, percent: bool, span: Option<Span>) -> Result<f64, CalcError> {
+        let relative = if percent { left_val * right_val } else { right_val };
         
-        match self.operator {
-            Operator::Add => Ok(left_val + right_val),
-            Operator::Subtract => Ok(left_val - right_val),
-            Operator::Multiply => Ok(left_val * right_val),
-            Operator::Divide => {
-                if right_val == 0.0 {
-                    Err("Division by zero".to_string())
+        match self {
+            Operator::Add => Ok(left_val + relative),
+            Operator::Subtract => Ok(left_val - relative),
+            Operator::Multiply | Operator::ImplicitMultiply => Ok(left_val * right_val),
+            Operator::Divide | Operator::Modulo | Operator::FloorDivide if right_val == 0.0 => {
+                Err(CalcError::DivisionByZero { span })
+            },
+            Operator::Divide => Ok(left_val / right_val),
+            Operator::Modulo => Ok(get_global_config().modulo_convention.remainder(left_val, right_val)),
+            Operator::FloorDivide => Ok((left_val / right_val).floor()),
+            Operator::Power => Ok(left_val.powf(right_val)),
+            Operator::Less => Ok(truth(left_val < right_val)),
+            Operator::LessEqual => Ok(truth(left_val <= right_val)),
+            Operator::Equal => Ok(truth(left_val == right_val)),
+            Operator::NotEqual => Ok(truth(left_val != right_val)),
+            Operator::GreaterEqual => Ok(truth(left_val >= right_val)),
+            Operator::Greater => Ok(truth(left_val > right_val)),
+            Operator::And => Ok(truth(is_true(left_val) && is_true(right_val))),
+            Operator::Or => Ok(truth(is_true(left_val) || is_true(right_val))),
+        }
+    }
+}
+
+impl UnaryOperator {
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            UnaryOperator::Negate => -value,
+            UnaryOperator::Plus => value,
+            UnaryOperator::Not => truth(!is_true(value)),
+        }
+    }
+}
+
+impl PostfixOperator {
+    pub fn apply(&self, value: f64, span: Option<Span>) -> Result<f64, CalcError> {
+        match self {
+            PostfixOperator::Factorial => {
+                // Γ has poles at 0, -1, -2, ..., so n! is undefined for negative integers
+                if value < 0.0 && value.fract() == 0.0 {
+                    Err(CalcError::DomainError {
+                        function: "factorial".to_string(),
+                        argument: value,
+                        span,
+                    })
                 } else {
-                    Ok(left_val / right_val)
+                    Ok(factorial(value))
                 }
             },
-            Operator::Power => Ok(left_val.powf(right_val)),
+            PostfixOperator::Percent => Ok(value / 100.0),
         }
     }
-    
-    fn to_string(&self) -> String {
-        let left_str = if self.left.precedence() < self.precedence() {
-            format!("({})", self.left.to_string())
-        } else {
-            self.left.to_string()
-        };
+}
+
+impl Function {
+    // Parsers call this as soon as the argument list is closed
+    pub fn check_arity(&self, found: usize, span: Option<Span>) -> Result<(), CalcError> {
+        let expected = self.arity();
         
-        let right_str = if self.right.precedence() < self.precedence() {
-            format!("({})", self.right.to_string())
+        if expected.accepts(found) {
+            Ok(())
         } else {
-            self.right.to_string()
+            Err(CalcError::WrongArgumentCount {
+                function: self.name().to_string(),
+                expected,
+                found,
+                span,
+            })
+        }
+    }
+    
+    // Applies the function to already evaluated arguments; also used to fold constants
+    pub fn apply(&self, args: &[f64], span: Option<Span>) -> Result<f64, CalcError> {
+        self.check_arity(args.len(), span)?;
+        
+        let domain_error = |argument: f64| CalcError::DomainError {
+            function: self.name().to_string(),
+            argument,
+            span,
         };
         
-        format!("{} {} {}", left_str, self.operator_symbol(), right_str)
+        match self {
+            Function::Sin => Ok(args[0].sin()),
+            Function::Cos => Ok(args[0].cos()),
+            Function::Tan => {
+                if (args[0] - std::f64::consts::PI/2.0).abs() % std::f64::consts::PI < 1e-10 {
+                    Err(domain_error(args[0]))
+                } else {
+                    Ok(args[0].tan())
+                }
+            },
+            Function::Sqrt => {
+                if args[0] < 0.0 {
+                    Err(domain_error(args[0]))
+                } else {
+                    Ok(args[0].sqrt())
+                }
+            },
+            Function::Log => {
+                let (value, base) = (args[0], args[1]);
+                if value <= 0.0 {
+                    Err(domain_error(value))
+                } else if base <= 0.0 || base == 1.0 {
+                    Err(domain_error(base))
+                } else {
+                    Ok(value.log(base))
+                }
+            },
+            Function::Atan2 => Ok(args[0].atan2(args[1])),
+            Function::Hypot => Ok(args[0].hypot(args[1])),
+            Function::Min => Ok(args.iter().copied().fold(f64::INFINITY, f64::min)),
+            Function::Max => Ok(args.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+            Function::Clamp => {
+                let (value, lo, hi) = (args[0], args[1], args[2]);
+                // f64::clamp panics on an empty range, so report it instead
+                if lo <= hi {
+                    Ok(value.clamp(lo, hi))
+                } else {
+                    Err(domain_error(lo))
+                }
+            },
+            // Evaluating the tree picks a branch of `if` lazily; this eager
+            // form only sees values that are already computed
+            Function::If => Ok(if is_true(args[0]) { args[1] } else { args[2] }),
+            Function::Re | Function::Conj => Ok(args[0]),
+            Function::Im => Ok(0.0),
+            Function::Abs => Ok(args[0].abs()),
+            // As for a complex number with no imaginary part; -0 is on the negative side
+            Function::Arg => Ok(0.0f64.atan2(args[0])),
+        }
     }
+}
+
+// The branch of `if(condition, then, else)` a known condition value selects
+pub fn branch(arguments: &[Expr], condition: f64) -> &Expr {
+    if is_true(condition) {
+        &arguments[1]
+    } else {
+        &arguments[2]
+    }
+}
+
+fn evaluate_all(arguments: &[Expr], context: &EvaluationContext) -> Result<Vec<f64>, CalcError> {
+    arguments
+        .iter()
+        .map(|argument| context.evaluate(argument))
+        .collect()
+}
+
+// The error for a variable that is neither set nor a constant. Typos are
+// usually close to a known variable, constant or function name
+pub fn undefined_variable<'a>(name: &str, variables: impl Iterator<Item = &'a str>, span: Option<Span>) -> CalcError {
+    let known = variables
+        .chain(constants::all().map(|constant| constant.name))
+        .chain(Function::all().iter().map(|function| function.name()));
     
-    fn precedence(&self) -> u8 {
-        match self.operator {
-            Operator::Add | Operator::Subtract => 1,
-            Operator::Multiply | Operator::Divide => 2,
-            Operator::Power => 3,
-        }
+    CalcError::UndefinedVariable {
+        name: name.to_string(),
+        suggestion: suggest(name, known),
+        span,
     }
+}
+
+// The user function a call names, if it exists and takes that many arguments
+pub fn user_function<'a>(name: &str, found: usize, functions: &'a HashMap<String, UserFunction>, span: Option<Span>) -> Result<&'a UserFunction, CalcError> {
+    let function = functions.get(name).ok_or_else(|| {
+        let known = functions.keys().map(String::as_str)
+            .chain(Function::all().iter().map(|function| function.name()));
+        CalcError::UnknownFunction {
+            name: name.to_string(),
+            suggestion: suggest(name, known),
+            span,
+        }
+    })?;
     
-    fn as_any(&self) -> &dyn Any {
-        self
+    if function.parameters.len() != found {
+        return Err(CalcError::WrongArgumentCount {
+            function: name.to_string(),
+            expected: Arity::Exact(function.parameters.len()),
+            found,
+            span,
+        });
     }
     
-    fn clone_box(&self) -> Box<dyn Expression> {
-        Box::new(self.clone())
+    Ok(function)
+}
+
+// The function a call with `found` arguments runs, unless calls are already nested too deep
+pub fn enter_user_function<'c, C: CallContext>(name: &str, found: usize, span: Option<Span>, context: &'c C) -> Result<&'c UserFunction, CalcError> {
+    let function = user_function(name, found, context.functions(), span)?;
+    
+    let (depth, max_depth) = context.depth();
+    if depth >= max_depth {
+        return Err(CalcError::RecursionLimit {
+            function: name.to_string(),
+            limit: max_depth,
+            span,
+        });
     }
+    Ok(function)
 }
 
-// Function call expression
-#[derive(Debug, Clone)]
-pub struct FunctionCall {
-    pub function: Function,
-    pub argument: Box<dyn Expression>,
+// Evaluates the body with the arguments' values bound over the caller's scope
+pub fn run_user_function<C: CallContext>(function: &UserFunction, args: Vec<C::Value>, span: Option<Span>, context: &C) -> Result<C::Value, CalcError> {
+    let mut scope = context.variables().clone();
+    scope.extend(function.parameters.iter().cloned().zip(args));
+    
+    // Spans inside the body refer to the definition, so point at the call instead
+    context.evaluate_nested(&scope, &function.body).map_err(|error| error.relocated(span))
 }
 
-impl FunctionCall {
-    pub fn new(function: Function, argument: Box<dyn Expression>) -> Self {
-        Self { function, argument }
+fn parenthesised(expr: &Expr, needed: bool) -> String {
+    if needed {
+        format!("({})", expr)
+    } else {
+        expr.to_string()
     }
 }
 
-impl Expression for FunctionCall {
-    fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, String> {
-        let arg_val = self.argument.evaluate(variables)?;
+// Evaluation closure of an operator registered at runtime; it receives one
+// value per operand, left to right
+pub type OperatorFn = Arc<dyn Fn(&[f64]) -> Result<f64, CalcError> + Send + Sync>;
+
+// Node for an operator registered in an `OperatorTable`, e.g. `r1 ∥ r2`
+#[derive(Clone)]
+pub struct CustomOperation {
+    pub symbol: String,
+    pub fixity: Fixity,
+    pub precedence: u8,
+    pub associativity: Associativity,
+    pub function: OperatorFn,
+    pub operands: Vec<Expr>,
+    pub span: Option<Span>,
+}
+
+impl CustomOperation {
+    // Applies the operator to already evaluated operands
+    pub fn apply(&self, values: &[f64]) -> Result<f64, CalcError> {
+        (self.function)(values).map_err(|error| match self.span {
+            Some(span) => error.with_span(span),
+            None => error,
+        })
+    }
+    
+    // Operand text, parenthesised where it would otherwise group differently
+    fn operand_string(&self, operand: &Expr, side: Associativity) -> String {
+        // A prefix operand stops before operators of the same precedence; a
+        // postfix operator already applies to everything on its left
+        let tie_needs_parens = match self.fixity {
+            Fixity::Infix => self.associativity != side,
+            Fixity::Prefix => true,
+            Fixity::Postfix => false,
+        };
         
-        match self.function {
-            Function::Sin => Ok(arg_val.sin()),
-            Function::Cos => Ok(arg_val.cos()),
-            Function::Tan => {
-                if (arg_val - std::f64::consts::PI/2.0).abs() % std::f64::consts::PI < 1e-10 {
-                    Err("Tangent undefined at this value".to_string())
-                } else {
-                    Ok(arg_val.tan())
-                }
+        let needed = operand.precedence() < self.precedence || (operand.precedence() == self.precedence && tie_needs_parens);
+        parenthesised(operand, needed)
+    }
+}
+
+impl Expression for CustomOperation {
+    fn evaluate_in(&self, context: &EvaluationContext) -> Result<f64, CalcError> {
+        let values = evaluate_all(&self.operands, context)?;
+        self.apply(&values)
+    }
+    
+    fn to_string(&self) -> String {
+        match (self.fixity, self.operands.as_slice()) {
+            (Fixity::Prefix, [operand]) => {
+                format!("{}{}", self.symbol, self.operand_string(operand, Associativity::Right))
             },
-            Function::Sqrt => {
-                if arg_val < 0.0 {
-                    Err("Cannot take square root of negative number".to_string())
-                } else {
-                    Ok(arg_val.sqrt())
-                }
+            (Fixity::Postfix, [operand]) => {
+                format!("{}{}", self.operand_string(operand, Associativity::Left), self.symbol)
+            },
+            (_, [left, right]) => format!(
+                "{} {} {}",
+                self.operand_string(left, Associativity::Left),
+                self.symbol,
+                self.operand_string(right, Associativity::Right),
+            ),
+            // Not produced by the parser, but still printable
+            (_, operands) => {
+                let operands: Vec<String> = operands.iter().map(|operand| operand.to_string()).collect();
+                format!("{}({})", self.symbol, operands.join(", "))
             },
         }
     }
     
-    fn to_string(&self) -> String {
-        let func_name = match self.function {
-            Function::Sin => "sin",
-            Function::Cos => "cos",
-            Function::Tan => "tan",
-            Function::Sqrt => "sqrt",
-        };
-        
-        format!("{}({})", func_name, self.argument.to_string())
+    fn precedence(&self) -> u8 {
+        self.precedence
     }
     
-    fn precedence(&self) -> u8 {
-        4 // Function calls have highest precedence
+    fn span(&self) -> Option<Span> {
+        self.span
+    }
+    
+    fn children(&self) -> &[Expr] {
+        &self.operands
+    }
+    
+    fn with_children(&self, children: Vec<Expr>) -> Box<dyn Expression> {
+        Box::new(CustomOperation { operands: children, ..self.clone() })
+    }
+    
+    // Registered operators are treated as pure, so constant operands fold
+    fn fold(&self, values: &[f64]) -> Option<Result<f64, CalcError>> {
+        Some(self.apply(values))
     }
     
     fn as_any(&self) -> &dyn Any {
@@ -232,3 +745,67 @@ impl Expression for FunctionCall {
         Box::new(self.clone())
     }
 }
+
+// Factory used by the parsers once an argument list is closed: checks the
+// argument count before building the call
+pub fn function_call(function: Function, arguments: Vec<Expr>, span: Option<Span>) -> Result<Expr, CalcError> {
+    function.check_arity(arguments.len(), span)?;
+    Ok(Expr::Call { function: Callee::Builtin(function), arguments, span })
+}
+
+// Conditions are numbers: zero is false, anything else is true
+pub fn is_true(value: f64) -> bool {
+    value != 0.0
+}
+
+pub fn truth(condition: bool) -> f64 {
+    if condition { 1.0 } else { 0.0 }
+}
+
+// n! for whole numbers, extended to fractions through Γ(x + 1)
+pub fn factorial(value: f64) -> f64 {
+    if value.fract() == 0.0 && value <= 170.0 {
+        // Exact up to the largest factorial an f64 can hold
+        (1..=value as u32).map(f64::from).product()
+    } else {
+        gamma(value + 1.0)
+    }
+}
+
+// Lanczos approximation (g = 7, n = 9), with the reflection formula below 1/2
+pub fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    
+    if x < 0.5 {
+        let pi = std::f64::consts::PI;
+        return pi / ((pi * x).sin() * gamma(1.0 - x));
+    }
+    
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let series = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| sum + c / (x + i as f64 + 1.0));
+    
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * series
+}
+
+// Helper for combining optional locations
+pub fn merge_spans(a: Option<Span>, b: Option<Span>) -> Option<Span> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.merge(b)),
+        (a, b) => a.or(b),
+    }
+}
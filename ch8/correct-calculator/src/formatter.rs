@@ -0,0 +1,207 @@
+// formatter.rs - Number formatting shared by every display path
+
+use crate::config::{CalculatorConfig, DecimalMark, get_global_config};
+use crate::token::{Number, NumberFormat, decimal_exponent, shift_decimal_point, group_digits_with};
+use crate::numeric::Value;
+
+// Beyond 17 significant digits an f64 only shows binary rounding noise
+pub const MAX_SIGNIFICANT_DIGITS: u32 = 17;
+
+// Turns results into text for people to read, following `CalculatorConfig`.
+// Expressions print through `Number::format` instead, so they parse back as-is
+#[derive(Debug, Clone)]
+pub struct NumberFormatter {
+    precision: u32, // Significant digits
+    notation: NumberFormat,
+    decimal_mark: DecimalMark,
+    thousands_separator: bool,
+}
+
+impl NumberFormatter {
+    pub fn new() -> Self {
+        Self::from_config(&get_global_config())
+    }
+    
+    pub fn from_config(config: &CalculatorConfig) -> Self {
+        Self {
+            precision: config.precision.clamp(1, MAX_SIGNIFICANT_DIGITS),
+            notation: config.notation,
+            decimal_mark: config.decimal_mark,
+            thousands_separator: config.thousands_separator,
+        }
+    }
+    
+    pub fn with_precision(mut self, precision: u32) -> Self {
+        self.precision = precision.clamp(1, MAX_SIGNIFICANT_DIGITS);
+        self
+    }
+    
+    pub fn with_notation(mut self, notation: NumberFormat) -> Self {
+        self.notation = notation;
+        self
+    }
+    
+    pub fn with_decimal_mark(mut self, mark: DecimalMark) -> Self {
+        self.decimal_mark = mark;
+        self
+    }
+    
+    pub fn with_thousands_separator(mut self, enabled: bool) -> Self {
+        self.thousands_separator = enabled;
+        self
+    }
+    
+    // `value` in the configured notation, with an SI prefix to suit its size
+    pub fn format(&self, value: f64) -> String {
+        self.format_as(value, self.notation.rescaled(self.round(value)))
+    }
+    
+    // A result of any number type. Floats follow the notation; decimals,
+    // fractions and complex numbers are shown in full, in the locale's marks
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::Float(value) => self.format(*value),
+            exact => self.localise_numbers(&exact.to_string()),
+        }
+    }
+    
+    // `value` in a given notation, e.g. the one its input was written in
+    pub fn format_as(&self, value: f64, notation: NumberFormat) -> String {
+        if value.is_nan() {
+            return "NaN".to_string();
+        }
+        if value.is_infinite() {
+            return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+        }
+        
+        // Radix notation shows whole numbers exactly, so it is never rounded;
+        // anything else falls back to decimal
+        let radix = matches!(notation, NumberFormat::Hexadecimal | NumberFormat::Binary | NumberFormat::Octal);
+        if radix && (value.fract() != 0.0 || value.abs() >= u64::MAX as f64) {
+            return self.format_as(value, NumberFormat::Decimal);
+        }
+        
+        let value = if radix { value } else { self.round(value) };
+        if value == 0.0 {
+            return "0".to_string(); // Never -0
+        }
+        
+        match notation {
+            NumberFormat::Decimal | NumberFormat::Grouped => {
+                // Past this many zeros scientific notation is easier to read
+                let exponent = decimal_exponent(value);
+                if !(-7..21).contains(&exponent) {
+                    return self.localise(&format!("{:e}", value), false);
+                }
+                
+                let grouped = self.thousands_separator || notation == NumberFormat::Grouped;
+                self.localise(&shift_decimal_point(value, 0), grouped)
+            },
+            // Scientific, engineering, radix and SI prefixes as expressions write them
+            other => self.localise(&Number::with_format(value, other).format(), false),
+        }
+    }
+    
+    // `value` cut to the configured number of significant digits
+    fn round(&self, value: f64) -> f64 {
+        format!("{:.*e}", self.precision as usize - 1, value)
+            .parse()
+            .unwrap_or(value)
+    }
+    
+    // Localises every number written in `text`, such as both parts of `1.5 + 2i`
+    // or a fraction's numerator and denominator. Exponents are left as they are
+    fn localise_numbers(&self, text: &str) -> String {
+        let mut localised = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+            localised.push_str(&rest[..start]);
+            let end = rest[start..].find(|c: char| !c.is_ascii_digit() && c != '.').map_or(rest.len(), |end| start + end);
+            let (number, after) = (&rest[start..end], &rest[end..]);
+            
+            // As in `format_as`, scientific notation is never grouped
+            let exponent = match after.strip_prefix('e') {
+                Some(digits) => 1 + digits.find(|c: char| !c.is_ascii_digit() && c != '-').unwrap_or(digits.len()),
+                None => 0,
+            };
+            localised.push_str(&self.localise(number, self.thousands_separator && exponent == 0));
+            localised.push_str(&after[..exponent]);
+            rest = &after[exponent..];
+        }
+        localised.push_str(rest);
+        localised
+    }
+    
+    // Swaps in the locale's decimal mark and, if asked, groups the integer part
+    fn localise(&self, text: &str, grouped: bool) -> String {
+        let (integer, fraction) = match text.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (text, None),
+        };
+        
+        let integer = if grouped {
+            group_digits_with(integer, self.decimal_mark.group_separator())
+        } else {
+            integer.to_string()
+        };
+        
+        match fraction {
+            Some(fraction) => format!("{}{}{}", integer, self.decimal_mark.symbol(), fraction),
+            None => integer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::numeric::Numeric;
+    use crate::decimal::Decimal;
+    use crate::rational::Rational;
+    use crate::complex::{Complex, ComplexForm};
+    use crate::test_support::evaluate_in;
+    
+    fn formatter() -> NumberFormatter {
+        NumberFormatter::from_config(&CalculatorConfig::default())
+    }
+    
+    #[test]
+    fn results_are_rounded_to_the_precision() {
+        assert_eq!(formatter().format(0.1 + 0.2), "0.3");
+        assert_eq!(formatter().with_precision(3).format(std::f64::consts::PI), "3.14");
+        assert_eq!(formatter().format(-0.0), "0");
+        assert_eq!(formatter().format(1e25), "1e25");
+        assert_eq!(formatter().format(f64::NEG_INFINITY), "-inf");
+    }
+    
+    #[test]
+    fn locale_marks_and_grouping() {
+        let comma = formatter().with_decimal_mark(DecimalMark::Comma).with_thousands_separator(true);
+        assert_eq!(comma.format(1234567.25), "1.234.567,25");
+        assert_eq!(formatter().with_thousands_separator(true).format(-1234.5), "-1,234.5");
+        assert_eq!(formatter().with_decimal_mark(DecimalMark::Comma).format(2.5e-9), "2,5e-9");
+    }
+    
+    #[test]
+    fn notations() {
+        assert_eq!(formatter().with_notation(NumberFormat::Scientific).format(1500.0), "1.5e3");
+        assert_eq!(formatter().with_notation(NumberFormat::Engineering).format(0.05), "50e-3");
+        assert_eq!(formatter().format_as(6900.0, NumberFormat::SiPrefix('k').rescaled(6900.0)), "6.9k");
+        assert_eq!(formatter().format_as(255.0, NumberFormat::Hexadecimal), "0xFF");
+        assert_eq!(formatter().format_as(2.5, NumberFormat::Hexadecimal), "2.5");
+    }
+    
+    #[test]
+    fn exact_results_use_the_locale_marks() {
+        let comma = formatter().with_decimal_mark(DecimalMark::Comma).with_thousands_separator(true);
+        let decimal = evaluate_in::<Decimal>("1234567.5", 30).unwrap().into_value();
+        assert_eq!(comma.format_value(&decimal), "1.234.567,5");
+        let fraction = evaluate_in::<Rational>("1/1000 + 2^-0.5", ()).unwrap().into_value();
+        assert_eq!(comma.format_value(&fraction), "≈0,7081067811865476");
+        let complex = evaluate_in::<Complex>("1500.25 - 2.5i", ComplexForm::Rectangular).unwrap().into_value();
+        assert_eq!(comma.format_value(&complex), "1.500,25 - 2,5i");
+        let large = evaluate_in::<Decimal>("1000!", 10).unwrap().into_value();
+        assert_eq!(comma.format_value(&large), "4,023872601e2567");
+        assert_eq!(formatter().format_value(&evaluate_in::<Rational>("1000/3", ()).unwrap().into_value()), "1000/3");
+    }
+}
@@ -0,0 +1,727 @@
+// interval.rs - Interval arithmetic: evaluates an expression over ranges of
+// values, giving bounds that are guaranteed to contain every result
+
+use std::collections::HashMap;
+use std::f64::consts::{PI, FRAC_PI_2};
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Neg};
+use crate::expression::{Expr, Callee, CallContext, UserFunction, factorial, undefined_variable, enter_user_function, run_user_function};
+use crate::token::{Operator, UnaryOperator, PostfixOperator, Function, ModuloConvention, Span};
+use crate::error::CalcError;
+use crate::config::{DEFAULT_RECURSION_DEPTH, get_global_config};
+use crate::constants;
+
+// Where x! = Γ(x + 1) is smallest for x >= 0, and that smallest value
+const FACTORIAL_MIN_AT: f64 = 0.461_632_144_968_362_3;
+const FACTORIAL_MIN: f64 = 0.885_603_194_410_888_7;
+
+// How far Γ's approximation, and products of many factors, may be off
+const FACTORIAL_ERROR: f64 = 1e-13;
+
+// The closed range [lo, hi]. Every bound is rounded outwards, so the exact
+// result lies inside even though the bounds themselves are rounded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+// Which way a bound is rounded
+#[derive(Clone, Copy)]
+enum Round {
+    Down,
+    Up,
+}
+
+impl Interval {
+    // A NaN bound, as from ∞ - ∞, could be anything
+    pub fn new(lo: f64, hi: f64) -> Self {
+        Self {
+            lo: if lo.is_nan() { f64::NEG_INFINITY } else { lo },
+            hi: if hi.is_nan() { f64::INFINITY } else { hi },
+        }
+    }
+    
+    pub fn point(value: f64) -> Self {
+        Self::new(value, value)
+    }
+    
+    // A value that was rounded to the nearest f64, such as π
+    pub fn around(value: f64) -> Self {
+        Self::new(value.next_down(), value.next_up())
+    }
+    
+    // A number as written. Whole numbers and short binary fractions such as
+    // 0.25 are exact; others such as 0.1 have no exact f64
+    pub fn literal(value: f64) -> Self {
+        if (value * 1024.0).fract() == 0.0 || !value.is_finite() {
+            Self::point(value)
+        } else {
+            Self::around(value)
+        }
+    }
+    
+    pub fn entire() -> Self {
+        Self::new(f64::NEG_INFINITY, f64::INFINITY)
+    }
+    
+    pub fn contains(&self, value: f64) -> bool {
+        self.lo <= value && value <= self.hi
+    }
+    
+    pub fn is_point(&self) -> bool {
+        self.lo == self.hi
+    }
+    
+    pub fn hull(&self, other: &Interval) -> Interval {
+        Interval::new(self.lo.min(other.lo), self.hi.max(other.hi))
+    }
+    
+    // Smallest and largest magnitude of a value inside
+    fn mignitude(&self) -> f64 {
+        if self.contains(0.0) { 0.0 } else { self.lo.abs().min(self.hi.abs()) }
+    }
+    
+    fn magnitude(&self) -> f64 {
+        self.lo.abs().max(self.hi.abs())
+    }
+    
+    // Whether every value inside is true, or every one false; None when it varies
+    pub fn truth(&self) -> Option<bool> {
+        if self.lo == 0.0 && self.hi == 0.0 {
+            Some(false)
+        } else if !self.contains(0.0) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+    
+    // A condition's result: 1 if it always holds, 0 if it never does, else both
+    fn condition(always: bool, never: bool) -> Interval {
+        if always {
+            Interval::point(1.0)
+        } else if never {
+            Interval::point(0.0)
+        } else {
+            Interval::new(0.0, 1.0)
+        }
+    }
+    
+    // Library functions are accurate to within an ulp, so one more each way covers them
+    fn widened(lo: f64, hi: f64) -> Interval {
+        Interval::new(rounded(lo, f64::NAN, Round::Down), rounded(hi, f64::NAN, Round::Up))
+    }
+    
+    // `percent` as in `Operator::apply`
+    pub fn apply(self, operator: &Operator, other: Interval, percent: bool, span: Option<Span>) -> Result<Interval, CalcError> {
+        let relative = if percent { self * other } else { other };
+        let (always, never) = match operator {
+            Operator::Add => return Ok(self + relative),
+            Operator::Subtract => return Ok(self - relative),
+            Operator::Multiply | Operator::ImplicitMultiply => return Ok(self * other),
+            Operator::Divide => return self.divide(other, span),
+            Operator::Modulo => return self.modulo(other, span),
+            Operator::FloorDivide => {
+                let quotient = self.divide(other, span)?;
+                return Ok(Interval::new(quotient.lo.floor(), quotient.hi.floor()));
+            },
+            Operator::Power => return self.pow(other, span),
+            Operator::Less => (self.hi < other.lo, self.lo >= other.hi),
+            Operator::LessEqual => (self.hi <= other.lo, self.lo > other.hi),
+            Operator::Greater => (self.lo > other.hi, self.hi <= other.lo),
+            Operator::GreaterEqual => (self.lo >= other.hi, self.hi < other.lo),
+            Operator::Equal | Operator::NotEqual => {
+                let equal = self.is_point() && other.is_point() && self.lo == other.lo;
+                let apart = self.hi < other.lo || self.lo > other.hi;
+                if *operator == Operator::Equal { (equal, apart) } else { (apart, equal) }
+            },
+            Operator::And => (
+                self.truth() == Some(true) && other.truth() == Some(true),
+                self.truth() == Some(false) || other.truth() == Some(false),
+            ),
+            Operator::Or => (
+                self.truth() == Some(true) || other.truth() == Some(true),
+                self.truth() == Some(false) && other.truth() == Some(false),
+            ),
+        };
+        Ok(Interval::condition(always, never))
+    }
+    
+    // Evaluation fails where the divisor is 0, so those points are left out:
+    // dividing by an interval containing 0 gives unbounded results instead
+    pub fn divide(self, divisor: Interval, span: Option<Span>) -> Result<Interval, CalcError> {
+        if divisor.lo == 0.0 && divisor.hi == 0.0 {
+            return Err(CalcError::DivisionByZero { span });
+        }
+        if self.lo == 0.0 && self.hi == 0.0 {
+            return Ok(self);
+        }
+        
+        let (lo, hi) = (f64::NEG_INFINITY, f64::INFINITY);
+        Ok(if divisor.lo > 0.0 || divisor.hi < 0.0 {
+            let corners = [(self.lo, divisor.lo), (self.lo, divisor.hi), (self.hi, divisor.lo), (self.hi, divisor.hi)];
+            Interval::new(
+                lowest(corners.map(|(a, b)| quotient(a, b, Round::Down))),
+                highest(corners.map(|(a, b)| quotient(a, b, Round::Up))),
+            )
+        } else if divisor.lo == 0.0 && self.lo >= 0.0 {
+            // (0, d]: the result grows without bound as the divisor nears 0
+            Interval::new(quotient(self.lo, divisor.hi, Round::Down), hi)
+        } else if divisor.lo == 0.0 && self.hi <= 0.0 {
+            Interval::new(lo, quotient(self.hi, divisor.hi, Round::Up))
+        } else if divisor.hi == 0.0 && self.lo >= 0.0 {
+            // [d, 0)
+            Interval::new(lo, quotient(self.lo, divisor.lo, Round::Up))
+        } else if divisor.hi == 0.0 && self.hi <= 0.0 {
+            Interval::new(quotient(self.hi, divisor.lo, Round::Down), hi)
+        } else {
+            Interval::entire()
+        })
+    }
+    
+    // A remainder is never larger than the divisor, and its sign follows the convention
+    pub fn modulo(self, divisor: Interval, span: Option<Span>) -> Result<Interval, CalcError> {
+        if divisor.lo == 0.0 && divisor.hi == 0.0 {
+            return Err(CalcError::DivisionByZero { span });
+        }
+        
+        let convention = get_global_config().modulo_convention;
+        if self.is_point() && divisor.is_point() {
+            return Ok(Interval::around(convention.remainder(self.lo, divisor.lo)));
+        }
+        // A dividend already smaller than any divisor is its own remainder
+        if self.lo >= 0.0 && self.hi < divisor.mignitude() && (divisor.lo > 0.0 || convention != ModuloConvention::Floored) {
+            return Ok(self);
+        }
+        
+        let bound = divisor.magnitude();
+        Ok(match convention {
+            ModuloConvention::Truncated => Interval::new(self.lo.max(-bound).min(0.0), self.hi.min(bound).max(0.0)),
+            ModuloConvention::Floored => Interval::new(if divisor.lo < 0.0 { -bound } else { 0.0 }, if divisor.hi > 0.0 { bound } else { 0.0 }),
+            ModuloConvention::Euclidean => Interval::new(0.0, bound),
+        })
+    }
+    
+    pub fn pow(self, exponent: Interval, span: Option<Span>) -> Result<Interval, CalcError> {
+        // Whole exponents are worked out by multiplying, which allows negative bases
+        if exponent.is_point() && exponent.lo.fract() == 0.0 && exponent.lo.abs() <= 1024.0 {
+            let power = self.powi(exponent.lo.abs() as u32);
+            return Ok(if exponent.lo < 0.0 { power.reciprocal() } else { power });
+        }
+        if self.lo < 0.0 {
+            // A negative base to a fractional power is not a real number
+            return Err(CalcError::DomainError { function: "^".to_string(), argument: self.lo, span });
+        }
+        
+        // x ^ y is monotonic in each argument, so its extremes are at the corners
+        let corners = [(self.lo, exponent.lo), (self.lo, exponent.hi), (self.hi, exponent.lo), (self.hi, exponent.hi)]
+            .map(|(base, exponent)| base.powf(exponent));
+        let power = Interval::widened(lowest(corners), highest(corners));
+        Ok(Interval::new(power.lo.max(0.0), power.hi))
+    }
+    
+    fn powi(self, n: u32) -> Interval {
+        if n == 0 {
+            Interval::point(1.0)
+        } else if n.is_multiple_of(2) {
+            Interval::new(power(self.mignitude(), n, Round::Down), power(self.magnitude(), n, Round::Up))
+        } else {
+            Interval::new(signed_power(self.lo, n, Round::Down), signed_power(self.hi, n, Round::Up))
+        }
+    }
+    
+    // 1 / x, where 1 / 0 is infinite as in x ^ -1
+    fn reciprocal(self) -> Interval {
+        if self.lo > 0.0 || self.hi < 0.0 {
+            Interval::new(quotient(1.0, self.hi, Round::Down), quotient(1.0, self.lo, Round::Up))
+        } else if self.lo >= 0.0 {
+            Interval::new(quotient(1.0, self.hi, Round::Down), f64::INFINITY)
+        } else {
+            Interval::entire()
+        }
+    }
+    
+    // Whether target + k * period lies within `slack` of the interval, for some
+    // whole k. Errs towards yes, which only ever widens a result
+    fn reaches(&self, target: f64, period: f64, slack: f64) -> bool {
+        let width = self.hi - self.lo;
+        if width.is_nan() || width >= period || self.magnitude() > 1e12 {
+            return true;
+        }
+        let slack = slack + 1e-12 * self.magnitude();
+        let k = ((self.lo - slack - target) / period).ceil();
+        target + k * period <= self.hi + slack
+    }
+    
+    // sin and cos: the endpoints, or ±1 where a peak or trough is inside
+    fn periodic(self, f: fn(f64) -> f64, peak: f64) -> Interval {
+        let ends = Interval::widened(f(self.lo).min(f(self.hi)), f(self.lo).max(f(self.hi)));
+        let lo = if self.reaches(peak + PI, 2.0 * PI, 1e-9) { -1.0 } else { ends.lo.max(-1.0) };
+        let hi = if self.reaches(peak, 2.0 * PI, 1e-9) { 1.0 } else { ends.hi.min(1.0) };
+        Interval::new(lo, hi)
+    }
+    
+    pub fn factorial(self, span: Option<Span>) -> Result<Interval, CalcError> {
+        if self.is_point() {
+            let value = PostfixOperator::Factorial.apply(self.lo, span)?;
+            return Ok(Interval::point(value).loosened(FACTORIAL_ERROR));
+        }
+        if self.lo < 0.0 {
+            // Γ has a pole at every negative whole number
+            return Err(CalcError::DomainError { function: "factorial".to_string(), argument: self.lo, span });
+        }
+        
+        let range = if self.lo >= FACTORIAL_MIN_AT {
+            Interval::new(factorial(self.lo), factorial(self.hi))
+        } else if self.hi <= FACTORIAL_MIN_AT {
+            Interval::new(factorial(self.hi), factorial(self.lo))
+        } else {
+            Interval::new(FACTORIAL_MIN, factorial(self.lo).max(factorial(self.hi)))
+        };
+        Ok(range.loosened(FACTORIAL_ERROR))
+    }
+    
+    // Widened by a relative error; infinite bounds stay as they are
+    fn loosened(self, error: f64) -> Interval {
+        let lo = if self.lo.is_finite() { self.lo - self.lo.abs() * error } else { self.lo };
+        let hi = if self.hi.is_finite() { self.hi + self.hi.abs() * error } else { self.hi };
+        Interval::widened(lo, hi)
+    }
+    
+    // Applies a built-in function to evaluated arguments. A range that reaches
+    // outside the function's domain is an error, as some value in it would be
+    pub fn call(function: &Function, args: &[Interval], span: Option<Span>) -> Result<Interval, CalcError> {
+        function.check_arity(args.len(), span)?;
+        
+        let domain_error = |argument: f64| CalcError::DomainError {
+            function: function.name().to_string(),
+            argument,
+            span,
+        };
+        let x = args[0];
+        
+        Ok(match function {
+            // Neither has a value at ±∞
+            Function::Sin | Function::Cos if !x.lo.is_finite() || !x.hi.is_finite() => {
+                return Err(domain_error(if x.lo.is_finite() { x.hi } else { x.lo }));
+            },
+            Function::Sin => x.periodic(f64::sin, FRAC_PI_2),
+            Function::Cos => x.periodic(f64::cos, 0.0),
+            Function::Tan => {
+                if x.reaches(FRAC_PI_2, PI, 1e-10) {
+                    return Err(domain_error(x.lo));
+                }
+                Interval::widened(x.lo.tan(), x.hi.tan())
+            },
+            Function::Sqrt => {
+                if x.lo < 0.0 {
+                    return Err(domain_error(x.lo));
+                }
+                Interval::new(root(x.lo, Round::Down).max(0.0), root(x.hi, Round::Up))
+            },
+            Function::Log => {
+                let base = args[1];
+                if x.lo <= 0.0 {
+                    return Err(domain_error(x.lo));
+                } else if base.lo <= 0.0 {
+                    return Err(domain_error(base.lo));
+                } else if base.contains(1.0) {
+                    return Err(domain_error(1.0));
+                }
+                let ln = |x: Interval| Interval::widened(x.lo.ln(), x.hi.ln());
+                ln(x).divide(ln(base), span)?
+            },
+            Function::Atan2 => {
+                let (y, x) = (args[0], args[1]);
+                // Around the origin, or across the negative x axis, every angle is reached
+                if x.lo <= 0.0 && y.contains(0.0) {
+                    Interval::new(-PI.next_up(), PI.next_up())
+                } else {
+                    let corners = [(y.lo, x.lo), (y.lo, x.hi), (y.hi, x.lo), (y.hi, x.hi)].map(|(y, x)| y.atan2(x));
+                    Interval::widened(lowest(corners), highest(corners))
+                }
+            },
+            Function::Hypot => {
+                let y = args[1];
+                let hypot = Interval::widened(x.mignitude().hypot(y.mignitude()), x.magnitude().hypot(y.magnitude()));
+                Interval::new(hypot.lo.max(0.0), hypot.hi)
+            },
+            Function::Min => args.iter().fold(Interval::point(f64::INFINITY), |min, arg| Interval::new(min.lo.min(arg.lo), min.hi.min(arg.hi))),
+            Function::Max => args.iter().fold(Interval::point(f64::NEG_INFINITY), |max, arg| Interval::new(max.lo.max(arg.lo), max.hi.max(arg.hi))),
+            Function::Clamp => {
+                let (lo, hi) = (args[1], args[2]);
+                if lo.hi > hi.lo {
+                    return Err(domain_error(lo.hi));
+                }
+                Interval::new(x.lo.max(lo.lo).min(hi.lo), x.hi.max(lo.hi).min(hi.hi))
+            },
+            // Evaluating picks a branch of `if` lazily; here both are already known
+            Function::If => match x.truth() {
+                Some(true) => args[1],
+                Some(false) => args[2],
+                None => args[1].hull(&args[2]),
+            },
+            Function::Re | Function::Conj => x,
+            Function::Im => Interval::point(0.0),
+            Function::Abs => Interval::new(x.mignitude(), x.magnitude()),
+            // 0 for positive numbers and π for negative ones, which -0 counts as
+            Function::Arg if x.lo > 0.0 => Interval::point(0.0),
+            Function::Arg if x.hi < 0.0 => Interval::new(PI, PI.next_up()),
+            Function::Arg => Interval::new(0.0, PI.next_up()),
+        })
+    }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+    
+    fn add(self, other: Interval) -> Interval {
+        Interval::new(sum(self.lo, other.lo, Round::Down), sum(self.hi, other.hi, Round::Up))
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+    
+    fn sub(self, other: Interval) -> Interval {
+        self + -other
+    }
+}
+
+impl Neg for Interval {
+    type Output = Interval;
+    
+    fn neg(self) -> Interval {
+        Interval::new(-self.hi, -self.lo)
+    }
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+    
+    fn mul(self, other: Interval) -> Interval {
+        let corners = [(self.lo, other.lo), (self.lo, other.hi), (self.hi, other.lo), (self.hi, other.hi)];
+        Interval::new(
+            lowest(corners.map(|(a, b)| product(a, b, Round::Down))),
+            highest(corners.map(|(a, b)| product(a, b, Round::Up))),
+        )
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+// `value` moved one f64 in the direction of `round`, unless `error` (the exact
+// result minus `value`) shows it is already on that side. NaN means unknown
+fn rounded(value: f64, error: f64, round: Round) -> f64 {
+    match round {
+        Round::Down if error < 0.0 || error.is_nan() => value.next_down(),
+        Round::Up if error > 0.0 || error.is_nan() => value.next_up(),
+        _ => value,
+    }
+}
+
+// The basic operations find their own rounding error exactly, so exact
+// results such as 1 + 2 keep exact bounds
+fn sum(a: f64, b: f64, round: Round) -> f64 {
+    let s = a + b;
+    let b_part = s - a;
+    rounded(s, (a - (s - b_part)) + (b - b_part), round)
+}
+
+fn product(a: f64, b: f64, round: Round) -> f64 {
+    // 0 * ∞ counts as 0, since an infinite bound is never actually reached
+    if a == 0.0 || b == 0.0 {
+        return 0.0;
+    }
+    let p = a * b;
+    rounded(p, a.mul_add(b, -p), round)
+}
+
+fn quotient(a: f64, b: f64, round: Round) -> f64 {
+    let q = a / b;
+    // a - q * b has the sign of the error when b is positive
+    rounded(q, q.mul_add(-b, a) * b.signum(), round)
+}
+
+fn root(x: f64, round: Round) -> f64 {
+    let s = x.sqrt();
+    rounded(s, s.mul_add(-s, x), round)
+}
+
+// base ^ n for base >= 0, by squaring, with every step rounded the same way
+fn power(base: f64, n: u32, round: Round) -> f64 {
+    let (mut result, mut square, mut n) = (1.0, base, n);
+    while n > 0 {
+        if n % 2 == 1 {
+            result = product(result, square, round);
+        }
+        square = product(square, square, round);
+        n /= 2;
+    }
+    result
+}
+
+// x ^ n for odd n, which keeps the sign of x
+fn signed_power(x: f64, n: u32, round: Round) -> f64 {
+    if x >= 0.0 {
+        power(x, n, round)
+    } else {
+        let opposite = match round {
+            Round::Down => Round::Up,
+            Round::Up => Round::Down,
+        };
+        -power(-x, n, opposite)
+    }
+}
+
+// Extremes of candidate bounds; a NaN candidate means the bound is unknown
+fn lowest(values: [f64; 4]) -> f64 {
+    values.into_iter().fold(f64::INFINITY, |lo, value| if value.is_nan() { f64::NEG_INFINITY } else { lo.min(value) })
+}
+
+fn highest(values: [f64; 4]) -> f64 {
+    values.into_iter().fold(f64::NEG_INFINITY, |hi, value| if value.is_nan() { f64::INFINITY } else { hi.max(value) })
+}
+
+// What an interval evaluation looks up: variables hold ranges rather than numbers
+pub struct IntervalContext<'a> {
+    pub variables: &'a HashMap<String, Interval>,
+    pub functions: &'a HashMap<String, UserFunction>,
+    pub depth: usize, // User function calls currently in progress
+    pub max_depth: usize,
+}
+
+impl<'a> IntervalContext<'a> {
+    pub fn new(variables: &'a HashMap<String, Interval>, functions: &'a HashMap<String, UserFunction>) -> Self {
+        Self {
+            variables,
+            functions,
+            depth: 0,
+            max_depth: DEFAULT_RECURSION_DEPTH,
+        }
+    }
+    
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    
+    // An interval holding every value `expr` takes over the variables' ranges.
+    // Fails where evaluating some value in them would
+    pub fn evaluate(&self, expr: &Expr) -> Result<Interval, CalcError> {
+        match expr {
+            Expr::Number { value, .. } => Ok(Interval::literal(*value)),
+            Expr::Variable { name, span } => {
+                constants::resolve_as(name, self.variables, |constant| Interval::around(constant.value)).ok_or_else(|| {
+                    undefined_variable(name, self.variables.keys().map(String::as_str), *span)
+                })
+            },
+            Expr::Binary { operator, left, right, span } => {
+                let left_val = self.evaluate(left)?;
+                
+                // `&&` and `||` only skip the right side when the left decides every case
+                match (operator, left_val.truth()) {
+                    (Operator::And, Some(false)) => return Ok(Interval::point(0.0)),
+                    (Operator::Or, Some(true)) => return Ok(Interval::point(1.0)),
+                    _ => {},
+                }
+                
+                let right_val = self.evaluate(right)?;
+                let percent = matches!(**right, Expr::Postfix { operator: PostfixOperator::Percent, .. });
+                left_val.apply(operator, right_val, percent, *span)
+            },
+            Expr::Unary { operator, operand, .. } => {
+                let value = self.evaluate(operand)?;
+                Ok(match operator {
+                    UnaryOperator::Negate => -value,
+                    UnaryOperator::Plus => value,
+                    UnaryOperator::Not => Interval::condition(value.truth() == Some(false), value.truth() == Some(true)),
+                })
+            },
+            Expr::Postfix { operator: PostfixOperator::Factorial, operand, span } => self.evaluate(operand)?.factorial(*span),
+            Expr::Postfix { operator: PostfixOperator::Percent, operand, span } => {
+                self.evaluate(operand)?.divide(Interval::point(100.0), *span)
+            },
+            // Only a branch the condition can select is evaluated
+            Expr::Call { function: Callee::Builtin(Function::If), arguments, .. } if arguments.len() == 3 => {
+                match self.evaluate(&arguments[0])?.truth() {
+                    Some(true) => self.evaluate(&arguments[1]),
+                    Some(false) => self.evaluate(&arguments[2]),
+                    None => Ok(self.evaluate(&arguments[1])?.hull(&self.evaluate(&arguments[2])?)),
+                }
+            },
+            Expr::Call { function: Callee::Builtin(function), arguments, span } => {
+                let args = arguments.iter().map(|argument| self.evaluate(argument)).collect::<Result<Vec<_>, _>>()?;
+                Interval::call(function, &args, *span)
+            },
+            Expr::Call { function: Callee::User(name), arguments, span } => self.call_user_function(name, arguments, *span),
+            Expr::Error { error, .. } => Err(error.clone()),
+            Expr::Custom(node) => {
+                // Custom nodes only know how to work on numbers
+                let values = node.children().iter().map(|child| self.evaluate(child)).collect::<Result<Vec<_>, _>>()?;
+                let points: Option<Vec<f64>> = values.iter().map(|value| value.is_point().then_some(value.lo)).collect();
+                match points.and_then(|points| node.fold(&points)) {
+                    Some(result) => Ok(Interval::around(result?)),
+                    None => Err(CalcError::Unsupported {
+                        what: format!("`{}`", node.to_string()),
+                        mode: "interval".to_string(),
+                        span: node.span(),
+                    }),
+                }
+            },
+        }
+    }
+    
+    fn call_user_function(&self, name: &str, arguments: &[Expr], span: Option<Span>) -> Result<Interval, CalcError> {
+        let function = enter_user_function(name, arguments.len(), span, self)?;
+        
+        // Arguments are evaluated in the caller's scope, then bound over it
+        let args = arguments.iter().map(|argument| self.evaluate(argument)).collect::<Result<Vec<_>, _>>()?;
+        run_user_function(function, args, span, self)
+    }
+}
+
+impl CallContext for IntervalContext<'_> {
+    type Value = Interval;
+    
+    fn functions(&self) -> &HashMap<String, UserFunction> {
+        self.functions
+    }
+    
+    fn variables(&self) -> &HashMap<String, Interval> {
+        self.variables
+    }
+    
+    fn depth(&self) -> (usize, usize) {
+        (self.depth, self.max_depth)
+    }
+    
+    fn evaluate_nested(&self, scope: &HashMap<String, Interval>, body: &Expr) -> Result<Interval, CalcError> {
+        let inner = IntervalContext {
+            variables: scope,
+            functions: self.functions,
+            depth: self.depth + 1,
+            max_depth: self.max_depth,
+        };
+        inner.evaluate(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ExpressionParser;
+    use crate::test_support::{check_expressions, random_expressions, Rng, CASES};
+    
+    // Ranges around the values `test_support::variables` gives
+    fn ranges() -> HashMap<String, Interval> {
+        [("x", (1.5, 2.5)), ("y", (-3.5, -2.5)), ("z", (0.25, 0.75))]
+            .into_iter()
+            .map(|(name, (lo, hi))| (name.to_string(), Interval::new(lo, hi)))
+            .collect()
+    }
+    
+    #[test]
+    fn intervals_enclose_every_point() {
+        let ranges = ranges();
+        let functions = HashMap::new();
+        let context = IntervalContext::new(&ranges, &functions);
+        
+        let (mut seed, mut checked) = (0, 0);
+        check_expressions(random_expressions(), |expr| {
+            // An interval evaluation may fail where only some values would
+            let bounds = context.evaluate(expr).ok()?;
+            
+            // Both ends of every range, then points between
+            seed += 1;
+            let mut rng = Rng::new(seed);
+            for sample in 0..10 {
+                let variables: HashMap<String, f64> = ranges
+                    .iter()
+                    .map(|(name, range)| {
+                        let share = match sample {
+                            0 => 0.0,
+                            1 => 1.0,
+                            _ => rng.below(1001) as f64 / 1000.0,
+                        };
+                        (name.clone(), range.lo + (range.hi - range.lo) * share)
+                    })
+                    .collect();
+                
+                // NaN is not a value to enclose
+                let Ok(value) = expr.evaluate(&variables) else { continue };
+                if value.is_nan() {
+                    continue;
+                }
+                checked += 1;
+                if !bounds.contains(value) {
+                    return Some(format!("{} at {:?}, outside {}", value, variables, bounds));
+                }
+            }
+            None
+        });
+        assert!(checked > CASES as usize, "only {} points checked", checked);
+    }
+    
+    #[test]
+    fn interval_bounds_are_tight() {
+        let parser = ExpressionParser::new();
+        let functions = HashMap::new();
+        let cases = [
+            ("x^2 - x", (1.0, 2.0), "[-1, 3]"),
+            ("x * x", (-1.0, 2.0), "[-2, 4]"), // Each x varies on its own, unlike in x^2
+            ("x^2", (-1.0, 2.0), "[0, 4]"),
+            ("1 / x", (1.0, 4.0), "[0.25, 1]"),
+            ("1 / x", (0.0, 2.0), "[0.5, inf]"),
+            ("1 / x", (-1.0, 0.0), "[-inf, -1]"),
+            ("1 / x", (-1.0, 1.0), "[-inf, inf]"),
+            ("sin(x)", (0.0, 4.0), "[-0.7568024953079283, 1]"),
+            ("cos(x)", (-1.0, 7.0), "[-1, 1]"),
+            ("x % 10", (1.0, 2.0), "[1, 2]"),
+            ("x > 3", (1.0, 2.0), "[0, 0]"),
+            ("x > 1.5", (1.0, 2.0), "[0, 1]"),
+            ("if(x > 0, x, -x)", (1.0, 2.0), "[1, 2]"),
+            ("x || 1 / 0", (1.0, 2.0), "[1, 1]"),
+        ];
+        
+        for (input, (lo, hi), expected) in cases {
+            let ranges: HashMap<String, Interval> = [("x".to_string(), Interval::new(lo, hi))].into_iter().collect();
+            let bounds = IntervalContext::new(&ranges, &functions).evaluate(&parser.parse(input).unwrap()).unwrap();
+            assert_eq!(bounds.to_string(), expected, "{} over [{}, {}]", input, lo, hi);
+        }
+        
+        // 0.1 has no exact f64, so its bounds are the f64s either side
+        let tenth = IntervalContext::new(&HashMap::new(), &functions).evaluate(&parser.parse("0.1 + 0.2").unwrap()).unwrap();
+        assert!(tenth.contains(0.30000000000000004) && tenth.contains(0.3) && tenth.hi - tenth.lo < 1e-15, "0.1 + 0.2 is {}", tenth);
+        
+        for (input, (lo, hi)) in [("sqrt(x)", (-1.0, 1.0)), ("x / 0", (1.0, 2.0)), ("tan(x)", (1.0, 2.0)), ("log(2, x)", (0.5, 2.0))] {
+            let ranges: HashMap<String, Interval> = [("x".to_string(), Interval::new(lo, hi))].into_iter().collect();
+            let result = IntervalContext::new(&ranges, &functions).evaluate(&parser.parse(input).unwrap());
+            assert!(result.is_err(), "{} over [{}, {}] should fail, got {:?}", input, lo, hi, result);
+        }
+    }
+    
+    #[test]
+    fn nested_calls_stop_at_the_depth_limit() {
+        let parser = ExpressionParser::new();
+        let functions: HashMap<String, UserFunction> = [
+            UserFunction::new("f", vec!["x".to_string()], parser.parse("x + 1").unwrap()),
+            UserFunction::new("g", vec!["x".to_string()], parser.parse("f(x) * 2").unwrap()),
+        ]
+        .into_iter()
+        .map(|function| (function.name.clone(), function))
+        .collect();
+        let ranges: HashMap<String, Interval> = [("x".to_string(), Interval::new(1.0, 2.0))].into_iter().collect();
+        let call = parser.parse("g(x)").unwrap();
+        
+        let context = IntervalContext::new(&ranges, &functions);
+        assert_eq!(context.evaluate(&call).unwrap().to_string(), "[4, 6]");
+        let limited = context.with_max_depth(1).evaluate(&call);
+        assert!(matches!(limited, Err(CalcError::RecursionLimit { limit: 1, .. })), "{:?}", limited);
+    }
+}
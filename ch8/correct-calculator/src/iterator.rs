@@ -2,7 +2,8 @@
 
 use std::collections::HashMap;
 use crate::command::Calculation;
-use crate::expression::{Expression, BinaryOperation, NumberExpression, VariableExpression, FunctionCall};
+use crate::expression::Expr;
+use crate::numeric::Value;
 
 // History iterator that provides access to past results
 pub struct HistoryIterator<'a> {
@@ -61,78 +62,35 @@ impl<'a> Iterator for ReverseHistoryIterator<'a> {
     }
 }
 
-// Extension trait for expression tree traversal
-pub trait ExpressionExt {
-    fn as_binary_op(&self) -> Option<&BinaryOperation> { None }
-    fn as_number(&self) -> Option<&NumberExpression> { None }
-    fn as_variable(&self) -> Option<&VariableExpression> { None }
-    fn as_function(&self) -> Option<&FunctionCall> { None }
-    fn is_constant(&self) -> bool { self.as_number().is_some() }
-}
-
-impl ExpressionExt for dyn Expression {
-    fn as_binary_op(&self) -> Option<&BinaryOperation> { None }
-    fn as_number(&self) -> Option<&NumberExpression> { None }
-    fn as_variable(&self) -> Option<&VariableExpression> { None }
-    fn as_function(&self) -> Option<&FunctionCall> { None }
-}
-
-impl ExpressionExt for BinaryOperation {
-    fn as_binary_op(&self) -> Option<&BinaryOperation> { Some(self) }
-}
-
-impl ExpressionExt for NumberExpression {
-    fn as_number(&self) -> Option<&NumberExpression> { Some(self) }
-    fn is_constant(&self) -> bool { true }
-}
-
-impl ExpressionExt for VariableExpression {
-    fn as_variable(&self) -> Option<&VariableExpression> { Some(self) }
-}
-
-impl ExpressionExt for FunctionCall {
-    fn as_function(&self) -> Option<&FunctionCall> { Some(self) }
-}
-
-// Iterator for traversing expression trees (depth-first)
+// Iterator for traversing expression trees (depth-first, parents before children)
 pub struct ExpressionIterator<'a> {
-    stack: Vec<&'a dyn Expression>,
+    stack: Vec<&'a Expr>,
 }
 
 impl<'a> ExpressionIterator<'a> {
-    pub fn new(root: &'a dyn Expression) -> Self {
-        let mut stack = Vec::new();
-        stack.push(root);
-        Self { stack }
+    pub fn new(root: &'a Expr) -> Self {
+        Self { stack: vec![root] }
     }
 }
 
 impl<'a> Iterator for ExpressionIterator<'a> {
-    type Item = &'a dyn Expression;
+    type Item = &'a Expr;
     
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(node) = self.stack.pop() {
-            // Push children onto stack for depth-first traversal
-            if let Some(op) = node.as_binary_op() {
-                self.stack.push(&*op.right);
-                self.stack.push(&*op.left);
-            } else if let Some(func) = node.as_function() {
-                self.stack.push(&*func.argument);
-            }
-            Some(node)
-        } else {
-            None
-        }
+        let node = self.stack.pop()?;
+        // Reversed, so the leftmost child comes out first
+        self.stack.extend(node.children().rev());
+        Some(node)
     }
 }
 
 // Variables map iterator
 pub struct VariablesIterator<'a> {
-    inner: std::collections::hash_map::Iter<'a, String, f64>,
+    inner: std::collections::hash_map::Iter<'a, String, Value>,
 }
 
 impl<'a> VariablesIterator<'a> {
-    pub fn new(variables: &'a HashMap<String, f64>) -> Self {
+    pub fn new(variables: &'a HashMap<String, Value>) -> Self {
         Self {
             inner: variables.iter(),
         }
@@ -140,7 +98,7 @@ impl<'a> VariablesIterator<'a> {
 }
 
 impl<'a> Iterator for VariablesIterator<'a> {
-    type Item = (&'a String, &'a f64);
+    type Item = (&'a String, &'a Value);
     
     fn next(&mut self) -> Option<Self::Item> {
         self.inner.next()
@@ -148,15 +106,15 @@ impl<'a> Iterator for VariablesIterator<'a> {
 }
 
 // Helper function to collect constants from an expression
-pub fn find_constant_nodes<'a>(expr: &'a dyn Expression) -> Vec<&'a dyn Expression> {
+pub fn find_constant_nodes(expr: &Expr) -> Vec<&Expr> {
     ExpressionIterator::new(expr)
-        .filter(|node| node.is_constant())
+        .filter(|node| matches!(node, Expr::Number { .. }))
         .collect()
 }
 
 // Helper function to collect variable nodes from an expression
-pub fn find_variable_nodes<'a>(expr: &'a dyn Expression) -> Vec<&'a dyn Expression> {
+pub fn find_variable_nodes(expr: &Expr) -> Vec<&Expr> {
     ExpressionIterator::new(expr)
-        .filter(|node| node.as_variable().is_some())
+        .filter(|node| matches!(node, Expr::Variable { .. }))
         .collect()
 }
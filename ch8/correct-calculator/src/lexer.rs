@@ -0,0 +1,425 @@
+// lexer.rs - Character-level scanner shared by all parsing front-ends
+
+use crate::token::{Token, Span, SpannedToken, Operator, UnaryOperator, PostfixOperator, si_exponent};
+use crate::error::CalcError;
+use crate::config::{get_global_config, DecimalMark};
+
+// Multi-character operators come first so the longest match wins
+const SYMBOLS: &[&str] = &[
+    "**", "//", "<=", ">=", "==", "!=", "&&", "||",
+    "+", "-", "*", "/", "%", "^", "<", ">", "!", "(", ")", ",",
+];
+
+pub struct Lexer<'a> {
+    input: &'a str,
+    pos: usize, // Byte offset of the next unread character
+    implicit_multiplication: bool,
+    custom_symbols: Vec<String>, // Operators registered at runtime, e.g. `∥`
+    decimal_mark: DecimalMark,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            pos: 0,
+            implicit_multiplication: get_global_config().implicit_multiplication,
+            custom_symbols: Vec::new(),
+            decimal_mark: get_global_config().decimal_mark,
+        }
+    }
+    
+    pub fn with_implicit_multiplication(mut self, enabled: bool) -> Self {
+        self.implicit_multiplication = enabled;
+        self
+    }
+    
+    pub fn with_custom_symbols(mut self, symbols: Vec<String>) -> Self {
+        self.custom_symbols = symbols;
+        self
+    }
+    
+    // With `DecimalMark::Comma`, `3,5` is 3.5 and `max(1; 2)` separates arguments.
+    // A `.` is still read as a decimal point, so printed expressions parse back
+    pub fn with_decimal_mark(mut self, mark: DecimalMark) -> Self {
+        self.decimal_mark = mark;
+        self
+    }
+    
+    pub fn tokenize(self) -> Result<Vec<SpannedToken>, CalcError> {
+        let (tokens, mut errors) = self.tokenize_recovering();
+        
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors.remove(0))
+        }
+    }
+    
+    // Like `tokenize`, but skips over text it cannot read and keeps going,
+    // returning every error alongside the tokens it did find
+    pub fn tokenize_recovering(mut self) -> (Vec<SpannedToken>, Vec<CalcError>) {
+        let mut scanned = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            match self.next_token() {
+                Ok(Some(token)) => scanned.push(token),
+                Ok(None) => break,
+                Err(error) => errors.push(error),
+            }
+        }
+        
+        let mut tokens: Vec<SpannedToken> = Vec::new();
+        let mut scanned = scanned.into_iter();
+        
+        while let Some(mut token) = scanned.next() {
+            if let Some(postfix) = postfix_reading(tokens.last(), &token, scanned.as_slice()) {
+                token.token = Token::PostfixOperator(postfix);
+            }
+            
+            if self.implicit_multiplication {
+                if let Some(previous) = tokens.last() {
+                    if implies_multiplication(&previous.token, &token.token) {
+                        // Zero-width, between the two operands
+                        let span = Span::new(previous.span.end, token.span.start);
+                        tokens.push(SpannedToken::new(Token::Operator(Operator::ImplicitMultiply), span));
+                    }
+                }
+            }
+            tokens.push(token);
+        }
+        
+        (tokens, errors)
+    }
+    
+    fn next_token(&mut self) -> Result<Option<SpannedToken>, CalcError> {
+        self.skip_whitespace();
+        
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        
+        let start = self.pos;
+        let token = if c.is_ascii_digit() || (c == '.' && self.peek_at(1).is_some_and(|n| n.is_ascii_digit())) {
+            self.scan_number()
+        } else if c.is_alphabetic() || c == '_' {
+            self.scan_identifier()
+        } else {
+            self.scan_symbol()
+        };
+        
+        // Past at least one character, so an unreadable one is skipped
+        let span = Span::new(start, self.pos.max(start + c.len_utf8()));
+        self.pos = span.end;
+        token
+            .map(|token| Some(SpannedToken::new(token, span)))
+            .map_err(|error| error.with_span(span))
+    }
+    
+    fn scan_number(&mut self) -> Result<Token, CalcError> {
+        let start = self.pos;
+        
+        // Radix prefix, as in `0xFF`, `0b1010` or `0o17`; `Number::parse` checks the digits
+        let radix = match (self.peek(), self.peek_at(1)) {
+            (Some('0'), Some('x' | 'X')) => Some(16),
+            (Some('0'), Some('b' | 'B')) => Some(2),
+            (Some('0'), Some('o' | 'O')) => Some(8),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            if self.peek_at(2).is_some_and(|c| c.is_digit(radix)) {
+                self.advance();
+                self.advance();
+                self.consume_while(|c| c.is_alphanumeric() || c == '_');
+                return Token::from_str(&self.input[start..self.pos]);
+            }
+        }
+        
+        // Integer part and optional fraction
+        self.consume_digits();
+        let comma_fraction = self.decimal_mark == DecimalMark::Comma
+            && self.peek() == Some(',')
+            && self.peek_at(1).is_some_and(|c| c.is_ascii_digit());
+        if self.peek() == Some('.') || comma_fraction {
+            self.advance();
+            self.consume_digits();
+        }
+        
+        // Exponent, only if digits actually follow (so `2e` stays `2` then `e`)
+        let mut exponent = false;
+        if let Some('e' | 'E') = self.peek() {
+            let digits_at = match self.peek_at(1) {
+                Some('+' | '-') => 2,
+                _ => 1,
+            };
+            if self.peek_at(digits_at).is_some_and(|c| c.is_ascii_digit()) {
+                for _ in 0..digits_at {
+                    self.advance();
+                }
+                self.consume_digits();
+                exponent = true;
+            }
+        }
+        
+        // SI suffix, as in `4.7k` or `220u`, but only as a word on its own:
+        // `2pi`, `3k_B` and `2m(x)` are still products
+        if let Some(prefix) = self.peek().filter(|c| !exponent && si_exponent(*c).is_some()) {
+            let after = self.peek_at(1);
+            if !after.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '(') {
+                self.pos += prefix.len_utf8();
+            }
+        }
+        
+        Token::from_str(&self.input[start..self.pos].replace(',', "."))
+    }
+    
+    // Digits with single `_` separators between them, as in `1_000_000`
+    fn consume_digits(&mut self) {
+        loop {
+            self.consume_while(|c| c.is_ascii_digit());
+            
+            let separator = self.peek() == Some('_') && self.peek_at(1).is_some_and(|c| c.is_ascii_digit());
+            if !separator {
+                break;
+            }
+            self.advance();
+        }
+    }
+    
+    fn scan_identifier(&mut self) -> Result<Token, CalcError> {
+        let start = self.pos;
+        self.consume_while(|c| c.is_alphanumeric() || c == '_');
+        Token::from_str(&self.input[start..self.pos])
+    }
+    
+    fn scan_symbol(&mut self) -> Result<Token, CalcError> {
+        let rest = &self.input[self.pos..];
+        if self.decimal_mark == DecimalMark::Comma && rest.starts_with(';') {
+            self.advance();
+            return Ok(Token::Comma);
+        }
+        
+        let builtin = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol));
+        let custom = self.custom_symbols
+            .iter()
+            .filter(|symbol| rest.starts_with(symbol.as_str()))
+            .max_by_key(|symbol| symbol.len());
+        
+        // The longest match wins, whichever list it comes from
+        match (builtin, custom) {
+            (Some(builtin), Some(custom)) if builtin.len() >= custom.len() => {
+                self.pos += builtin.len();
+                Token::from_str(builtin)
+            },
+            (_, Some(custom)) => {
+                self.pos += custom.len();
+                Ok(Token::CustomOperator(custom.clone()))
+            },
+            (Some(builtin), None) => {
+                self.pos += builtin.len();
+                Token::from_str(builtin)
+            },
+            (None, None) => Err(CalcError::Syntax {
+                span: None,
+                expected: "a number, name, operator or parenthesis".to_string(),
+            }),
+        }
+    }
+    
+    fn skip_whitespace(&mut self) {
+        self.consume_while(char::is_whitespace);
+    }
+    
+    fn consume_while(&mut self, predicate: impl Fn(char) -> bool) {
+        while let Some(c) = self.peek() {
+            if !predicate(c) {
+                break;
+            }
+            self.advance();
+        }
+    }
+    
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+    
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.input[self.pos..].chars().nth(offset)
+    }
+    
+    fn advance(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+}
+
+// Whether `previous` ends an operand and `next` starts another, as in `2x`,
+// `3(a+b)`, `(a)(b)` or `2pi`. A name before `(` is a function call, and two
+// numbers in a row are far more likely a typo than a product, so neither counts
+fn implies_multiplication(previous: &Token, next: &Token) -> bool {
+    match (previous, next) {
+        (Token::Number(_), Token::Number(_)) => false,
+        (Token::Variable(_), Token::OpenParen) => false,
+        (Token::Number(_) | Token::Variable(_) | Token::CloseParen | Token::PostfixOperator(_),
+         Token::Number(_) | Token::Variable(_) | Token::Function(_) | Token::OpenParen) => true,
+        _ => false,
+    }
+}
+
+// Whether `token` is a postfix operator here. `!` right after an operand is a
+// factorial rather than `not`. `%` after an operand is a percentage unless
+// another operand follows, so `10 % 3` is a remainder but `200 + 10% - 5`
+// is not. A sign that touches the operand after it but not the `%` is that
+// operand's sign, so `10 % -3` is a remainder too, while `10%-3` is not
+fn postfix_reading(previous: Option<&SpannedToken>, token: &SpannedToken, rest: &[SpannedToken]) -> Option<PostfixOperator> {
+    let after_operand = matches!(
+        previous.map(|t| &t.token),
+        Some(Token::Number(_) | Token::Variable(_) | Token::CloseParen | Token::PostfixOperator(_))
+    );
+    let starts_operand = |next: &SpannedToken| matches!(
+        next.token,
+        Token::Number(_) | Token::Variable(_) | Token::Function(_) | Token::OpenParen
+    );
+    let before_operand = match rest {
+        [next, ..] if starts_operand(next) => true,
+        [sign, operand, ..] => {
+            matches!(sign.token, Token::Operator(Operator::Add | Operator::Subtract))
+                && sign.span.start > token.span.end
+                && sign.span.end == operand.span.start
+                && starts_operand(operand)
+        },
+        _ => false,
+    };
+    
+    match token.token {
+        Token::UnaryOperator(UnaryOperator::Not) if after_operand => Some(PostfixOperator::Factorial),
+        Token::Operator(Operator::Modulo) if after_operand && !before_operand => Some(PostfixOperator::Percent),
+        _ => None,
+    }
+}
+
+// Whether `symbol` is one of the operators or brackets the lexer always knows
+pub fn is_reserved_symbol(symbol: &str) -> bool {
+    SYMBOLS.contains(&symbol)
+}
+
+// Convenience wrapper used by the parsers
+pub fn tokenize(input: &str) -> Result<Vec<SpannedToken>, CalcError> {
+    Lexer::new(input).tokenize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{Function, Operator, Number};
+    
+    fn tokens(input: &str) -> Vec<Token> {
+        tokenize(input).unwrap().into_iter().map(|t| t.token).collect()
+    }
+    
+    #[test]
+    fn spaces_are_optional() {
+        assert_eq!(tokens("2+3*x"), tokens("2 + 3 * x"));
+        assert_eq!(tokens("sin(x)"), vec![
+            Token::Function(Function::Sin),
+            Token::OpenParen,
+            Token::variable("x"),
+            Token::CloseParen,
+        ]);
+    }
+    
+    #[test]
+    fn longest_operator_wins() {
+        assert_eq!(tokens("2**3"), vec![Token::number(2.0), Token::operator(Operator::Power), Token::number(3.0)]);
+        assert_eq!(tokens("2*3"), vec![Token::number(2.0), Token::operator(Operator::Multiply), Token::number(3.0)]);
+    }
+    
+    #[test]
+    fn numbers_and_names() {
+        assert_eq!(tokens(".5"), vec![Token::number(0.5)]);
+        assert_eq!(tokens("1.5e3"), vec![Token::scientific_number(1500.0)]);
+        assert_eq!(tokens("x_1"), vec![Token::variable("x_1")]);
+        // No digits after `e`, so it is a name of its own, multiplied implicitly
+        assert_eq!(tokens("2e"), vec![Token::number(2.0), Token::operator(Operator::ImplicitMultiply), Token::variable("e")]);
+        // Words `f64` would parse are still names
+        for name in ["inf", "nan", "infinity", "NaN"] {
+            assert_eq!(tokens(name), vec![Token::variable(name)]);
+        }
+    }
+    
+    #[test]
+    fn adjacent_operands_multiply() {
+        let implicit = |input: &str| {
+            tokenize(input).unwrap().iter().filter(|t| t.token == Token::operator(Operator::ImplicitMultiply)).count()
+        };
+        assert_eq!(implicit("2x"), 1);
+        assert_eq!(implicit("3(a+b)"), 1);
+        assert_eq!(implicit("(a)(b)"), 1);
+        assert_eq!(implicit("2pi x"), 2);
+        assert_eq!(implicit("2sin(x)"), 1);
+        // A call, and a likely typo
+        assert_eq!(implicit("f(x)"), 0);
+        assert_eq!(implicit("1 2"), 0);
+    }
+    
+    #[test]
+    fn implicit_multiplication_can_be_turned_off() {
+        let tokens = Lexer::new("2x").with_implicit_multiplication(false).tokenize().unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokenize("2x").unwrap()[1].span, Span::new(1, 1)); // Zero-width, between the operands
+    }
+    
+    #[test]
+    fn percent_or_remainder() {
+        let percent = Token::PostfixOperator(PostfixOperator::Percent);
+        for input in ["10%", "200 + 10% - 5", "10%-3", "10% + x", "(x)% * 2"] {
+            assert!(tokens(input).contains(&percent), "{}", input);
+        }
+        for input in ["10 % 3", "10%3", "10 % -3", "10% -3", "10 % +x", "10 % (3)"] {
+            assert!(!tokens(input).contains(&percent), "{}", input);
+        }
+    }
+    
+    #[test]
+    fn factorial_or_not() {
+        let factorial = Token::PostfixOperator(PostfixOperator::Factorial);
+        assert_eq!(tokens("3!"), vec![Token::number(3.0), factorial.clone()]);
+        assert_eq!(tokens("3!!"), vec![Token::number(3.0), factorial.clone(), factorial]);
+        assert_eq!(tokens("!x")[0], Token::UnaryOperator(UnaryOperator::Not));
+        assert_eq!(tokens("1 != 2")[1], Token::operator(Operator::NotEqual));
+    }
+    
+    #[test]
+    fn si_suffixes_only_as_a_word_on_their_own() {
+        assert_eq!(tokens("4.7k"), vec![Token::Number(Number::parse("4.7k").unwrap())]);
+        assert_eq!(tokens("2pi"), vec![Token::number(2.0), Token::operator(Operator::ImplicitMultiply), Token::variable("pi")]);
+        assert_eq!(tokens("3k_B")[2], Token::variable("k_B"));
+        assert_eq!(tokens("2m(x)")[2], Token::variable("m"));
+        assert_eq!(tokens("0x1F + 1_000"), vec![Token::Number(Number::parse("0x1F").unwrap()), Token::operator(Operator::Add), Token::Number(Number::parse("1_000").unwrap())]);
+    }
+    
+    #[test]
+    fn comma_decimal_mark() {
+        let tokens = |input: &str| -> Vec<Token> {
+            Lexer::new(input).with_decimal_mark(DecimalMark::Comma).tokenize().unwrap().into_iter().map(|t| t.token).collect()
+        };
+        assert_eq!(tokens("3,5"), vec![Token::number(3.5)]);
+        assert_eq!(tokens("2.5"), vec![Token::number(2.5)]);
+        assert_eq!(tokens("max(1; 2)")[3], Token::Comma);
+        assert_eq!(tokens("max(1, 2)")[3], Token::Comma); // No digit after the comma
+    }
+    
+    #[test]
+    fn tokens_carry_byte_spans() {
+        let spans: Vec<Span> = tokenize("12 +  π").unwrap().into_iter().map(|t| t.span).collect();
+        assert_eq!(spans, vec![Span::new(0, 2), Span::new(3, 4), Span::new(6, 8)]);
+    }
+    
+    #[test]
+    fn unknown_characters_are_errors() {
+        assert_eq!(tokenize("2 $ 3").unwrap_err().span(), Some(Span::new(2, 3)));
+        assert!(tokenize("").unwrap().is_empty());
+    }
+}
@@ -3,8 +3,13 @@
 
 // Chapter 5-7 modules
 mod token;
+mod lexer;
+mod error;
 mod expression;
 mod config;
+mod constants;
+mod formatter;
+mod operators;
 mod command;
 mod chain;
 mod strategy;
@@ -20,20 +25,52 @@ mod state;
 mod memento;
 mod observer;
 mod visitor;
+mod vm;
+mod simplifier;
+mod cse;
+mod interval;
+mod bigint;
+mod numeric;
+mod decimal;
+mod rational;
+mod complex;
+
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod differential;
 
 use std::io::{self, Write};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::hint::black_box;
+use std::time::Instant;
 
-use command::{CommandProcessor, EvaluateCommand, SetVariableCommand, Calculator, ClearVariablesCommand};
+use command::{CommandProcessor, SetVariableCommand, ClearVariablesCommand, DefineFunctionCommand};
 use chain::create_input_chain;
-use parser::ExpressionParser;
-use iterator::HistoryIterator;
+use parser::{ExpressionParser, split_assignment};
+use expression::{Expr, Callee, EvaluationContext, UserFunction};
+use iterator::{HistoryIterator, ExpressionIterator};
 use state::{StateCalculator, CalculatorState, StandardMode};
-use memento::{CalculatorStateManager, MementoOriginator, CalculatorMemento, SaveStateCommand, RestoreStateCommand, CalculatorStateType, get_calculator_state_type, get_angle_mode, get_number_base};
-use observer::{Subject, Observer, ObservableCalculator, DisplayObserver, DependentVariableObserver, LoggerObserver, CalculatorEvent, VariableProvider};
-use visitor::{optimize_expression, validate_expression};
-use bridge::{Display, ConsoleDisplay};
+use memento::{CalculatorStateManager, MementoOriginator, CalculatorMemento, SaveStateCommand, RestoreStateCommand, get_calculator_state_type, get_angle_mode, get_number_base};
+use observer::{Subject, Observer, ObservableCalculator, DisplayObserver, DependentVariableObserver, HistoryObserver, LoggerObserver, CalculatorEvent, VariableProvider};
+use visitor::{optimize_expression, validate_expression, diagnose, derivative, DifferentiationVisitor, ValidationVisitor};
+use vm::{compile, Vm};
+use simplifier::simplify;
+use cse::Dag;
+use bridge::{Display, ConsoleDisplay, Evaluator, IntervalEvaluator, NumericEvaluator};
+use interval::Interval;
+use numeric::{Numeric, NumericBackend, Value};
+use decimal::Decimal;
+use rational::Rational;
+use complex::Complex;
+use error::CalcError;
+use token::Associativity;
+use operators::OperatorTable;
+use formatter::NumberFormatter;
+
+// How many times /bench evaluates an expression each way
+const BENCH_RUNS: u32 = 100_000;
 
 // Complete calculator that combines all patterns
 struct CorrectCalculator {
@@ -47,9 +84,11 @@ struct CorrectCalculator {
     observable: ObservableCalculator,
     
     // Core data
-    variables: HashMap<String, f64>,
+    variables: HashMap<String, Value>, // In the number type each was worked out in
+    numeric: NumericBackend,
     parser: ExpressionParser,
-    next_observer_id: usize,
+    formatter: NumberFormatter,
+    display: Arc<Mutex<ConsoleDisplay>>, // Kept, so `/set` can give it a new formatter
 }
 
 impl CorrectCalculator {
@@ -57,6 +96,8 @@ impl CorrectCalculator {
         let parser = ExpressionParser::new();
         let command_processor = CommandProcessor::new();
         let input_chain = create_input_chain(parser.clone());
+        let formatter = NumberFormatter::new();
+        let display = Arc::new(Mutex::new(ConsoleDisplay::new().with_formatter(formatter.clone())));
         
         let mut calculator = Self {
             command_processor,
@@ -65,12 +106,13 @@ impl CorrectCalculator {
             state_manager: CalculatorStateManager::new(),
             observable: ObservableCalculator::new(),
             variables: HashMap::new(),
+            numeric: config::get_global_config().numeric,
             parser,
-            next_observer_id: 0,
+            formatter,
+            display: display.clone(),
         };
         
         // Add standard observers
-        let display = Arc::new(Mutex::new(ConsoleDisplay));
         calculator.attach_observer(Box::new(DisplayObserver::new(display)));
         calculator.attach_observer(Box::new(LoggerObserver));
         
@@ -81,55 +123,94 @@ impl CorrectCalculator {
         self.observable.attach(observer)
     }
     
-    fn detach_observer(&mut self, observer_id: usize) {
-        self.observable.detach(observer_id);
-    }
-    
     fn notify(&self, event: &CalculatorEvent) {
         self.observable.notify(event);
     }
     
-    fn process_input(&mut self, input: &str) -> Result<Option<f64>, String> {
-        if input.starts_with("/") {
-            self.process_command(&input[1..])
-        } else if let Some((name, value_str)) = input.split_once('=') {
+    // Shows a result in the notation its input was written in, so `0xF0 + 0x0F`
+    // answers 0xFF and `4.7k + 2.2k` answers 6.9k; otherwise as the mode shows it.
+    // Decimals and fractions are shown in full
+    fn echo(&self, input: &str, result: &Value) -> String {
+        let expression = split_assignment(input).map_or(input, |(_, value)| value);
+        let result = match result {
+            Value::Float(result) => *result,
+            exact => return self.formatter.format_value(exact),
+        };
+        
+        match self.parser.notation(expression) {
+            Some(format) if !input.starts_with('/') => self.formatter.format_as(result, format.rescaled(result)),
+            _ => self.state.format_result(result, &self.formatter),
+        }
+    }
+    
+    fn process_input(&mut self, input: &str) -> Result<Option<Value>, CalcError> {
+        if let Some(command) = input.strip_prefix('/') {
+            self.process_command(command)
+                .map(|result| result.map(Value::Float))
+                .map_err(|e| e.shifted(1))
+        } else if let Some((name, value_str)) = split_assignment(input) {
+            // Function definition, e.g. f(x, y) = x^2 + y
+            if name.contains('(') {
+                let function = self.parser.parse_function_definition(input)?;
+                self.define_function(function)?;
+                return Ok(None);
+            }
+            
             // Variable assignment
             let name = name.trim();
             let value_str = value_str.trim();
+            let offset = input.len() - value_str.len();
+            constants::check_assignable(name)?;
+            if self.numeric.reserves(name) {
+                return Err(CalcError::ReadOnlyConstant { name: name.to_string() });
+            }
             
             // Parse and evaluate the expression
-            let expr = self.parser.parse(value_str)?;
-            let value = expr.evaluate(&self.variables)?;
+            let expr = self.parser.parse(value_str).map_err(|e| e.shifted(offset))?;
+            let value = self.evaluate_numeric(&expr).map_err(|e| e.shifted(offset))?;
             
-            // Set the variable
-            self.set_variable(name, value);
+            // Set the variable, keeping the exact value for later expressions
+            self.set_variable(name, value.clone());
             
             Ok(Some(value))
         } else {
             // Expression evaluation
             let expr = self.parser.parse(input)?;
             
-            // Optimize and validate the expression
-            let optimized = optimize_expression(&*expr, &self.variables)?;
-            validate_expression(&*optimized)?;
-            
-            // Evaluate the optimized expression
-            let result = optimized.evaluate(&self.variables)?;
+            let value = match self.numeric {
+                NumericBackend::Float => {
+                    // Optimize and validate the expression
+                    self.check_real(&expr)?;
+                    let variables = self.float_variables();
+                    let optimized = optimize_expression(&expr, &variables)?;
+                    validate_expression(&optimized)?;
+                    
+                    // Evaluate the optimized expression, working out repeated parts once
+                    Value::Float(Dag::new(&optimized).evaluate_in(&self.context(&variables))?)
+                },
+                // Optimizing folds constants in f64, so other types evaluate the
+                // expression as written
+                _ => {
+                    self.validator().validate(&expr)?;
+                    self.evaluate_numeric(&expr)?
+                },
+            };
             
             // Store the result
-            self.command_processor.get_calculator_mut().store_calculation(input.to_string(), result);
+            self.command_processor.get_calculator_mut().store_calculation(input.to_string(), value.clone());
             
             // Notify observers
-            self.notify(&CalculatorEvent::ResultCalculated(result, input.to_string()));
+            self.notify(&CalculatorEvent::ResultCalculated(value.clone(), input.to_string()));
             
-            Ok(Some(result))
+            Ok(Some(value))
         }
     }
     
-    fn process_command(&mut self, command: &str) -> Result<Option<f64>, String> {
+    // Errors carry spans relative to `command`, i.e. the input without its leading `/`
+    fn process_command(&mut self, command: &str) -> Result<Option<f64>, CalcError> {
         let parts: Vec<&str> = command.split_whitespace().collect();
         if parts.is_empty() {
-            return Err("Empty command".to_string());
+            return Err(CalcError::usage("/<command>, see /help"));
         }
         
         match parts[0] {
@@ -143,15 +224,25 @@ impl CorrectCalculator {
                 println!("  /list                - List saved states");
                 println!("  /delete [name]       - Delete a saved state");
                 println!("  /vars                - List all variables");
+                println!("  /functions           - List user-defined functions");
+                println!("  /constants           - List built-in constants");
                 println!("  /clear               - Clear all variables");
+                println!("  /undo                - Undo the last change");
+                println!("  /redo                - Redo the last undone change");
                 println!("  /history             - Show calculation history");
-                println!("  /optimize [expr]     - Show optimized version of expression");
-                println!("  /validate [expr]     - Validate an expression");
+                println!("  /optimize [expr]     - Show simplified version of expression");
+                println!("  /validate [expr]     - List every problem in an expression");
+                println!("  /bench [expr]        - Time tree walking against compiled evaluation");
+                println!("  /diff [expr] wrt [x] - Differentiate an expression with respect to x");
+                println!("  /cse [expr]          - Show repeated subexpressions as temporaries");
+                println!("  /interval [expr] where x in [a, b], ... - Bound an expression over ranges");
+                println!("  /numeric [type]      - Evaluate in float, decimal [digits], rational or complex [polar] numbers");
+                println!("  /set [name] [value]  - Change a setting, or list them all");
                 Ok(None)
             },
             "mode" => {
                 if parts.len() < 2 {
-                    return Err("Missing mode argument. Use /mode [standard|scientific|programmer]".to_string());
+                    return Err(CalcError::usage("/mode [standard|scientific|programmer]"));
                 }
                 
                 match parts[1] {
@@ -167,7 +258,7 @@ impl CorrectCalculator {
                         self.state = Box::new(state::ProgrammerMode::new());
                         self.notify(&CalculatorEvent::ModeChanged("Programmer".to_string()));
                     },
-                    _ => return Err(format!("Unknown mode: {}", parts[1])),
+                    _ => return Err(CalcError::UnknownMode { name: parts[1].to_string() }),
                 }
                 
                 println!("Switched to {} mode", self.state.name());
@@ -175,7 +266,7 @@ impl CorrectCalculator {
             },
             "save" => {
                 if parts.len() < 2 {
-                    return Err("Missing name argument. Use /save [name]".to_string());
+                    return Err(CalcError::usage("/save [name]"));
                 }
                 let name = parts[1];
                 
@@ -188,7 +279,7 @@ impl CorrectCalculator {
             },
             "restore" => {
                 if parts.len() < 2 {
-                    return Err("Missing name argument. Use /restore [name]".to_string());
+                    return Err(CalcError::usage("/restore [name]"));
                 }
                 let name = parts[1];
                 
@@ -215,7 +306,7 @@ impl CorrectCalculator {
             },
             "delete" => {
                 if parts.len() < 2 {
-                    return Err("Missing name argument. Use /delete [name]".to_string());
+                    return Err(CalcError::usage("/delete [name]"));
                 }
                 let name = parts[1];
                 
@@ -228,11 +319,63 @@ impl CorrectCalculator {
                 } else {
                     println!("Variables:");
                     for (name, value) in &self.variables {
-                        println!("  {} = {}", name, value);
+                        println!("  {} = {}", name, self.formatter.format_value(value));
+                    }
+                }
+                Ok(None)
+            },
+            "functions" => {
+                let functions = &self.command_processor.get_calculator().functions;
+                if functions.is_empty() {
+                    println!("No functions defined");
+                } else {
+                    println!("Functions:");
+                    for function in functions.values() {
+                        println!("  {} = {}", function.signature(), function.body);
                     }
                 }
                 Ok(None)
             },
+            "constants" => {
+                println!("Constants:");
+                for constant in constants::all() {
+                    println!("  {:<4} = {:<22} {}", constant.name, self.formatter.format(constant.value), constant.description);
+                }
+                Ok(None)
+            },
+            "numeric" => {
+                if parts.len() > 1 {
+                    self.numeric = NumericBackend::parse(&parts[1..])?;
+                }
+                println!("Number type: {}", self.numeric);
+                Ok(None)
+            },
+            "set" => {
+                match parts.len() {
+                    1 => {},
+                    3 => {
+                        config::update_global_config(|config| config.set(parts[1], parts[2]))?;
+                        self.apply_config();
+                    },
+                    _ => return Err(CalcError::usage("/set [name] [value]")),
+                }
+                
+                println!("Settings:");
+                for (name, value) in config::get_global_config().settings() {
+                    println!("  {:<24} {}", name, value);
+                }
+                Ok(None)
+            },
+            "undo" => {
+                self.command_processor.undo()?;
+                self.variables = self.command_processor.get_calculator().variables.clone();
+                Ok(None)
+            },
+            "redo" => {
+                self.command_processor.redo()?;
+                self.variables = self.command_processor.get_calculator().variables.clone();
+                Ok(None)
+            },
             "clear" => {
                 let command = Box::new(ClearVariablesCommand::new());
                 self.command_processor.execute(command)?;
@@ -247,86 +390,370 @@ impl CorrectCalculator {
                 } else {
                     println!("Calculation history:");
                     for (i, calc) in HistoryIterator::new(&history).enumerate() {
-                        println!("  {}. {} = {}", i + 1, calc.expression, calc.result);
+                        println!("  {}. {} = {}", i + 1, calc.expression, self.formatter.format_value(&calc.result));
                     }
                 }
                 Ok(None)
             },
             "optimize" => {
                 if parts.len() < 2 {
-                    return Err("Missing expression. Use /optimize [expression]".to_string());
+                    return Err(CalcError::usage("/optimize [expression]"));
                 }
                 
                 let expr_str = &command[parts[0].len()..].trim();
-                let expr = self.parser.parse(expr_str)?;
-                let optimized = optimize_expression(&*expr, &self.variables)?;
+                let offset = command.len() - expr_str.len();
+                let expr = self.parser.parse(expr_str).map_err(|e| e.shifted(offset))?;
+                self.check_real(&expr).map_err(|e| e.shifted(offset))?;
+                let optimized = optimize_expression(&expr, &self.float_variables()).map_err(|e| e.shifted(offset))?;
+                let simplified = simplify(&optimized);
                 
-                println!("Original: {}", expr.to_string());
-                println!("Optimized: {}", optimized.to_string());
+                println!("Original: {}", expr);
+                println!("Optimized: {}", simplified);
                 
                 Ok(None)
             },
             "validate" => {
                 if parts.len() < 2 {
-                    return Err("Missing expression. Use /validate [expression]".to_string());
+                    return Err(CalcError::usage("/validate [expression]"));
                 }
                 
+                // Syntax and semantic problems together, rather than one per attempt
                 let expr_str = &command[parts[0].len()..].trim();
-                let expr = self.parser.parse(expr_str)?;
+                let errors = diagnose(&self.parser, expr_str, self.validator());
                 
-                match validate_expression(&*expr) {
-                    Ok(_) => println!("Expression is valid"),
-                    Err(e) => println!("Validation errors: {}", e),
+                if errors.is_empty() {
+                    println!("Expression is valid");
+                } else {
+                    println!("Validation errors ({}):", errors.len());
+                    for error in &errors {
+                        println!("{}", error.render(expr_str));
+                    }
                 }
                 
                 Ok(None)
             },
-            _ => Err(format!("Unknown command: {}", parts[0])),
+            "diff" => {
+                let usage = || CalcError::usage("/diff [expression] wrt [variable]");
+                let rest = &command[parts[0].len()..];
+                let (expr_str, variable) = rest.rsplit_once(" wrt ").ok_or_else(usage)?;
+                let variable = variable.trim();
+                if variable.is_empty() || expr_str.trim().is_empty() {
+                    return Err(usage());
+                }
+                if !variable.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(CalcError::InvalidName { name: variable.to_string() });
+                }
+                
+                let expr_str = expr_str.trim();
+                let offset = parts[0].len() + (rest.len() - rest.trim_start().len());
+                let expr = self.parser.parse(expr_str).map_err(|e| e.shifted(offset))?;
+                
+                // User functions are expanded, so their bodies are differentiated too
+                let functions = self.command_processor.get_calculator().functions.clone();
+                let derivative = DifferentiationVisitor::new(variable)
+                    .with_functions(functions)
+                    .differentiate(&expr)
+                    .map_err(|e| e.shifted(offset))?;
+                
+                println!("Expression: {}", expr);
+                println!("d/d{}: {}", variable, derivative);
+                Ok(None)
+            },
+            "cse" => {
+                if parts.len() < 2 {
+                    return Err(CalcError::usage("/cse [expression]"));
+                }
+                
+                let expr_str = &command[parts[0].len()..].trim();
+                let offset = command.len() - expr_str.len();
+                let expr = self.parser.parse(expr_str).map_err(|e| e.shifted(offset))?;
+                
+                // Constant parts are folded first, so `2 * 3` and `6` are one node.
+                // Variables stay as written, or every repeat would become a number
+                let folded = optimize_expression(&expr, &HashMap::new()).map_err(|e| e.shifted(offset))?;
+                let dag = Dag::new(&folded);
+                let (temporaries, rewritten) = dag.temporaries();
+                
+                if temporaries.is_empty() {
+                    println!("No repeated subexpressions");
+                }
+                for (name, value) in &temporaries {
+                    println!("{} = {}", name, value);
+                }
+                println!("Expression: {}", rewritten);
+                println!("Nodes: {} in the tree, {} shared", ExpressionIterator::new(&folded).count(), dag.node_count());
+                Ok(None)
+            },
+            "interval" => {
+                let usage = || CalcError::usage("/interval [expression] where [variable] in [low, high], ...");
+                let rest = &command[parts[0].len()..];
+                let (expr_str, ranges_str) = rest.split_once(" where ").unwrap_or((rest, ""));
+                if expr_str.trim().is_empty() || !ranges_str.trim().is_empty() && !ranges_str.trim_end().ends_with(']') {
+                    return Err(usage());
+                }
+                
+                let expr_str = expr_str.trim();
+                let offset = parts[0].len() + (rest.len() - rest.trim_start().len());
+                let expr = self.parser.parse(expr_str).map_err(|e| e.shifted(offset))?;
+                self.check_real(&expr).map_err(|e| e.shifted(offset))?;
+                
+                // Variables without a range keep the value they hold now
+                let mut ranges: HashMap<String, Interval> = self.variables.iter()
+                    .map(|(name, value)| (name.clone(), Interval::point(value.to_f64())))
+                    .collect();
+                let mut range_offset = command.len() - ranges_str.len();
+                for range in ranges_str.split_inclusive(']') {
+                    let (name, bounds) = self.range(range.trim_end_matches(']'), range_offset)?;
+                    ranges.insert(name, bounds);
+                    range_offset += range.len();
+                }
+                
+                let calculator = self.command_processor.get_calculator();
+                let interval_evaluator = IntervalEvaluator::new()
+                    .with_functions(calculator.functions.clone())
+                    .with_max_depth(calculator.max_recursion_depth);
+                let evaluator = Evaluator::new(Box::new(interval_evaluator));
+                let bounds = evaluator.evaluate(&expr, &ranges).map_err(|e| e.shifted(offset))?;
+                
+                println!("Expression: {}", expr);
+                println!("Range: {}", bounds);
+                Ok(None)
+            },
+            "bench" => {
+                if parts.len() < 2 {
+                    return Err(CalcError::usage("/bench [expression]"));
+                }
+                
+                let expr_str = &command[parts[0].len()..].trim();
+                let offset = command.len() - expr_str.len();
+                let expr = self.parser.parse(expr_str).map_err(|e| e.shifted(offset))?;
+                self.check_real(&expr).map_err(|e| e.shifted(offset))?;
+                let variables = self.float_variables();
+                let context = self.context(&variables);
+                let program = compile(&expr, context.functions).with_max_depth(context.max_depth);
+                let mut vm = Vm::new(&program, &variables);
+                
+                // black_box keeps the optimiser from hoisting evaluation out of the loops
+                let start = Instant::now();
+                let mut tree_result = Ok(0.0);
+                for _ in 0..BENCH_RUNS {
+                    tree_result = black_box(&expr).evaluate_in(&context);
+                }
+                let tree_time = start.elapsed();
+                
+                let start = Instant::now();
+                let mut vm_result = Ok(0.0);
+                for _ in 0..BENCH_RUNS {
+                    vm_result = black_box(&mut vm).run();
+                }
+                let vm_time = start.elapsed();
+                
+                // Results are compared in full, so errors must match too
+                if format!("{:?}", tree_result) != format!("{:?}", vm_result) {
+                    println!("Results differ: tree {:?}, compiled {:?}", tree_result, vm_result);
+                }
+                
+                println!("Tree:     {:?} per evaluation", tree_time / BENCH_RUNS);
+                println!("Compiled: {:?} per evaluation ({} instructions)", vm_time / BENCH_RUNS, program.instructions().len());
+                println!("Speedup:  {:.1}x", tree_time.as_secs_f64() / vm_time.as_secs_f64());
+                
+                tree_result.map(Some).map_err(|e| e.shifted(offset))
+            },
+            _ => Err(CalcError::UnknownCommand { name: parts[0].to_string() }),
         }
     }
     
-    fn set_variable(&mut self, name: &str, value: f64) {
-        self.variables.insert(name.to_string(), value);
+    fn set_variable(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value.clone());
         
         // Execute SetVariableCommand to enable undo/redo
-        let command = Box::new(SetVariableCommand::new(name.to_string(), value));
+        let command = Box::new(SetVariableCommand::new(name.to_string(), value.clone()));
         let _ = self.command_processor.execute(command);
         
         // Notify observers
         self.notify(&CalculatorEvent::VariableChanged(name.to_string(), value));
     }
     
+    fn define_function(&mut self, function: UserFunction) -> Result<(), CalcError> {
+        let signature = function.signature();
+        
+        // Execute DefineFunctionCommand to enable undo/redo
+        let command = Box::new(DefineFunctionCommand::new(function));
+        self.command_processor.execute(command)?;
+        
+        // Notify observers
+        self.notify(&CalculatorEvent::FunctionDefined(signature));
+        Ok(())
+    }
+    
+    // Rebuilds what copied the global config when it was made, after `/set`
+    fn apply_config(&mut self) {
+        let config = config::get_global_config();
+        self.parser = self.parser.clone()
+            .with_implicit_multiplication(config.implicit_multiplication)
+            .with_decimal_mark(config.decimal_mark);
+        self.input_chain = create_input_chain(self.parser.clone());
+        
+        self.formatter = NumberFormatter::from_config(&config);
+        *self.display.lock().unwrap() = ConsoleDisplay::new().with_formatter(self.formatter.clone());
+    }
+    
+    // One `name in [low, high` of /interval, starting `offset` bytes into the
+    // command. Each bound is an expression, ending at a comma outside parentheses
+    fn range(&self, text: &str, offset: usize) -> Result<(String, Interval), CalcError> {
+        let usage = || CalcError::usage("[variable] in [low, high]");
+        let (name, bounds) = text.split_once(" in [").ok_or_else(usage)?;
+        let name = name.trim_start_matches(',').trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(CalcError::InvalidName { name: name.to_string() });
+        }
+        
+        let mut depth = 0;
+        let comma = bounds.char_indices()
+            .find(|&(_, c)| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {},
+                }
+                c == ',' && depth == 0
+            })
+            .map(|(index, _)| index)
+            .ok_or_else(usage)?;
+        
+        let variables = self.float_variables();
+        let context = self.context(&variables);
+        let start = offset + text.len() - bounds.len();
+        let bound = |text: &str, start: usize| {
+            let trimmed = text.trim_start();
+            let start = start + text.len() - trimmed.len();
+            self.parser.parse(trimmed.trim_end())
+                .and_then(|expr| self.check_real(&expr).and_then(|_| expr.evaluate_in(&context)))
+                .map_err(|e| e.shifted(start))
+        };
+        let low = bound(&bounds[..comma], start)?;
+        let high = bound(&bounds[comma + 1..], start + comma + 1)?;
+        if low.is_nan() || high.is_nan() || low > high {
+            return Err(usage());
+        }
+        
+        // A bound such as 0.1 or pi may have been rounded, so it is widened
+        // to the f64s either side
+        Ok((name.to_string(), Interval::new(Interval::literal(low).lo, Interval::literal(high).hi)))
+    }
+    
+    // Variables live on this struct, user functions on the command processor's calculator
+    fn context<'a>(&'a self, variables: &'a HashMap<String, f64>) -> EvaluationContext<'a> {
+        self.command_processor.get_calculator().context(variables)
+    }
+    
+    fn float_variables(&self) -> HashMap<String, f64> {
+        command::float_variables(&self.variables)
+    }
+    
+    // Square roots of negative literals are only mistakes without complex numbers
+    fn validator(&self) -> ValidationVisitor {
+        ValidationVisitor::new().with_complex_numbers(matches!(self.numeric, NumericBackend::Complex { .. }))
+    }
+    
+    // Outside complex mode a variable holding a non-real number has no value,
+    // as its f64 is NaN, so using it or calling a function that does is an error
+    fn check_real(&self, expr: &Expr) -> Result<(), CalcError> {
+        let mode = match self.numeric {
+            NumericBackend::Float => f64::NAME,
+            NumericBackend::Decimal { .. } => Decimal::NAME,
+            NumericBackend::Rational => Rational::NAME,
+            NumericBackend::Complex { .. } => return Ok(()),
+        };
+        
+        // Function bodies are searched once each, skipping their parameters;
+        // their spans point into the definition, so are left out
+        let functions = &self.command_processor.get_calculator().functions;
+        let mut pending: Vec<(&Expr, Option<&[String]>)> = vec![(expr, None)];
+        let mut searched = HashSet::new();
+        while let Some((expr, parameters)) = pending.pop() {
+            for node in ExpressionIterator::new(expr) {
+                match node {
+                    Expr::Variable { name, span } if !parameters.is_some_and(|parameters| parameters.contains(name)) => {
+                        if let Some(Value::Complex(value)) = self.variables.get(name) {
+                            if !value.is_real() {
+                                return Err(CalcError::Unsupported {
+                                    what: format!("The complex value of {} ({})", name, value),
+                                    mode: mode.to_string(),
+                                    span: if parameters.is_none() { *span } else { None },
+                                });
+                            }
+                        }
+                    },
+                    Expr::Call { function: Callee::User(name), .. } if searched.insert(name) => {
+                        if let Some(function) = functions.get(name) {
+                            pending.push((&function.body, Some(&function.parameters)));
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    // Evaluates in the session's number type
+    fn evaluate_numeric(&self, expr: &Expr) -> Result<Value, CalcError> {
+        self.check_real(expr)?;
+        match self.numeric {
+            NumericBackend::Float => Ok(Value::Float(expr.evaluate_in(&self.context(&self.float_variables()))?)),
+            NumericBackend::Decimal { precision } => self.evaluate_as::<Decimal>(expr, precision),
+            NumericBackend::Rational => self.evaluate_as::<Rational>(expr, ()),
+            NumericBackend::Complex { form } => self.evaluate_as::<Complex>(expr, form),
+        }
+    }
+    
+    fn evaluate_as<N: Numeric + 'static>(&self, expr: &Expr, settings: N::Settings) -> Result<Value, CalcError> {
+        let variables: HashMap<String, N> = self.variables.iter()
+            .filter_map(|(name, value)| {
+                let converted = N::from_value(value, settings).or_else(|| N::from_f64(value.to_f64(), settings));
+                converted.map(|value| (name.clone(), value))
+            })
+            .collect();
+        
+        let calculator = self.command_processor.get_calculator();
+        let strategy = NumericEvaluator::<N>::new(settings)
+            .with_functions(calculator.functions.clone())
+            .with_max_depth(calculator.max_recursion_depth);
+        Ok(Evaluator::new(Box::new(strategy)).evaluate(expr, &variables)?.into_value())
+    }
+    
     fn run(&mut self) {
         println!("Correct Calculator - Chapter 8");
         println!("Incorporating patterns from Chapters 5-8");
         println!("Type expressions to evaluate, variables to set (x = 5),");
         println!("commands (/help, /mode, /save, /restore), or /exit to quit");
-
+        
         loop {
             print!("{} ", self.state.display_prompt());
             io::stdout().flush().unwrap();
-
+            
             let mut input = String::new();
             if io::stdin().read_line(&mut input).is_err() {
                 println!("Error reading input, please try again");
                 continue;
             }
-
+            
             let input = input.trim();
             if input == "/exit" {
                 break;
             }
             
             match self.process_input(input) {
-                Ok(Some(result)) => println!("= {}", result),
+                Ok(Some(result)) => println!("= {}", self.echo(input, &result)),
                 Ok(None) => {}, // Command executed with no result to display
                 Err(error) => {
-                    println!("Error: {}", error);
-                    self.notify(&CalculatorEvent::Error(error));
+                    println!("Error: {}", error.render(input));
+                    self.notify(&CalculatorEvent::Error(error.to_string()));
                 }
             }
         }
-
+        
         println!("Goodbye!");
     }
 }
@@ -336,6 +763,7 @@ impl MementoOriginator for CorrectCalculator {
     fn create_memento(&self) -> CalculatorMemento {
         CalculatorMemento {
             variables: self.variables.clone(),
+            functions: self.command_processor.get_calculator().functions.clone(),
             history: self.command_processor.get_calculator().history.clone(),
             mode: get_calculator_state_type(&*self.state),
             angle_mode: get_angle_mode(&*self.state),
@@ -343,9 +771,13 @@ impl MementoOriginator for CorrectCalculator {
         }
     }
     
-    fn restore_from_memento(&mut self, memento: &CalculatorMemento) -> Result<(), String> {
-        // Restore variables
+    fn restore_from_memento(&mut self, memento: &CalculatorMemento) -> Result<(), CalcError> {
+        // Restore variables, keeping the command calculator's copy in step for /undo
         self.variables = memento.variables.clone();
+        self.command_processor.get_calculator_mut().variables = self.variables.clone();
+        
+        // Restore user-defined functions
+        self.command_processor.get_calculator_mut().functions = memento.functions.clone();
         
         // Restore history
         self.command_processor.get_calculator_mut().history = memento.history.clone();
@@ -360,16 +792,32 @@ impl MementoOriginator for CorrectCalculator {
 // Implement VariableProvider for Arc<Mutex<CorrectCalculator>>
 impl VariableProvider for CorrectCalculator {
     fn get_variable(&self, name: &str) -> Option<f64> {
-        self.variables.get(name).copied()
+        self.variables.get(name).map(Value::to_f64)
     }
     
     fn set_variable(&mut self, name: &str, value: f64) {
-        self.set_variable(name, value);
+        self.set_variable(name, Value::Float(value));
     }
     
-    fn evaluate_expression(&mut self, expr: &str) -> Result<f64, String> {
+    fn evaluate_expression(&mut self, expr: &str) -> Result<f64, CalcError> {
         let expr_tree = self.parser.parse(expr)?;
-        expr_tree.evaluate(&self.variables)
+        expr_tree.evaluate_in(&self.context(&self.float_variables()))
+    }
+}
+
+// The mediator's storage only holds plain values, so it is `Send` and can back a DependentVariableObserver
+impl VariableProvider for mediator::VariableStorage {
+    fn get_variable(&self, name: &str) -> Option<f64> {
+        self.get_variable(name)
+    }
+    
+    fn set_variable(&mut self, name: &str, value: f64) {
+        self.set_variable(name, value);
+    }
+    
+    fn evaluate_expression(&mut self, expr: &str) -> Result<f64, CalcError> {
+        let expr_tree = ExpressionParser::new().parse(expr)?;
+        expr_tree.evaluate(&self.get_all_variables())
     }
 }
 
@@ -380,6 +828,7 @@ fn main() {
 }
 
 // Example using the State pattern directly
+#[allow(dead_code)]
 fn _run_with_state() {
     println!("Correct Calculator with State Pattern");
     
@@ -388,20 +837,20 @@ fn _run_with_state() {
     loop {
         print!("{}", calculator.display_prompt());
         io::stdout().flush().unwrap();
-
+        
         let mut input = String::new();
         if io::stdin().read_line(&mut input).is_err() {
             println!("Error reading input, please try again");
             continue;
         }
-
+        
         let input = input.trim();
         if input == "/exit" {
             break;
         }
-
+        
         match calculator.process_input(input) {
-            Ok(Some(result)) => println!("= {}", result),
+            Ok(Some(result)) => println!("= {}", calculator.format_result(result)),
             Ok(None) => {}, // Command executed with no result to display
             Err(error) => println!("Error: {}", error),
         }
@@ -411,6 +860,7 @@ fn _run_with_state() {
 }
 
 // Example using the Memento pattern directly
+#[allow(dead_code)]
 fn _run_with_memento() {
     println!("Correct Calculator with Memento Pattern");
     
@@ -420,30 +870,35 @@ fn _run_with_memento() {
     loop {
         print!("{}", calculator.display_prompt());
         io::stdout().flush().unwrap();
-
+        
         let mut input = String::new();
         if io::stdin().read_line(&mut input).is_err() {
             println!("Error reading input, please try again");
             continue;
         }
-
+        
         let input = input.trim();
         if input == "/exit" {
             break;
         }
         
         if input.starts_with("/save ") {
-            let name = input.trim_start_matches("/save ").trim();
+            let command = SaveStateCommand::new(input.trim_start_matches("/save ").trim());
+            if state_manager.has_state(&command.name) {
+                println!("Replacing saved state '{}'", command.name);
+            }
+            
             let state_type = get_calculator_state_type(&*calculator.state);
             let angle_mode = get_angle_mode(&*calculator.state);
             let number_base = get_number_base(&*calculator.state);
             
             let memento = CalculatorMemento {
-                variables: calculator.variables.clone(),
+                variables: calculator.variables.iter().map(|(name, value)| (name.clone(), Value::Float(*value))).collect(),
+                functions: HashMap::new(),
                 history: calculator.results_history.clone().into_iter()
                     .map(|(expr, result)| command::Calculation {
                         expression: expr,
-                        result,
+                        result: Value::Float(result),
                         timestamp: std::time::SystemTime::now(),
                     })
                     .collect(),
@@ -452,26 +907,26 @@ fn _run_with_memento() {
                 number_base,
             };
             
-            state_manager.save_state(name, memento);
+            state_manager.save_state(&command.name, memento);
             continue;
         } else if input.starts_with("/restore ") {
-            let name = input.trim_start_matches("/restore ").trim();
-            match state_manager.restore_state(name) {
+            let command = RestoreStateCommand::new(input.trim_start_matches("/restore ").trim());
+            match state_manager.restore_state(&command.name) {
                 Ok(memento) => {
-                    calculator.variables = memento.variables.clone();
+                    calculator.variables = memento.variables.iter().map(|(name, value)| (name.clone(), value.to_f64())).collect();
                     calculator.results_history = memento.history.iter()
-                        .map(|calc| (calc.expression.clone(), calc.result))
+                        .map(|calc| (calc.expression.clone(), calc.result.to_f64()))
                         .collect();
-                    calculator.state = memento::create_state_from_memento(&memento);
-                    println!("State '{}' restored", name);
+                    calculator.state = memento::create_state_from_memento(&memento).into();
+                    println!("State '{}' restored", command.name);
                 },
                 Err(e) => println!("Error: {}", e),
             }
             continue;
         }
-
+        
         match calculator.process_input(input) {
-            Ok(Some(result)) => println!("= {}", result),
+            Ok(Some(result)) => println!("= {}", calculator.format_result(result)),
             Ok(None) => {}, // Command executed with no result to display
             Err(error) => println!("Error: {}", error),
         }
@@ -481,39 +936,56 @@ fn _run_with_memento() {
 }
 
 // Example using the Observer pattern directly
+#[allow(dead_code)]
 fn _run_with_observer() {
     println!("Correct Calculator with Observer Pattern");
     
     let mut calculator = StateCalculator::new();
     let mut observable = ObservableCalculator::new();
     
-    // Add observers
-    observable.attach(Box::new(LoggerObserver));
+    // Add observers, keeping the logger's id so it can be detached
+    let logger = observable.attach(Box::new(LoggerObserver));
+    let history = HistoryObserver::new(10);
+    let recent = history.get_history();
+    observable.attach(Box::new(history));
     
     loop {
         print!("{}", calculator.display_prompt());
         io::stdout().flush().unwrap();
-
+        
         let mut input = String::new();
         if io::stdin().read_line(&mut input).is_err() {
             println!("Error reading input, please try again");
             continue;
         }
-
+        
         let input = input.trim();
         if input == "/exit" {
             break;
+        } else if input == "/quiet" {
+            observable.detach(logger);
+            continue;
+        } else if input == "/recent" {
+            for calc in recent.lock().unwrap().iter() {
+                println!("  {} = {}", calc.expression, calc.result);
+            }
+            continue;
         }
-
+        
         match calculator.process_input(input) {
             Ok(Some(result)) => {
-                println!("= {}", result);
-                observable.notify(&CalculatorEvent::ResultCalculated(result, input.to_string()));
+                println!("= {}", calculator.format_result(result));
+                observable.notify(&CalculatorEvent::ResultCalculated(Value::Float(result), input.to_string()));
+                observable.notify(&CalculatorEvent::HistoryAdded(command::Calculation {
+                    expression: input.to_string(),
+                    result: Value::Float(result),
+                    timestamp: std::time::SystemTime::now(),
+                }));
             },
             Ok(None) => {}, // Command executed with no result to display
             Err(error) => {
                 println!("Error: {}", error);
-                observable.notify(&CalculatorEvent::Error(error));
+                observable.notify(&CalculatorEvent::Error(error.to_string()));
             },
         }
     }
@@ -522,22 +994,23 @@ fn _run_with_observer() {
 }
 
 // Example using the Visitor pattern directly
+#[allow(dead_code)]
 fn _run_with_visitor() {
     println!("Correct Calculator with Visitor Pattern");
     
     let parser = ExpressionParser::new();
-    let mut variables = HashMap::new();
+    let variables = HashMap::new();
     
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
-
+        
         let mut input = String::new();
         if io::stdin().read_line(&mut input).is_err() {
             println!("Error reading input, please try again");
             continue;
         }
-
+        
         let input = input.trim();
         if input == "exit" {
             break;
@@ -547,34 +1020,471 @@ fn _run_with_visitor() {
             let expr_str = input.trim_start_matches("optimize ").trim();
             match parser.parse(expr_str) {
                 Ok(expr) => {
-                    match optimize_expression(&*expr, &variables) {
+                    match optimize_expression(&expr, &variables) {
                         Ok(optimized) => {
-                            println!("Original: {}", expr.to_string());
-                            println!("Optimized: {}", optimized.to_string());
+                            let optimized = simplify(&optimized);
+                            println!("Original: {}", expr);
+                            println!("Optimized: {}", optimized);
                             
                             match optimized.evaluate(&variables) {
                                 Ok(result) => println!("Result: {}", result),
-                                Err(e) => println!("Evaluation error: {}", e),
+                                Err(e) => println!("Evaluation error: {}", e.render(expr_str)),
                             }
                         },
-                        Err(e) => println!("Optimization error: {}", e),
+                        Err(e) => println!("Optimization error: {}", e.render(expr_str)),
                     }
                 },
-                Err(e) => println!("Parsing error: {}", e),
+                Err(e) => println!("Parsing error: {}", e.render(expr_str)),
             }
             continue;
         }
-
+        
+        // Derivatives are always taken with respect to x here
+        if let Some(expr_str) = input.strip_prefix("diff ") {
+            let expr_str = expr_str.trim();
+            match parser.parse(expr_str).and_then(|expr| derivative(&expr, "x")) {
+                Ok(result) => println!("d/dx: {}", result),
+                Err(e) => println!("Error: {}", e.render(expr_str)),
+            }
+            continue;
+        }
+        
         match parser.parse(input) {
             Ok(expr) => {
                 match expr.evaluate(&variables) {
                     Ok(result) => println!("= {}", result),
-                    Err(e) => println!("Error: {}", e),
+                    Err(e) => println!("Error: {}", e.render(input)),
+                }
+            },
+            Err(e) => println!("Error: {}", e.render(input)),
+        }
+    }
+    
+    println!("Goodbye!");
+}
+
+// Example using the Chain of Responsibility and Command patterns directly
+#[allow(dead_code)]
+fn _run_with_chain() {
+    println!("Correct Calculator with Chain of Responsibility Pattern");
+    
+    let mut calculator = CorrectCalculator::new();
+    
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Error reading input, please try again");
+            continue;
+        }
+        
+        let input = input.trim();
+        if input == "/exit" {
+            break;
+        }
+        
+        // Commands, assignments and expressions each go to the first handler that accepts them
+        match calculator.input_chain.handle(input, &mut calculator.command_processor) {
+            Ok(Some(result)) => println!("= {}", result),
+            Ok(None) => {}, // Command executed with no result to display
+            Err(error) => println!("Error: {}", error),
+        }
+    }
+    
+    println!("Goodbye!");
+}
+
+// Example using the Strategy pattern directly
+#[allow(dead_code)]
+fn _run_with_strategy() {
+    println!("Correct Calculator with Strategy Pattern");
+    
+    let variables = HashMap::from([("x".to_string(), 2.0)]);
+    let show = |name: &str, evaluator: &strategy::ExpressionEvaluator| {
+        match evaluator.evaluate("sqrt(x) * 3 + 1", &variables) {
+            Ok(result) => println!("{}: {}", name, evaluator.format_result(result)),
+            Err(error) => println!("{}: Error: {}", name, error),
+        }
+    };
+    
+    let mut evaluator = strategy::create_standard_evaluator();
+    show("shunting yard, 10 places", &evaluator);
+    
+    // The same evaluator changes behaviour as its strategies are swapped
+    evaluator.set_evaluation_strategy(Box::new(strategy::RecursiveDescentStrategy::new(Box::new(strategy::SimpleTokenizer))));
+    evaluator.set_precision_strategy(Box::new(strategy::StandardPrecision::new(2)));
+    show("recursive descent, 2 places", &evaluator);
+    
+    show("scientific", &strategy::create_scientific_evaluator());
+    show("fractions", &strategy::create_fraction_evaluator());
+    
+    // Improper fractions with small denominators, e.g. 22/7
+    evaluator.set_precision_strategy(Box::new(strategy::FractionPrecision::new(10).with_mixed_numbers(false)));
+    show("fractions up to tenths", &evaluator);
+}
+
+// Example using the Template Method pattern directly
+#[allow(dead_code)]
+fn _run_with_template() {
+    println!("Correct Calculator with Template Method Pattern");
+    
+    let variables = HashMap::from([("x".to_string(), 2.0)]);
+    
+    // Both evaluators share the tokenize, validate, parse, evaluate steps
+    for (name, use_recursive_descent) in [("recursive descent", true), ("shunting yard", false)] {
+        let evaluator = template::create_evaluator(use_recursive_descent);
+        
+        match evaluator.evaluate("(1 + x) * 3", &variables) {
+            Ok(result) => println!("{}: {}", name, result),
+            Err(error) => println!("{}: Error: {}", name, error),
+        }
+    }
+}
+
+// Example using the Mediator pattern directly
+#[allow(dead_code)]
+fn _run_with_mediator() {
+    println!("Correct Calculator with Mediator Pattern");
+    
+    // The components only talk to each other through the mediator
+    let mediator = mediator::create_mediator_system();
+    let mut mediator = mediator.lock().unwrap();
+    
+    mediator.set_variable("x", 2.0);
+    mediator.change_angle_mode(config::AngleMode::Degrees);
+    
+    if let Err(error) = mediator.evaluate("x * 3") {
+        mediator.notify("example", mediator::CalculatorEvent::ErrorOccurred(error.to_string()));
+    }
+    
+    let summary = format!(
+        "Last result: {:?}, x = {:?}, {} variables",
+        mediator.get_result(),
+        mediator.get_variable("x"),
+        mediator.get_all_variables().len(),
+    );
+    mediator.notify("example", mediator::CalculatorEvent::DisplayUpdate(summary));
+    mediator.clear();
+}
+
+// Example using the Adapter and Bridge patterns directly
+#[allow(dead_code)]
+fn _run_with_adapter() {
+    println!("Correct Calculator with Adapter and Bridge Patterns");
+    
+    // Either implementation can stand behind the scientific operations interface
+    let operations: [(&str, Box<dyn adapter::ScientificOperations>); 2] = [
+        ("standard, radians", StandardMode::new().sci_ops),
+        ("external library, degrees", Box::new(adapter::ExternalLibraryAdapter::new(config::AngleMode::Degrees))),
+    ];
+    
+    for (name, ops) in &operations {
+        println!("{}: sin(30) = {}, cos(30) = {}, tan(30) = {}", name, ops.sin(30.0), ops.cos(30.0), ops.tan(30.0));
+        match ops.log(8.0, 2.0) {
+            Ok(result) => println!("{}: log(8, 2) = {}", name, result),
+            Err(error) => println!("{}: Error: {}", name, error),
+        }
+    }
+    
+    // The display and evaluation sides of the bridge vary independently
+    let display = ConsoleDisplay::new();
+    let mut evaluator = bridge::Evaluator::new(Box::new(bridge::StandardEvaluator));
+    evaluator.change_strategy(Box::new(bridge::StandardEvaluator));
+    
+    match ExpressionParser::new().parse("2 ^ 10") {
+        Ok(expr) => {
+            display.show_expression(&expr);
+            match evaluator.evaluate(&expr, &HashMap::new()) {
+                Ok(result) => display.show_result(&Value::Float(result)),
+                Err(error) => display.show_error(&error.to_string()),
+            }
+        },
+        Err(error) => display.show_error(&error.to_string()),
+    }
+}
+
+// Example using the shared configuration directly
+#[allow(dead_code)]
+fn _run_with_config() {
+    println!("Correct Calculator with Shared Configuration");
+    
+    let global = config::get_global_config();
+    println!("Global: precision {} (default {}, at most {}), {:?}", global.precision, config::DEFAULT_PRECISION, config::MAX_PRECISION, global.angle_mode);
+    
+    // Calculators in a pool all read the same configuration
+    for configuration in [config::CalculatorConfig::scientific(), config::CalculatorConfig::engineering()] {
+        let pool = config::CalculatorPool::new(configuration);
+        let shared = pool.get_config();
+        let number = token::Number::with_format(123456.789, shared.notation);
+        println!("{:?}: {}", shared.notation, number.format());
+    }
+    
+    if let token::Token::Number(number) = token::Token::scientific_number(0.00042) {
+        println!("Scientific token: {}", number.format());
+    }
+    
+    // A formatter can override the configured defaults too, e.g. for a European locale
+    let european = NumberFormatter::new()
+        .with_precision(6)
+        .with_notation(token::NumberFormat::Decimal)
+        .with_decimal_mark(config::DecimalMark::Comma)
+        .with_thousands_separator(true);
+    println!("European: {}", european.format(1234567.891));
+    
+    // A parser can override the configured default, e.g. to reject `2x`
+    let strict = ExpressionParser::new().with_implicit_multiplication(false);
+    match strict.parse("2x") {
+        Ok(expr) => println!("Strict: {}", expr),
+        Err(error) => println!("Strict: {}", error),
+    }
+}
+
+// Example using the Iterator pattern directly
+#[allow(dead_code)]
+fn _run_with_iterator() {
+    println!("Correct Calculator with Iterator Pattern");
+    
+    let mut processor = CommandProcessor::new();
+    let parser = ExpressionParser::new();
+    
+    for input in ["x = 3", "y = 4", "x * x + y * y", "sqrt(x * x + y * y)"] {
+        if let Some((name, value)) = input.split_once('=') {
+            if let Ok(expr) = parser.parse(value.trim()) {
+                if let Ok(value) = expr.evaluate(&processor.get_calculator().float_variables()) {
+                    let _ = processor.execute(Box::new(SetVariableCommand::new(name.trim().to_string(), Value::Float(value))));
+                }
+            }
+        } else if let Ok(expr) = parser.parse(input) {
+            let _ = processor.execute(Box::new(command::EvaluateCommand::new(input.to_string(), expr)));
+        }
+    }
+    
+    let calculator = processor.get_calculator();
+    for (name, value) in iterator::VariablesIterator::new(&calculator.variables) {
+        println!("{} = {}", name, value);
+    }
+    
+    println!("Most recent first:");
+    for calc in iterator::ReverseHistoryIterator::new(&calculator.history) {
+        println!("  {} = {} ({:?} ago)", calc.expression, calc.result, calc.timestamp.elapsed().unwrap_or_default());
+        
+        if let Ok(expr) = parser.parse(&calc.expression) {
+            println!(
+                "    {} nodes, {} constants, {} variables",
+                iterator::ExpressionIterator::new(&expr).count(),
+                iterator::find_constant_nodes(&expr).len(),
+                iterator::find_variable_nodes(&expr).len(),
+            );
+        }
+    }
+    
+    // Every command can be undone and redone, in order
+    let _ = processor.undo();
+    let _ = processor.redo();
+    for description in processor.history() {
+        println!("{}", description);
+    }
+}
+
+// Example keeping variables in step with the ones they depend on
+#[allow(dead_code)]
+fn _run_with_dependent_variables() {
+    println!("Correct Calculator with Dependent Variables");
+    
+    let calculator: Arc<Mutex<dyn VariableProvider + Send>> = Arc::new(Mutex::new(mediator::VariableStorage::new()));
+    let mut observer = DependentVariableObserver::new(calculator.clone());
+    observer.add_dependency("r", "area", "3.14159 * r ^ 2");
+    observer.add_dependency("r", "diameter", "2 * r");
+    observer.remove_dependency("r", "diameter");
+    
+    for r in [1.0, 2.0, 3.0] {
+        calculator.lock().unwrap().set_variable("r", r);
+        observer.update(&CalculatorEvent::VariableChanged("r".to_string(), Value::Float(r)));
+        
+        let calculator = calculator.lock().unwrap();
+        println!("r = {}: area = {:?}, diameter = {:?}", r, calculator.get_variable("area"), calculator.get_variable("diameter"));
+    }
+}
+
+// Example registering custom operators on the operator table
+#[allow(dead_code)]
+fn _run_with_custom_operators() {
+    println!("Correct Calculator with custom operators: a ∥ b (parallel resistance), √x (square root), x‰ (per mille)");
+    
+    let mut operators = OperatorTable::new();
+    operators
+        .register_infix("∥", 6, Associativity::Left, |a, b| {
+            if a + b == 0.0 {
+                Err(CalcError::DivisionByZero { span: None })
+            } else {
+                Ok(a * b / (a + b))
+            }
+        })
+        .expect("∥ is not a built-in symbol");
+    operators
+        .register_prefix("√", 8, |x| Ok(x.sqrt()))
+        .expect("√ is not a built-in symbol");
+    let mut parser = ExpressionParser::new().with_operators(operators);
+    
+    // Operators can also be added to a parser that is already in use
+    parser
+        .operators_mut()
+        .register_postfix("‰", 10, |x| Ok(x / 1000.0))
+        .expect("‰ is not a built-in symbol");
+    
+    // The template and strategy parsers can read the same table
+    let operators = parser.operators().clone();
+    let templates: [Box<dyn template::ExpressionEvaluator>; 2] = [
+        Box::new(template::RecursiveDescentEvaluator::new().with_operators(operators.clone())),
+        Box::new(template::ShuntingYardEvaluator::new().with_operators(operators.clone())),
+    ];
+    let strategies: [Box<dyn strategy::EvaluationStrategy>; 2] = [
+        Box::new(strategy::RecursiveDescentStrategy::new(Box::new(strategy::CustomSymbolTokenizer::new(&operators))).with_operators(operators.clone())),
+        Box::new(strategy::ShuntingYardStrategy::new(Box::new(strategy::CustomSymbolTokenizer::new(&operators))).with_operators(operators)),
+    ];
+    
+    let variables = HashMap::new();
+    
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Error reading input, please try again");
+            continue;
+        }
+        
+        let input = input.trim();
+        if input == "exit" {
+            break;
+        }
+        
+        let parsed = parser.parse(input);
+        match &parsed {
+            Ok(expr) => {
+                match expr.evaluate(&variables) {
+                    Ok(result) => println!("= {}", result),
+                    Err(e) => println!("Error: {}", e.render(input)),
                 }
             },
-            Err(e) => println!("Error: {}", e),
+            Err(e) => println!("Error: {}", e.render(input)),
+        }
+        
+        // Every parser should build the same tree
+        let tree = parsed.ok();
+        let agree = templates.iter().all(|evaluator| evaluator.parse_input(input).ok() == tree)
+            && strategies.iter().all(|strategy| strategy.parse(input).ok() == tree);
+        if !agree {
+            println!("(the template and strategy parsers read this differently)");
         }
     }
     
     println!("Goodbye!");
 }
+
+// Example compiling an expression once and evaluating it many times
+#[allow(dead_code)]
+fn _run_with_vm() {
+    println!("Correct Calculator with a compiled expression");
+    
+    let expr = match ExpressionParser::new().parse("x^2 - 2x + 1") {
+        Ok(expr) => expr,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        },
+    };
+    let program = compile(&expr, &HashMap::new());
+    
+    // A one-off evaluation builds its own VM
+    let variables = HashMap::from([("x".to_string(), 3.0)]);
+    match program.evaluate(&variables) {
+        Ok(result) => println!("x = 3: {}", result),
+        Err(error) => println!("Error: {}", error),
+    }
+    
+    // A kept VM only needs the changed slot on each run
+    let mut vm = Vm::new(&program, &variables);
+    if let Some(x) = program.slot("x") {
+        for value in [0.0, 1.0, 2.0] {
+            vm.set(x, value);
+            match vm.run() {
+                Ok(result) => println!("x = {}: {}", value, result),
+                Err(error) => println!("Error: {}", error),
+            }
+        }
+    }
+}
+
+// Example evaluating repeated subexpressions only once
+#[allow(dead_code)]
+fn _run_with_dag() {
+    println!("Correct Calculator with shared subexpressions");
+    
+    let variables = HashMap::from([("x".to_string(), 0.5)]);
+    match ExpressionParser::new().parse("sin(x)^2 + sin(x) * cos(x) + cos(x)^2") {
+        Ok(expr) => {
+            let dag = Dag::new(&expr);
+            println!("{} nodes in the tree, {} once shared", iterator::ExpressionIterator::new(&expr).count(), dag.node_count());
+            match dag.evaluate(&variables) {
+                Ok(result) => println!("= {}", result),
+                Err(error) => println!("Error: {}", error),
+            }
+        },
+        Err(error) => println!("Error: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::Span;
+    
+    fn run(calculator: &mut CorrectCalculator, inputs: &[&str]) -> Option<Value> {
+        inputs.iter().map(|input| calculator.process_input(input).unwrap()).last().flatten()
+    }
+    
+    #[test]
+    fn history_keeps_results_in_their_own_type() {
+        let mut calculator = CorrectCalculator::new();
+        run(&mut calculator, &["/numeric decimal 30", "1/3", "/numeric rational", "1/7"]);
+        let results: Vec<String> = calculator.command_processor.get_calculator().history.iter()
+            .map(|calc| calc.result.to_string())
+            .collect();
+        assert_eq!(results, ["0.333333333333333333333333333333", "1/7"]);
+    }
+    
+    #[test]
+    fn saved_states_keep_exact_variables() {
+        let mut calculator = CorrectCalculator::new();
+        let result = run(&mut calculator, &["/numeric rational", "y = 1/7", "/save s", "y = 5", "/restore s", "y * 7"]);
+        assert_eq!(result.unwrap().to_string(), "1");
+    }
+    
+    #[test]
+    fn undo_brings_back_exact_variables() {
+        let mut calculator = CorrectCalculator::new();
+        let result = run(&mut calculator, &["/numeric rational", "y = 1/3", "y = 2", "/undo", "y * 3"]);
+        assert_eq!(result.unwrap().to_string(), "1");
+        assert_eq!(calculator.command_processor.get_calculator().get_variable("y"), calculator.variables.get("y"));
+    }
+    
+    #[test]
+    fn complex_values_are_errors_outside_complex_mode() {
+        let mut calculator = CorrectCalculator::new();
+        run(&mut calculator, &["/numeric complex", "z = sqrt(-4)", "w = sqrt(-4)^2", "f(x) = x + z", "g(z) = z + 1", "/numeric float"]);
+        for input in ["z + 1", "f(1)", "/optimize z * 0"] {
+            let error = calculator.process_input(input).unwrap_err();
+            assert!(matches!(error, CalcError::Unsupported { .. }), "{}: {:?}", input, error);
+        }
+        assert_eq!(calculator.process_input("z + 1").unwrap_err().span(), Some(Span::new(0, 1)));
+        
+        // A complex result with no imaginary part is a real number, and a parameter hides the variable
+        assert_eq!(run(&mut calculator, &["w + 1"]).unwrap().to_string(), "-3");
+        assert_eq!(run(&mut calculator, &["g(2)"]).unwrap().to_string(), "3");
+        assert_eq!(run(&mut calculator, &["/numeric complex", "z + 1"]).unwrap().to_string(), "1 + 2i");
+    }
+}
@@ -3,6 +3,7 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use crate::config::AngleMode;
+use crate::error::CalcError;
 
 // Mediator interface
 pub trait CalculatorMediator: Send + Sync {
@@ -11,7 +12,8 @@ pub trait CalculatorMediator: Send + Sync {
     fn get_variable(&self, name: &str) -> Option<f64>;
     fn get_all_variables(&self) -> HashMap<String, f64>;
     fn set_variable(&mut self, name: &str, value: f64);
-    fn evaluate(&mut self, expression: &str) -> Result<f64, String>;
+    fn clear(&mut self);
+    fn evaluate(&mut self, expression: &str) -> Result<f64, CalcError>;
     fn change_angle_mode(&mut self, mode: AngleMode);
 }
 
@@ -32,63 +34,39 @@ pub trait Display: Send + Sync {
     fn clear(&mut self);
 }
 
-// Component that handles evaluation
+// Component that handles evaluation.
+// Components never lock the mediator themselves: it is already locked while it calls them
 pub struct EvaluationComponent {
-    mediator: Arc<Mutex<dyn CalculatorMediator>>,
     parser: crate::parser::ExpressionParser,
 }
 
 impl EvaluationComponent {
-    pub fn new(mediator: Arc<Mutex<dyn CalculatorMediator>>) -> Self {
+    pub fn new() -> Self {
         Self {
-            mediator,
             parser: crate::parser::ExpressionParser::new(),
         }
     }
     
-    pub fn evaluate(&self, expression: &str) -> Result<f64, String> {
-        // Parse expression
+    pub fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
         let expr = self.parser.parse(expression)?;
-        
-        // Get variables from mediator
-        let variables = {
-            let mediator = self.mediator.lock().unwrap();
-            mediator.get_all_variables()
-        };
-        
-        // Evaluate
-        let result = expr.evaluate(&variables)?;
-        
-        // Notify mediator of result
-        {
-            let mut mediator = self.mediator.lock().unwrap();
-            mediator.notify("evaluator", CalculatorEvent::ResultComputed(result));
-        }
-        
-        Ok(result)
+        expr.evaluate(variables)
     }
 }
 
 // Component that manages variables
 pub struct VariableStorage {
-    mediator: Arc<Mutex<dyn CalculatorMediator>>,
     variables: HashMap<String, f64>,
 }
 
 impl VariableStorage {
-    pub fn new(mediator: Arc<Mutex<dyn CalculatorMediator>>) -> Self {
+    pub fn new() -> Self {
         Self {
-            mediator,
             variables: HashMap::new(),
         }
     }
     
     pub fn set_variable(&mut self, name: &str, value: f64) {
         self.variables.insert(name.to_string(), value);
-        
-        // Notify mediator
-        let mut mediator = self.mediator.lock().unwrap();
-        mediator.notify("variables", CalculatorEvent::VariableChanged(name.to_string(), value));
     }
     
     pub fn get_variable(&self, name: &str) -> Option<f64> {
@@ -105,15 +83,7 @@ impl VariableStorage {
 }
 
 // Console display component
-pub struct ConsoleDisplay {
-    mediator: Arc<Mutex<dyn CalculatorMediator>>,
-}
-
-impl ConsoleDisplay {
-    pub fn new(mediator: Arc<Mutex<dyn CalculatorMediator>>) -> Self {
-        Self { mediator }
-    }
-}
+pub struct ConsoleDisplay;
 
 impl Display for ConsoleDisplay {
     fn show_result(&mut self, result: f64) {
@@ -169,7 +139,7 @@ impl CalculatorMediatorImpl {
 }
 
 impl CalculatorMediator for CalculatorMediatorImpl {
-    fn notify(&mut self, sender: &str, event: CalculatorEvent) {
+    fn notify(&mut self, _sender: &str, event: CalculatorEvent) {
         match event {
             CalculatorEvent::ResultComputed(result) => {
                 self.last_result = Some(result);
@@ -233,14 +203,28 @@ impl CalculatorMediator for CalculatorMediatorImpl {
             let mut variables = variables.lock().unwrap();
             variables.set_variable(name, value);
         }
+        
+        self.notify("variables", CalculatorEvent::VariableChanged(name.to_string(), value));
     }
     
-    fn evaluate(&mut self, expression: &str) -> Result<f64, String> {
-        if let Some(evaluator) = &self.evaluator {
-            evaluator.evaluate(expression)
-        } else {
-            Err("Evaluator not initialized".to_string())
+    fn clear(&mut self) {
+        if let Some(variables) = &self.variables {
+            variables.lock().unwrap().clear();
         }
+        
+        if let Some(display) = &self.display {
+            display.lock().unwrap().clear();
+        }
+    }
+    
+    fn evaluate(&mut self, expression: &str) -> Result<f64, CalcError> {
+        let result = match &self.evaluator {
+            Some(evaluator) => evaluator.evaluate(expression, &self.get_all_variables())?,
+            None => return Err(CalcError::internal("Evaluator not initialized")),
+        };
+        
+        self.notify("evaluator", CalculatorEvent::ResultComputed(result));
+        Ok(result)
     }
     
     fn change_angle_mode(&mut self, mode: AngleMode) {
@@ -258,22 +242,12 @@ impl CalculatorMediator for CalculatorMediatorImpl {
 // Helper function to set up mediator system
 pub fn create_mediator_system() -> Arc<Mutex<dyn CalculatorMediator>> {
     // Create mediator
-    let mediator = Arc::new(Mutex::new(CalculatorMediatorImpl::new()) as Mutex<dyn CalculatorMediator>);
+    let mut mediator = CalculatorMediatorImpl::new();
     
-    // Create components
-    let evaluator = Arc::new(EvaluationComponent::new(mediator.clone()));
-    let variables = Arc::new(Mutex::new(VariableStorage::new(mediator.clone())));
-    let display = Arc::new(Mutex::new(ConsoleDisplay::new(mediator.clone())) as Mutex<dyn Display>);
-    
-    // Register components with mediator
-    {
-        let mut mediator_lock = mediator.lock().unwrap();
-        if let Some(mediator_impl) = mediator_lock.downcast_mut::<CalculatorMediatorImpl>() {
-            mediator_impl.set_evaluator(evaluator);
-            mediator_impl.set_variables(variables);
-            mediator_impl.set_display(display);
-        }
-    }
+    // Create components and register them with the mediator
+    mediator.set_evaluator(Arc::new(EvaluationComponent::new()));
+    mediator.set_variables(Arc::new(Mutex::new(VariableStorage::new())));
+    mediator.set_display(Arc::new(Mutex::new(ConsoleDisplay)));
     
-    mediator
+    Arc::new(Mutex::new(mediator))
 }
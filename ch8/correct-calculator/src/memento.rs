@@ -2,13 +2,17 @@
 
 use std::collections::HashMap;
 use crate::command::Calculation;
+use crate::expression::UserFunction;
 use crate::state::{CalculatorState, StandardMode, ScientificMode, ProgrammerMode, NumberBase};
 use crate::config::AngleMode;
+use crate::error::CalcError;
+use crate::numeric::Value;
 
 // Memento to store calculator state
 #[derive(Clone)]
 pub struct CalculatorMemento {
-    pub variables: HashMap<String, f64>,
+    pub variables: HashMap<String, Value>, // As they were worked out, so exact values stay exact
+    pub functions: HashMap<String, UserFunction>,
     pub history: Vec<Calculation>,
     pub mode: CalculatorStateType,
     pub angle_mode: AngleMode,
@@ -40,12 +44,12 @@ impl CalculatorStateManager {
         println!("State saved as '{}'", name);
     }
     
-    pub fn restore_state(&self, name: &str) -> Result<CalculatorMemento, String> {
+    pub fn restore_state(&self, name: &str) -> Result<CalculatorMemento, CalcError> {
         if let Some(memento) = self.saved_states.get(name) {
             println!("State '{}' restored", name);
             Ok(memento.clone())
         } else {
-            Err(format!("No saved state named '{}'", name))
+            Err(CalcError::UnknownState { name: name.to_string() })
         }
     }
     
@@ -57,12 +61,12 @@ impl CalculatorStateManager {
         self.saved_states.contains_key(name)
     }
     
-    pub fn delete_state(&mut self, name: &str) -> Result<(), String> {
+    pub fn delete_state(&mut self, name: &str) -> Result<(), CalcError> {
         if self.saved_states.remove(name).is_some() {
             println!("State '{}' deleted", name);
             Ok(())
         } else {
-            Err(format!("No saved state named '{}'", name))
+            Err(CalcError::UnknownState { name: name.to_string() })
         }
     }
 }
@@ -70,7 +74,7 @@ impl CalculatorStateManager {
 // Originator trait for creating and applying mementos
 pub trait MementoOriginator {
     fn create_memento(&self) -> CalculatorMemento;
-    fn restore_from_memento(&mut self, memento: &CalculatorMemento) -> Result<(), String>;
+    fn restore_from_memento(&mut self, memento: &CalculatorMemento) -> Result<(), CalcError>;
 }
 
 // Command for saving state
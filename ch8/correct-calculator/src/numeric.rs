@@ -0,0 +1,664 @@
+// numeric.rs - Number types an expression can be evaluated in: f64, the
+// high-precision and exact types in decimal.rs and rational.rs, or complex.rs
+
+use std::collections::HashMap;
+use std::f64::consts::{PI, FRAC_PI_2};
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+use crate::expression::{Expr, Expression, Callee, CallContext, UserFunction, gamma, undefined_variable, enter_user_function, run_user_function};
+use crate::token::{Operator, UnaryOperator, PostfixOperator, Function, ModuloConvention, Span};
+use crate::error::CalcError;
+use crate::config::{DEFAULT_RECURSION_DEPTH, MAX_PRECISION, get_global_config};
+use crate::constants::{self, Constant};
+use crate::bigint::BigInt;
+use crate::decimal::Decimal;
+use crate::rational::Rational;
+use crate::complex::{Complex, ComplexForm, IMAGINARY_UNIT};
+
+// Whole exponents up to this size are multiplied out, so exact types stay exact
+const MAX_EXACT_POWER: f64 = 10_000.0;
+
+// ...unless the result would have more digits than this, which would take
+// too long to work out; it is then approximated in f64
+const MAX_EXACT_DIGITS: f64 = 100_000.0;
+
+// Largest n whose factorial is multiplied out rather than taken from Γ
+const MAX_EXACT_FACTORIAL: f64 = 1_000.0;
+
+// A kind of number to evaluate in. Operators and functions behave as
+// `Operator::apply` and `Function::apply` do for f64, with the same errors;
+// the defaults build them from the arithmetic and functions below
+pub trait Numeric:
+    Clone + PartialOrd + fmt::Display
+    + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self>
+{
+    // What each value carries besides its number, such as a decimal's precision
+    type Settings: Copy;
+    
+    // As /numeric and error messages name the type
+    const NAME: &'static str;
+    
+    // Whether negative numbers have square roots and logarithms in the type
+    const COMPLEX: bool = false;
+    
+    // None when the type has no such value, as for ∞ in a decimal. The shortest
+    // decimal that gives `value` is read, so the literal 0.1 is exactly 0.1
+    fn from_f64(value: f64, settings: Self::Settings) -> Option<Self>;
+    fn from_i64(value: i64, settings: Self::Settings) -> Self;
+    fn to_f64(&self) -> f64;
+    fn settings(&self) -> Self::Settings;
+    
+    // To and from a `Value`, which can hold any of the types
+    fn into_value(self) -> Value;
+    fn from_value(value: &Value, settings: Self::Settings) -> Option<Self>;
+    
+    fn is_zero(&self) -> bool;
+    fn floor(&self) -> Self;
+    fn trunc(&self) -> Self;
+    
+    // Digits the value is held with, for types that grow as they are
+    // multiplied; 0 for types of a fixed size
+    fn digits(&self) -> usize {
+        0
+    }
+    
+    fn is_true(&self) -> bool {
+        !self.is_zero()
+    }
+    
+    // A value the type has a name for, such as the imaginary unit; it comes
+    // before variables and constants
+    fn named(_name: &str, _settings: Self::Settings) -> Option<Self> {
+        None
+    }
+    
+    // Parts of a complex number, which for real types are the number and 0
+    fn re(&self) -> Self {
+        self.clone()
+    }
+    
+    fn im(&self) -> Self {
+        Self::from_i64(0, self.settings())
+    }
+    
+    fn conj(&self) -> Self {
+        self.clone()
+    }
+    
+    fn abs(&self) -> Self {
+        if self.clone() < Self::from_i64(0, self.settings()) { -self.clone() } else { self.clone() }
+    }
+    
+    // 0 for a positive number, π for a negative one
+    fn arg(&self) -> Option<Self> {
+        Self::from_i64(0, self.settings()).atan2(self)
+    }
+    
+    // These default to working in f64, so are only as precise as an f64. None
+    // when the result is not a finite number
+    fn sqrt(&self) -> Option<Self> {
+        self.through_f64(f64::sqrt)
+    }
+    
+    fn exp(&self) -> Option<Self> {
+        self.through_f64(f64::exp)
+    }
+    
+    fn ln(&self) -> Option<Self> {
+        self.through_f64(f64::ln)
+    }
+    
+    // Logarithm of self in `base`
+    fn log(&self, base: &Self) -> Option<Self> {
+        Some(self.ln()? / base.ln()?)
+    }
+    
+    fn sin(&self) -> Option<Self> {
+        self.through_f64(f64::sin)
+    }
+    
+    fn cos(&self) -> Option<Self> {
+        self.through_f64(f64::cos)
+    }
+    
+    // Angle of the point (x, self)
+    fn atan2(&self, x: &Self) -> Option<Self> {
+        let x = x.to_f64();
+        self.through_f64(|y| y.atan2(x))
+    }
+    
+    fn gamma(&self) -> Option<Self> {
+        self.through_f64(gamma)
+    }
+    
+    // None when the result does not fit the type, as when a decimal's
+    // exponent would overflow
+    fn checked_mul(self, other: Self) -> Option<Self> {
+        Some(self * other)
+    }
+    
+    fn checked_div(self, other: Self) -> Option<Self> {
+        Some(self / other)
+    }
+    
+    fn powi(&self, exponent: u32) -> Option<Self> {
+        multiply_out(self.clone(), exponent)
+    }
+    
+    fn factorial(n: u32, settings: Self::Settings) -> Self {
+        (1..=i64::from(n)).fold(Self::from_i64(1, settings), |product, k| product * Self::from_i64(k, settings))
+    }
+    
+    fn through_f64(&self, f: impl Fn(f64) -> f64) -> Option<Self> {
+        let result = f(self.to_f64());
+        if result.is_finite() {
+            Self::from_f64(result, self.settings())
+        } else {
+            None
+        }
+    }
+    
+    // A custom node's value, for types it can evaluate in itself; others
+    // fold the node over its children's values in f64
+    fn evaluate_custom(_node: &dyn Expression, _context: &NumericContext<Self>) -> Option<Result<Self, CalcError>> {
+        None
+    }
+    
+    fn constant(constant: &Constant, settings: Self::Settings) -> Self {
+        Self::from_f64(constant.value, settings).expect("constants are finite")
+    }
+    
+    // `percent` says whether the right operand was written as a percentage
+    fn apply(operator: &Operator, left: Self, right: Self, percent: bool, span: Option<Span>) -> Result<Self, CalcError> {
+        let settings = left.settings();
+        let truth = |condition: bool| Self::from_i64(i64::from(condition), settings);
+        let defined = |value: Option<Self>| value.ok_or_else(|| unrepresentable::<Self>(span));
+        let relative = || if percent { defined(left.clone().checked_mul(right.clone())) } else { Ok(right.clone()) };
+        
+        match operator {
+            Operator::Add => Ok(left.clone() + relative()?),
+            Operator::Subtract => Ok(left.clone() - relative()?),
+            Operator::Multiply | Operator::ImplicitMultiply => defined(left.checked_mul(right)),
+            Operator::Divide | Operator::Modulo | Operator::FloorDivide if right.is_zero() => {
+                Err(CalcError::DivisionByZero { span })
+            },
+            Operator::Divide => defined(left.checked_div(right)),
+            Operator::Modulo => defined(remainder(left, right, get_global_config().modulo_convention)),
+            Operator::FloorDivide => Ok(defined(left.checked_div(right))?.floor()),
+            Operator::Power => power(left, right, span),
+            Operator::Less | Operator::LessEqual | Operator::GreaterEqual | Operator::Greater if left.partial_cmp(&right).is_none() => {
+                Err(unordered::<Self>(format!("Comparing {} {} {}", left, operator.symbol(), right), span))
+            },
+            Operator::Less => Ok(truth(left < right)),
+            Operator::LessEqual => Ok(truth(left <= right)),
+            Operator::Equal => Ok(truth(left == right)),
+            Operator::NotEqual => Ok(truth(left != right)),
+            Operator::GreaterEqual => Ok(truth(left >= right)),
+            Operator::Greater => Ok(truth(left > right)),
+            Operator::And => Ok(truth(left.is_true() && right.is_true())),
+            Operator::Or => Ok(truth(left.is_true() || right.is_true())),
+        }
+    }
+    
+    fn apply_unary(operator: &UnaryOperator, value: Self) -> Self {
+        match operator {
+            UnaryOperator::Negate => -value,
+            UnaryOperator::Plus => value,
+            UnaryOperator::Not => Self::from_i64(i64::from(!value.is_true()), value.settings()),
+        }
+    }
+    
+    fn apply_postfix(operator: &PostfixOperator, value: Self, span: Option<Span>) -> Result<Self, CalcError> {
+        let settings = value.settings();
+        match operator {
+            PostfixOperator::Factorial => {
+                let whole = value.floor() == value;
+                let n = value.to_f64();
+                if whole && n < 0.0 {
+                    Err(CalcError::DomainError { function: "factorial".to_string(), argument: n, span })
+                } else if whole && n <= MAX_EXACT_FACTORIAL {
+                    Ok(Self::factorial(n as u32, settings))
+                } else {
+                    (value + Self::from_i64(1, settings)).gamma().ok_or_else(|| unrepresentable::<Self>(span))
+                }
+            },
+            PostfixOperator::Percent => Ok(value / Self::from_i64(100, settings)),
+        }
+    }
+    
+    fn call(function: &Function, args: &[Self], span: Option<Span>) -> Result<Self, CalcError> {
+        function.check_arity(args.len(), span)?;
+        
+        let settings = args[0].settings();
+        let zero = Self::from_i64(0, settings);
+        let domain_error = |argument: &Self| CalcError::DomainError {
+            function: function.name().to_string(),
+            argument: argument.to_f64(),
+            span,
+        };
+        let defined = |value: Option<Self>| value.ok_or_else(|| unrepresentable::<Self>(span));
+        
+        match function {
+            Function::Sin => defined(args[0].sin()),
+            Function::Cos => defined(args[0].cos()),
+            Function::Tan => {
+                // The poles are found as they are for f64
+                let cos = defined(args[0].cos())?;
+                if (args[0].to_f64() - FRAC_PI_2).abs() % PI < 1e-10 || cos.is_zero() {
+                    Err(domain_error(&args[0]))
+                } else {
+                    Ok(defined(args[0].sin())? / cos)
+                }
+            },
+            Function::Sqrt if !Self::COMPLEX && args[0] < zero => Err(domain_error(&args[0])),
+            Function::Sqrt => defined(args[0].sqrt()),
+            Function::Log => {
+                let (value, base) = (&args[0], &args[1]);
+                let outside = |x: &Self| x.is_zero() || (!Self::COMPLEX && *x < zero);
+                if outside(value) {
+                    Err(domain_error(value))
+                } else if outside(base) || *base == Self::from_i64(1, settings) {
+                    Err(domain_error(base))
+                } else {
+                    defined(value.log(base))
+                }
+            },
+            Function::Atan2 => defined(args[0].atan2(&args[1])),
+            Function::Hypot => {
+                let square = |x: &Self| defined(x.clone().checked_mul(x.clone()));
+                defined((square(&args[0])? + square(&args[1])?).sqrt())
+            },
+            Function::Min | Function::Max | Function::Clamp if args.iter().any(|arg| arg.partial_cmp(&args[0]).is_none()) => {
+                let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+                Err(unordered::<Self>(format!("{}({})", function.name(), args.join(", ")), span))
+            },
+            Function::Min => Ok(args[1..].iter().fold(args[0].clone(), |min, arg| if *arg < min { arg.clone() } else { min })),
+            Function::Max => Ok(args[1..].iter().fold(args[0].clone(), |max, arg| if *arg > max { arg.clone() } else { max })),
+            Function::Clamp => {
+                let (value, lo, hi) = (&args[0], &args[1], &args[2]);
+                if lo > hi {
+                    Err(domain_error(lo))
+                } else if value < lo {
+                    Ok(lo.clone())
+                } else if value > hi {
+                    Ok(hi.clone())
+                } else {
+                    Ok(value.clone())
+                }
+            },
+            Function::If => Ok(if args[0].is_true() { args[1].clone() } else { args[2].clone() }),
+            Function::Re => Ok(args[0].re()),
+            Function::Im => Ok(args[0].im()),
+            Function::Abs => Ok(args[0].abs()),
+            Function::Arg => defined(args[0].arg()),
+            Function::Conj => Ok(args[0].conj()),
+        }
+    }
+}
+
+// dividend - divisor * q, where the convention decides how the quotient q is
+// rounded. The remainder is smaller than the divisor, so only q can overflow
+fn remainder<N: Numeric>(dividend: N, divisor: N, convention: ModuloConvention) -> Option<N> {
+    let quotient = dividend.clone().checked_div(divisor.clone())?;
+    Some(match convention {
+        ModuloConvention::Truncated => dividend - divisor * quotient.trunc(),
+        ModuloConvention::Floored => dividend - divisor * quotient.floor(),
+        ModuloConvention::Euclidean => {
+            let negative = divisor < N::from_i64(0, divisor.settings());
+            let quotient = if negative { -quotient } else { quotient };
+            let divisor = if negative { -divisor } else { divisor };
+            dividend - divisor * quotient.floor()
+        },
+    })
+}
+
+// Whole exponents are multiplied out by squaring; others go through exp and ln
+fn power<N: Numeric>(base: N, exponent: N, span: Option<Span>) -> Result<N, CalcError> {
+    let settings = base.settings();
+    let (zero, one) = (N::from_i64(0, settings), N::from_i64(1, settings));
+    let n = exponent.to_f64();
+    
+    if exponent.floor() == exponent && n.abs() <= MAX_EXACT_POWER {
+        if base.digits() as f64 * n.abs() > MAX_EXACT_DIGITS {
+            return base.through_f64(|base| base.powf(n)).ok_or_else(|| unrepresentable::<N>(span));
+        }
+        let result = base.powi(n.abs() as u32).ok_or_else(|| unrepresentable::<N>(span))?;
+        return match n < 0.0 {
+            true if result.is_zero() => Err(CalcError::DivisionByZero { span }),
+            true => Ok(one / result),
+            false => Ok(result),
+        };
+    }
+    
+    if !N::COMPLEX && base < zero {
+        Err(CalcError::DomainError { function: "^".to_string(), argument: base.to_f64(), span })
+    } else if base.is_zero() && exponent > zero {
+        Ok(zero)
+    } else if base.is_zero() {
+        Err(CalcError::DivisionByZero { span })
+    } else {
+        let logarithm = base.ln().ok_or_else(|| unrepresentable::<N>(span))?;
+        (exponent * logarithm).exp().ok_or_else(|| unrepresentable::<N>(span))
+    }
+}
+
+// The error for comparing numbers that have no order, such as 1 + 2i and 3
+fn unordered<N: Numeric>(what: String, span: Option<Span>) -> CalcError {
+    CalcError::Unsupported {
+        what,
+        mode: N::NAME.to_string(),
+        span,
+    }
+}
+
+// base^exponent by repeated squaring; None if a product does not fit the type
+pub fn multiply_out<N: Numeric>(base: N, mut exponent: u32) -> Option<N> {
+    let (mut result, mut square) = (N::from_i64(1, base.settings()), base);
+    while exponent > 0 {
+        if exponent % 2 == 1 {
+            result = result.checked_mul(square.clone())?;
+        }
+        exponent /= 2;
+        if exponent > 0 {
+            square = square.clone().checked_mul(square)?;
+        }
+    }
+    Some(result)
+}
+
+// `value` as mantissa × 10^exponent, from the shortest decimal that reads
+// back as `value`; None for ∞ and NaN
+pub fn decimal_parts(value: f64) -> Option<(BigInt, i64)> {
+    if !value.is_finite() {
+        return None;
+    }
+    // `{:e}` prints those digits in scientific notation, as in -1.25e-7
+    let text = format!("{:e}", value);
+    let (digits, exponent) = text.split_once('e')?;
+    let fraction = digits.split_once('.').map_or(0, |(_, fraction)| fraction.len() as i64);
+    let mantissa = BigInt::parse(&digits.replace('.', ""))?;
+    Some((mantissa, exponent.parse::<i64>().ok()? - fraction))
+}
+
+// The error for a result the type has no value for, such as ∞ or NaN
+fn unrepresentable<N: Numeric>(span: Option<Span>) -> CalcError {
+    CalcError::Unsupported {
+        what: "A result that is not a finite number".to_string(),
+        mode: N::NAME.to_string(),
+        span,
+    }
+}
+
+// f64 keeps the evaluation code it has always used
+impl Numeric for f64 {
+    type Settings = ();
+    
+    const NAME: &'static str = "float";
+    
+    fn from_f64(value: f64, _: ()) -> Option<Self> {
+        Some(value)
+    }
+    
+    fn from_i64(value: i64, _: ()) -> Self {
+        value as f64
+    }
+    
+    fn to_f64(&self) -> f64 {
+        *self
+    }
+    
+    fn settings(&self) {}
+    
+    fn into_value(self) -> Value {
+        Value::Float(self)
+    }
+    
+    fn from_value(value: &Value, _: ()) -> Option<Self> {
+        match value {
+            Value::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+    
+    fn is_zero(&self) -> bool {
+        *self == 0.0
+    }
+    
+    fn floor(&self) -> Self {
+        f64::floor(*self)
+    }
+    
+    fn trunc(&self) -> Self {
+        f64::trunc(*self)
+    }
+    
+    fn apply(operator: &Operator, left: f64, right: f64, percent: bool, span: Option<Span>) -> Result<f64, CalcError> {
+        operator.apply(left, right, percent, span)
+    }
+    
+    fn apply_unary(operator: &UnaryOperator, value: f64) -> f64 {
+        operator.apply(value)
+    }
+    
+    fn apply_postfix(operator: &PostfixOperator, value: f64, span: Option<Span>) -> Result<f64, CalcError> {
+        operator.apply(value, span)
+    }
+    
+    fn call(function: &Function, args: &[f64], span: Option<Span>) -> Result<f64, CalcError> {
+        function.apply(args, span)
+    }
+    
+    fn evaluate_custom(node: &dyn Expression, context: &NumericContext<f64>) -> Option<Result<f64, CalcError>> {
+        Some(node.evaluate_in(context))
+    }
+}
+
+// A value of any of the types, for code that picks the type at run time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Float(f64),
+    Decimal(Decimal),
+    Rational(Rational),
+    Complex(Complex),
+}
+
+impl Value {
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Value::Float(value) => *value,
+            Value::Decimal(value) => value.to_f64(),
+            Value::Rational(value) => value.to_f64(),
+            Value::Complex(value) => value.to_f64(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Decimal(value) => write!(f, "{}", value),
+            Value::Rational(value) => write!(f, "{}", value),
+            Value::Complex(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// The type a session evaluates in, chosen in the configuration or with /numeric
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NumericBackend {
+    Float,
+    Decimal { precision: u32 }, // Significant digits, up to MAX_PRECISION
+    Rational,
+    Complex { form: ComplexForm }, // Opt-in, so `i` stays an ordinary name otherwise
+}
+
+impl NumericBackend {
+    // Whether `name` is a value of the type, so cannot be assigned
+    pub fn reserves(&self, name: &str) -> bool {
+        matches!(self, NumericBackend::Complex { .. }) && name == IMAGINARY_UNIT
+    }
+    
+    // From the arguments of /numeric, e.g. `decimal 50`. Without a digit count
+    // a decimal has the configured precision
+    pub fn parse(args: &[&str]) -> Result<Self, CalcError> {
+        let usage = || CalcError::usage(format!("/numeric [float | decimal [1-{}] | rational | complex [rectangular | polar]]", MAX_PRECISION));
+        
+        match args {
+            ["float"] => Ok(NumericBackend::Float),
+            ["rational"] => Ok(NumericBackend::Rational),
+            ["decimal"] => Ok(NumericBackend::Decimal { precision: get_global_config().precision.clamp(1, MAX_PRECISION) }),
+            ["decimal", digits] => match digits.parse::<u32>() {
+                Ok(precision @ 1..=MAX_PRECISION) => Ok(NumericBackend::Decimal { precision }),
+                _ => Err(usage()),
+            },
+            ["complex"] | ["complex", "rectangular"] => Ok(NumericBackend::Complex { form: ComplexForm::Rectangular }),
+            ["complex", "polar"] => Ok(NumericBackend::Complex { form: ComplexForm::Polar }),
+            _ => Err(usage()),
+        }
+    }
+}
+
+impl fmt::Display for NumericBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumericBackend::Float => write!(f, "float"),
+            NumericBackend::Decimal { precision } => write!(f, "decimal, {} digits", precision),
+            NumericBackend::Rational => write!(f, "rational"),
+            NumericBackend::Complex { form: ComplexForm::Rectangular } => write!(f, "complex"),
+            NumericBackend::Complex { form: ComplexForm::Polar } => write!(f, "complex, polar form"),
+        }
+    }
+}
+
+// What an evaluation in `N` looks up; the counterpart of `EvaluationContext`
+pub struct NumericContext<'a, N: Numeric> {
+    pub variables: &'a HashMap<String, N>,
+    pub functions: &'a HashMap<String, UserFunction>,
+    pub settings: N::Settings, // For the literals and constants in the expression
+    pub depth: usize, // User function calls currently in progress
+    pub max_depth: usize,
+}
+
+impl<'a, N: Numeric> NumericContext<'a, N> {
+    pub fn new(variables: &'a HashMap<String, N>, functions: &'a HashMap<String, UserFunction>, settings: N::Settings) -> Self {
+        Self {
+            variables,
+            functions,
+            settings,
+            depth: 0,
+            max_depth: DEFAULT_RECURSION_DEPTH,
+        }
+    }
+    
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    
+    pub fn evaluate(&self, expr: &Expr) -> Result<N, CalcError> {
+        match expr {
+            Expr::Number { value, span, .. } => N::from_f64(*value, self.settings).ok_or_else(|| unrepresentable::<N>(*span)),
+            Expr::Variable { name, span } => {
+                N::named(name, self.settings)
+                    .or_else(|| constants::resolve_as(name, self.variables, |constant| N::constant(constant, self.settings)))
+                    .ok_or_else(|| undefined_variable(name, self.variables.keys().map(String::as_str), *span))
+            },
+            Expr::Binary { operator, left, right, span } => {
+                let left_val = self.evaluate(left)?;
+                
+                // `&&` and `||` short-circuit, so the right side may be an error when unused
+                match operator {
+                    Operator::And if !left_val.is_true() => return Ok(N::from_i64(0, self.settings)),
+                    Operator::Or if left_val.is_true() => return Ok(N::from_i64(1, self.settings)),
+                    _ => {},
+                }
+                
+                let right_val = self.evaluate(right)?;
+                let percent = matches!(**right, Expr::Postfix { operator: PostfixOperator::Percent, .. });
+                N::apply(operator, left_val, right_val, percent, *span)
+            },
+            Expr::Unary { operator, operand, .. } => Ok(N::apply_unary(operator, self.evaluate(operand)?)),
+            Expr::Postfix { operator, operand, span } => N::apply_postfix(operator, self.evaluate(operand)?, *span),
+            // Only the chosen branch of `if` is evaluated
+            Expr::Call { function: Callee::Builtin(Function::If), arguments, .. } if arguments.len() == 3 => {
+                let condition = self.evaluate(&arguments[0])?;
+                self.evaluate(if condition.is_true() { &arguments[1] } else { &arguments[2] })
+            },
+            Expr::Call { function: Callee::Builtin(function), arguments, span } => {
+                let args = self.evaluate_all(arguments)?;
+                N::call(function, &args, *span)
+            },
+            Expr::Call { function: Callee::User(name), arguments, span } => self.call_user_function(name, arguments, *span),
+            Expr::Error { error, .. } => Err(error.clone()),
+            Expr::Custom(node) => {
+                if let Some(result) = N::evaluate_custom(&**node, self) {
+                    return result;
+                }
+                
+                // Otherwise custom nodes only know how to work on f64s
+                let values: Vec<f64> = self.evaluate_all(node.children())?.iter().map(N::to_f64).collect();
+                match node.fold(&values) {
+                    Some(result) => N::from_f64(result?, self.settings).ok_or_else(|| unrepresentable::<N>(node.span())),
+                    None => Err(CalcError::Unsupported {
+                        what: format!("`{}`", node.to_string()),
+                        mode: N::NAME.to_string(),
+                        span: node.span(),
+                    }),
+                }
+            },
+        }
+    }
+    
+    fn evaluate_all(&self, arguments: &[Expr]) -> Result<Vec<N>, CalcError> {
+        arguments.iter().map(|argument| self.evaluate(argument)).collect()
+    }
+    
+    fn call_user_function(&self, name: &str, arguments: &[Expr], span: Option<Span>) -> Result<N, CalcError> {
+        let function = enter_user_function(name, arguments.len(), span, self)?;
+        
+        // Arguments are evaluated in the caller's scope, then bound over it
+        let args = self.evaluate_all(arguments)?;
+        run_user_function(function, args, span, self)
+    }
+}
+
+impl<N: Numeric> CallContext for NumericContext<'_, N> {
+    type Value = N;
+    
+    fn functions(&self) -> &HashMap<String, UserFunction> {
+        self.functions
+    }
+    
+    fn variables(&self) -> &HashMap<String, N> {
+        self.variables
+    }
+    
+    fn depth(&self) -> (usize, usize) {
+        (self.depth, self.max_depth)
+    }
+    
+    fn evaluate_nested(&self, scope: &HashMap<String, N>, body: &Expr) -> Result<N, CalcError> {
+        let inner = NumericContext {
+            variables: scope,
+            functions: self.functions,
+            settings: self.settings,
+            depth: self.depth + 1,
+            max_depth: self.max_depth,
+        };
+        inner.evaluate(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::evaluate_in;
+    
+    #[test]
+    fn powers_too_large_to_multiply_out_are_approximated() {
+        assert_eq!(evaluate_in::<Rational>("(2^100)^99 / 2^9900", ()).unwrap().to_string(), "1");
+        assert_eq!(evaluate_in::<Rational>("(1 + 1/10^6)^10000", ()).unwrap().to_string(), "≈1.0100501620330897");
+        assert!(evaluate_in::<Rational>("(2^10000)^10000", ()).is_err());
+    }
+}
@@ -4,12 +4,15 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use crate::command::Calculation;
 use crate::bridge::Display;
+use crate::error::CalcError;
+use crate::numeric::Value;
 
 // Events that can be observed
 #[derive(Clone, Debug)]
 pub enum CalculatorEvent {
-    VariableChanged(String, f64),
-    ResultCalculated(f64, String), // Result and expression
+    VariableChanged(String, Value),
+    FunctionDefined(String), // Signature, e.g. "f(x, y)"
+    ResultCalculated(Value, String), // Result and expression
     ModeChanged(String),
     HistoryAdded(Calculation),
     StateRestored,
@@ -30,25 +33,28 @@ pub trait Subject {
 
 // Display observer that updates UI when calculator state changes
 pub struct DisplayObserver {
-    display: Arc<Mutex<dyn Display>>,
+    display: Arc<Mutex<dyn Display + Send>>,
 }
 
 impl DisplayObserver {
-    pub fn new(display: Arc<Mutex<dyn Display>>) -> Self {
+    pub fn new(display: Arc<Mutex<dyn Display + Send>>) -> Self {
         Self { display }
     }
 }
 
 impl Observer for DisplayObserver {
     fn update(&self, event: &CalculatorEvent) {
-        let mut display = self.display.lock().unwrap();
+        let display = self.display.lock().unwrap();
         match event {
             CalculatorEvent::ResultCalculated(result, expr) => {
-                display.show_result(*result);
+                display.show_result(result);
                 display.show_message(&format!("Evaluated: {}", expr));
             },
             CalculatorEvent::VariableChanged(name, value) => {
-                display.show_message(&format!("Variable {} = {}", name, value));
+                display.show_message(&format!("Variable {} = {}", name, display.format_value(value)));
+            },
+            CalculatorEvent::FunctionDefined(signature) => {
+                display.show_message(&format!("Function {} defined", signature));
             },
             CalculatorEvent::ModeChanged(mode) => {
                 display.show_message(&format!("Switched to {} mode", mode));
@@ -68,19 +74,19 @@ impl Observer for DisplayObserver {
 
 // Observer for dependent variables
 pub struct DependentVariableObserver {
-    calculator: Arc<Mutex<dyn VariableProvider>>,
+    calculator: Arc<Mutex<dyn VariableProvider + Send>>,
     dependencies: HashMap<String, Vec<(String, String)>>, // Map of variable to tuples of dependent var name and expression
 }
 
 // Interface for calculator to provide variable evaluation
-pub trait VariableProvider: Send + Sync {
+pub trait VariableProvider {
     fn get_variable(&self, name: &str) -> Option<f64>;
     fn set_variable(&mut self, name: &str, value: f64);
-    fn evaluate_expression(&mut self, expr: &str) -> Result<f64, String>;
+    fn evaluate_expression(&mut self, expr: &str) -> Result<f64, CalcError>;
 }
 
 impl DependentVariableObserver {
-    pub fn new(calculator: Arc<Mutex<dyn VariableProvider>>) -> Self {
+    pub fn new(calculator: Arc<Mutex<dyn VariableProvider + Send>>) -> Self {
         Self {
             calculator,
             dependencies: HashMap::new(),
@@ -90,7 +96,7 @@ impl DependentVariableObserver {
     pub fn add_dependency(&mut self, variable: &str, dependent: &str, expression: &str) {
         let dependencies = self.dependencies
             .entry(variable.to_string())
-            .or_insert_with(Vec::new);
+            .or_default();
         
         dependencies.push((dependent.to_string(), expression.to_string()));
     }
@@ -122,7 +128,7 @@ impl Observer for DependentVariableObserver {
         } else if let CalculatorEvent::StateRestored = event {
             // Re-evaluate all dependent variables
             let mut calc = self.calculator.lock().unwrap();
-            for (_, dependents) in &self.dependencies {
+            for dependents in self.dependencies.values() {
                 for (dependent, expr) in dependents {
                     if let Ok(value) = calc.evaluate_expression(expr) {
                         calc.set_variable(dependent, value);
@@ -142,6 +148,9 @@ impl Observer for LoggerObserver {
             CalculatorEvent::VariableChanged(name, value) => {
                 println!("[LOG] Variable changed: {} = {}", name, value);
             },
+            CalculatorEvent::FunctionDefined(signature) => {
+                println!("[LOG] Function defined: {}", signature);
+            },
             CalculatorEvent::ResultCalculated(result, expr) => {
                 println!("[LOG] Calculation: {} = {}", expr, result);
             },
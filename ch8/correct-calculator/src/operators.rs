@@ -0,0 +1,264 @@
+// operators.rs - Operator table driving the Pratt parser
+
+use std::sync::Arc;
+use crate::token::{Token, SpannedToken, Operator, UnaryOperator, PostfixOperator, Associativity, Fixity, Span};
+use crate::expression::{Expr, CustomOperation, OperatorFn};
+use crate::error::CalcError;
+use crate::lexer::is_reserved_symbol;
+
+// What an operator builds once its operands are parsed
+#[derive(Clone)]
+pub enum OperatorAction {
+    Binary(Operator),
+    Unary(UnaryOperator),
+    Postfix(PostfixOperator),
+    Custom(OperatorFn),
+}
+
+// One row of the table: how an operator is written and how tightly it binds
+#[derive(Clone)]
+pub struct OperatorDefinition {
+    pub symbol: String,
+    pub fixity: Fixity,
+    pub precedence: u8,
+    pub associativity: Associativity,
+    pub action: OperatorAction,
+}
+
+impl OperatorDefinition {
+    // Whether `token` stands for this operator
+    fn matches(&self, token: &Token) -> bool {
+        match (&self.action, token) {
+            (OperatorAction::Binary(op), Token::Operator(other)) => op == other,
+            (OperatorAction::Unary(op), Token::UnaryOperator(other)) => op == other,
+            // `-` and `+` reach the parser as binary operators and turn prefix by position
+            (OperatorAction::Unary(op), Token::Operator(other)) => other.as_unary().as_ref() == Some(op),
+            (OperatorAction::Postfix(op), Token::PostfixOperator(other)) => op == other,
+            (OperatorAction::Custom(_), Token::CustomOperator(symbol)) => *symbol == self.symbol,
+            _ => false,
+        }
+    }
+    
+    // Builds the node for this operator; `operands` has one entry per operand, left to right
+    pub fn build(&self, mut operands: Vec<Expr>, span: Span) -> Expr {
+        let span = Some(span);
+        match &self.action {
+            OperatorAction::Binary(op) => {
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                Expr::binary(left, right, op.clone()).with_span(span)
+            },
+            OperatorAction::Unary(op) => {
+                Expr::unary(op.clone(), operands.pop().unwrap()).with_span(span)
+            },
+            OperatorAction::Postfix(op) => {
+                Expr::postfix(op.clone(), operands.pop().unwrap()).with_span(span)
+            },
+            OperatorAction::Custom(function) => Expr::Custom(Box::new(CustomOperation {
+                symbol: self.symbol.clone(),
+                fixity: self.fixity,
+                precedence: self.precedence,
+                associativity: self.associativity,
+                function: Arc::clone(function),
+                operands,
+                span,
+            })),
+        }
+    }
+}
+
+// Every operator the parser knows, built-in or registered by an embedder.
+// Precedences share one scale with `Operator::precedence()`: `||` is 1, `+`
+// is 5, `*` is 6, `^` is 9 and postfix operators are 10
+#[derive(Clone)]
+pub struct OperatorTable {
+    definitions: Vec<OperatorDefinition>,
+}
+
+impl OperatorTable {
+    // The built-in operators, as described by token.rs
+    pub fn new() -> Self {
+        let mut definitions = Vec::new();
+        
+        for op in Operator::all() {
+            definitions.push(OperatorDefinition {
+                symbol: op.symbol().to_string(),
+                fixity: Fixity::Infix,
+                precedence: op.precedence(),
+                associativity: op.associativity(),
+                action: OperatorAction::Binary(op.clone()),
+            });
+        }
+        
+        for op in UnaryOperator::all() {
+            definitions.push(OperatorDefinition {
+                symbol: op.symbol().to_string(),
+                fixity: Fixity::Prefix,
+                precedence: op.precedence(),
+                associativity: Associativity::Right,
+                action: OperatorAction::Unary(op.clone()),
+            });
+        }
+        
+        for op in PostfixOperator::all() {
+            definitions.push(OperatorDefinition {
+                symbol: op.symbol().to_string(),
+                fixity: Fixity::Postfix,
+                precedence: op.precedence(),
+                associativity: Associativity::Left,
+                action: OperatorAction::Postfix(op.clone()),
+            });
+        }
+        
+        Self { definitions }
+    }
+    
+    // Adds `left symbol right`, e.g. `∥` for resistors in parallel
+    pub fn register_infix<F>(&mut self, symbol: &str, precedence: u8, associativity: Associativity, function: F) -> Result<(), CalcError>
+    where
+        F: Fn(f64, f64) -> Result<f64, CalcError> + Send + Sync + 'static,
+    {
+        let function: OperatorFn = Arc::new(move |values: &[f64]| function(values[0], values[1]));
+        self.register(symbol, Fixity::Infix, precedence, associativity, function)
+    }
+    
+    // Adds `symbol operand`; its operand extends over operators that bind tighter
+    pub fn register_prefix<F>(&mut self, symbol: &str, precedence: u8, function: F) -> Result<(), CalcError>
+    where
+        F: Fn(f64) -> Result<f64, CalcError> + Send + Sync + 'static,
+    {
+        let function: OperatorFn = Arc::new(move |values: &[f64]| function(values[0]));
+        self.register(symbol, Fixity::Prefix, precedence, Associativity::Right, function)
+    }
+    
+    // Adds `operand symbol`, e.g. `‰` for per mille
+    pub fn register_postfix<F>(&mut self, symbol: &str, precedence: u8, function: F) -> Result<(), CalcError>
+    where
+        F: Fn(f64) -> Result<f64, CalcError> + Send + Sync + 'static,
+    {
+        let function: OperatorFn = Arc::new(move |values: &[f64]| function(values[0]));
+        self.register(symbol, Fixity::Postfix, precedence, Associativity::Left, function)
+    }
+    
+    // Registering a symbol again with the same fixity replaces the earlier definition
+    fn register(&mut self, symbol: &str, fixity: Fixity, precedence: u8, associativity: Associativity, function: OperatorFn) -> Result<(), CalcError> {
+        // Names, numbers, brackets and built-in symbols already mean something to the lexer
+        let clashes = symbol.is_empty()
+            || is_reserved_symbol(symbol)
+            || symbol.chars().any(|c| c.is_alphanumeric() || c.is_whitespace() || "_.(),".contains(c));
+        if clashes {
+            return Err(CalcError::InvalidName { name: symbol.to_string() });
+        }
+        
+        // u8::MAX is reserved for leaves, which never need parentheses
+        if precedence == 0 || precedence == u8::MAX {
+            return Err(CalcError::usage("operator precedence from 1 to 254"));
+        }
+        
+        self.definitions.retain(|definition| {
+            !(definition.fixity == fixity && matches!(definition.action, OperatorAction::Custom(_)) && definition.symbol == symbol)
+        });
+        self.definitions.push(OperatorDefinition {
+            symbol: symbol.to_string(),
+            fixity,
+            precedence,
+            associativity,
+            action: OperatorAction::Custom(function),
+        });
+        
+        Ok(())
+    }
+    
+    // The operator `token` stands for in the given position, if any
+    pub fn lookup(&self, fixity: Fixity, token: &Token) -> Option<&OperatorDefinition> {
+        self.definitions
+            .iter()
+            .find(|definition| definition.fixity == fixity && definition.matches(token))
+    }
+    
+    // The infix or postfix operator at `pos`, which follows an operand. A symbol
+    // registered both ways, like `%`, is infix only when an operand follows it
+    pub fn after_operand(&self, tokens: &[SpannedToken], pos: usize) -> Option<&OperatorDefinition> {
+        let token = &tokens.get(pos)?.token;
+        let postfix = self.lookup(Fixity::Postfix, token);
+        let infix = self.lookup(Fixity::Infix, token);
+        match (postfix, infix) {
+            (Some(_), Some(infix)) if tokens.get(pos + 1).is_some_and(|next| self.starts_operand(&next.token)) => Some(infix),
+            (Some(postfix), _) => Some(postfix),
+            (None, infix) => infix,
+        }
+    }
+    
+    // Whether `token` can begin an operand
+    pub fn starts_operand(&self, token: &Token) -> bool {
+        match token {
+            Token::Number(_) | Token::Variable(_) | Token::Function(_) | Token::OpenParen => true,
+            other => self.lookup(Fixity::Prefix, other).is_some(),
+        }
+    }
+    
+    // Symbols the lexer has to recognise on top of its built-in ones
+    pub fn custom_symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = self.definitions
+            .iter()
+            .filter(|definition| matches!(definition.action, OperatorAction::Custom(_)))
+            .map(|definition| definition.symbol.clone())
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::parser::ExpressionParser;
+    
+    fn evaluate(operators: &OperatorTable, input: &str) -> Result<f64, CalcError> {
+        ExpressionParser::new().with_operators(operators.clone()).parse(input)?.evaluate(&HashMap::new())
+    }
+    
+    #[test]
+    fn built_in_operators_follow_token_precedence() {
+        let table = OperatorTable::new();
+        let power = table.lookup(Fixity::Infix, &Token::operator(Operator::Power)).unwrap();
+        assert_eq!((power.precedence, power.associativity), (9, Associativity::Right));
+        let negate = table.lookup(Fixity::Prefix, &Token::operator(Operator::Subtract)).unwrap();
+        assert_eq!(negate.symbol, "-");
+        assert!(table.lookup(Fixity::Postfix, &Token::operator(Operator::Add)).is_none());
+    }
+    
+    #[test]
+    fn registered_operators_parse_and_evaluate() {
+        let mut table = OperatorTable::new();
+        table.register_infix("∥", 6, Associativity::Left, |a, b| Ok(a * b / (a + b))).unwrap();
+        table.register_postfix("‰", 10, |value| Ok(value / 1000.0)).unwrap();
+        table.register_prefix("√", 8, |value| Ok(value.sqrt())).unwrap();
+        
+        assert_eq!(evaluate(&table, "6 ∥ 3"), Ok(2.0));
+        assert_eq!(evaluate(&table, "1 + 6∥3"), Ok(3.0));
+        assert_eq!(evaluate(&table, "5‰ * 2"), Ok(0.01));
+        assert_eq!(evaluate(&table, "√16 + 1"), Ok(5.0));
+        assert_eq!(table.custom_symbols(), vec!["‰", "√", "∥"]); // Sorted
+    }
+    
+    #[test]
+    fn registering_again_replaces_the_definition() {
+        let mut table = OperatorTable::new();
+        table.register_infix("∥", 6, Associativity::Left, |a, b| Ok(a + b)).unwrap();
+        table.register_infix("∥", 6, Associativity::Left, |a, b| Ok(a - b)).unwrap();
+        assert_eq!(evaluate(&table, "5 ∥ 3"), Ok(2.0));
+    }
+    
+    #[test]
+    fn clashing_symbols_and_precedences_are_rejected() {
+        let mut table = OperatorTable::new();
+        for symbol in ["+", "**", "x", "(", "", "a b"] {
+            assert!(matches!(table.register_postfix(symbol, 10, Ok), Err(CalcError::InvalidName { .. })), "{}", symbol);
+        }
+        assert!(table.register_prefix("√", 0, Ok).is_err());
+        assert!(table.register_prefix("√", u8::MAX, Ok).is_err());
+    }
+}
@@ -1,149 +1,612 @@
-// parser.rs - Parser for expressions
+// parser.rs - Pratt parser for expressions, driven by an operator table
 
-use crate::token::{Token, Operator, Function};
-use crate::expression::{Expression, NumberExpression, VariableExpression, BinaryOperation, FunctionCall};
+use crate::token::{Token, SpannedToken, Span, Associativity, Fixity, NumberFormat};
+use crate::expression::{Expr, UserFunction, function_call};
+use crate::error::CalcError;
+use crate::lexer::Lexer;
+use crate::config::{get_global_config, DecimalMark};
+use crate::constants::check_assignable;
+use crate::operators::OperatorTable;
+
+// Call arguments, the index just past their `)`, and the error if it is missing
+type ParsedArguments = (Vec<Expr>, usize, Option<CalcError>);
 
 #[derive(Clone)]
-pub struct ExpressionParser;
+pub struct ExpressionParser {
+    implicit_multiplication: bool,
+    decimal_mark: DecimalMark,
+    operators: OperatorTable,
+}
 
 impl ExpressionParser {
     pub fn new() -> Self {
-        Self
+        Self {
+            implicit_multiplication: get_global_config().implicit_multiplication,
+            decimal_mark: get_global_config().decimal_mark,
+            operators: OperatorTable::new(),
+        }
+    }
+    
+    // Strict parsing rejects `2x`; the default comes from `CalculatorConfig`
+    pub fn with_implicit_multiplication(mut self, enabled: bool) -> Self {
+        self.implicit_multiplication = enabled;
+        self
+    }
+    
+    // Whether `3,5` means 3.5; the default comes from `CalculatorConfig`
+    pub fn with_decimal_mark(mut self, mark: DecimalMark) -> Self {
+        self.decimal_mark = mark;
+        self
+    }
+    
+    // Replaces the built-in operator table, e.g. with one extended by `register_infix`
+    pub fn with_operators(mut self, operators: OperatorTable) -> Self {
+        self.operators = operators;
+        self
     }
     
-    pub fn parse(&self, expression: &str) -> Result<Box<dyn Expression>, String> {
+    pub fn operators(&self) -> &OperatorTable {
+        &self.operators
+    }
+    
+    // Lets embedders register operators on a parser that is already in use
+    pub fn operators_mut(&mut self) -> &mut OperatorTable {
+        &mut self.operators
+    }
+    
+    pub fn parse(&self, expression: &str) -> Result<Expr, CalcError> {
         // Tokenize
-        let tokens = self.tokenize(expression)?;
+        let tokens = self.lexer(expression).tokenize()?;
         
-        // Parse using Shunting-yard algorithm
-        self.build_expression_tree(tokens)
+        // Parse using the operator table
+        self.parse_tokens(tokens)
     }
     
-    fn tokenize(&self, input: &str) -> Result<Vec<Token>, String> {
-        // This is a simplistic tokenizer for demonstration
-        // A real tokenizer would be more sophisticated
+    // Keeps going after a syntax error instead of stopping at the first one.
+    // Returns every error, in source order, and a partial tree in which an
+    // `Expr::Error` stands in for each part that could not be parsed
+    pub fn parse_recovering(&self, expression: &str) -> (Expr, Vec<CalcError>) {
+        let (tokens, errors) = self.lexer(expression).tokenize_recovering();
+        let mut diagnostics = Diagnostics { recovering: true, errors };
         
-        // Separate tokens by spaces for simplicity
-        let tokens: Result<Vec<Token>, String> = input
-            .split_whitespace()
-            .map(Token::from_str)
-            .collect();
+        let expr = match self.parse_all(&tokens, &mut diagnostics) {
+            Ok(expr) => expr,
+            Err(error) => {
+                diagnostics.errors.push(error.clone());
+                Expr::error(error, Vec::new())
+            },
+        };
         
-        tokens
+        // One error per position: a stray `)` after a missing operand is reported once
+        let mut errors = diagnostics.errors;
+        errors.sort_by_key(|error| error.span().map(|span| span.start));
+        errors.dedup_by_key(|error| error.span().map(|span| span.start));
+        (expr, errors)
     }
     
-    fn build_expression_tree(&self, tokens: Vec<Token>) -> Result<Box<dyn Expression>, String> {
-        // Implementation of the shunting yard algorithm
-        let mut output_queue: Vec<Box<dyn Expression>> = Vec::new();
-        let mut operator_stack: Vec<Token> = Vec::new();
+    // The notation shared by every number literal in `input` that isn't plain
+    // decimal, e.g. hexadecimal for `0xFF + 0x10`; None if they disagree or there are none
+    pub fn notation(&self, input: &str) -> Option<NumberFormat> {
+        let tokens = self.lexer(input).tokenize().ok()?;
+        let mut formats = tokens.iter().filter_map(|spanned| match &spanned.token {
+            Token::Number(number) if number.format != NumberFormat::Decimal => Some(number.format),
+            _ => None,
+        });
         
-        for token in tokens {
-            match token {
-                Token::Number(num) => {
-                    output_queue.push(Box::new(NumberExpression::new(num.value)));
-                },
-                Token::Variable(name) => {
-                    output_queue.push(Box::new(VariableExpression::new(name)));
-                },
-                Token::Operator(op) => {
-                    // While there's an operator on the stack with greater precedence
-                    while let Some(Token::Operator(top_op)) = operator_stack.last() {
-                        if top_op.precedence() >= op.precedence() {
-                            operator_stack.pop();
-                            
-                            if output_queue.len() < 2 {
-                                return Err("Invalid expression: not enough operands".to_string());
-                            }
-                            
-                            let right = output_queue.pop().unwrap();
-                            let left = output_queue.pop().unwrap();
-                            
-                            output_queue.push(Box::new(BinaryOperation::new(left, right, top_op.clone())));
-                        } else {
-                            break;
-                        }
+        let first = formats.next()?;
+        match first {
+            // Every SI prefix counts as the same notation
+            NumberFormat::SiPrefix(_) => formats.all(|format| matches!(format, NumberFormat::SiPrefix(_))),
+            _ => formats.all(|format| format == first),
+        }
+        .then_some(first)
+    }
+    
+    fn lexer<'a>(&self, input: &'a str) -> Lexer<'a> {
+        // Scan character by character, so `2+3*x` and `sin(x)` need no spaces
+        Lexer::new(input)
+            .with_implicit_multiplication(self.implicit_multiplication)
+            .with_decimal_mark(self.decimal_mark)
+            .with_custom_symbols(self.operators.custom_symbols())
+    }
+    
+    // Parses a definition such as `f(x, y) = x^2 + y`; error spans are relative to `input`
+    pub fn parse_function_definition(&self, input: &str) -> Result<UserFunction, CalcError> {
+        let (head, body) = match split_assignment(input) {
+            Some(sides) => sides,
+            None => return Err(CalcError::usage("name(parameters) = expression")),
+        };
+        
+        // The head is a name and a parenthesised, comma-separated parameter list
+        let tokens = self.lexer(head).tokenize()?;
+        let mut tokens = tokens.iter();
+        let end = Span::new(head.len(), head.len() + 1);
+        
+        let name = match tokens.next() {
+            Some(SpannedToken { token: Token::Variable(name), .. }) => name.clone(),
+            Some(SpannedToken { token: Token::Function(function), .. }) => {
+                return Err(CalcError::InvalidName { name: function.name().to_string() });
+            },
+            Some(other) => return Err(CalcError::syntax(other.span, "a function name")),
+            None => return Err(CalcError::syntax(end, "a function name")),
+        };
+        
+        match tokens.next() {
+            Some(SpannedToken { token: Token::OpenParen, .. }) => {},
+            other => return Err(CalcError::syntax(other.map_or(end, |t| t.span), "'(' after the function name")),
+        }
+        
+        let mut parameters: Vec<String> = Vec::new();
+        loop {
+            match tokens.next() {
+                Some(SpannedToken { token: Token::CloseParen, .. }) if parameters.is_empty() => break,
+                Some(SpannedToken { token: Token::Variable(parameter), span }) => {
+                    if parameters.contains(parameter) {
+                        return Err(CalcError::syntax(*span, "a parameter name that is not already used"));
                     }
-                    
-                    operator_stack.push(Token::Operator(op));
+                    check_assignable(parameter)?;
+                    parameters.push(parameter.clone());
                 },
-                Token::Function(func) => {
-                    operator_stack.push(Token::Function(func));
-                },
-                Token::OpenParen => {
-                    operator_stack.push(token);
-                },
-                Token::CloseParen => {
-                    // Pop until matching open paren
-                    let mut found_open_paren = false;
-                    
-                    while let Some(top) = operator_stack.pop() {
-                        match top {
-                            Token::OpenParen => {
-                                found_open_paren = true;
-                                
-                                // If there's a function on the stack, apply it
-                                if let Some(Token::Function(func)) = operator_stack.last() {
-                                    operator_stack.pop();
-                                    
-                                    if output_queue.is_empty() {
-                                        return Err("Invalid function call: missing argument".to_string());
-                                    }
-                                    
-                                    let arg = output_queue.pop().unwrap();
-                                    output_queue.push(Box::new(FunctionCall::new(func.clone(), arg)));
-                                }
-                                
-                                break;
-                            },
-                            Token::Operator(op) => {
-                                if output_queue.len() < 2 {
-                                    return Err("Invalid expression: not enough operands".to_string());
-                                }
-                                
-                                let right = output_queue.pop().unwrap();
-                                let left = output_queue.pop().unwrap();
-                                
-                                output_queue.push(Box::new(BinaryOperation::new(left, right, op)));
-                            },
-                            _ => {
-                                return Err(format!("Unexpected token on operator stack: {:?}", top));
-                            }
-                        }
-                    }
-                    
-                    if !found_open_paren {
-                        return Err("Mismatched parentheses".to_string());
-                    }
-                }
+                other => return Err(CalcError::syntax(other.map_or(end, |t| t.span), "a parameter name")),
+            }
+            
+            match tokens.next() {
+                Some(SpannedToken { token: Token::Comma, .. }) => {},
+                Some(SpannedToken { token: Token::CloseParen, .. }) => break,
+                other => return Err(CalcError::syntax(other.map_or(end, |t| t.span), "',' or ')' after the parameter")),
             }
         }
         
-        // Process remaining operators
-        while let Some(token) = operator_stack.pop() {
-            match token {
-                Token::Operator(op) => {
-                    if output_queue.len() < 2 {
-                        return Err("Invalid expression: not enough operands".to_string());
+        if let Some(extra) = tokens.next() {
+            return Err(CalcError::syntax(extra.span, "'=' after the parameter list"));
+        }
+        
+        let offset = head.len() + 1;
+        let body = self.parse(body).map_err(|e| e.shifted(offset))?;
+        
+        Ok(UserFunction::new(name, parameters, body))
+    }
+    
+    // Builds the tree from already scanned tokens, keeping their spans
+    pub fn parse_tokens(&self, tokens: Vec<SpannedToken>) -> Result<Expr, CalcError> {
+        let mut diagnostics = Diagnostics { recovering: false, errors: Vec::new() };
+        self.parse_all(&tokens, &mut diagnostics)
+    }
+    
+    fn parse_all(&self, tokens: &[SpannedToken], diagnostics: &mut Diagnostics) -> Result<Expr, CalcError> {
+        if tokens.is_empty() {
+            return diagnostics.report(CalcError::EmptyExpression, Vec::new());
+        }
+        
+        let (mut expr, _, mut pos) = self.parse_expression(tokens, 0, 0, diagnostics)?;
+        
+        while let Some(extra) = tokens.get(pos) {
+            let error = match extra.token {
+                Token::CloseParen => CalcError::syntax(extra.span, "a matching '(' for this ')'"),
+                Token::Comma => CalcError::syntax(extra.span, "an operator; ',' only separates function arguments"),
+                // Point at the first value that has nothing to combine with
+                _ => CalcError::syntax(extra.span, "an operator before this value"),
+            };
+            (expr, pos) = self.skip_unexpected(tokens, pos, expr, error, diagnostics)?;
+        }
+        
+        Ok(expr)
+    }
+    
+    // Pratt parsing: an operand, then every infix or postfix operator that binds at
+    // least as tightly as `min_precedence`. Returns the node, its full extent in the
+    // source (parentheses included) and the index of the first unused token
+    fn parse_expression(&self, tokens: &[SpannedToken], pos: usize, min_precedence: u8, diagnostics: &mut Diagnostics) -> Result<(Expr, Span, usize), CalcError> {
+        let (mut left, mut span, mut pos) = self.parse_operand(tokens, pos, diagnostics)?;
+        
+        while let Some(spanned) = tokens.get(pos) {
+            let definition = match self.operators.after_operand(tokens, pos) {
+                Some(definition) => definition,
+                None => break,
+            };
+            
+            if definition.precedence < min_precedence {
+                break;
+            }
+            
+            span = span.merge(spanned.span);
+            
+            if definition.fixity == Fixity::Postfix {
+                left = definition.build(vec![left], span);
+                pos += 1;
+                continue;
+            }
+            
+            if pos + 1 >= tokens.len() {
+                let error = CalcError::syntax(spanned.span, "an operand on each side of this operator");
+                return Ok((diagnostics.report(error, vec![left])?, span, pos + 1));
+            }
+            
+            // Left-associative: the right operand stops at operators of the same
+            // precedence. Right-associative: it takes the rest of the chain.
+            let right_min = match definition.associativity {
+                Associativity::Left => definition.precedence + 1,
+                Associativity::Right => definition.precedence,
+            };
+            
+            let (right, right_span, next) = self.parse_expression(tokens, pos + 1, right_min, diagnostics)?;
+            span = span.merge(right_span);
+            left = definition.build(vec![left, right], span);
+            pos = next;
+        }
+        
+        Ok((left, span, pos))
+    }
+    
+    // A single operand: a value, a call, a parenthesised group or a prefix operator
+    // applied to whatever binds tighter than it
+    fn parse_operand(&self, tokens: &[SpannedToken], pos: usize, diagnostics: &mut Diagnostics) -> Result<(Expr, Span, usize), CalcError> {
+        let spanned = match tokens.get(pos) {
+            Some(spanned) => spanned,
+            None => {
+                let end = end_of_input(tokens);
+                return Ok((diagnostics.report(CalcError::syntax(end, "an operand"), Vec::new())?, end, pos));
+            },
+        };
+        
+        let span = spanned.span;
+        // Where the operand is missing, the error node takes no room before the next token
+        let missing = Span::new(span.start, span.start);
+        
+        match &spanned.token {
+            Token::Number(num) => Ok((Expr::Number { value: num.value, format: num.format, span: Some(span) }, span, pos + 1)),
+            Token::Variable(name) => {
+                // A name directly followed by `(` calls a user-defined function;
+                // user functions may be redefined, so their arity is only checked when called
+                if let Some(SpannedToken { token: Token::OpenParen, .. }) = tokens.get(pos + 1) {
+                    let (args, next, unclosed) = self.parse_arguments(tokens, pos + 1, diagnostics)?;
+                    let call_span = span.merge(tokens[next - 1].span);
+                    let call = match unclosed {
+                        Some(error) => diagnostics.report(error, args)?,
+                        None => Expr::user_call(name.clone(), args).with_span(Some(call_span)),
+                    };
+                    return Ok((call, call_span, next));
+                }
+                
+                Ok((Expr::Variable { name: name.clone(), span: Some(span) }, span, pos + 1))
+            },
+            Token::Function(func) => {
+                if tokens.get(pos + 1).map(|t| &t.token) != Some(&Token::OpenParen) {
+                    let error = CalcError::syntax(span, "a parenthesised argument after the function name");
+                    if !diagnostics.recovering {
+                        return Err(error);
                     }
                     
-                    let right = output_queue.pop().unwrap();
-                    let left = output_queue.pop().unwrap();
-                    
-                    output_queue.push(Box::new(BinaryOperation::new(left, right, op)));
-                },
-                Token::OpenParen | Token::CloseParen => {
-                    return Err("Mismatched parentheses".to_string());
-                },
-                _ => {
-                    return Err(format!("Unexpected token on operator stack: {:?}", token));
+                    // Read `sin x` as an attempt at `sin(x)`, so `x` isn't reported as well
+                    let (parts, end, next) = match tokens.get(pos + 1) {
+                        Some(next) if self.operators.starts_operand(&next.token) => {
+                            let (argument, argument_span, next) = self.parse_operand(tokens, pos + 1, diagnostics)?;
+                            (vec![argument], argument_span, next)
+                        },
+                        _ => (Vec::new(), span, pos + 1),
+                    };
+                    return Ok((diagnostics.report(error, parts)?, span.merge(end), next));
+                }
+                
+                let (args, next, unclosed) = self.parse_arguments(tokens, pos + 1, diagnostics)?;
+                let call_span = span.merge(tokens[next - 1].span);
+                if let Some(error) = unclosed {
+                    return Ok((diagnostics.report(error, args)?, call_span, next));
+                }
+                
+                match func.check_arity(args.len(), Some(call_span)) {
+                    Ok(()) => Ok((function_call(func.clone(), args, Some(call_span))?, call_span, next)),
+                    // The arguments are kept, so validation still looks inside them
+                    Err(error) => Ok((diagnostics.report(error, args)?, call_span, next)),
+                }
+            },
+            Token::OpenParen => {
+                let closing = CalcError::syntax(span, "a closing ')' for this '('");
+                if pos + 1 >= tokens.len() {
+                    return Ok((diagnostics.report(closing, Vec::new())?, span, pos + 1));
+                }
+                
+                let (mut expr, _, mut close) = self.parse_expression(tokens, pos + 1, 0, diagnostics)?;
+                
+                while let Some(extra) = tokens.get(close).filter(|t| t.token != Token::CloseParen) {
+                    let error = match extra.token {
+                        Token::Comma => CalcError::syntax(extra.span, "an operator; ',' only separates function arguments"),
+                        _ => CalcError::syntax(extra.span, "an operator before this value"),
+                    };
+                    (expr, close) = self.skip_unexpected(tokens, close, expr, error, diagnostics)?;
+                }
+                
+                match tokens.get(close) {
+                    Some(close_paren) => Ok((expr, span.merge(close_paren.span), close + 1)),
+                    None => Ok((diagnostics.report(closing, vec![expr])?, span.merge(tokens[close - 1].span), close)),
                 }
+            },
+            Token::CloseParen => {
+                let error = CalcError::syntax(span, "an operand before this ')'");
+                Ok((diagnostics.report(error, Vec::new())?, missing, pos))
+            },
+            Token::Comma => {
+                let error = CalcError::syntax(span, "an argument before this ','");
+                Ok((diagnostics.report(error, Vec::new())?, missing, pos))
+            },
+            Token::UserFunction(name) => {
+                let error = CalcError::internal(format!("Unexpected user function token: {}", name));
+                Ok((diagnostics.report(error, Vec::new())?, span, pos + 1))
+            },
+            token => {
+                // Prefix position: only operators with a prefix form make sense here.
+                // Anything else is left for the caller, which reads it as infix
+                let definition = match self.operators.lookup(Fixity::Prefix, token) {
+                    Some(definition) => definition,
+                    None => {
+                        let error = CalcError::syntax(span, "an operand before this operator");
+                        return Ok((diagnostics.report(error, Vec::new())?, missing, pos));
+                    },
+                };
+                
+                if pos + 1 >= tokens.len() {
+                    let error = CalcError::syntax(span, "an operand after this operator");
+                    return Ok((diagnostics.report(error, Vec::new())?, span, pos + 1));
+                }
+                
+                // The operand only extends over operators that bind tighter, so -2^2 is -(2^2)
+                let (operand, operand_span, next) = self.parse_expression(tokens, pos + 1, definition.precedence + 1, diagnostics)?;
+                let span = span.merge(operand_span);
+                Ok((definition.build(vec![operand], span), span, next))
+            },
+        }
+    }
+    
+    // Comma-separated arguments after the `(` at `open`; returns them with the index
+    // just past the `)`. `f()` has no arguments. A missing `)` is returned as the
+    // third value, so the caller can wrap the arguments in an error node
+    fn parse_arguments(&self, tokens: &[SpannedToken], open: usize, diagnostics: &mut Diagnostics) -> Result<ParsedArguments, CalcError> {
+        let unclosed = CalcError::syntax(tokens[open].span, "a closing ')' for this '('");
+        let mut args = Vec::new();
+        let mut pos = open + 1;
+        
+        if let Some(SpannedToken { token: Token::CloseParen, .. }) = tokens.get(pos) {
+            return Ok((args, pos + 1, None));
+        }
+        
+        loop {
+            if pos >= tokens.len() {
+                return Ok((args, pos, Some(unclosed)));
+            }
+            
+            let (mut arg, _, mut next) = self.parse_expression(tokens, pos, 0, diagnostics)?;
+            
+            while let Some(extra) = tokens.get(next).filter(|t| !matches!(t.token, Token::Comma | Token::CloseParen)) {
+                let error = CalcError::syntax(extra.span, "a ',' or operator before this value");
+                (arg, next) = self.skip_unexpected(tokens, next, arg, error, diagnostics)?;
+            }
+            args.push(arg);
+            
+            match tokens.get(next) {
+                Some(SpannedToken { token: Token::Comma, .. }) => pos = next + 1,
+                Some(_) => return Ok((args, next + 1, None)),
+                None => return Ok((args, next, Some(unclosed))),
             }
         }
+    }
+    
+    // Reports the out-of-place token at `pos`. When recovering, parsing resumes
+    // there, and `left` and whatever follows become the parts of an error node
+    fn skip_unexpected(&self, tokens: &[SpannedToken], pos: usize, left: Expr, error: CalcError, diagnostics: &mut Diagnostics) -> Result<(Expr, usize), CalcError> {
+        if !diagnostics.recovering {
+            return Err(error);
+        }
+        
+        // A stray `)` or `,` cannot start anything, so step over it
+        let start = match tokens[pos].token {
+            Token::CloseParen | Token::Comma => pos + 1,
+            _ => pos,
+        };
+        
+        let mut parts = vec![left];
+        let mut next = start;
+        if tokens.get(start).is_some_and(|t| !matches!(t.token, Token::CloseParen | Token::Comma)) {
+            let (rest, _, after) = self.parse_expression(tokens, start, 0, diagnostics)?;
+            parts.push(rest);
+            next = after;
+        }
+        
+        // Always make progress, whatever the token was
+        Ok((diagnostics.report(error, parts)?, next.max(pos + 1)))
+    }
+}
+
+// Syntax errors found so far. Unless recovering, the first one ends the parse
+struct Diagnostics {
+    recovering: bool,
+    errors: Vec<CalcError>,
+}
+
+impl Diagnostics {
+    // Records `error` and returns the node that takes the place of the broken
+    // input, or fails straight away when not recovering
+    fn report(&mut self, error: CalcError, parts: Vec<Expr>) -> Result<Expr, CalcError> {
+        if !self.recovering {
+            return Err(error);
+        }
         
-        if output_queue.len() != 1 {
-            return Err("Invalid expression: too many values".to_string());
+        self.errors.push(error.clone());
+        Ok(Expr::error(error, parts))
+    }
+}
+
+// Splits `name = expression` at the assignment `=`, skipping the `=` that is
+// part of a comparison such as `==`, `<=`, `>=` or `!=`
+pub fn split_assignment(input: &str) -> Option<(&str, &str)> {
+    for (i, c) in input.char_indices() {
+        if c != '=' {
+            continue;
         }
         
-        Ok(output_queue.pop().unwrap())
+        let before = input[..i].chars().last();
+        let after = input[i + 1..].chars().next();
+        if !matches!(before, Some('=' | '<' | '>' | '!')) && after != Some('=') {
+            return Some((&input[..i], &input[i + 1..]));
+        }
+    }
+    
+    None
+}
+
+// Zero-width position just past the last token
+fn end_of_input(tokens: &[SpannedToken]) -> Span {
+    let end = tokens.last().map_or(0, |t| t.span.end);
+    Span::new(end, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    
+    fn evaluate(input: &str) -> Result<f64, CalcError> {
+        let variables = HashMap::from([("x".to_string(), 2.0)]);
+        ExpressionParser::new().parse(input)?.evaluate(&variables)
+    }
+    
+    // The tree as printed, which shows how the input was grouped
+    fn tree(input: &str) -> String {
+        ExpressionParser::new().parse(input).unwrap().to_string()
+    }
+    
+    #[test]
+    fn unary_signs() {
+        assert_eq!(evaluate("-3"), Ok(-3.0));
+        assert_eq!(evaluate("--3"), Ok(3.0));
+        assert_eq!(evaluate("+x"), Ok(2.0));
+        assert_eq!(evaluate("2 * -x"), Ok(-4.0));
+        assert_eq!(evaluate("1 - -1"), Ok(2.0));
+        assert_eq!(evaluate("-(1 + 2)"), Ok(-3.0));
+    }
+    
+    #[test]
+    fn negation_binds_looser_than_power() {
+        assert_eq!(evaluate("-2^2"), Ok(-4.0));
+        assert_eq!(evaluate("2^-1"), Ok(0.5));
+        assert_eq!(tree("-x^2"), "-x ^ 2");
+    }
+    
+    #[test]
+    fn power_groups_to_the_right() {
+        assert_eq!(evaluate("2^3^2"), Ok(512.0));
+        assert_eq!(evaluate("2**3**2"), Ok(512.0));
+        assert_eq!(tree("(2^3)^2"), "(2 ^ 3) ^ 2");
+        assert_eq!(tree("2^(3^2)"), "2 ^ 3 ^ 2");
+    }
+    
+    #[test]
+    fn other_operators_group_to_the_left() {
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("64 / 4 / 2"), Ok(8.0));
+        assert_eq!(tree("a - (b - c)"), "a - (b - c)");
+        assert_eq!(tree("(a - b) - c"), "a - b - c");
+    }
+    
+    #[test]
+    fn precedence_ladder() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("2 * 3 ^ 2"), Ok(18.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(tree("a + b * c ^ d"), "a + b * c ^ d");
+    }
+    
+    #[test]
+    fn functions_take_several_arguments() {
+        assert_eq!(evaluate("max(1, x, 3)"), Ok(3.0));
+        assert_eq!(evaluate("min(4)"), Ok(4.0));
+        assert_eq!(evaluate("hypot(3, 4)"), Ok(5.0));
+        assert_eq!(evaluate("clamp(-x, 0, 1)"), Ok(0.0));
+        assert_eq!(evaluate("log(8, 2)"), Ok(3.0));
+        assert_eq!(tree("atan2(x,1+2)"), "atan2(x, 1 + 2)");
+    }
+    
+    #[test]
+    fn argument_counts_are_checked() {
+        assert!(matches!(evaluate("sin(1, 2)"), Err(CalcError::WrongArgumentCount { found: 2, .. })));
+        assert!(matches!(evaluate("max()"), Err(CalcError::WrongArgumentCount { found: 0, .. })));
+        assert!(matches!(evaluate("max(1,)"), Err(CalcError::Syntax { .. })));
+        assert!(matches!(evaluate("1, 2"), Err(CalcError::Syntax { .. })));
+    }
+    
+    #[test]
+    fn function_definitions() {
+        let parser = ExpressionParser::new();
+        let function = parser.parse_function_definition("f(x, y) = x^2 + y").unwrap();
+        assert_eq!(function.signature(), "f(x, y)");
+        assert_eq!(function.body.to_string(), "x ^ 2 + y");
+        assert_eq!(parser.parse_function_definition("g() = 1").unwrap().parameters.len(), 0);
+        
+        assert!(matches!(parser.parse_function_definition("sin(x) = x"), Err(CalcError::InvalidName { .. })));
+        let error = parser.parse_function_definition("f(x, x) = x").err().unwrap();
+        assert_eq!(error.span(), Some(Span::new(5, 6)));
+        let error = parser.parse_function_definition("f(x) = x +").err().unwrap();
+        assert_eq!(error.span().map(|span| span.start), Some(9)); // The `+`, in the full line
+    }
+    
+    #[test]
+    fn comparisons_and_logic() {
+        assert_eq!(evaluate("1 < 2"), Ok(1.0));
+        assert_eq!(evaluate("x >= 3"), Ok(0.0));
+        assert_eq!(evaluate("1 + 1 == x"), Ok(1.0));
+        assert_eq!(evaluate("!0 && 1 || 0"), Ok(1.0));
+        assert_eq!(tree("a || b && c"), "a || b && c");
+        assert_eq!(tree("a < b == c"), "a < b == c");
+    }
+    
+    #[test]
+    fn only_the_chosen_branch_is_evaluated() {
+        assert_eq!(evaluate("if(x > 1, x, 1/0)"), Ok(2.0));
+        assert_eq!(evaluate("0 && 1/0"), Ok(0.0));
+        assert_eq!(evaluate("1 || 1/0"), Ok(1.0));
+        assert!(matches!(evaluate("if(x < 1, x, 1/0)"), Err(CalcError::DivisionByZero { .. })));
+    }
+    
+    #[test]
+    fn remainder_floor_division_factorial_and_percent() {
+        assert_eq!(evaluate("-7 % 3"), Ok(2.0));
+        assert_eq!(evaluate("10 % -3"), Ok(-2.0));
+        assert_eq!(evaluate("-7 // 2"), Ok(-4.0));
+        assert_eq!(evaluate("2^3!"), Ok(64.0));
+        assert_eq!(evaluate("-x!"), Ok(-2.0));
+        assert_eq!(evaluate("200 + 10% - 5"), Ok(215.0));
+        assert_eq!(evaluate("50%"), Ok(0.5));
+        assert_eq!(evaluate("10 * 50%"), Ok(5.0));
+        assert!(matches!(evaluate("7 % 0"), Err(CalcError::DivisionByZero { .. })));
+        assert!(matches!(evaluate("(-1)!"), Err(CalcError::DomainError { .. })));
+    }
+    
+    #[test]
+    fn recovering_reports_every_syntax_error() {
+        let (expr, errors) = ExpressionParser::new().parse_recovering("1 + * 2 + (3 $ 4");
+        let starts: Vec<Option<usize>> = errors.iter().map(|error| error.span().map(|span| span.start)).collect();
+        // The `*`, the unclosed `(`, the `$` and the `4` with no operator before it
+        assert_eq!(starts, vec![Some(4), Some(10), Some(13), Some(15)]);
+        assert!(expr.to_string().contains('?'));
+    }
+    
+    #[test]
+    fn recovering_finds_nothing_in_valid_input() {
+        let (expr, errors) = ExpressionParser::new().parse_recovering("max(1, 2) * x");
+        assert!(errors.is_empty());
+        assert_eq!(expr.to_string(), tree("max(1, 2) * x"));
+    }
+    
+    #[test]
+    fn the_first_recovered_error_is_the_one_parse_reports() {
+        for input in ["1 +", "(1", "1)", "sin 5", "f(1, 2", "1 2 + * 3"] {
+            let (_, errors) = ExpressionParser::new().parse_recovering(input);
+            assert_eq!(errors.first(), ExpressionParser::new().parse(input).err().as_ref(), "{}", input);
+        }
+    }
+    
+    #[test]
+    fn a_sign_needs_an_operand() {
+        assert!(matches!(evaluate("1 + -"), Err(CalcError::Syntax { .. })));
+        assert!(matches!(evaluate("-"), Err(CalcError::Syntax { .. })));
     }
 }
@@ -0,0 +1,258 @@
+// rational.rs - Exact fractions of arbitrary-size integers, falling back to
+// f64 for functions with irrational results
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+use crate::bigint::{BigInt, gcd};
+use crate::numeric::{Numeric, Value, decimal_parts};
+use crate::constants::{self, Constant};
+
+// numerator / denominator in lowest terms, with the denominator positive
+#[derive(Debug, Clone)]
+pub struct Rational {
+    numerator: BigInt,
+    denominator: BigInt,
+    approximate: bool, // Worked out in f64 somewhere along the way, as sqrt(2) is
+}
+
+impl Rational {
+    // Panics when `denominator` is zero, which callers check for first
+    pub fn new(numerator: BigInt, denominator: BigInt) -> Self {
+        assert!(!denominator.is_zero(), "Rational with a zero denominator");
+        let divisor = gcd(&numerator, &denominator);
+        let divisor = if denominator.is_negative() { -&divisor } else { divisor };
+        Self {
+            numerator: numerator.div_rem(&divisor).0,
+            denominator: denominator.div_rem(&divisor).0,
+            approximate: false,
+        }
+    }
+    
+    fn integer(value: BigInt, approximate: bool) -> Self {
+        Self { numerator: value, denominator: BigInt::from(1), approximate }
+    }
+    
+    // Marks the value approximate if `approximate` is set; an approximate value stays so
+    fn marked(mut self, approximate: bool) -> Self {
+        self.approximate |= approximate;
+        self
+    }
+    
+    pub fn is_integer(&self) -> bool {
+        self.denominator == BigInt::from(1)
+    }
+}
+
+impl Numeric for Rational {
+    type Settings = ();
+    
+    const NAME: &'static str = "rational";
+    
+    fn from_f64(value: f64, _: ()) -> Option<Self> {
+        let (mantissa, exponent) = decimal_parts(value)?;
+        let scale = BigInt::pow10(exponent.unsigned_abs() as usize);
+        Some(if exponent < 0 {
+            Rational::new(mantissa, scale)
+        } else {
+            Rational::new(&mantissa * &scale, BigInt::from(1))
+        })
+    }
+    
+    fn from_i64(value: i64, _: ()) -> Self {
+        Rational::integer(BigInt::from(value), false)
+    }
+    
+    // Twenty digits of the quotient, then a final 1 standing for any
+    // remainder, so the standard library's parser rounds it correctly
+    fn to_f64(&self) -> f64 {
+        let shift = 20 + self.denominator.digits() as i64 - self.numerator.digits() as i64;
+        let scale = BigInt::pow10(shift.unsigned_abs() as usize);
+        let (numerator, denominator) = if shift >= 0 {
+            (&self.numerator * &scale, self.denominator.clone())
+        } else {
+            (self.numerator.clone(), &self.denominator * &scale)
+        };
+        
+        let (quotient, remainder) = numerator.div_rem(&denominator);
+        let sticky = if remainder.is_zero() { "0" } else { "1" };
+        format!("{}{}e{}", quotient, sticky, -shift - 1).parse().unwrap_or(f64::NAN)
+    }
+    
+    fn settings(&self) {}
+    
+    fn into_value(self) -> Value {
+        Value::Rational(self)
+    }
+    
+    fn from_value(value: &Value, _: ()) -> Option<Self> {
+        match value {
+            Value::Rational(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+    
+    fn is_zero(&self) -> bool {
+        self.numerator.is_zero()
+    }
+    
+    fn floor(&self) -> Self {
+        let (quotient, remainder) = self.numerator.div_rem(&self.denominator);
+        let quotient = if remainder.is_negative() { &quotient - &BigInt::from(1) } else { quotient };
+        Rational::integer(quotient, self.approximate)
+    }
+    
+    fn trunc(&self) -> Self {
+        let (quotient, _) = self.numerator.div_rem(&self.denominator);
+        Rational::integer(quotient, self.approximate)
+    }
+    
+    // A denominator of 1 adds nothing
+    fn digits(&self) -> usize {
+        self.numerator.digits() + self.denominator.digits() - 1
+    }
+    
+    // Exact when both parts are perfect squares, as in sqrt(9/4)
+    fn sqrt(&self) -> Option<Self> {
+        let exact_root = |n: &BigInt| n.sqrt().filter(|root| &(root * root) == n);
+        match (exact_root(&self.numerator), exact_root(&self.denominator)) {
+            (Some(numerator), Some(denominator)) => Some(Rational { numerator, denominator, approximate: self.approximate }),
+            _ => self.through_f64(f64::sqrt),
+        }
+    }
+    
+    // Every function without an exact result lands here, so its result is marked
+    fn through_f64(&self, f: impl Fn(f64) -> f64) -> Option<Self> {
+        Rational::from_f64(f(self.to_f64()), ()).map(|result| result.marked(true))
+    }
+    
+    // The mathematical constants are irrational; the physical ones have exact decimal values
+    fn constant(constant: &Constant, _: ()) -> Self {
+        let irrational = constants::MATH_CONSTANTS.iter().any(|math| math.name == constant.name);
+        Rational::from_f64(constant.value, ()).expect("constants are finite").marked(irrational)
+    }
+}
+
+impl PartialEq for Rational {
+    // By value, so an approximate 1/2 equals an exact one
+    fn eq(&self, other: &Self) -> bool {
+        self.numerator == other.numerator && self.denominator == other.denominator
+    }
+}
+
+impl PartialOrd for Rational {
+    // Denominators are positive, so cross-multiplying keeps the order
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some((&self.numerator * &other.denominator).cmp(&(&other.numerator * &self.denominator)))
+    }
+}
+
+impl Add for Rational {
+    type Output = Rational;
+    
+    fn add(self, other: Rational) -> Rational {
+        let numerator = &(&self.numerator * &other.denominator) + &(&other.numerator * &self.denominator);
+        Rational::new(numerator, &self.denominator * &other.denominator).marked(self.approximate || other.approximate)
+    }
+}
+
+impl Sub for Rational {
+    type Output = Rational;
+    
+    fn sub(self, other: Rational) -> Rational {
+        self + -other
+    }
+}
+
+impl Mul for Rational {
+    type Output = Rational;
+    
+    fn mul(self, other: Rational) -> Rational {
+        Rational::new(&self.numerator * &other.numerator, &self.denominator * &other.denominator)
+            .marked(self.approximate || other.approximate)
+    }
+}
+
+impl Div for Rational {
+    type Output = Rational;
+    
+    // Panics when dividing by zero, which callers check for first
+    fn div(self, other: Rational) -> Rational {
+        Rational::new(&self.numerator * &other.denominator, &self.denominator * &other.numerator)
+            .marked(self.approximate || other.approximate)
+    }
+}
+
+impl Neg for Rational {
+    type Output = Rational;
+    
+    fn neg(self) -> Rational {
+        Rational { numerator: -&self.numerator, ..self }
+    }
+}
+
+impl fmt::Display for Rational {
+    // An approximate value is shown as the f64 it came from, marked with ≈
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.approximate {
+            write!(f, "≈{}", self.to_f64())
+        } else if self.is_integer() {
+            write!(f, "{}", self.numerator)
+        } else {
+            write!(f, "{}/{}", self.numerator, self.denominator)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::evaluate_in;
+    
+    #[test]
+    fn rational_results_are_exact() {
+        let cases = [
+            ("0.1 + 0.2", "3/10"),
+            ("1/3 + 1/6", "1/2"),
+            ("(1/3) * 3 == 1", "1"),
+            ("2^-3 - 1/8", "0"),
+            ("2^100", "1267650600228229401496703205376"),
+            ("25!", "15511210043330985984000000"),
+            ("1e20 + 1 - 1e20", "1"),
+            ("-7 % 3", "2"),
+            ("-7 // 2", "-4"),
+            ("sqrt(9/4)", "3/2"),
+            ("max(1/3, 0.3)", "1/3"),
+            ("200 + 10%", "220"),
+        ];
+        
+        for (input, expected) in cases {
+            assert_eq!(evaluate_in::<Rational>(input, ()).unwrap().to_string(), expected, "{}", input);
+        }
+        assert_eq!(evaluate_in::<Rational>("1/3", ()).unwrap().to_f64(), 1.0 / 3.0);
+        
+        // The same errors as in f64
+        for input in ["1 / 0", "sqrt(-1)", "log(0, 10)", "tan(pi / 2)", "(-8)^(1/3)", "x"] {
+            assert!(evaluate_in::<Rational>(input, ()).is_err(), "{} should fail", input);
+        }
+    }
+    
+    #[test]
+    fn irrational_results_are_marked_approximate() {
+        let cases = [
+            ("sqrt(2)", "≈1.4142135623730951"),
+            ("sqrt(2)^2", "≈2"), // Squared exactly, then rounded to an f64
+            ("pi", "≈3.141592653589793"),
+            ("sin(1) + 1/2", "≈1.3414709848078965"),
+            ("0.5!", "≈0.8862269254527586"),
+            ("sqrt(2) == sqrt(2)", "1"), // Comparisons are exact either way
+            ("sqrt(9/4)", "3/2"),
+            ("c * 2", "599584916"),
+            ("2^-2 + 0.5!^0", "5/4"),
+        ];
+        
+        for (input, expected) in cases {
+            assert_eq!(evaluate_in::<Rational>(input, ()).unwrap().to_string(), expected, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+// simplifier.rs - Algebraic simplification: identities, like terms and canonical ordering
+
+use std::collections::HashMap;
+use crate::expression::{Expr, Callee, branch};
+use crate::token::{Operator, UnaryOperator, PostfixOperator, Function};
+use crate::visitor::{ExpressionVisitor, Visitable};
+
+// Passes before giving up on reaching a fixed point
+const MAX_PASSES: usize = 16;
+
+// Rewrites an expression into a canonical, usually shorter form:
+//  - identities such as x * 1, x + 0, x * 0, x ^ 1, x - x and x / x
+//  - sums and products flattened, with their operands in a fixed order
+//  - like terms and powers collected, so 2x + 3x is 5x and x * x is x ^ 2
+// Wherever the original is defined the value is unchanged, up to rounding
+// and the sign of zero. Some rewrites define it at more points: x / x is 1
+// even at x = 0, where dividing failed, and 0 * x is 0 even for x = NaN.
+// Anything that divides by a literal zero is left as written, so it still fails
+pub struct SimplificationVisitor;
+
+impl SimplificationVisitor {
+    pub fn new() -> Self {
+        Self
+    }
+    
+    // Repeats passes until one changes nothing
+    pub fn simplify(&mut self, expr: &Expr) -> Expr {
+        let mut current = expr.accept(self);
+        for _ in 1..MAX_PASSES {
+            let next = current.accept(self);
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+        current
+    }
+}
+
+impl ExpressionVisitor for SimplificationVisitor {
+    type Output = Expr;
+    
+    fn visit(&mut self, expr: &Expr) -> Expr {
+        // Percentages depend on where they sit, so they go before anything moves
+        if let Some(rewritten) = without_percent(expr) {
+            return rewritten.accept(self);
+        }
+        
+        let expr = expr.map_children(|child| child.accept(self));
+        match &expr {
+            Expr::Binary { operator: Operator::Add | Operator::Subtract, .. } |
+            Expr::Unary { operator: UnaryOperator::Negate | UnaryOperator::Plus, .. } => {
+                Sum::of(&expr).into_expr()
+            },
+            Expr::Binary { operator: Operator::Multiply | Operator::ImplicitMultiply | Operator::Divide | Operator::Power, .. } => {
+                match Product::of(&expr) {
+                    Some(product) => product.to_expr(),
+                    None => expr, // Dividing by zero has to keep failing
+                }
+            },
+            Expr::Call { function: Callee::Builtin(Function::If), arguments, .. } if arguments.len() == 3 => {
+                match arguments[0].as_number() {
+                    Some(condition) => branch(arguments, condition).clone(),
+                    None if arguments[1] == arguments[2] => arguments[1].clone(),
+                    None => expr,
+                }
+            },
+            Expr::Number { .. } | Expr::Variable { .. } | Expr::Error { .. } => expr,
+            // Anything else over numbers alone is worked out now, unless it fails
+            _ if expr.children().all(|child| child.as_number().is_some()) => {
+                match expr.evaluate(&HashMap::new()) {
+                    Ok(value) => Expr::number(value),
+                    Err(_) => expr,
+                }
+            },
+            _ => expr,
+        }
+    }
+}
+
+// a + b% means a + a * (b / 100), worked out as evaluation does it, and any
+// other b% is b / 100
+fn without_percent(expr: &Expr) -> Option<Expr> {
+    match expr {
+        Expr::Binary { operator: operator @ (Operator::Add | Operator::Subtract), left, right, .. } => match &**right {
+            Expr::Postfix { operator: PostfixOperator::Percent, operand, .. } => {
+                let share = Expr::binary((**operand).clone(), Expr::number(100.0), Operator::Divide);
+                let change = Expr::binary((**left).clone(), share, Operator::Multiply);
+                Some(Expr::binary((**left).clone(), change, operator.clone()))
+            },
+            _ => None,
+        },
+        Expr::Postfix { operator: PostfixOperator::Percent, operand, .. } => {
+            Some(Expr::binary((**operand).clone(), Expr::number(100.0), Operator::Divide))
+        },
+        _ => None,
+    }
+}
+
+// A sum as a constant plus terms, each a coefficient times a monomial.
+// Terms with equal monomials are like terms and share one entry
+struct Sum {
+    constant: f64,
+    terms: Vec<(Expr, f64, Product)>, // Monomial, coefficient and its factors
+    failing: Vec<(Expr, f64)>, // Terms that divide by zero, with their signs; never collected
+}
+
+impl Sum {
+    fn of(expr: &Expr) -> Sum {
+        let mut sum = Sum { constant: 0.0, terms: Vec::new(), failing: Vec::new() };
+        sum.collect(expr, 1.0);
+        sum
+    }
+    
+    fn collect(&mut self, expr: &Expr, sign: f64) {
+        match expr {
+            Expr::Number { value, .. } => self.constant += sign * value,
+            Expr::Binary { operator: Operator::Add, left, right, .. } => {
+                self.collect(left, sign);
+                self.collect(right, sign);
+            },
+            Expr::Binary { operator: Operator::Subtract, left, right, .. } => {
+                self.collect(left, sign);
+                self.collect(right, -sign);
+            },
+            Expr::Unary { operator: UnaryOperator::Negate, operand, .. } => self.collect(operand, -sign),
+            Expr::Unary { operator: UnaryOperator::Plus, operand, .. } => self.collect(operand, sign),
+            _ => {
+                // A term that divides by zero stays whole so it still fails,
+                // rather than cancelling against a copy of itself
+                let Some(product) = Product::of(expr) else {
+                    self.failing.push((expr.clone(), sign));
+                    return;
+                };
+                if product.factors.is_empty() {
+                    self.constant += sign * product.coefficient;
+                    return;
+                }
+                let coefficient = sign * product.coefficient;
+                let unit = Product { coefficient: 1.0, factors: product.factors };
+                let monomial = unit.to_expr();
+                match self.terms.iter_mut().find(|(existing, _, _)| *existing == monomial) {
+                    Some((_, total, _)) => *total += coefficient,
+                    None => self.terms.push((monomial, coefficient, unit)),
+                }
+            },
+        }
+    }
+    
+    // Highest degree first, then alphabetical, with the constant last
+    fn into_expr(mut self) -> Expr {
+        self.terms.retain(|(_, coefficient, _)| *coefficient != 0.0);
+        self.terms.sort_by(|(a, _, a_unit), (b, _, b_unit)| {
+            b_unit.degree().total_cmp(&a_unit.degree()).then_with(|| a.to_string().cmp(&b.to_string()))
+        });
+        
+        let mut result: Option<Expr> = None;
+        for (_, coefficient, unit) in self.terms {
+            result = Some(match result {
+                None => Product { coefficient, ..unit }.to_expr(),
+                Some(sum) if coefficient < 0.0 => {
+                    let term = Product { coefficient: -coefficient, ..unit }.to_expr();
+                    Expr::binary(sum, term, Operator::Subtract)
+                },
+                Some(sum) => Expr::binary(sum, Product { coefficient, ..unit }.to_expr(), Operator::Add),
+            });
+        }
+        for (term, sign) in self.failing {
+            result = Some(match result {
+                None if sign < 0.0 => Expr::unary(UnaryOperator::Negate, term),
+                None => term,
+                Some(sum) if sign < 0.0 => Expr::binary(sum, term, Operator::Subtract),
+                Some(sum) => Expr::binary(sum, term, Operator::Add),
+            });
+        }
+        match result {
+            None => Expr::number(self.constant + 0.0), // + 0.0 turns -0 into 0
+            Some(sum) if self.constant == 0.0 => sum,
+            Some(sum) if self.constant < 0.0 => Expr::binary(sum, Expr::number(-self.constant), Operator::Subtract),
+            Some(sum) => Expr::binary(sum, Expr::number(self.constant), Operator::Add),
+        }
+    }
+}
+
+// A product as a coefficient times distinct bases, each raised to an exponent
+struct Product {
+    coefficient: f64,
+    factors: Vec<(Expr, Expr, bool)>, // Base, exponent and whether it was ever divided by
+}
+
+impl Product {
+    // None when the product divides by zero anywhere inside
+    fn of(expr: &Expr) -> Option<Product> {
+        if divides_by_zero(expr) {
+            return None;
+        }
+        let mut product = Product { coefficient: 1.0, factors: Vec::new() };
+        product.collect(expr, false).then_some(product)
+    }
+    
+    // Multiplies by `expr`, or divides by it when `inverse`
+    fn collect(&mut self, expr: &Expr, inverse: bool) -> bool {
+        match expr {
+            Expr::Number { value, .. } if inverse && *value == 0.0 => false,
+            Expr::Number { value, .. } if inverse => {
+                self.coefficient /= value;
+                true
+            },
+            Expr::Number { value, .. } => {
+                self.coefficient *= value;
+                true
+            },
+            Expr::Binary { operator: Operator::Multiply | Operator::ImplicitMultiply, left, right, .. } => {
+                self.collect(left, inverse) && self.collect(right, inverse)
+            },
+            Expr::Binary { operator: Operator::Divide, left, right, .. } => {
+                self.collect(left, inverse) && self.collect(right, !inverse)
+            },
+            Expr::Binary { operator: Operator::Power, left, right, .. } => {
+                self.raise(left, (**right).clone(), inverse);
+                true
+            },
+            Expr::Unary { operator: UnaryOperator::Negate, operand, .. } => {
+                self.coefficient = -self.coefficient;
+                self.collect(operand, inverse)
+            },
+            _ => {
+                self.raise(expr, Expr::number(1.0), inverse);
+                true
+            },
+        }
+    }
+    
+    // Multiplies by base ^ exponent, adding exponents of a base already there
+    fn raise(&mut self, base: &Expr, exponent: Expr, inverse: bool) {
+        let exponent = if inverse { negated(exponent) } else { exponent };
+        if let (Some(base), Some(exponent)) = (base.as_number(), exponent.as_number()) {
+            self.coefficient *= base.powf(exponent);
+            return;
+        }
+        match self.factors.iter_mut().find(|(existing, _, _)| existing == base) {
+            Some((_, total, divided)) => {
+                *total = added(total.clone(), exponent);
+                *divided |= inverse;
+            },
+            None => self.factors.push((base.clone(), exponent, inverse)),
+        }
+    }
+    
+    // Sum of the numeric exponents, for ordering terms
+    fn degree(&self) -> f64 {
+        self.factors.iter().map(|(_, exponent, _)| exponent.as_number().unwrap_or(1.0)).sum()
+    }
+    
+    // Bases that were divided by go back under a division, as does a
+    // coefficient like 1/2. Others keep a negative exponent: x ^ -2 is
+    // infinite at x = 0, where 1 / x ^ 2 would fail
+    fn to_expr(&self) -> Expr {
+        if self.coefficient == 0.0 {
+            return Expr::number(0.0);
+        }
+        let mut factors: Vec<&(Expr, Expr, bool)> = self.factors.iter()
+            .filter(|(_, exponent, _)| exponent.as_number() != Some(0.0))
+            .collect();
+        factors.sort_by_cached_key(|(base, _, _)| base.to_string());
+        
+        let mut coefficient = self.coefficient;
+        let mut numerator = Vec::new();
+        let mut denominator = Vec::new();
+        let divisor = 1.0 / coefficient.abs();
+        if coefficient.abs() < 1.0 && divisor.fract() == 0.0 && !factors.is_empty() {
+            denominator.push(Expr::number(divisor));
+            coefficient = coefficient.signum();
+        }
+        for (base, exponent, divided) in factors {
+            match exponent.as_number() {
+                Some(power) if power < 0.0 && *divided => denominator.push(raised(base, Expr::number(-power))),
+                _ => numerator.push(raised(base, exponent.clone())),
+            }
+        }
+        
+        let numerator = if numerator.is_empty() {
+            Expr::number(coefficient)
+        } else if coefficient == 1.0 {
+            product(numerator)
+        } else if coefficient == -1.0 {
+            Expr::unary(UnaryOperator::Negate, product(numerator))
+        } else {
+            numerator.insert(0, Expr::number(coefficient));
+            product(numerator)
+        };
+        if denominator.is_empty() {
+            numerator
+        } else {
+            Expr::binary(numerator, product(denominator), Operator::Divide)
+        }
+    }
+}
+
+// Whether any division in `expr`, however deeply nested, is by a literal zero
+fn divides_by_zero(expr: &Expr) -> bool {
+    match expr {
+        Expr::Binary { operator: Operator::Divide | Operator::Modulo | Operator::FloorDivide, right, .. } if right.as_number() == Some(0.0) => true,
+        _ => expr.children().any(divides_by_zero),
+    }
+}
+
+fn raised(base: &Expr, exponent: Expr) -> Expr {
+    if exponent.as_number() == Some(1.0) {
+        base.clone()
+    } else {
+        Expr::binary(base.clone(), exponent, Operator::Power)
+    }
+}
+
+fn product(factors: Vec<Expr>) -> Expr {
+    factors.into_iter()
+        .reduce(|left, right| Expr::binary(left, right, Operator::Multiply))
+        .unwrap_or_else(|| Expr::number(1.0))
+}
+
+fn added(a: Expr, b: Expr) -> Expr {
+    Sum::of(&Expr::binary(a, b, Operator::Add)).into_expr()
+}
+
+fn negated(expr: Expr) -> Expr {
+    Sum::of(&Expr::unary(UnaryOperator::Negate, expr)).into_expr()
+}
+
+// Simplifies an expression to a fixed point
+pub fn simplify(expr: &Expr) -> Expr {
+    SimplificationVisitor::new().simplify(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ExpressionParser;
+    use crate::test_support::{check_expressions, random_expressions, variables};
+    
+    // Whether every part of `expr` evaluates to a finite number
+    fn defined(expr: &Expr, variables: &HashMap<String, f64>) -> bool {
+        expr.evaluate(variables).is_ok_and(f64::is_finite) && expr.children().all(|child| defined(child, variables))
+    }
+    
+    #[test]
+    fn simplifying_keeps_the_value() {
+        let variables = variables();
+        check_expressions(random_expressions(), |expr| {
+            // Where some part fails or overflows, the simplified form may do anything
+            if !defined(expr, &variables) {
+                return None;
+            }
+            
+            let simplified = simplify(expr);
+            let original = expr.evaluate(&variables).unwrap();
+            let value = simplified.evaluate(&variables);
+            let close = value.as_ref().is_ok_and(|value| (original - value).abs() <= 1e-9 * original.abs().max(1.0));
+            (!close).then(|| format!("simplified to `{}`: {}, then {:?}", simplified, original, value))
+        });
+    }
+    
+    #[test]
+    fn simplified_forms_are_canonical() {
+        let parser = ExpressionParser::new();
+        let cases = [
+            ("2x + 3x", "5 * x"),
+            ("x * x", "x ^ 2"),
+            ("x - x", "0"),
+            ("x / x", "1"),
+            ("x * 1 + 0", "x"),
+            ("x ^ 1 * 0 + y", "y"),
+            ("y + x + 1 + x^2", "x ^ 2 + x + y + 1"),
+            ("x * y * x / y^3", "x ^ 2 / y ^ 2"),
+            ("x / 2", "x / 2"),
+            ("x / 2 - 3 * x", "-2.5 * x"),
+            ("x ^ -2", "x ^ (-2)"),
+            ("2 * (x + 1) + 3 * (1 + x)", "5 * (x + 1)"),
+            ("200 + 10%", "220"),
+            ("if(1 > 2, x, y)", "y"),
+            ("x / 0", "x / 0"),
+            ("x / (y / 0)", "x / (y / 0)"),
+            ("x / 0 - x / 0", "x / 0 - x / 0"),
+            ("(x % 0) ^ 0 + 2 - 1", "(x % 0) ^ 0 + 1"),
+            ("2x - x / 0 + x", "3 * x - x / 0"),
+        ];
+        
+        for (input, expected) in cases {
+            assert_eq!(simplify(&parser.parse(input).unwrap()).to_string(), expected, "simplifying {}", input);
+        }
+    }
+}
@@ -1,10 +1,13 @@
 // state.rs - State pattern implementation for calculator modes
 
+use std::rc::Rc;
 use std::collections::HashMap;
-use crate::expression::{Expression, NumberExpression};
-use crate::parser::ExpressionParser;
+use crate::parser::{ExpressionParser, split_assignment};
 use crate::config::AngleMode;
 use crate::adapter::ScientificOperations;
+use crate::error::CalcError;
+use crate::constants::check_assignable;
+use crate::formatter::NumberFormatter;
 
 // Enum to represent different number bases for programmer mode
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -26,37 +29,43 @@ impl NumberBase {
         }
     }
     
-    pub fn parse(&self, text: &str) -> Result<f64, String> {
+    pub fn parse(&self, text: &str) -> Result<f64, CalcError> {
+        // Prefixes are mandatory outside decimal, so a missing one is also invalid
+        let invalid = |base: &str| CalcError::InvalidNumber {
+            text: text.to_string(),
+            base: base.to_string(),
+        };
+        
         match self {
             NumberBase::Binary => {
                 if let Some(value) = text.strip_prefix("0b") {
                     i64::from_str_radix(value, 2)
                         .map(|v| v as f64)
-                        .map_err(|_| format!("Invalid binary number: {}", text))
+                        .map_err(|_| invalid("binary"))
                 } else {
-                    Err(format!("Binary numbers must start with 0b: {}", text))
+                    Err(invalid("binary"))
                 }
             },
             NumberBase::Octal => {
                 if let Some(value) = text.strip_prefix("0o") {
                     i64::from_str_radix(value, 8)
                         .map(|v| v as f64)
-                        .map_err(|_| format!("Invalid octal number: {}", text))
+                        .map_err(|_| invalid("octal"))
                 } else {
-                    Err(format!("Octal numbers must start with 0o: {}", text))
+                    Err(invalid("octal"))
                 }
             },
             NumberBase::Decimal => {
                 text.parse::<f64>()
-                    .map_err(|_| format!("Invalid decimal number: {}", text))
+                    .map_err(|_| invalid("decimal"))
             },
             NumberBase::Hexadecimal => {
                 if let Some(value) = text.strip_prefix("0x") {
                     i64::from_str_radix(value, 16)
                         .map(|v| v as f64)
-                        .map_err(|_| format!("Invalid hexadecimal number: {}", text))
+                        .map_err(|_| invalid("hexadecimal"))
                 } else {
-                    Err(format!("Hexadecimal numbers must start with 0x: {}", text))
+                    Err(invalid("hexadecimal"))
                 }
             },
         }
@@ -65,29 +74,32 @@ impl NumberBase {
 
 // Calculator context for state pattern
 pub struct StateCalculator {
-    pub state: Box<dyn CalculatorState>,
+    pub state: Rc<dyn CalculatorState>, // Shared, so a state can run while it switches to another
     pub variables: HashMap<String, f64>,
     pub parser: ExpressionParser,
     pub results_history: Vec<(String, f64)>,
+    pub formatter: NumberFormatter,
 }
 
 impl StateCalculator {
     pub fn new() -> Self {
         Self {
-            state: Box::new(StandardMode::new()),
+            state: Rc::new(StandardMode::new()),
             variables: HashMap::new(),
             parser: ExpressionParser::new(),
             results_history: Vec::new(),
+            formatter: NumberFormatter::new(),
         }
     }
     
     pub fn change_state(&mut self, new_state: Box<dyn CalculatorState>) {
         println!("Switching to {} mode", new_state.name());
-        self.state = new_state;
+        self.state = new_state.into();
     }
     
-    pub fn process_input(&mut self, input: &str) -> Result<Option<f64>, String> {
-        self.state.handle_input(input, self)
+    pub fn process_input(&mut self, input: &str) -> Result<Option<f64>, CalcError> {
+        let state = Rc::clone(&self.state);
+        state.handle_input(input, self)
     }
     
     pub fn store_result(&mut self, input: String, result: f64) {
@@ -98,14 +110,24 @@ impl StateCalculator {
     pub fn display_prompt(&self) -> String {
         self.state.display_prompt()
     }
+    
+    // A result as the current mode shows it
+    pub fn format_result(&self, result: f64) -> String {
+        self.state.format_result(result, &self.formatter)
+    }
 }
 
 // State interface
 pub trait CalculatorState {
     fn name(&self) -> &str;
-    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, String>;
+    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, CalcError>;
     fn available_operations(&self) -> Vec<&'static str>;
     fn display_prompt(&self) -> String;
+    
+    // Modes share the calculator's formatter unless they show numbers their own way
+    fn format_result(&self, result: f64, formatter: &NumberFormatter) -> String {
+        formatter.format(result)
+    }
 }
 
 // Standard calculator mode
@@ -129,7 +151,7 @@ impl CalculatorState for StandardMode {
         "Standard"
     }
     
-    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, String> {
+    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, CalcError> {
         // Handle basic arithmetic expressions
         if input.starts_with("mode") {
             // Change mode based on command
@@ -143,15 +165,16 @@ impl CalculatorState for StandardMode {
                     calculator.change_state(Box::new(ProgrammerMode::new()));
                     Ok(None)
                 },
-                _ => Err(format!("Unknown mode: {}", mode)),
+                _ => Err(CalcError::UnknownMode { name: mode.to_string() }),
             }
         } else if input.starts_with("help") {
             println!("Available operations: {}", self.available_operations().join(", "));
             println!("Type 'mode scientific' or 'mode programmer' to switch modes");
             Ok(None)
-        } else if let Some((var_name, expression)) = input.split_once('=') {
+        } else if let Some((var_name, expression)) = split_assignment(input) {
             let var_name = var_name.trim();
             let expression = expression.trim();
+            check_assignable(var_name)?;
             
             // Evaluate the expression and set the variable
             let expr = calculator.parser.parse(expression)?;
@@ -169,7 +192,7 @@ impl CalculatorState for StandardMode {
     }
     
     fn available_operations(&self) -> Vec<&'static str> {
-        vec!["+", "-", "*", "/", "^"]
+        vec!["+", "-", "*", "/", "%", "//", "^", "<", "<=", "==", "!=", ">=", ">", "&&", "||", "!", "if"]
     }
     
     fn display_prompt(&self) -> String {
@@ -200,7 +223,7 @@ impl CalculatorState for ScientificMode {
         "Scientific"
     }
     
-    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, String> {
+    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, CalcError> {
         // Handle scientific expressions and functions
         if input.starts_with("mode") {
             // Handle mode change
@@ -214,7 +237,7 @@ impl CalculatorState for ScientificMode {
                     calculator.change_state(Box::new(ProgrammerMode::new()));
                     Ok(None)
                 },
-                _ => Err(format!("Unknown mode: {}", mode)),
+                _ => Err(CalcError::UnknownMode { name: mode.to_string() }),
             }
         } else if input == "angle deg" {
             // Change angle mode to degrees
@@ -258,11 +281,11 @@ impl CalculatorState for ScientificMode {
             
             calculator.store_result(input.to_string(), result);
             Ok(Some(result))
-        } else if input.starts_with("log") {
+        } else if input.starts_with("log ") {
             // Handle logarithm with base
             let parts: Vec<&str> = input.splitn(3, ' ').collect();
             if parts.len() != 3 {
-                return Err("Usage: log <base> <value>".to_string());
+                return Err(CalcError::usage("log <base> <value>"));
             }
             
             let base_expr = calculator.parser.parse(parts[1])?;
@@ -274,10 +297,11 @@ impl CalculatorState for ScientificMode {
             let result = self.sci_ops.log(value, base)?;
             calculator.store_result(input.to_string(), result);
             Ok(Some(result))
-        } else if let Some((var_name, expression)) = input.split_once('=') {
+        } else if let Some((var_name, expression)) = split_assignment(input) {
             // Handle variable assignment
             let var_name = var_name.trim();
             let expression = expression.trim();
+            check_assignable(var_name)?;
             
             let expr = calculator.parser.parse(expression)?;
             let result = expr.evaluate(&calculator.variables)?;
@@ -294,7 +318,7 @@ impl CalculatorState for ScientificMode {
     }
     
     fn available_operations(&self) -> Vec<&'static str> {
-        vec!["+", "-", "*", "/", "^", "sin", "cos", "tan", "log", "ln", "sqrt"]
+        vec!["+", "-", "*", "/", "%", "//", "^", "!", "sin", "cos", "tan", "log", "ln", "sqrt", "atan2", "hypot", "min", "max", "clamp", "if"]
     }
     
     fn display_prompt(&self) -> String {
@@ -330,7 +354,7 @@ impl CalculatorState for ProgrammerMode {
         "Programmer"
     }
     
-    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, String> {
+    fn handle_input(&self, input: &str, calculator: &mut StateCalculator) -> Result<Option<f64>, CalcError> {
         // Handle programmer mode commands and operations
         if input.starts_with("mode") {
             // Handle mode change
@@ -344,7 +368,7 @@ impl CalculatorState for ProgrammerMode {
                     calculator.change_state(Box::new(ScientificMode::new()));
                     Ok(None)
                 },
-                _ => Err(format!("Unknown mode: {}", mode)),
+                _ => Err(CalcError::UnknownMode { name: mode.to_string() }),
             }
         } else if input.starts_with("base") {
             // Change number base
@@ -354,7 +378,7 @@ impl CalculatorState for ProgrammerMode {
                 "oct" | "octal" => NumberBase::Octal,
                 "dec" | "decimal" => NumberBase::Decimal,
                 "hex" | "hexadecimal" => NumberBase::Hexadecimal,
-                _ => return Err(format!("Unknown base: {}", base)),
+                _ => return Err(CalcError::UnknownBase { name: base.to_string() }),
             };
             
             calculator.change_state(Box::new(ProgrammerMode { base: new_base }));
@@ -370,7 +394,7 @@ impl CalculatorState for ProgrammerMode {
             // Handle bitwise binary operations
             let parts: Vec<&str> = input.splitn(3, ' ').collect();
             if parts.len() != 3 {
-                return Err(format!("Usage: {} <operand1> <operand2>", parts[0]));
+                return Err(CalcError::usage(format!("{} <operand1> <operand2>", parts[0])));
             }
             
             let op = parts[0];
@@ -388,7 +412,7 @@ impl CalculatorState for ProgrammerMode {
             };
             
             calculator.store_result(input.to_string(), result);
-            println!("{} = {}", input, self.base.format(result));
+            println!("{} = {}", input, self.format_result(result, &calculator.formatter));
             Ok(Some(result))
         } else if input.starts_with("NOT ") {
             // Handle bitwise NOT operation
@@ -398,13 +422,13 @@ impl CalculatorState for ProgrammerMode {
             
             let result = self.execute_bitwise_op(value, 0.0, |a, _| !a);
             calculator.store_result(input.to_string(), result);
-            println!("{} = {}", input, self.base.format(result));
+            println!("{} = {}", input, self.format_result(result, &calculator.formatter));
             Ok(Some(result))
         } else if input.starts_with("SHL ") || input.starts_with("SHR ") {
             // Handle shift operations
             let parts: Vec<&str> = input.splitn(3, ' ').collect();
             if parts.len() != 3 {
-                return Err(format!("Usage: {} <value> <bits>", parts[0]));
+                return Err(CalcError::usage(format!("{} <value> <bits>", parts[0])));
             }
             
             let op = parts[0];
@@ -421,15 +445,16 @@ impl CalculatorState for ProgrammerMode {
             };
             
             calculator.store_result(input.to_string(), result);
-            println!("{} = {}", input, self.base.format(result));
+            println!("{} = {}", input, self.format_result(result, &calculator.formatter));
             Ok(Some(result))
-        } else if let Some((var_name, expression)) = input.split_once('=') {
+        } else if let Some((var_name, expression)) = split_assignment(input) {
             // Handle variable assignment
             let var_name = var_name.trim();
             let expression = expression.trim();
+            check_assignable(var_name)?;
             
             // Try to parse according to current base
-            let result = if !expression.contains(|c: char| c.is_ascii_letter() || "+-*/()^".contains(c)) {
+            let result = if !expression.contains(|c: char| c.is_ascii_alphabetic() || "+-*/()^".contains(c)) {
                 match self.base.parse(expression) {
                     Ok(value) => value,
                     Err(_) => {
@@ -446,14 +471,13 @@ impl CalculatorState for ProgrammerMode {
             
             calculator.variables.insert(var_name.to_string(), result);
             calculator.store_result(format!("{} = {}", var_name, expression), result);
-            println!("{} = {}", var_name, self.base.format(result));
+            println!("{} = {}", var_name, self.format_result(result, &calculator.formatter));
             Ok(Some(result))
         } else {
             // Normal expression evaluation
             let expr = calculator.parser.parse(input)?;
             let result = expr.evaluate(&calculator.variables)?;
             calculator.store_result(input.to_string(), result);
-            println!("= {}", self.base.format(result));
             Ok(Some(result))
         }
     }
@@ -462,6 +486,14 @@ impl CalculatorState for ProgrammerMode {
         vec!["+", "-", "*", "/", "AND", "OR", "XOR", "NOT", "SHL", "SHR"]
     }
     
+    // Whole numbers in the current base; decimal goes through the formatter for grouping
+    fn format_result(&self, result: f64, formatter: &NumberFormatter) -> String {
+        match self.base {
+            NumberBase::Decimal => formatter.format(result.trunc()),
+            base => base.format(result),
+        }
+    }
+    
     fn display_prompt(&self) -> String {
         match self.base {
             NumberBase::Binary => "[Programmer (BIN)] > ".to_string(),
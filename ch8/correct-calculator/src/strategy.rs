@@ -1,17 +1,24 @@
 // strategy.rs - Strategy pattern implementation
 
 use std::collections::HashMap;
-use crate::token::{Token, Operator, Function};
-use crate::expression::{Expression, NumberExpression, VariableExpression, BinaryOperation, FunctionCall};
+use crate::token::{Token, SpannedToken, Span, Associativity, Fixity};
+use crate::expression::{Expr, function_call};
+use crate::error::CalcError;
+use crate::lexer::Lexer;
+use crate::operators::{OperatorTable, OperatorDefinition};
 
 // Strategy interface for expression evaluation
 pub trait EvaluationStrategy {
-    fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, String>;
+    fn parse(&self, expression: &str) -> Result<Expr, CalcError>;
+    
+    fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
+        self.parse(expression)?.evaluate(variables)
+    }
 }
 
 // Strategy for tokenization
 pub trait TokenizationStrategy {
-    fn tokenize(&self, input: &str) -> Result<Vec<Token>, String>;
+    fn tokenize(&self, input: &str) -> Result<Vec<SpannedToken>, CalcError>;
 }
 
 // Strategy for numeric precision
@@ -74,252 +81,475 @@ impl PrecisionStrategy for ScientificPrecision {
     }
 }
 
+// Fraction precision implementation: the nearest fraction whose denominator is
+// at most `max_denominator`, found from the continued fraction of the value
+pub struct FractionPrecision {
+    max_denominator: u128,
+    mixed_numbers: bool, // 1 1/2 rather than 3/2
+}
+
+impl FractionPrecision {
+    // Denominators up to u32::MAX keep every numerator within a u128
+    pub fn new(max_denominator: u32) -> Self {
+        Self { max_denominator: u128::from(max_denominator.max(1)), mixed_numbers: true }
+    }
+    
+    pub fn with_mixed_numbers(mut self, enabled: bool) -> Self {
+        self.mixed_numbers = enabled;
+        self
+    }
+    
+    // Nearest numerator / denominator to |value|. Each convergent of the
+    // continued fraction is the nearest fraction with a denominator up to its
+    // own; past the limit, the best semiconvergent may be nearer still
+    fn nearest(&self, value: f64) -> (u128, u128) {
+        let target = value.abs();
+        let (mut numerator, mut denominator) = (target.floor() as u128, 1);
+        let (mut previous_numerator, mut previous_denominator) = (1, 0);
+        let mut remainder = target - target.floor();
+        
+        while remainder > 0.0 && (numerator as f64 / denominator as f64) != target {
+            remainder = 1.0 / remainder;
+            let term = remainder.floor();
+            remainder -= term;
+            
+            // The largest term that keeps the denominator within the limit
+            let room = (self.max_denominator - previous_denominator) / denominator;
+            if term > room as f64 {
+                let candidate = (room * numerator + previous_numerator, room * denominator + previous_denominator);
+                let error = |(n, d): (u128, u128)| (n as f64 / d as f64 - target).abs();
+                if room > 0 && error(candidate) < error((numerator, denominator)) {
+                    return candidate;
+                }
+                break;
+            }
+            
+            let term = term as u128;
+            (numerator, previous_numerator) = (term * numerator + previous_numerator, numerator);
+            (denominator, previous_denominator) = (term * denominator + previous_denominator, denominator);
+        }
+        (numerator, denominator)
+    }
+}
+
+impl PrecisionStrategy for FractionPrecision {
+    // A result the fraction only approximates is marked with ≈, as in ≈3 1/7 for pi
+    fn format(&self, value: f64) -> String {
+        // Whole numbers, including any too large for the fraction's parts
+        if !value.is_finite() || value.fract() == 0.0 || value.abs() >= u64::MAX as f64 {
+            return format!("{}", value);
+        }
+        
+        let (numerator, denominator) = self.nearest(value);
+        let marker = if self.round(value) == value { "" } else { "≈" };
+        let sign = if value < 0.0 && numerator > 0 { "-" } else { "" };
+        let (whole, part) = (numerator / denominator, numerator % denominator);
+        
+        match (whole, part) {
+            (_, 0) => format!("{}{}{}", marker, sign, whole),
+            (0, _) => format!("{}{}{}/{}", marker, sign, part, denominator),
+            _ if self.mixed_numbers => format!("{}{}{} {}/{}", marker, sign, whole, part, denominator),
+            _ => format!("{}{}{}/{}", marker, sign, numerator, denominator),
+        }
+    }
+    
+    fn round(&self, value: f64) -> f64 {
+        if !value.is_finite() || value.abs() >= u64::MAX as f64 {
+            return value;
+        }
+        let (numerator, denominator) = self.nearest(value);
+        value.signum() * (numerator as f64 / denominator as f64)
+    }
+}
+
 // Standard tokenization strategy
 pub struct SimpleTokenizer;
 
 impl TokenizationStrategy for SimpleTokenizer {
-    fn tokenize(&self, input: &str) -> Result<Vec<Token>, String> {
-        // Simple space-delimited tokenization
-        let tokens: Result<Vec<Token>, String> = input
-            .split_whitespace()
-            .map(Token::from_str)
-            .collect();
-        
-        tokens
+    fn tokenize(&self, input: &str) -> Result<Vec<SpannedToken>, CalcError> {
+        // Delegate to the shared character-level lexer
+        crate::lexer::tokenize(input)
+    }
+}
+
+// Tokenization that also recognises the symbols of registered operators, e.g. `∥`
+pub struct CustomSymbolTokenizer {
+    symbols: Vec<String>,
+}
+
+impl CustomSymbolTokenizer {
+    pub fn new(operators: &OperatorTable) -> Self {
+        Self { symbols: operators.custom_symbols() }
+    }
+}
+
+impl TokenizationStrategy for CustomSymbolTokenizer {
+    fn tokenize(&self, input: &str) -> Result<Vec<SpannedToken>, CalcError> {
+        Lexer::new(input).with_custom_symbols(self.symbols.clone()).tokenize()
     }
 }
 
 // Recursive descent parser strategy
 pub struct RecursiveDescentStrategy {
     tokenizer: Box<dyn TokenizationStrategy>,
+    operators: OperatorTable,
 }
 
 impl RecursiveDescentStrategy {
     pub fn new(tokenizer: Box<dyn TokenizationStrategy>) -> Self {
-        Self { tokenizer }
+        Self { tokenizer, operators: OperatorTable::new() }
     }
     
-    // Helper function to parse expressions
-    fn parse_expression(&self, tokens: &[Token]) -> Result<Box<dyn Expression>, String> {
+    // Parses the operators of `operators` as well; registered symbols also need
+    // a tokenizer that knows them, such as `CustomSymbolTokenizer`
+    pub fn with_operators(mut self, operators: OperatorTable) -> Self {
+        self.operators = operators;
+        self
+    }
+    
+    // Helper function to parse expressions; each level returns the node and
+    // the index of the first token it did not consume
+    fn parse_expression(&self, tokens: &[SpannedToken]) -> Result<Expr, CalcError> {
         if tokens.is_empty() {
-            return Err("Empty expression".to_string());
+            return Err(CalcError::EmptyExpression);
         }
         
-        // This is a simplified recursive descent parser
-        // A real one would be more complex with proper grammar rules
-        self.parse_addition(tokens)
+        let (expr, pos) = self.parse_binary(tokens, 0, 0)?;
+        
+        if let Some(extra) = tokens.get(pos) {
+            return Err(CalcError::syntax(extra.span, "an operator or the end of input"));
+        }
+        
+        Ok(expr)
     }
     
-    fn parse_addition(&self, tokens: &[Token]) -> Result<Box<dyn Expression>, String> {
-        let mut left = self.parse_multiplication(tokens)?;
+    // Precedence climbing driven by the operator table: parses a chain of infix
+    // and postfix operators that bind at least as tightly as `min_precedence`
+    fn parse_binary(&self, tokens: &[SpannedToken], pos: usize, min_precedence: u8) -> Result<(Expr, usize), CalcError> {
+        let (mut left, mut pos) = self.parse_unary(tokens, pos)?;
         
-        // For simplicity, we're not handling the token indices correctly here
-        // A real implementation would keep track of the current token index
-        for i in 0..tokens.len() {
-            if let Token::Operator(op @ (Operator::Add | Operator::Subtract)) = &tokens[i] {
-                if i + 1 < tokens.len() {
-                    let right = self.parse_multiplication(&tokens[i+1..])?;
-                    left = Box::new(BinaryOperation::new(left, right, op.clone()));
-                }
+        while let Some(definition) = self.operators.after_operand(tokens, pos) {
+            if definition.precedence < min_precedence {
+                break;
+            }
+            
+            let span = left.span().map_or(tokens[pos].span, |left_span| left_span.merge(tokens[pos].span));
+            if definition.fixity == Fixity::Postfix {
+                left = definition.build(vec![left], span);
+                pos += 1;
+                continue;
             }
+            
+            // A right-associative operator lets its right operand continue the same chain
+            let right_min = match definition.associativity {
+                Associativity::Left => definition.precedence + 1,
+                Associativity::Right => definition.precedence,
+            };
+            
+            let (right, next) = self.parse_binary(tokens, pos + 1, right_min)?;
+            let span = right.span().map_or(span, |right_span| span.merge(right_span));
+            left = definition.build(vec![left, right], span);
+            pos = next;
         }
         
-        Ok(left)
+        Ok((left, pos))
     }
     
-    fn parse_multiplication(&self, tokens: &[Token]) -> Result<Box<dyn Expression>, String> {
-        let mut left = self.parse_primary(tokens)?;
-        
-        // Simplified for demonstration
-        for i in 0..tokens.len() {
-            if let Token::Operator(op @ (Operator::Multiply | Operator::Divide | Operator::Power)) = &tokens[i] {
-                if i + 1 < tokens.len() {
-                    let right = self.parse_primary(&tokens[i+1..])?;
-                    left = Box::new(BinaryOperation::new(left, right, op.clone()));
-                }
+    // A prefix operator's operand only covers operators that bind tighter, so -2^2 is -(2^2)
+    fn parse_unary(&self, tokens: &[SpannedToken], pos: usize) -> Result<(Expr, usize), CalcError> {
+        if let Some(SpannedToken { token, span }) = tokens.get(pos) {
+            if let Some(definition) = self.operators.lookup(Fixity::Prefix, token) {
+                let (operand, next) = self.parse_binary(tokens, pos + 1, definition.precedence + 1)?;
+                let span = operand.span().map_or(*span, |operand_span| span.merge(operand_span));
+                return Ok((definition.build(vec![operand], span), next));
             }
         }
         
-        Ok(left)
+        self.parse_primary(tokens, pos)
     }
     
-    fn parse_primary(&self, tokens: &[Token]) -> Result<Box<dyn Expression>, String> {
-        if tokens.is_empty() {
-            return Err("Unexpected end of expression".to_string());
-        }
+    fn parse_primary(&self, tokens: &[SpannedToken], pos: usize) -> Result<(Expr, usize), CalcError> {
+        let spanned = match tokens.get(pos) {
+            Some(spanned) => spanned,
+            None => return Err(CalcError::Syntax { span: None, expected: "an operand".to_string() }),
+        };
         
-        match &tokens[0] {
-            Token::Number(num) => Ok(Box::new(NumberExpression::new(num.value))),
-            Token::Variable(name) => Ok(Box::new(VariableExpression::new(name.clone()))),
-            Token::Function(func) => {
-                if tokens.len() < 3 || tokens[1] != Token::OpenParen || tokens[tokens.len() - 1] != Token::CloseParen {
-                    return Err("Invalid function call syntax".to_string());
+        let span = spanned.span;
+        match &spanned.token {
+            Token::Number(num) => Ok((Expr::Number { value: num.value, format: num.format, span: Some(span) }, pos + 1)),
+            Token::Variable(name) => {
+                // A name followed by `(` calls a user-defined function
+                if let Some(SpannedToken { token: Token::OpenParen, .. }) = tokens.get(pos + 1) {
+                    let (args, close) = self.parse_arguments(tokens, pos + 2)?;
+                    let close_span = self.expect_close_paren(tokens, close)?;
+                    
+                    let call = Expr::user_call(name.clone(), args).with_span(Some(span.merge(close_span)));
+                    return Ok((call, close + 1));
                 }
-                let arg_tokens = &tokens[2..tokens.len() - 1];
-                let arg = self.parse_expression(arg_tokens)?;
-                Ok(Box::new(FunctionCall::new(func.clone(), arg)))
+                Ok((Expr::Variable { name: name.clone(), span: Some(span) }, pos + 1))
             },
-            Token::OpenParen => {
-                // Find matching closing paren
-                let mut depth = 1;
-                let mut close_idx = 0;
-                
-                for (i, token) in tokens.iter().enumerate().skip(1) {
-                    match token {
-                        Token::OpenParen => depth += 1,
-                        Token::CloseParen => {
-                            depth -= 1;
-                            if depth == 0 {
-                                close_idx = i;
-                                break;
-                            }
-                        },
-                        _ => {}
-                    }
-                }
-                
-                if depth != 0 {
-                    return Err("Mismatched parentheses".to_string());
+            Token::Function(func) => {
+                if tokens.get(pos + 1).map(|t| &t.token) != Some(&Token::OpenParen) {
+                    return Err(CalcError::syntax(span, "a parenthesised argument after the function name"));
                 }
+                let (args, close) = self.parse_arguments(tokens, pos + 2)?;
+                let close_span = self.expect_close_paren(tokens, close)?;
                 
-                self.parse_expression(&tokens[1..close_idx])
+                let call = function_call(func.clone(), args, Some(span.merge(close_span)))?;
+                Ok((call, close + 1))
             },
-            _ => Err(format!("Unexpected token: {:?}", tokens[0])),
+            Token::OpenParen => {
+                let (expr, close) = self.parse_binary(tokens, pos + 1, 0)?;
+                self.expect_close_paren(tokens, close)?;
+                Ok((expr, close + 1))
+            },
+            _ => Err(CalcError::syntax(span, "a number, name or '('")),
+        }
+    }
+    
+    // Comma-separated arguments up to, but not including, the closing parenthesis
+    fn parse_arguments(&self, tokens: &[SpannedToken], pos: usize) -> Result<(Vec<Expr>, usize), CalcError> {
+        let mut args = Vec::new();
+        
+        if let Some(SpannedToken { token: Token::CloseParen, .. }) = tokens.get(pos) {
+            return Ok((args, pos));
+        }
+        
+        let mut pos = pos;
+        loop {
+            let (arg, next) = self.parse_binary(tokens, pos, 0)?;
+            args.push(arg);
+            
+            match tokens.get(next) {
+                Some(SpannedToken { token: Token::Comma, .. }) => pos = next + 1,
+                _ => return Ok((args, next)),
+            }
+        }
+    }
+    
+    fn expect_close_paren(&self, tokens: &[SpannedToken], pos: usize) -> Result<Span, CalcError> {
+        match tokens.get(pos) {
+            Some(SpannedToken { token: Token::CloseParen, span }) => Ok(*span),
+            Some(other) => Err(CalcError::syntax(other.span, "')'")),
+            None => Err(CalcError::Syntax { span: None, expected: "')'".to_string() }),
         }
     }
 }
 
 impl EvaluationStrategy for RecursiveDescentStrategy {
-    fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    fn parse(&self, expression: &str) -> Result<Expr, CalcError> {
         let tokens = self.tokenizer.tokenize(expression)?;
-        let expr = self.parse_expression(&tokens)?;
-        expr.evaluate(variables)
+        self.parse_expression(&tokens)
     }
 }
 
 // Shunting yard algorithm strategy
 pub struct ShuntingYardStrategy {
     tokenizer: Box<dyn TokenizationStrategy>,
+    operators: OperatorTable,
+}
+
+// What waits on the shunting yard's operator stack
+enum Pending {
+    Operator(OperatorDefinition, Span),
+    Token(SpannedToken), // `(`, or the name of the function it belongs to
 }
 
 impl ShuntingYardStrategy {
     pub fn new(tokenizer: Box<dyn TokenizationStrategy>) -> Self {
-        Self { tokenizer }
+        Self { tokenizer, operators: OperatorTable::new() }
     }
     
-    fn build_expression_tree(&self, tokens: Vec<Token>) -> Result<Box<dyn Expression>, String> {
+    // Parses the operators of `operators` as well; registered symbols also need
+    // a tokenizer that knows them, such as `CustomSymbolTokenizer`
+    pub fn with_operators(mut self, operators: OperatorTable) -> Self {
+        self.operators = operators;
+        self
+    }
+    
+    fn build_expression_tree(&self, tokens: Vec<SpannedToken>) -> Result<Expr, CalcError> {
         // This is a simplified implementation of the shunting yard algorithm
-        let mut output_queue: Vec<Box<dyn Expression>> = Vec::new();
-        let mut operator_stack: Vec<Token> = Vec::new();
+        let mut output_queue: Vec<Expr> = Vec::new();
+        let mut operator_stack: Vec<Pending> = Vec::new();
+        let mut expect_operand = true;
+        
+        // Output length and comma count for each open parenthesis
+        let mut groups: Vec<(usize, usize)> = Vec::new();
         
-        for token in tokens {
-            match token {
+        for (pos, spanned) in tokens.iter().enumerate() {
+            let span = spanned.span;
+            match &spanned.token {
                 Token::Number(num) => {
-                    output_queue.push(Box::new(NumberExpression::new(num.value)));
+                    output_queue.push(Expr::Number { value: num.value, format: num.format, span: Some(span) });
+                    expect_operand = false;
                 },
                 Token::Variable(name) => {
-                    output_queue.push(Box::new(VariableExpression::new(name)));
-                },
-                Token::Operator(op) => {
-                    // While there's an operator on the stack with greater precedence
-                    while let Some(Token::Operator(top_op)) = operator_stack.last() {
-                        if top_op.precedence() >= op.precedence() {
-                            operator_stack.pop();
-                            
-                            if output_queue.len() < 2 {
-                                return Err("Invalid expression: not enough operands".to_string());
-                            }
-                            
-                            let right = output_queue.pop().unwrap();
-                            let left = output_queue.pop().unwrap();
-                            
-                            output_queue.push(Box::new(BinaryOperation::new(left, right, top_op.clone())));
-                        } else {
-                            break;
-                        }
+                    // A name followed by `(` calls a user-defined function
+                    if let Some(SpannedToken { token: Token::OpenParen, .. }) = tokens.get(pos + 1) {
+                        operator_stack.push(Pending::Token(SpannedToken::new(Token::UserFunction(name.clone()), span)));
+                        expect_operand = true;
+                        continue;
                     }
                     
-                    operator_stack.push(Token::Operator(op));
-                },
-                Token::Function(func) => {
-                    operator_stack.push(Token::Function(func));
+                    output_queue.push(Expr::Variable { name: name.clone(), span: Some(span) });
+                    expect_operand = false;
                 },
                 Token::OpenParen => {
-                    operator_stack.push(token);
+                    groups.push((output_queue.len(), 0));
+                    operator_stack.push(Pending::Token(spanned.clone()));
+                    expect_operand = true;
                 },
-                Token::CloseParen => {
-                    // Pop until matching open paren
-                    while let Some(top) = operator_stack.last() {
-                        if let Token::OpenParen = top {
-                            operator_stack.pop();
-                            
-                            // If there's a function on the stack, apply it
-                            if let Some(Token::Function(func)) = operator_stack.last() {
-                                operator_stack.pop();
-                                
-                                if output_queue.is_empty() {
-                                    return Err("Invalid function call: missing argument".to_string());
-                                }
-                                
-                                let arg = output_queue.pop().unwrap();
-                                output_queue.push(Box::new(FunctionCall::new(func.clone(), arg)));
-                            }
-                            
-                            break;
-                        } else if let Token::Operator(op) = top {
-                            operator_stack.pop();
-                            
-                            if output_queue.len() < 2 {
-                                return Err("Invalid expression: not enough operands".to_string());
-                            }
-                            
-                            let right = output_queue.pop().unwrap();
-                            let left = output_queue.pop().unwrap();
-                            
-                            output_queue.push(Box::new(BinaryOperation::new(left, right, op.clone())));
-                        } else {
-                            operator_stack.pop();
-                        }
+                Token::Function(_) | Token::UserFunction(_) => {
+                    operator_stack.push(Pending::Token(spanned.clone()));
+                    expect_operand = true;
+                },
+                Token::Comma => {
+                    if expect_operand {
+                        return Err(CalcError::syntax(span, "an argument before this ','"));
                     }
-                }
-            }
-        }
-        
-        // Process remaining operators
-        while let Some(token) = operator_stack.pop() {
-            match token {
-                Token::Operator(op) => {
-                    if output_queue.len() < 2 {
-                        return Err("Invalid expression: not enough operands".to_string());
+                    
+                    // Finish the argument before the comma
+                    Self::apply_waiting(&mut operator_stack, &mut output_queue)?;
+                    
+                    match (operator_stack.len().checked_sub(2).map(|i| &operator_stack[i]), groups.last_mut()) {
+                        (Some(Pending::Token(SpannedToken { token: Token::Function(_) | Token::UserFunction(_), .. })), Some((_, commas))) => *commas += 1,
+                        _ => return Err(CalcError::syntax(span, "an operator; ',' only separates function arguments")),
+                    }
+                    
+                    expect_operand = true;
+                },
+                Token::CloseParen => {
+                    // Apply everything back to the matching open paren
+                    Self::apply_waiting(&mut operator_stack, &mut output_queue)?;
+                    if !matches!(operator_stack.pop(), Some(Pending::Token(SpannedToken { token: Token::OpenParen, .. }))) {
+                        return Err(CalcError::syntax(span, "a matching '(' for this ')'"));
                     }
+                    let (start, commas) = groups.pop().unwrap_or_default();
                     
-                    let right = output_queue.pop().unwrap();
-                    let left = output_queue.pop().unwrap();
+                    // If there's a function on the stack, apply it to its arguments
+                    if let Some(Pending::Token(SpannedToken { token: Token::Function(_) | Token::UserFunction(_), .. })) = operator_stack.last() {
+                        let Some(Pending::Token(func)) = operator_stack.pop() else { unreachable!() };
+                        let call_span = func.span.merge(span);
+                        
+                        // `f()` has no arguments; otherwise each comma separates two
+                        let count = if output_queue.len() == start && commas == 0 { 0 } else { commas + 1 };
+                        if expect_operand && count > 0 || output_queue.len() != start + count {
+                            return Err(CalcError::syntax(call_span, "one value per argument of this function"));
+                        }
+                        
+                        let args = output_queue.split_off(start);
+                        match func.token {
+                            Token::Function(function) => {
+                                output_queue.push(function_call(function, args, Some(call_span))?);
+                            },
+                            Token::UserFunction(name) => {
+                                output_queue.push(Expr::user_call(name, args).with_span(Some(call_span)));
+                            },
+                            _ => {}
+                        }
+                    }
                     
-                    output_queue.push(Box::new(BinaryOperation::new(left, right, op)));
+                    expect_operand = false;
                 },
-                Token::OpenParen | Token::CloseParen => {
-                    return Err("Mismatched parentheses".to_string());
+                token if expect_operand => {
+                    // Where an operand should start, only a prefix operator fits
+                    match self.operators.lookup(Fixity::Prefix, token) {
+                        Some(definition) => operator_stack.push(Pending::Operator(definition.clone(), span)),
+                        None => return Err(CalcError::syntax(span, "an operand before this operator")),
+                    }
                 },
                 _ => {
-                    return Err(format!("Unexpected token on operator stack: {:?}", token));
-                }
+                    let definition = match self.operators.after_operand(&tokens, pos) {
+                        Some(definition) => definition.clone(),
+                        None => return Err(CalcError::syntax(span, "an operator before this value")),
+                    };
+                    
+                    // Waiting operators that bind tighter take the operand before this one
+                    while let Some(Pending::Operator(top, _)) = operator_stack.last() {
+                        if !Self::binds_first(top, definition.precedence) {
+                            break;
+                        }
+                        Self::apply_top(&mut operator_stack, &mut output_queue)?;
+                    }
+                    
+                    // A postfix operator takes the operand just completed straight away
+                    if definition.fixity == Fixity::Postfix {
+                        Self::apply_operator(&mut output_queue, &definition, span)?;
+                    } else {
+                        operator_stack.push(Pending::Operator(definition, span));
+                        expect_operand = true;
+                    }
+                },
             }
         }
         
-        if output_queue.len() != 1 {
-            return Err("Invalid expression: too many values".to_string());
+        // Process remaining operators
+        Self::apply_waiting(&mut operator_stack, &mut output_queue)?;
+        if let Some(Pending::Token(top)) = operator_stack.pop() {
+            return match top.token {
+                Token::OpenParen => Err(CalcError::syntax(top.span, "a closing ')' for this '('")),
+                _ => Err(CalcError::internal(format!("Unexpected token on operator stack: {:?}", top.token))),
+            };
+        }
+        
+        if output_queue.is_empty() {
+            return Err(CalcError::EmptyExpression);
+        }
+        
+        if output_queue.len() > 1 {
+            let span = output_queue[1].span();
+            return Err(CalcError::Syntax { span, expected: "an operator before this value".to_string() });
         }
         
         Ok(output_queue.pop().unwrap())
     }
+    
+    // Whether the operator waiting on the stack takes the operand before an
+    // operator of `precedence`. As in the Pratt parser, the waiting one's
+    // associativity decides between equal precedences
+    fn binds_first(top: &OperatorDefinition, precedence: u8) -> bool {
+        match (top.fixity, top.associativity) {
+            (Fixity::Infix, Associativity::Right) => top.precedence > precedence,
+            _ => top.precedence >= precedence,
+        }
+    }
+    
+    // Applies every operator waiting above the nearest `(` or function name
+    fn apply_waiting(operator_stack: &mut Vec<Pending>, output_queue: &mut Vec<Expr>) -> Result<(), CalcError> {
+        while let Some(Pending::Operator(..)) = operator_stack.last() {
+            Self::apply_top(operator_stack, output_queue)?;
+        }
+        Ok(())
+    }
+    
+    fn apply_top(operator_stack: &mut Vec<Pending>, output_queue: &mut Vec<Expr>) -> Result<(), CalcError> {
+        match operator_stack.pop() {
+            Some(Pending::Operator(definition, span)) => Self::apply_operator(output_queue, &definition, span),
+            _ => Err(CalcError::internal("No operator on the stack to apply".to_string())),
+        }
+    }
+    
+    fn apply_operator(output_queue: &mut Vec<Expr>, definition: &OperatorDefinition, span: Span) -> Result<(), CalcError> {
+        let (count, expected) = match definition.fixity {
+            Fixity::Infix => (2, "an operand on each side of this operator"),
+            Fixity::Prefix => (1, "an operand after this operator"),
+            Fixity::Postfix => (1, "an operand before this operator"),
+        };
+        if output_queue.len() < count {
+            return Err(CalcError::syntax(span, expected));
+        }
+        
+        let operands = output_queue.split_off(output_queue.len() - count);
+        let span = operands.iter().filter_map(Expr::span).fold(span, Span::merge);
+        output_queue.push(definition.build(operands, span));
+        Ok(())
+    }
 }
 
 impl EvaluationStrategy for ShuntingYardStrategy {
-    fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    fn parse(&self, expression: &str) -> Result<Expr, CalcError> {
         let tokens = self.tokenizer.tokenize(expression)?;
-        let expr = self.build_expression_tree(tokens)?;
-        expr.evaluate(variables)
+        self.build_expression_tree(tokens)
     }
 }
 
@@ -340,7 +570,7 @@ impl ExpressionEvaluator {
         }
     }
     
-    pub fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    pub fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
         let result = self.evaluation_strategy.evaluate(expression, variables)?;
         Ok(self.precision_strategy.round(result))
     }
@@ -374,3 +604,40 @@ pub fn create_scientific_evaluator() -> ExpressionEvaluator {
     
     ExpressionEvaluator::new(evaluation_strategy, precision_strategy)
 }
+
+pub fn create_fraction_evaluator() -> ExpressionEvaluator {
+    let tokenizer = Box::new(SimpleTokenizer);
+    let evaluation_strategy = Box::new(ShuntingYardStrategy::new(tokenizer));
+    let precision_strategy = Box::new(FractionPrecision::new(1000));
+    
+    ExpressionEvaluator::new(evaluation_strategy, precision_strategy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn fractions_are_the_nearest_with_a_small_denominator() {
+        let pi = std::f64::consts::PI;
+        let cases = [
+            (1.0 / 3.0, 1000, "1/3"),
+            (0.1 + 0.2, 1000, "≈3/10"), // 0.30000000000000004
+            (1.5, 1000, "1 1/2"),
+            (-2.75, 1000, "-2 3/4"),
+            (0.0625, 10, "≈1/10"),
+            (pi, 10, "≈3 1/7"),
+            (pi, 1000, "≈3 16/113"),
+            (0.9999, 1000, "≈1"),
+            (-1e-10, 1000, "≈0"),
+            (42.0, 1000, "42"),
+            (f64::INFINITY, 1000, "inf"),
+        ];
+        
+        for (value, max_denominator, expected) in cases {
+            assert_eq!(FractionPrecision::new(max_denominator).format(value), expected, "{} up to /{}", value, max_denominator);
+        }
+        assert_eq!(FractionPrecision::new(1000).with_mixed_numbers(false).format(-1.5), "-3/2");
+        assert_eq!(FractionPrecision::new(1000).round(pi), 355.0 / 113.0);
+    }
+}
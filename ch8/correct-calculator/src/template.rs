@@ -1,13 +1,25 @@
 // template.rs - Template Method pattern implementation
 
 use std::collections::HashMap;
-use crate::token::Token;
-use crate::expression::Expression;
+use crate::token::{Token, SpannedToken, Span, Associativity, Fixity};
+use crate::expression::{Expr, function_call};
+use crate::error::CalcError;
+use crate::lexer::Lexer;
+use crate::operators::OperatorTable;
 
 // Abstract base class defining template method
 pub trait ExpressionEvaluator {
     // Template method defining the algorithm
-    fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    fn evaluate(&self, expression: &str, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
+        // 1-3. Tokenize, validate and parse
+        let parsed = self.parse_input(expression)?;
+        
+        // 4. Evaluate the structure
+        self.evaluate_parsed(parsed, variables)
+    }
+    
+    // The first three steps on their own, for callers that want the tree
+    fn parse_input(&self, expression: &str) -> Result<Expr, CalcError> {
         // 1. Tokenize the expression
         let tokens = self.tokenize(expression)?;
         
@@ -15,251 +27,281 @@ pub trait ExpressionEvaluator {
         self.validate_tokens(&tokens)?;
         
         // 3. Parse into structured form (implementation varies)
-        let parsed = self.parse(tokens)?;
-        
-        // 4. Evaluate the structure
-        self.evaluate_parsed(parsed, variables)
+        self.parse(tokens)
     }
     
     // Common steps implemented in base trait
-    fn tokenize(&self, expression: &str) -> Result<Vec<Token>, String> {
-        // Default tokenization implementation
-        // Space-delimited for simplicity
-        let tokens: Result<Vec<Token>, String> = expression
-            .split_whitespace()
-            .map(Token::from_str)
-            .collect();
-        
-        tokens
+    fn tokenize(&self, expression: &str) -> Result<Vec<SpannedToken>, CalcError> {
+        // Default tokenization uses the shared character-level lexer
+        crate::lexer::tokenize(expression)
     }
     
-    fn validate_tokens(&self, tokens: &[Token]) -> Result<(), String> {
+    fn validate_tokens(&self, tokens: &[SpannedToken]) -> Result<(), CalcError> {
         // Default validation implementation
-        if tokens.is_empty() {
-            return Err("Empty expression".to_string());
-        }
-        
-        // Ensure parentheses are balanced
-        let mut paren_depth = 0;
-        
-        for token in tokens {
-            match token {
-                Token::OpenParen => paren_depth += 1,
-                Token::CloseParen => {
-                    paren_depth -= 1;
-                    if paren_depth < 0 {
-                        return Err("Mismatched parentheses".to_string());
-                    }
-                },
-                _ => {}
-            }
-        }
-        
-        if paren_depth != 0 {
-            return Err("Mismatched parentheses".to_string());
-        }
-        
-        Ok(())
+        validate_parentheses(tokens)
     }
     
     // Steps that implementations must provide
-    fn parse(&self, tokens: Vec<Token>) -> Result<Box<dyn Expression>, String>;
+    fn parse(&self, tokens: Vec<SpannedToken>) -> Result<Expr, CalcError>;
     
-    fn evaluate_parsed(&self, expression: Box<dyn Expression>, variables: &HashMap<String, f64>) -> Result<f64, String>;
+    fn evaluate_parsed(&self, expression: Expr, variables: &HashMap<String, f64>) -> Result<f64, CalcError>;
+}
+
+// Checks every evaluator needs: something to parse, and balanced parentheses.
+// A free function, so overriding `validate_tokens` can still build on it
+pub fn validate_parentheses(tokens: &[SpannedToken]) -> Result<(), CalcError> {
+    if tokens.is_empty() {
+        return Err(CalcError::EmptyExpression);
+    }
+    
+    // Ensure parentheses are balanced, remembering where each open one is
+    let mut open_parens = Vec::new();
+    
+    for token in tokens {
+        match token.token {
+            Token::OpenParen => open_parens.push(token.span),
+            Token::CloseParen => {
+                open_parens.pop().ok_or_else(|| CalcError::syntax(token.span, "a matching '(' for this ')'"))?;
+            },
+            _ => {}
+        }
+    }
+    
+    if let Some(unclosed) = open_parens.pop() {
+        return Err(CalcError::syntax(unclosed, "a closing ')' for this '('"));
+    }
+    
+    Ok(())
 }
 
 // Concrete implementation using recursive descent
-pub struct RecursiveDescentEvaluator;
+pub struct RecursiveDescentEvaluator {
+    operators: OperatorTable,
+}
 
 impl RecursiveDescentEvaluator {
     pub fn new() -> Self {
-        Self
+        Self { operators: OperatorTable::new() }
+    }
+    
+    // Parses the operators of `operators` as well, e.g. one extended by `register_infix`
+    pub fn with_operators(mut self, operators: OperatorTable) -> Self {
+        self.operators = operators;
+        self
     }
     
     // Helper function for recursive descent parsing
-    fn parse_expression(&self, tokens: &[Token]) -> Result<Box<dyn Expression>, String> {
+    fn parse_expression(&self, tokens: &[SpannedToken]) -> Result<Expr, CalcError> {
         if tokens.is_empty() {
-            return Err("Empty expression".to_string());
+            return Err(CalcError::EmptyExpression);
         }
         
-        self.parse_addition(tokens, 0).map(|(expr, _)| expr)
+        let (expr, next_pos) = self.parse_binary(tokens, 0, 0)?;
+        
+        // Anything left over means the expression didn't end where it should
+        if next_pos < tokens.len() {
+            return Err(Self::unexpected(tokens, next_pos, "an operator or the end of input"));
+        }
+        
+        Ok(expr)
     }
     
-    fn parse_addition(&self, tokens: &[Token], pos: usize) -> Result<(Box<dyn Expression>, usize), String> {
-        // Parse left operand (higher precedence)
-        let (mut left, mut next_pos) = self.parse_multiplication(tokens, pos)?;
+    // Parses infix and postfix operators binding at least as tightly as
+    // `min_precedence`, using the precedence and associativity in the operator table
+    fn parse_binary(&self, tokens: &[SpannedToken], pos: usize, min_precedence: u8) -> Result<(Expr, usize), CalcError> {
+        // Parse left operand
+        let (mut left, mut next_pos) = self.parse_unary(tokens, pos)?;
         
-        // Continue parsing addition/subtraction operators
-        while next_pos < tokens.len() {
-            match &tokens[next_pos] {
-                Token::Operator(op) if op.precedence() == 1 => {
-                    // Parse right operand
-                    let (right, new_pos) = self.parse_multiplication(tokens, next_pos + 1)?;
-                    
-                    // Create binary operation node
-                    left = Box::new(crate::expression::BinaryOperation::new(
-                        left, 
-                        right, 
-                        op.clone()
-                    ));
-                    
-                    next_pos = new_pos;
-                },
-                _ => break,
+        // Continue while the next operator binds tightly enough for this level
+        while let Some(definition) = self.operators.after_operand(tokens, next_pos) {
+            if definition.precedence < min_precedence {
+                break;
+            }
+            
+            let span = left.span().map_or(tokens[next_pos].span, |left_span| left_span.merge(tokens[next_pos].span));
+            if definition.fixity == Fixity::Postfix {
+                left = definition.build(vec![left], span);
+                next_pos += 1;
+                continue;
             }
+            
+            // Left-associative: the right operand stops at operators of the same
+            // precedence. Right-associative: it takes the rest of the chain.
+            let right_min = match definition.associativity {
+                Associativity::Left => definition.precedence + 1,
+                Associativity::Right => definition.precedence,
+            };
+            
+            // Parse right operand
+            let (right, new_pos) = self.parse_binary(tokens, next_pos + 1, right_min)?;
+            
+            // Create the operator's node
+            let span = right.span().map_or(span, |right_span| span.merge(right_span));
+            left = definition.build(vec![left, right], span);
+            
+            next_pos = new_pos;
         }
         
         Ok((left, next_pos))
     }
     
-    fn parse_multiplication(&self, tokens: &[Token], pos: usize) -> Result<(Box<dyn Expression>, usize), String> {
-        // Parse left operand (higher precedence)
-        let (mut left, mut next_pos) = self.parse_primary(tokens, pos)?;
-        
-        // Continue parsing multiplication/division operators
-        while next_pos < tokens.len() {
-            match &tokens[next_pos] {
-                Token::Operator(op) if op.precedence() >= 2 => {
-                    // Parse right operand
-                    let (right, new_pos) = self.parse_primary(tokens, next_pos + 1)?;
-                    
-                    // Create binary operation node
-                    left = Box::new(crate::expression::BinaryOperation::new(
-                        left, 
-                        right, 
-                        op.clone()
-                    ));
-                    
-                    next_pos = new_pos;
-                },
-                _ => break,
+    fn parse_unary(&self, tokens: &[SpannedToken], pos: usize) -> Result<(Expr, usize), CalcError> {
+        // A prefix operator such as `-` or `!` where an operand should start;
+        // its operand only extends over operators that bind tighter, such as `^`
+        if let Some(SpannedToken { token, span }) = tokens.get(pos) {
+            if let Some(definition) = self.operators.lookup(Fixity::Prefix, token) {
+                let (operand, next_pos) = self.parse_binary(tokens, pos + 1, definition.precedence + 1)?;
+                let span = operand.span().map_or(*span, |operand_span| span.merge(operand_span));
+                return Ok((definition.build(vec![operand], span), next_pos));
             }
         }
         
-        Ok((left, next_pos))
+        self.parse_primary(tokens, pos)
     }
     
-    fn parse_primary(&self, tokens: &[Token], pos: usize) -> Result<(Box<dyn Expression>, usize), String> {
+    fn parse_primary(&self, tokens: &[SpannedToken], pos: usize) -> Result<(Expr, usize), CalcError> {
         if pos >= tokens.len() {
-            return Err("Unexpected end of expression".to_string());
+            return Err(Self::end_of_input(tokens, "an operand"));
         }
         
-        match &tokens[pos] {
+        let span = tokens[pos].span;
+        match &tokens[pos].token {
             Token::Number(num) => {
                 // Parse number literal
-                Ok((Box::new(crate::expression::NumberExpression::new(num.value)), pos + 1))
+                let number = Expr::Number { value: num.value, format: num.format, span: Some(span) };
+                Ok((number, pos + 1))
             },
             Token::Variable(name) => {
+                // A name followed by `(` calls a user-defined function
+                if let Some(SpannedToken { token: Token::OpenParen, .. }) = tokens.get(pos + 1) {
+                    let (args, close_pos) = self.parse_arguments(tokens, pos + 2)?;
+                    let call = Expr::user_call(name.clone(), args)
+                        .with_span(Some(span.merge(tokens[close_pos].span)));
+                    return Ok((call, close_pos + 1));
+                }
+                
                 // Parse variable
-                Ok((Box::new(crate::expression::VariableExpression::new(name.clone())), pos + 1))
+                let variable = Expr::Variable { name: name.clone(), span: Some(span) };
+                Ok((variable, pos + 1))
             },
             Token::Function(func) => {
                 // Parse function call
-                if pos + 1 >= tokens.len() || tokens[pos + 1] != Token::OpenParen {
-                    return Err("Expected '(' after function name".to_string());
+                if pos + 1 >= tokens.len() || tokens[pos + 1].token != Token::OpenParen {
+                    return Err(CalcError::syntax(span, "'(' after the function name"));
                 }
                 
-                // Parse argument expression
-                let (arg, next_pos) = self.parse_expression(&tokens[pos + 2..]).map(|e| (e, pos + 2))?;
-                
-                // Ensure closing parenthesis
-                if next_pos >= tokens.len() || tokens[next_pos] != Token::CloseParen {
-                    return Err("Expected ')' after function argument".to_string());
-                }
+                // Parse argument expressions
+                let (args, close_pos) = self.parse_arguments(tokens, pos + 2)?;
                 
-                Ok((Box::new(crate::expression::FunctionCall::new(func.clone(), arg)), next_pos + 1))
+                let call = function_call(func.clone(), args, Some(span.merge(tokens[close_pos].span)))?;
+                Ok((call, close_pos + 1))
             },
             Token::OpenParen => {
                 // Parse parenthesized expression
-                let (expr, next_pos) = self.parse_expression(&tokens[pos + 1..]).map(|e| (e, pos + 1))?;
+                let (expr, next_pos) = self.parse_binary(tokens, pos + 1, 0)?;
                 
                 // Ensure closing parenthesis
-                if next_pos >= tokens.len() || tokens[next_pos] != Token::CloseParen {
-                    return Err("Expected ')'".to_string());
+                if next_pos >= tokens.len() || tokens[next_pos].token != Token::CloseParen {
+                    return Err(Self::unexpected(tokens, next_pos, "')'"));
                 }
                 
                 Ok((expr, next_pos + 1))
             },
-            _ => Err(format!("Unexpected token: {:?}", tokens[pos])),
+            _ => Err(CalcError::syntax(span, "a number, name or '('")),
+        }
+    }
+    
+    // Comma-separated arguments starting at `pos`; `f()` has none. Returns them
+    // with the position of the closing parenthesis
+    fn parse_arguments(&self, tokens: &[SpannedToken], pos: usize) -> Result<(Vec<Expr>, usize), CalcError> {
+        let mut args = Vec::new();
+        let mut next_pos = pos;
+        
+        if tokens.get(next_pos).map(|t| &t.token) != Some(&Token::CloseParen) {
+            loop {
+                let (arg, after) = self.parse_binary(tokens, next_pos, 0)?;
+                args.push(arg);
+                next_pos = after;
+                
+                if tokens.get(next_pos).map(|t| &t.token) == Some(&Token::Comma) {
+                    next_pos += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        
+        // Ensure closing parenthesis
+        if next_pos >= tokens.len() || tokens[next_pos].token != Token::CloseParen {
+            return Err(Self::unexpected(tokens, next_pos, "',' or ')' after the function argument"));
+        }
+        
+        Ok((args, next_pos))
+    }
+    
+    // Error at the token at `pos`, or just past the last token if input ran out
+    fn unexpected(tokens: &[SpannedToken], pos: usize, expected: &str) -> CalcError {
+        match tokens.get(pos) {
+            Some(token) => CalcError::syntax(token.span, expected),
+            None => Self::end_of_input(tokens, expected),
+        }
+    }
+    
+    fn end_of_input(tokens: &[SpannedToken], expected: &str) -> CalcError {
+        CalcError::Syntax {
+            span: tokens.last().map(|last| Span::new(last.span.end, last.span.end + 1)),
+            expected: expected.to_string(),
         }
     }
 }
 
 impl ExpressionEvaluator for RecursiveDescentEvaluator {
-    fn parse(&self, tokens: Vec<Token>) -> Result<Box<dyn Expression>, String> {
+    fn tokenize(&self, expression: &str) -> Result<Vec<SpannedToken>, CalcError> {
+        // The shared lexer, told about any registered operator symbols
+        Lexer::new(expression).with_custom_symbols(self.operators.custom_symbols()).tokenize()
+    }
+    
+    fn parse(&self, tokens: Vec<SpannedToken>) -> Result<Expr, CalcError> {
         self.parse_expression(&tokens)
     }
     
-    fn evaluate_parsed(&self, expression: Box<dyn Expression>, variables: &HashMap<String, f64>) -> Result<f64, String> {
+    fn evaluate_parsed(&self, expression: Expr, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
         expression.evaluate(variables)
     }
 }
 
 // Concrete implementation using shunting yard algorithm
-pub struct ShuntingYardEvaluator;
+pub struct ShuntingYardEvaluator {
+    operators: OperatorTable,
+}
 
 impl ShuntingYardEvaluator {
     pub fn new() -> Self {
-        Self
+        Self { operators: OperatorTable::new() }
+    }
+    
+    // Parses the operators of `operators` as well, e.g. one extended by `register_infix`
+    pub fn with_operators(mut self, operators: OperatorTable) -> Self {
+        self.operators = operators;
+        self
     }
 }
 
 impl ExpressionEvaluator for ShuntingYardEvaluator {
-    fn parse(&self, tokens: Vec<Token>) -> Result<Box<dyn Expression>, String> {
-        // Implementation of shunting yard algorithm
-        // Use our parser instead of trying to reimplement
-        crate::parser::ExpressionParser::new().parse(&tokens
-            .iter()
-            .map(|t| match t {
-                Token::Number(n) => format!("{}", n.value),
-                Token::Variable(v) => v.clone(),
-                Token::Operator(op) => op.symbol().to_string(),
-                Token::Function(f) => match f {
-                    crate::token::Function::Sin => "sin".to_string(),
-                    crate::token::Function::Cos => "cos".to_string(),
-                    crate::token::Function::Tan => "tan".to_string(),
-                    crate::token::Function::Sqrt => "sqrt".to_string(),
-                },
-                Token::OpenParen => "(".to_string(),
-                Token::CloseParen => ")".to_string(),
-            })
-            .collect::<Vec<String>>()
-            .join(" "))
+    fn tokenize(&self, expression: &str) -> Result<Vec<SpannedToken>, CalcError> {
+        // The shared lexer, told about any registered operator symbols
+        Lexer::new(expression).with_custom_symbols(self.operators.custom_symbols()).tokenize()
     }
     
-    fn evaluate_parsed(&self, expression: Box<dyn Expression>, variables: &HashMap<String, f64>) -> Result<f64, String> {
-        expression.evaluate(variables)
+    fn parse(&self, tokens: Vec<SpannedToken>) -> Result<Expr, CalcError> {
+        // Use the operator-table parser instead of reimplementing shunting yard, handing over the
+        // tokens directly so their spans still point into the original input. It reports a
+        // missing operand or operator itself, so only the default validation runs first
+        crate::parser::ExpressionParser::new().with_operators(self.operators.clone()).parse_tokens(tokens)
     }
     
-    // Custom validation specific to shunting yard
-    fn validate_tokens(&self, tokens: &[Token]) -> Result<(), String> {
-        // Call the default implementation
-        <Self as ExpressionEvaluator>::validate_tokens(self, tokens)?;
-        
-        // Additional validation for shunting yard
-        let mut operand_count = 0;
-        let mut operator_count = 0;
-        
-        for token in tokens {
-            match token {
-                Token::Number(_) | Token::Variable(_) => operand_count += 1,
-                Token::Operator(_) => operator_count += 1,
-                _ => {}
-            }
-        }
-        
-        // Basic check for balanced expressions
-        if operand_count == 0 {
-            return Err("Expression must contain at least one operand".to_string());
-        }
-        
-        if operand_count != operator_count + 1 && !tokens.is_empty() {
-            // This is a simplified check - real validation would be more complex
-            // We're ignoring parentheses and functions here
-            return Err("Unbalanced expression: check operands and operators".to_string());
-        }
-        
-        Ok(())
+    fn evaluate_parsed(&self, expression: Expr, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
+        expression.evaluate(variables)
     }
 }
 
@@ -271,3 +313,24 @@ pub fn create_evaluator(use_recursive_descent: bool) -> Box<dyn ExpressionEvalua
         Box::new(ShuntingYardEvaluator::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ExpressionParser;
+    
+    #[test]
+    fn both_evaluators_read_calls_without_arguments() {
+        let expected = ExpressionParser::new().parse("f() + g(1, 2)").unwrap();
+        for evaluator in [create_evaluator(true), create_evaluator(false)] {
+            assert_eq!(evaluator.parse_input("f() + g(1, 2)").unwrap(), expected);
+        }
+    }
+    
+    #[test]
+    fn unbalanced_parentheses_are_caught_before_parsing() {
+        let error = ShuntingYardEvaluator::new().parse_input("(1 + 2").err().unwrap();
+        assert_eq!(error.span(), Some(Span::new(0, 1)));
+        assert!(matches!(RecursiveDescentEvaluator::new().parse_input(""), Err(CalcError::EmptyExpression)));
+    }
+}
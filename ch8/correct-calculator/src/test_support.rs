@@ -0,0 +1,208 @@
+// test_support.rs - Random expressions and helpers the tests of every module share.
+// Each way of evaluating is checked against the tree on the same expressions
+
+use std::collections::HashMap;
+use std::fmt;
+use crate::parser::ExpressionParser;
+use crate::expression::Expr;
+use crate::numeric::{Numeric, NumericContext};
+use crate::error::CalcError;
+
+pub(crate) const CASES: u64 = 2000;
+pub(crate) const MAX_DEPTH: usize = 5;
+
+const NUMBERS: &[&str] = &["1", "2", "3", "7", "10", "0.5", "2.5", "1e3", "1.5e-2", "1_000", "0xFF", "0b101", "4.7k"];
+const VARIABLES: &[&str] = &["x", "y", "z"];
+const BINARY: &[&str] = &["+", "-", "*", "/", "%", "//", "^", "**", "<", "<=", "==", "!=", ">=", ">", "&&", "||"];
+const PREFIX: &[&str] = &["-", "+", "!"];
+const POSTFIX: &[&str] = &["!", "%"];
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("sin", 1), ("sqrt", 1), ("log", 2), ("atan2", 2), ("hypot", 2),
+    ("min", 1), ("max", 3), ("clamp", 3), ("if", 3), ("abs", 1),
+];
+
+// Xorshift, so a failing case can be replayed from its seed without extra crates
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15).max(1)) // Never 0, which xorshift cannot leave
+    }
+    
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+// A generated expression, kept as a tree so a failure can be shrunk part by part
+#[derive(Debug, Clone)]
+pub(crate) enum Sample {
+    Number(&'static str),
+    Variable(&'static str),
+    Binary(Box<Sample>, &'static str, Box<Sample>),
+    Implicit(Box<Sample>, Box<Sample>), // `2 x`, a number then an operand
+    Prefix(&'static str, Box<Sample>),
+    Postfix(Box<Sample>, &'static str),
+    Call(&'static str, Vec<Sample>),
+    Group(Box<Sample>),
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sample::Number(text) | Sample::Variable(text) => write!(f, "{}", text),
+            Sample::Binary(left, op, right) => write!(f, "{} {} {}", left, op, right),
+            Sample::Implicit(left, right) => write!(f, "{} {}", left, right),
+            Sample::Prefix(op, operand) => write!(f, "{}{}", op, operand),
+            Sample::Postfix(operand, op) => write!(f, "{}{}", operand, op),
+            Sample::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            },
+            Sample::Group(inner) => write!(f, "({})", inner),
+        }
+    }
+}
+
+impl Sample {
+    pub(crate) fn generate(rng: &mut Rng, depth: usize) -> Sample {
+        if depth == 0 || rng.below(4) == 0 {
+            return Self::leaf(rng);
+        }
+        
+        let sub = |rng: &mut Rng| Box::new(Self::generate(rng, depth - 1));
+        match rng.below(8) {
+            0..=2 => Sample::Binary(sub(rng), rng.pick(BINARY), sub(rng)),
+            3 => Sample::Prefix(rng.pick(PREFIX), sub(rng)),
+            4 => Sample::Postfix(sub(rng), rng.pick(POSTFIX)),
+            5 => {
+                let (name, arity) = rng.pick(FUNCTIONS);
+                Sample::Call(name, (0..arity).map(|_| Self::generate(rng, depth - 1)).collect())
+            },
+            6 => Sample::Group(sub(rng)),
+            _ => {
+                // Only a number on the left, since `x (y)` would be a call
+                let right = match rng.below(3) {
+                    0 => Sample::Variable(rng.pick(VARIABLES)),
+                    1 => Sample::Group(sub(rng)),
+                    _ => Sample::Call("sqrt", vec![Self::generate(rng, depth - 1)]),
+                };
+                Sample::Implicit(Box::new(Sample::Number(rng.pick(NUMBERS))), Box::new(right))
+            },
+        }
+    }
+    
+    fn leaf(rng: &mut Rng) -> Sample {
+        if rng.below(2) == 0 {
+            Sample::Number(rng.pick(NUMBERS))
+        } else {
+            Sample::Variable(rng.pick(VARIABLES))
+        }
+    }
+    
+    // Smaller variations of this sample, most drastic first
+    pub(crate) fn simpler(&self) -> Vec<Sample> {
+        let mut candidates = Vec::new();
+        match self {
+            Sample::Number("1") => {},
+            Sample::Number(_) | Sample::Variable(_) => candidates.push(Sample::Number("1")),
+            Sample::Binary(left, op, right) => {
+                candidates.push((**left).clone());
+                candidates.push((**right).clone());
+                for left in left.simpler() {
+                    candidates.push(Sample::Binary(Box::new(left), op, right.clone()));
+                }
+                for right in right.simpler() {
+                    candidates.push(Sample::Binary(left.clone(), op, Box::new(right)));
+                }
+            },
+            Sample::Implicit(left, right) => {
+                candidates.push((**left).clone());
+                candidates.push((**right).clone());
+                for right in right.simpler() {
+                    candidates.push(Sample::Implicit(left.clone(), Box::new(right)));
+                }
+            },
+            Sample::Prefix(op, operand) => {
+                candidates.push((**operand).clone());
+                for operand in operand.simpler() {
+                    candidates.push(Sample::Prefix(op, Box::new(operand)));
+                }
+            },
+            Sample::Postfix(operand, op) => {
+                candidates.push((**operand).clone());
+                for operand in operand.simpler() {
+                    candidates.push(Sample::Postfix(Box::new(operand), op));
+                }
+            },
+            Sample::Call(name, args) => {
+                candidates.extend(args.iter().cloned());
+                for (i, arg) in args.iter().enumerate() {
+                    for simpler in arg.simpler() {
+                        let mut args = args.clone();
+                        args[i] = simpler;
+                        candidates.push(Sample::Call(name, args));
+                    }
+                }
+            },
+            Sample::Group(inner) => {
+                candidates.push((**inner).clone());
+                for inner in inner.simpler() {
+                    candidates.push(Sample::Group(Box::new(inner)));
+                }
+            },
+        }
+        candidates
+    }
+}
+
+// The random expressions, one per seed
+pub(crate) fn random_expressions() -> impl Iterator<Item = String> {
+    (1..=CASES).map(|seed| Sample::generate(&mut Rng::new(seed), MAX_DEPTH).to_string())
+}
+
+// Values for the variables random expressions use
+pub(crate) fn variables() -> HashMap<String, f64> {
+    [("x", 2.0), ("y", -3.0), ("z", 0.5)]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+// Runs `check` on every input that parses, and fails listing each problem it reports
+pub(crate) fn check_expressions(inputs: impl IntoIterator<Item = String>, mut check: impl FnMut(&Expr) -> Option<String>) {
+    let parser = ExpressionParser::new();
+    let failures: Vec<String> = inputs
+        .into_iter()
+        .filter_map(|input| parser.parse(&input).ok().map(|expr| (input, expr)))
+        .filter_map(|(input, expr)| check(&expr).map(|problem| format!("`{}`: {}", input, problem)))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+// Both results, if `other` disagrees with the tree
+pub(crate) fn mismatch(name: &str, tree: &Result<f64, CalcError>, other: &Result<f64, CalcError>) -> Option<String> {
+    (!same(tree, other)).then(|| format!("tree {:?}, {} {:?}", tree, name, other))
+}
+
+// Debug formatting, so NaN compares equal to NaN and errors compare with their spans
+pub(crate) fn same(tree: &Result<f64, CalcError>, other: &Result<f64, CalcError>) -> bool {
+    format!("{:?}", tree) == format!("{:?}", other)
+}
+
+// Evaluates `input` with no variables in the number type `N`
+pub(crate) fn evaluate_in<N: Numeric>(input: &str, settings: N::Settings) -> Result<N, CalcError> {
+    let expr = ExpressionParser::new().parse(input).unwrap();
+    NumericContext::new(&HashMap::new(), &HashMap::new(), settings).evaluate(&expr)
+}
@@ -1,29 +1,121 @@
 // token.rs - Core token types and factory methods
 
-// Number formats
-#[derive(Debug, Clone, PartialEq)]
+use std::fmt;
+use crate::error::CalcError;
+
+// Number formats; the lexer records which one a literal was written in
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NumberFormat {
     Decimal,
     Scientific,
     Engineering,
+    Grouped, // Decimal with `_` digit separators, e.g. 1_000_000
+    Hexadecimal,
+    Binary,
+    Octal,
+    SiPrefix(char), // Engineering suffix, e.g. the `k` in 4.7k
+}
+
+// SI prefixes accepted as number suffixes, with their powers of ten
+pub const SI_PREFIXES: &[(char, i32)] = &[
+    ('T', 12), ('G', 9), ('M', 6), ('k', 3),
+    ('m', -3), ('u', -6), ('µ', -6), ('n', -9), ('p', -12),
+];
+
+pub fn si_exponent(prefix: char) -> Option<i32> {
+    SI_PREFIXES.iter().find(|(c, _)| *c == prefix).map(|(_, exponent)| *exponent)
 }
 
 // Basic token types
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Operator {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,      // Remainder; the sign convention comes from `CalculatorConfig`
+    FloorDivide, // `//`, division rounded towards negative infinity
     Power,
+    ImplicitMultiply, // Inserted by the lexer between adjacent operands, as in `2x`
+    // Comparisons give 1 for true and 0 for false
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+    GreaterEqual,
+    Greater,
+    // Logical operators treat any non-zero value as true
+    And,
+    Or,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// How a chain of operators with equal precedence groups: a - b - c is
+// (a - b) - c, but a ^ b ^ c is a ^ (b ^ c)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+// Where an operator stands relative to its operands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fixity {
+    Prefix,
+    Infix,
+    Postfix,
+}
+
+// Prefix operators; the lexer produces `!` directly, while for `+` and `-`
+// the parsers decide from context whether they are unary
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UnaryOperator {
+    Negate,
+    Plus,
+    Not,
+}
+
+// Postfix operators, which bind tighter than anything else: 2^3! is 2^(3!).
+// The lexer decides from context whether `!` and `%` are postfix
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PostfixOperator {
+    Factorial,
+    Percent, // `a + b%` adds b percent of a; elsewhere `b%` is just b / 100
+}
+
+// How `%` chooses the sign of a non-zero remainder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModuloConvention {
+    Truncated, // Sign of the dividend, as in C and Rust: -7 % 3 == -1
+    Floored,   // Sign of the divisor, matching `//`: -7 % 3 == 2
+    Euclidean, // Never negative: -7 % -3 == 2
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Function {
     Sin,
     Cos,
     Tan,
     Sqrt,
+    Log,   // log(value, base)
+    Atan2, // atan2(y, x)
+    Hypot,
+    Min,
+    Max,
+    Clamp, // clamp(x, lo, hi)
+    If,    // if(condition, then, else); only the chosen branch is evaluated
+    // Parts of a complex number; a real number is its own real part
+    Re,
+    Im,
+    Abs,
+    Arg,   // Angle from the positive real axis, in (-π, π]
+    Conj,
+}
+
+// Number of arguments a function accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -44,28 +136,243 @@ impl Number {
         Self { value, format }
     }
     
+    // Reads a literal as the lexer scanned it: `1.5`, `2e-3`, `1_000`, `0xFF`,
+    // `0b1010`, `0o17` or `4.7k`
+    pub fn parse(text: &str) -> Result<Self, CalcError> {
+        let invalid = |base: &str| CalcError::InvalidNumber {
+            text: text.to_string(),
+            base: base.to_string(),
+        };
+        
+        let radix = match text.get(..2).map(|prefix| prefix.to_ascii_lowercase()).as_deref() {
+            Some("0x") => Some((16, NumberFormat::Hexadecimal, "hexadecimal")),
+            Some("0b") => Some((2, NumberFormat::Binary, "binary")),
+            Some("0o") => Some((8, NumberFormat::Octal, "octal")),
+            _ => None,
+        };
+        
+        if let Some((radix, format, base)) = radix {
+            return without_separators(&text[2..], |c| c.is_digit(radix))
+                .and_then(|digits| u64::from_str_radix(&digits, radix).ok())
+                .map(|value| Self::with_format(value as f64, format))
+                .ok_or_else(|| invalid(base));
+        }
+        
+        // An SI suffix scales by a power of ten; going through the exponent keeps
+        // 3.3m exactly as close to 0.0033 as typing 0.0033 would
+        let (body, prefix) = match text.chars().last() {
+            Some(c) if si_exponent(c).is_some() => (&text[..text.len() - c.len_utf8()], Some(c)),
+            _ => (text, None),
+        };
+        
+        let digits = without_separators(body, |c| c.is_ascii_digit()).ok_or_else(|| invalid("decimal"))?;
+        let scientific = digits.contains(['e', 'E']);
+        let (digits, format) = match prefix {
+            Some(_) if scientific => return Err(invalid("decimal")),
+            Some(prefix) => (format!("{}e{}", digits, si_exponent(prefix).unwrap()), NumberFormat::SiPrefix(prefix)),
+            None if scientific => (digits, NumberFormat::Scientific),
+            None if body.contains('_') => (digits, NumberFormat::Grouped),
+            None => (digits, NumberFormat::Decimal),
+        };
+        
+        digits
+            .parse::<f64>()
+            .map(|value| Self::with_format(value, format))
+            .map_err(|_| invalid("decimal"))
+    }
+    
     pub fn format(&self) -> String {
         match self.format {
             NumberFormat::Decimal => format!("{}", self.value),
             NumberFormat::Scientific => format!("{:e}", self.value),
+            NumberFormat::Engineering if self.value == 0.0 || !self.value.is_finite() => {
+                format!("{}", self.value)
+            },
             NumberFormat::Engineering => {
-                let exp = self.value.abs().log10().floor();
-                let adj_exp = (exp - exp % 3.0).floor();
-                let coeff = self.value / 10_f64.powf(adj_exp);
-                format!("{}e{}", coeff, adj_exp)
-            }
+                // Exponent a multiple of 3, rounding down for negative ones: 0.05 is 50e-3
+                let exponent = decimal_exponent(self.value).div_euclid(3) * 3;
+                format!("{}e{}", shift_decimal_point(self.value, -exponent), exponent)
+            },
+            NumberFormat::Grouped => group_digits(&format!("{}", self.value)),
+            NumberFormat::Hexadecimal => self.format_radix("0x", |n| format!("{:X}", n)),
+            NumberFormat::Binary => self.format_radix("0b", |n| format!("{:b}", n)),
+            NumberFormat::Octal => self.format_radix("0o", |n| format!("{:o}", n)),
+            NumberFormat::SiPrefix(prefix) => {
+                let exponent = si_exponent(prefix).unwrap_or(0);
+                format!("{}{}", shift_decimal_point(self.value, -exponent), prefix)
+            },
+        }
+    }
+    
+    // Radix notation only fits whole numbers; anything else stays decimal
+    fn format_radix(&self, prefix: &str, digits: impl Fn(u64) -> String) -> String {
+        if self.value.fract() != 0.0 || self.value.abs() >= u64::MAX as f64 {
+            return format!("{}", self.value);
+        }
+        
+        let sign = if self.value < 0.0 { "-" } else { "" };
+        format!("{}{}{}", sign, prefix, digits(self.value.abs() as u64))
+    }
+}
+
+impl NumberFormat {
+    // The same notation, adjusted to suit `value`. Only an SI prefix changes, to
+    // the one that leaves 1 to 999 in front of it, as in 6.9k rather than 6900
+    pub fn rescaled(&self, value: f64) -> NumberFormat {
+        match self {
+            NumberFormat::SiPrefix(current) if value != 0.0 && value.is_finite() => {
+                let exponent = ((value.abs().log10() / 3.0).floor() as i32 * 3).clamp(-12, 12);
+                if si_exponent(*current) == Some(exponent) {
+                    return *self; // Keeps µ as µ
+                }
+                
+                match SI_PREFIXES.iter().find(|(_, e)| *e == exponent) {
+                    Some((prefix, _)) if exponent != 0 => NumberFormat::SiPrefix(*prefix),
+                    _ => NumberFormat::Decimal,
+                }
+            },
+            NumberFormat::SiPrefix(_) => NumberFormat::Decimal,
+            other => *other,
         }
     }
 }
 
+// Power of ten of the leading digit, e.g. 3 for 1234.5 and -2 for 0.05. Read
+// from the shortest representation, since `log10` is off by one for some
+// exact powers of ten
+pub fn decimal_exponent(value: f64) -> i32 {
+    if !value.is_finite() || value == 0.0 {
+        return 0;
+    }
+    
+    let scientific = format!("{:e}", value);
+    let (_, exponent) = scientific.split_once('e').unwrap();
+    exponent.parse().unwrap()
+}
+
+// `value` times 10^`places`, written out by moving the decimal point in its
+// shortest representation, so 0.00022 shifted by 6 is exactly 220
+pub fn shift_decimal_point(value: f64, places: i32) -> String {
+    if !value.is_finite() || value == 0.0 {
+        return format!("{}", value);
+    }
+    
+    // `{:e}` gives the shortest digits that read back as `value`, e.g. -2.2e-4
+    let scientific = format!("{:e}", value.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    
+    // Digits before the decimal point once it has moved
+    let point = 1 + exponent + places;
+    let unsigned = if point <= 0 {
+        format!("0.{}{}", "0".repeat(-point as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!("{}{}", digits, "0".repeat(point as usize - digits.len()))
+    } else {
+        format!("{}.{}", &digits[..point as usize], &digits[point as usize..])
+    };
+    
+    let sign = if value < 0.0 { "-" } else { "" };
+    format!("{}{}", sign, unsigned)
+}
+
+// `digits` with each `_` removed, provided every `_` sits between two digits
+fn without_separators(digits: &str, is_digit: impl Fn(char) -> bool) -> Option<String> {
+    let chars: Vec<char> = digits.chars().collect();
+    
+    for (i, c) in chars.iter().enumerate() {
+        let between_digits = i > 0 && is_digit(chars[i - 1]) && chars.get(i + 1).is_some_and(|next| is_digit(*next));
+        if *c == '_' && !between_digits {
+            return None;
+        }
+    }
+    
+    Some(chars.into_iter().filter(|c| *c != '_').collect())
+}
+
+// Puts a `_` between every three digits of the integer part, as in 1_234.5
+fn group_digits(text: &str) -> String {
+    group_digits_with(text, '_')
+}
+
+// Same, with any separator, for display in the configured locale
+pub fn group_digits_with(text: &str, separator: char) -> String {
+    let (sign, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", text),
+    };
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(dot) => unsigned.split_at(dot),
+        None => (unsigned, ""),
+    };
+    
+    if !integer.chars().all(|c| c.is_ascii_digit()) {
+        return text.to_string(); // inf or NaN
+    }
+    
+    let mut grouped = String::new();
+    for (i, c) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(separator);
+        }
+        grouped.push(c);
+    }
+    
+    format!("{}{}{}", sign, grouped, fraction)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Number(Number),
     Operator(Operator),
+    UnaryOperator(UnaryOperator),
+    PostfixOperator(PostfixOperator),
+    CustomOperator(String), // A symbol registered in the parser's `OperatorTable`
     Function(Function),
+    UserFunction(String), // Produced by the parsers for a name followed by `(`
     Variable(String),
     OpenParen,
     CloseParen,
+    Comma,
+}
+
+// Byte range in the source text that a token or expression came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+    
+    // Smallest span covering both spans
+    pub fn merge(self, other: Span) -> Self {
+        Self {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+    
+    pub fn shifted(self, offset: usize) -> Self {
+        Self::new(self.start + offset, self.end + offset)
+    }
+}
+
+// Token together with its location, as produced by the lexer
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl SpannedToken {
+    pub fn new(token: Token, span: Span) -> Self {
+        Self { token, span }
+    }
 }
 
 // Factory methods for Token
@@ -96,45 +403,174 @@ impl Token {
     }
     
     // Factory method from string
-    pub fn from_str(s: &str) -> Result<Self, String> {
-        // Try parsing as a number first
-        if let Ok(num) = s.parse::<f64>() {
-            if s.contains('e') || s.contains('E') {
-                return Ok(Self::Number(Number::with_format(num, NumberFormat::Scientific)));
+    pub fn from_str(s: &str) -> Result<Self, CalcError> {
+        // Only a digit or `.` starts a number, so `inf` and `nan` stay names
+        if s.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            if let Ok(num) = s.parse::<f64>() {
+                if s.contains('e') || s.contains('E') {
+                    return Ok(Self::Number(Number::with_format(num, NumberFormat::Scientific)));
+                }
+                return Ok(Self::number(num));
             }
-            return Ok(Self::number(num));
+            
+            // Separators, radix prefixes and SI suffixes are beyond `f64::from_str`
+            return Number::parse(s).map(Self::Number);
         }
-
+        
         // Check for operators
         match s {
             "+" => Ok(Self::operator(Operator::Add)),
             "-" => Ok(Self::operator(Operator::Subtract)),
             "*" => Ok(Self::operator(Operator::Multiply)),
             "/" => Ok(Self::operator(Operator::Divide)),
-            "^" => Ok(Self::operator(Operator::Power)),
+            "%" => Ok(Self::operator(Operator::Modulo)),
+            "//" => Ok(Self::operator(Operator::FloorDivide)),
+            "^" | "**" => Ok(Self::operator(Operator::Power)),
+            "<" => Ok(Self::operator(Operator::Less)),
+            "<=" => Ok(Self::operator(Operator::LessEqual)),
+            "==" => Ok(Self::operator(Operator::Equal)),
+            "!=" => Ok(Self::operator(Operator::NotEqual)),
+            ">=" => Ok(Self::operator(Operator::GreaterEqual)),
+            ">" => Ok(Self::operator(Operator::Greater)),
+            "&&" => Ok(Self::operator(Operator::And)),
+            "||" => Ok(Self::operator(Operator::Or)),
+            "!" => Ok(Self::UnaryOperator(UnaryOperator::Not)),
             // Functions
             "sin" => Ok(Self::function(Function::Sin)),
             "cos" => Ok(Self::function(Function::Cos)),
             "tan" => Ok(Self::function(Function::Tan)),
             "sqrt" => Ok(Self::function(Function::Sqrt)),
-            // Parentheses
+            "log" => Ok(Self::function(Function::Log)),
+            "atan2" => Ok(Self::function(Function::Atan2)),
+            "hypot" => Ok(Self::function(Function::Hypot)),
+            "min" => Ok(Self::function(Function::Min)),
+            "max" => Ok(Self::function(Function::Max)),
+            "clamp" => Ok(Self::function(Function::Clamp)),
+            "if" => Ok(Self::function(Function::If)),
+            "re" => Ok(Self::function(Function::Re)),
+            "im" => Ok(Self::function(Function::Im)),
+            "abs" => Ok(Self::function(Function::Abs)),
+            "arg" => Ok(Self::function(Function::Arg)),
+            "conj" => Ok(Self::function(Function::Conj)),
+            // Parentheses and argument separator
             "(" => Ok(Self::OpenParen),
             ")" => Ok(Self::CloseParen),
+            "," => Ok(Self::Comma),
             // Must be a variable
             name if name.chars().all(|c| c.is_alphanumeric() || c == '_') => 
                 Ok(Self::variable(name)),
             // Invalid token
-            _ => Err(format!("Invalid token: {}", s)),
+            _ => Err(CalcError::Syntax {
+                span: None,
+                expected: "a number, name, operator or parenthesis".to_string(),
+            }),
         }
     }
 }
 
+impl Function {
+    pub fn all() -> &'static [Function] {
+        &[
+            Function::Sin, Function::Cos, Function::Tan, Function::Sqrt,
+            Function::Log, Function::Atan2, Function::Hypot,
+            Function::Min, Function::Max, Function::Clamp, Function::If,
+            Function::Re, Function::Im, Function::Abs, Function::Arg, Function::Conj,
+        ]
+    }
+    
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Sin => "sin",
+            Function::Cos => "cos",
+            Function::Tan => "tan",
+            Function::Sqrt => "sqrt",
+            Function::Log => "log",
+            Function::Atan2 => "atan2",
+            Function::Hypot => "hypot",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Clamp => "clamp",
+            Function::If => "if",
+            Function::Re => "re",
+            Function::Im => "im",
+            Function::Abs => "abs",
+            Function::Arg => "arg",
+            Function::Conj => "conj",
+        }
+    }
+    
+    pub fn arity(&self) -> Arity {
+        match self {
+            Function::Sin | Function::Cos | Function::Tan | Function::Sqrt => Arity::Exact(1),
+            Function::Re | Function::Im | Function::Abs | Function::Arg | Function::Conj => Arity::Exact(1),
+            Function::Log | Function::Atan2 | Function::Hypot => Arity::Exact(2),
+            Function::Min | Function::Max => Arity::AtLeast(1),
+            Function::Clamp | Function::If => Arity::Exact(3),
+        }
+    }
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        }
+    }
+}
+
+// Reads as the object of "expected", e.g. "expected at least 1 argument"
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (prefix, n) = match *self {
+            Arity::Exact(n) => ("", n),
+            Arity::AtLeast(n) => ("at least ", n),
+        };
+        write!(f, "{}{} argument{}", prefix, n, if n == 1 { "" } else { "s" })
+    }
+}
+
 impl Operator {
+    pub fn all() -> &'static [Operator] {
+        &[
+            Operator::Add, Operator::Subtract, Operator::Multiply, Operator::Divide,
+            Operator::Modulo, Operator::FloorDivide, Operator::Power, Operator::ImplicitMultiply,
+            Operator::Less, Operator::LessEqual, Operator::Equal, Operator::NotEqual,
+            Operator::GreaterEqual, Operator::Greater, Operator::And, Operator::Or,
+        ]
+    }
+    
     pub fn precedence(&self) -> u8 {
         match self {
-            Operator::Add | Operator::Subtract => 1,
-            Operator::Multiply | Operator::Divide => 2,
-            Operator::Power => 3,
+            // Loosest first, following C: a < b && c == d is (a < b) && (c == d)
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Equal | Operator::NotEqual => 3,
+            Operator::Less | Operator::LessEqual | Operator::GreaterEqual | Operator::Greater => 4,
+            Operator::Add | Operator::Subtract => 5,
+            Operator::Multiply | Operator::Divide | Operator::Modulo | Operator::FloorDivide => 6,
+            // Above `/`, so 1/2x is 1/(2x) as it would be read on paper
+            Operator::ImplicitMultiply => 7,
+            Operator::Power => 9, // Above unary minus, so -2^2 is -(2^2)
+        }
+    }
+    
+    pub fn associativity(&self) -> Associativity {
+        match self {
+            Operator::Add | Operator::Subtract => Associativity::Left,
+            Operator::Multiply | Operator::Divide | Operator::ImplicitMultiply => Associativity::Left,
+            Operator::Modulo | Operator::FloorDivide => Associativity::Left,
+            Operator::Power => Associativity::Right,
+            _ => Associativity::Left, // Comparisons and logical operators
+        }
+    }
+    
+    // The prefix form of this operator, if it has one
+    pub fn as_unary(&self) -> Option<UnaryOperator> {
+        match self {
+            Operator::Add => Some(UnaryOperator::Plus),
+            Operator::Subtract => Some(UnaryOperator::Negate),
+            _ => None,
         }
     }
     
@@ -142,9 +578,122 @@ impl Operator {
         match self {
             Operator::Add => "+",
             Operator::Subtract => "-",
-            Operator::Multiply => "*",
+            Operator::Multiply | Operator::ImplicitMultiply => "*",
             Operator::Divide => "/",
+            Operator::Modulo => "%",
+            Operator::FloorDivide => "//",
             Operator::Power => "^",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::GreaterEqual => ">=",
+            Operator::Greater => ">",
+            Operator::And => "&&",
+            Operator::Or => "||",
         }
     }
 }
+
+impl UnaryOperator {
+    pub fn all() -> &'static [UnaryOperator] {
+        &[UnaryOperator::Negate, UnaryOperator::Plus, UnaryOperator::Not]
+    }
+    
+    pub fn precedence(&self) -> u8 {
+        8 // Binds tighter than *, / and implicit multiplication, looser than ^
+    }
+    
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOperator::Negate => "-",
+            UnaryOperator::Plus => "+",
+            UnaryOperator::Not => "!",
+        }
+    }
+}
+
+impl PostfixOperator {
+    pub fn all() -> &'static [PostfixOperator] {
+        &[PostfixOperator::Factorial, PostfixOperator::Percent]
+    }
+    
+    pub fn precedence(&self) -> u8 {
+        10 // Above ^, so only the operand directly before the operator is affected
+    }
+    
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            PostfixOperator::Factorial => "!",
+            PostfixOperator::Percent => "%",
+        }
+    }
+}
+
+impl ModuloConvention {
+    pub fn remainder(&self, dividend: f64, divisor: f64) -> f64 {
+        match self {
+            ModuloConvention::Truncated => dividend % divisor,
+            ModuloConvention::Floored => dividend - divisor * (dividend / divisor).floor(),
+            ModuloConvention::Euclidean => dividend.rem_euclid(divisor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn remainder_conventions() {
+        let cases = [(-7.0, 3.0), (7.0, -3.0), (-7.0, -3.0)];
+        let remainders = |convention: ModuloConvention| {
+            cases.iter().map(|&(a, b)| convention.remainder(a, b)).collect::<Vec<f64>>()
+        };
+        assert_eq!(remainders(ModuloConvention::Truncated), vec![-1.0, 1.0, -1.0]);
+        assert_eq!(remainders(ModuloConvention::Floored), vec![2.0, -2.0, -1.0]);
+        assert_eq!(remainders(ModuloConvention::Euclidean), vec![2.0, 1.0, 2.0]);
+    }
+    
+    fn parsed(text: &str) -> (f64, NumberFormat) {
+        let number = Number::parse(text).unwrap();
+        (number.value, number.format)
+    }
+    
+    #[test]
+    fn literal_notations() {
+        assert_eq!(parsed("1_000_000"), (1e6, NumberFormat::Grouped));
+        assert_eq!(parsed("0xFF"), (255.0, NumberFormat::Hexadecimal));
+        assert_eq!(parsed("0b1010_1010"), (170.0, NumberFormat::Binary));
+        assert_eq!(parsed("0o17"), (15.0, NumberFormat::Octal));
+        assert_eq!(parsed("4.7k"), (4700.0, NumberFormat::SiPrefix('k')));
+        assert_eq!(parsed("3.3m"), (0.0033, NumberFormat::SiPrefix('m')));
+        assert_eq!(parsed("2e-3"), (0.002, NumberFormat::Scientific));
+    }
+    
+    #[test]
+    fn malformed_literals_name_their_base() {
+        for (text, base) in [("0xG", "hexadecimal"), ("0b102", "binary"), ("1__000", "decimal"), ("1_", "decimal"), ("1e3k", "decimal")] {
+            assert_eq!(Number::parse(text), Err(CalcError::InvalidNumber { text: text.to_string(), base: base.to_string() }));
+        }
+    }
+    
+    #[test]
+    fn literals_print_back_in_their_notation() {
+        for text in ["1_000_000", "0xFF", "0b1010", "0o17", "4.7k", "220u", "1.5e-2"] {
+            let number = Number::parse(text).unwrap();
+            assert_eq!(Number::parse(&number.format()).unwrap(), number, "{}", text);
+        }
+        assert_eq!(Number::with_format(-2.5, NumberFormat::Hexadecimal).format(), "-2.5");
+    }
+    
+    #[test]
+    fn si_prefixes_rescale_to_suit_the_value() {
+        let kilo = NumberFormat::SiPrefix('k');
+        assert_eq!(kilo.rescaled(6900.0), kilo);
+        assert_eq!(kilo.rescaled(6.9e6), NumberFormat::SiPrefix('M'));
+        assert_eq!(kilo.rescaled(12.0), NumberFormat::Decimal);
+        assert_eq!(shift_decimal_point(0.00022, 6), "220");
+        assert_eq!(decimal_exponent(1000.0), 3);
+    }
+}
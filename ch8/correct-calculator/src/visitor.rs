@@ -1,445 +1,829 @@
 // visitor.rs - Visitor pattern implementation for traversing and transforming expressions
 
 use std::collections::HashMap;
-use std::any::Any;
-use crate::expression::{Expression, NumberExpression, VariableExpression, BinaryOperation, FunctionCall};
-use crate::token::{Operator, Function};
+use crate::expression::{Expr, Callee, UserFunction, apply_binary, branch, is_true, user_function};
+use crate::parser::ExpressionParser;
+use crate::token::{Operator, UnaryOperator, PostfixOperator, Function, Span};
+use crate::error::CalcError;
+use crate::constants;
+
+// Visitor interface for expression operations. The tree is a closed enum, so
+// a visitor matches on each node and visits the children it needs itself
+pub trait ExpressionVisitor {
+    type Output;
+    
+    fn visit(&mut self, expr: &Expr) -> Self::Output;
+}
 
 // Visitable interface for expressions
 pub trait Visitable {
-    fn accept(&self, visitor: &mut dyn ExpressionVisitor) -> Result<(), String>;
-    
-    // Allow downcasting from trait object
-    fn as_any(&self) -> &dyn Any;
+    fn accept<V: ExpressionVisitor>(&self, visitor: &mut V) -> V::Output;
 }
 
-// Visitor interface for expression operations
-pub trait ExpressionVisitor {
-    fn visit_number(&mut self, expr: &NumberExpression) -> Result<(), String>;
-    fn visit_variable(&mut self, expr: &VariableExpression) -> Result<(), String>;
-    fn visit_binary_op(&mut self, expr: &BinaryOperation) -> Result<(), String>;
-    fn visit_function_call(&mut self, expr: &FunctionCall) -> Result<(), String>;
+impl Visitable for Expr {
+    fn accept<V: ExpressionVisitor>(&self, visitor: &mut V) -> V::Output {
+        visitor.visit(self)
+    }
 }
 
-// Extend the Expression trait to include Visitable
-pub trait VisitableExpression: Expression + Visitable {}
+// Concrete visitor for optimizing expressions
+pub struct OptimizationVisitor {
+    variables: HashMap<String, f64>,
+}
 
-// Implementation of Visitable for each expression type
-impl Visitable for NumberExpression {
-    fn accept(&self, visitor: &mut dyn ExpressionVisitor) -> Result<(), String> {
-        visitor.visit_number(self)
+impl OptimizationVisitor {
+    pub fn new(variables: HashMap<String, f64>) -> Self {
+        Self { variables }
     }
     
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-}
-
-impl Visitable for VariableExpression {
-    fn accept(&self, visitor: &mut dyn ExpressionVisitor) -> Result<(), String> {
-        visitor.visit_variable(self)
+    pub fn optimize(&mut self, expr: &Expr) -> Result<Expr, CalcError> {
+        expr.accept(self)
     }
     
-    fn as_any(&self) -> &dyn Any {
-        self
+    fn get_constant_value(&self, expr: &Expr) -> Option<f64> {
+        match expr {
+            Expr::Number { value, .. } => Some(*value),
+            // Percentages stay as nodes so `+` and `-` can still see them
+            Expr::Postfix { operator: PostfixOperator::Percent, operand, .. } => {
+                self.get_constant_value(operand).map(|value| value / 100.0)
+            },
+            _ => None,
+        }
     }
-}
-
-impl Visitable for BinaryOperation {
-    fn accept(&self, visitor: &mut dyn ExpressionVisitor) -> Result<(), String> {
-        // First visit the children recursively
-        if let Some(left) = self.left.as_any().downcast_ref::<NumberExpression>() {
-            left.accept(visitor)?;
-        } else if let Some(left) = self.left.as_any().downcast_ref::<VariableExpression>() {
-            left.accept(visitor)?;
-        } else if let Some(left) = self.left.as_any().downcast_ref::<BinaryOperation>() {
-            left.accept(visitor)?;
-        } else if let Some(left) = self.left.as_any().downcast_ref::<FunctionCall>() {
-            left.accept(visitor)?;
+    
+    fn optimize_binary(&mut self, operator: &Operator, left: &Expr, right: &Expr, span: Option<Span>) -> Result<Expr, CalcError> {
+        let left_opt = left.accept(self)?;
+        let left_val = self.get_constant_value(&left_opt);
+        
+        // A known left side decides `&&` and `||` on its own: 0 && x = 0, 1 || x = 1.
+        // The right side is left alone, since it is never evaluated
+        match (operator, left_val) {
+            (Operator::And, Some(value)) if !is_true(value) => return Ok(Expr::number(0.0)),
+            (Operator::Or, Some(value)) if is_true(value) => return Ok(Expr::number(1.0)),
+            _ => {},
         }
         
-        // Then visit this node
-        visitor.visit_binary_op(self)?;
+        // With an unknown left side, the right side of `&&` and `||` may never run,
+        // so one that fails to fold is kept as written, as `if` does with its branches
+        let right_opt = match right.accept(self) {
+            Ok(right_opt) => right_opt,
+            Err(_) if matches!(operator, Operator::And | Operator::Or) && left_val.is_none() => right.clone(),
+            Err(error) => return Err(error),
+        };
+        let right_val = self.get_constant_value(&right_opt);
         
-        // Finally visit the right child
-        if let Some(right) = self.right.as_any().downcast_ref::<NumberExpression>() {
-            right.accept(visitor)?;
-        } else if let Some(right) = self.right.as_any().downcast_ref::<VariableExpression>() {
-            right.accept(visitor)?;
-        } else if let Some(right) = self.right.as_any().downcast_ref::<BinaryOperation>() {
-            right.accept(visitor)?;
-        } else if let Some(right) = self.right.as_any().downcast_ref::<FunctionCall>() {
-            right.accept(visitor)?;
+        // If both operands are constants, evaluate them
+        if let (Some(left_val), Some(right_val)) = (left_val, right_val) {
+            let result = apply_binary(operator, left_val, &right_opt, right_val, span)?;
+            return Ok(Expr::number(result).with_span(span));
         }
         
-        Ok(())
+        // Some special cases for further optimization; comparisons only fold
+        // when both sides are known. Only identities that keep the other operand
+        // apply: 0 * x, x ^ 0 and 0 / x would drop an operand that may be
+        // infinite, NaN or an error, such as a call that recurses too deeply
+        match (operator, left_val, right_val) {
+            // Multiply by 1 = other operand
+            (Operator::Multiply, Some(1.0), _) => Ok(right_opt),
+            (Operator::Multiply, _, Some(1.0)) => Ok(left_opt),
+            // Add 0 = other operand, subtract 0 = left operand; but 0 + y% adds
+            // y percent of 0, so a percentage is kept
+            (Operator::Add, Some(0.0), _) if !matches!(right_opt, Expr::Postfix { operator: PostfixOperator::Percent, .. }) => Ok(right_opt),
+            (Operator::Add | Operator::Subtract, _, Some(0.0)) => Ok(left_opt),
+            // Divide by 1 = left operand, anything^1 = itself
+            (Operator::Divide | Operator::Power, _, Some(1.0)) => Ok(left_opt),
+            // Cannot fully optimize, create a new operation with optimized operands
+            _ => Ok(Expr::binary(left_opt, right_opt, operator.clone())),
+        }
     }
     
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-}
-
-impl Visitable for FunctionCall {
-    fn accept(&self, visitor: &mut dyn ExpressionVisitor) -> Result<(), String> {
-        // First visit the argument recursively
-        if let Some(arg) = self.argument.as_any().downcast_ref::<NumberExpression>() {
-            arg.accept(visitor)?;
-        } else if let Some(arg) = self.argument.as_any().downcast_ref::<VariableExpression>() {
-            arg.accept(visitor)?;
-        } else if let Some(arg) = self.argument.as_any().downcast_ref::<BinaryOperation>() {
-            arg.accept(visitor)?;
-        } else if let Some(arg) = self.argument.as_any().downcast_ref::<FunctionCall>() {
-            arg.accept(visitor)?;
+    // `if` with a known condition leaves only the taken branch
+    fn optimize_conditional(&mut self, arguments: &[Expr], span: Option<Span>) -> Result<Expr, CalcError> {
+        let condition_opt = arguments[0].accept(self)?;
+        if let Some(condition) = self.get_constant_value(&condition_opt) {
+            return branch(arguments, condition).accept(self);
         }
         
-        // Then visit this node
-        visitor.visit_function_call(self)
-    }
-    
-    fn as_any(&self) -> &dyn Any {
-        self
+        // Otherwise either branch may run; one that fails to fold (say, a
+        // division by zero) is kept as written, since it may never be taken
+        let mut arguments_opt = vec![condition_opt];
+        for argument in &arguments[1..] {
+            arguments_opt.push(argument.accept(self).unwrap_or_else(|_| argument.clone()));
+        }
+        
+        Ok(Expr::Call { function: Callee::Builtin(Function::If), arguments: arguments_opt, span })
     }
 }
 
-// Ensure Expression types implement As_Any
-impl Expression for dyn Visitable {
-    fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, String> {
-        // This is a bit of a hack, but it allows us to downcast visitor objects
-        if let Some(expr) = self.as_any().downcast_ref::<NumberExpression>() {
-            expr.evaluate(variables)
-        } else if let Some(expr) = self.as_any().downcast_ref::<VariableExpression>() {
-            expr.evaluate(variables)
-        } else if let Some(expr) = self.as_any().downcast_ref::<BinaryOperation>() {
-            expr.evaluate(variables)
-        } else if let Some(expr) = self.as_any().downcast_ref::<FunctionCall>() {
-            expr.evaluate(variables)
-        } else {
-            Err("Unknown expression type".to_string())
-        }
-    }
+impl ExpressionVisitor for OptimizationVisitor {
+    type Output = Result<Expr, CalcError>;
     
-    fn to_string(&self) -> String {
-        // Similar implementation as above
-        if let Some(expr) = self.as_any().downcast_ref::<NumberExpression>() {
-            expr.to_string()
-        } else if let Some(expr) = self.as_any().downcast_ref::<VariableExpression>() {
-            expr.to_string()
-        } else if let Some(expr) = self.as_any().downcast_ref::<BinaryOperation>() {
-            expr.to_string()
-        } else if let Some(expr) = self.as_any().downcast_ref::<FunctionCall>() {
-            expr.to_string()
-        } else {
-            "Unknown expression".to_string()
+    fn visit(&mut self, expr: &Expr) -> Result<Expr, CalcError> {
+        match expr {
+            // Numbers are already optimized
+            Expr::Number { .. } => Ok(expr.clone()),
+            // Built-in constants and variables with a known value become numbers
+            Expr::Variable { name, span } => match constants::resolve(name, &self.variables) {
+                Some(value) => Ok(Expr::number(value).with_span(*span)),
+                None => Ok(expr.clone()),
+            },
+            Expr::Binary { operator, left, right, span } => self.optimize_binary(operator, left, right, *span),
+            Expr::Unary { operator, operand, span } => {
+                let operand_opt = operand.accept(self)?;
+                
+                // Unary plus changes nothing
+                if *operator == UnaryOperator::Plus {
+                    return Ok(operand_opt);
+                }
+                
+                // Fold constant operands, and -(-x) back to x
+                if let Some(value) = self.get_constant_value(&operand_opt) {
+                    return Ok(Expr::number(operator.apply(value)).with_span(*span));
+                }
+                match operand_opt {
+                    Expr::Unary { operator: UnaryOperator::Negate, operand: inner, .. } if *operator == UnaryOperator::Negate => Ok(*inner),
+                    operand_opt => Ok(Expr::unary(operator.clone(), operand_opt).with_span(*span)),
+                }
+            },
+            Expr::Postfix { operator, operand, span } => {
+                let operand_opt = operand.accept(self)?;
+                
+                // Fold factorials of constants; a percentage is only folded by its
+                // parent, since `a + b%` depends on the node still being there
+                match (operator, &operand_opt) {
+                    (PostfixOperator::Factorial, Expr::Number { value, .. }) => {
+                        Ok(Expr::number(operator.apply(*value, *span)?).with_span(*span))
+                    },
+                    _ => Ok(Expr::postfix(operator.clone(), operand_opt).with_span(*span)),
+                }
+            },
+            Expr::Call { function: Callee::Builtin(Function::If), arguments, span } if arguments.len() == 3 => {
+                self.optimize_conditional(arguments, *span)
+            },
+            Expr::Call { function, arguments, span } => {
+                let args_opt = arguments
+                    .iter()
+                    .map(|argument| argument.accept(self))
+                    .collect::<Result<Vec<Expr>, CalcError>>()?;
+                
+                // If every argument is a constant, evaluate the function now. A user
+                // function is never folded, since it may be redefined before it runs
+                if let Callee::Builtin(builtin) = function {
+                    let values: Option<Vec<f64>> = args_opt.iter().map(|arg| self.get_constant_value(arg)).collect();
+                    if let Some(values) = values {
+                        return Ok(Expr::number(builtin.apply(&values, *span)?).with_span(*span));
+                    }
+                }
+                
+                Ok(Expr::Call { function: function.clone(), arguments: args_opt, span: *span })
+            },
+            Expr::Error { error, parts, span } => {
+                // The node still fails when evaluated; only the parts inside it fold
+                let parts_opt = parts
+                    .iter()
+                    .map(|part| part.accept(self).unwrap_or_else(|_| part.clone()))
+                    .collect();
+                Ok(Expr::Error { error: error.clone(), parts: parts_opt, span: *span })
+            },
+            Expr::Custom(node) => {
+                let children = node.children()
+                    .iter()
+                    .map(|child| child.accept(self))
+                    .collect::<Result<Vec<Expr>, CalcError>>()?;
+                
+                // Nodes that are pure functions of their children fold like built-in operators
+                let values: Option<Vec<f64>> = children.iter().map(|child| self.get_constant_value(child)).collect();
+                if let Some(result) = values.and_then(|values| node.fold(&values)) {
+                    return Ok(Expr::number(result?).with_span(node.span()));
+                }
+                
+                Ok(Expr::Custom(node.with_children(children)))
+            },
         }
     }
 }
 
-// Concrete visitor for optimizing expressions
-pub struct OptimizationVisitor {
-    variables: HashMap<String, f64>,
-    pub optimized_expression: Option<Box<dyn Expression>>,
+// Concrete visitor for symbolic differentiation with respect to one variable.
+// Calls to user functions are expanded into their bodies first
+pub struct DifferentiationVisitor {
+    variable: String,
+    functions: HashMap<String, UserFunction>,
 }
 
-impl OptimizationVisitor {
-    pub fn new(variables: HashMap<String, f64>) -> Self {
+impl DifferentiationVisitor {
+    pub fn new(variable: impl Into<String>) -> Self {
         Self {
-            variables,
-            optimized_expression: None,
+            variable: variable.into(),
+            functions: HashMap::new(),
         }
     }
     
-    pub fn optimize(&mut self, expr: &dyn Visitable) -> Result<Box<dyn Expression>, String> {
-        expr.accept(self)?;
-        
-        match &self.optimized_expression {
-            Some(optimized) => Ok(optimized.clone()),
-            None => Err("Optimization failed".to_string()),
-        }
+    pub fn with_functions(mut self, functions: HashMap<String, UserFunction>) -> Self {
+        self.functions = functions;
+        self
     }
     
-    fn get_constant_value(&self, expr: &dyn Expression) -> Option<f64> {
-        if let Some(num_expr) = expr.as_any().downcast_ref::<NumberExpression>() {
-            Some(num_expr.value)
-        } else {
-            None
-        }
+    pub fn differentiate(&mut self, expr: &Expr) -> Result<Expr, CalcError> {
+        let expanded = self.expand(expr, &HashMap::new(), &mut Vec::new())?;
+        expanded.accept(self)
     }
     
-    fn optimize_subexpression(&mut self, expr: &dyn Visitable) -> Result<Box<dyn Expression>, String> {
-        let saved = self.optimized_expression.take();
-        expr.accept(self)?;
-        let result = self.optimized_expression.take()
-            .ok_or_else(|| "Failed to optimize subexpression".to_string())?;
-        self.optimized_expression = saved;
-        Ok(result)
-    }
-}
-
-impl ExpressionVisitor for OptimizationVisitor {
-    fn visit_number(&mut self, expr: &NumberExpression) -> Result<(), String> {
-        // Numbers are already optimized
-        self.optimized_expression = Some(Box::new(expr.clone()));
-        Ok(())
+    // `expr` with every user function call replaced by the function's body.
+    // Parameters are bound over the caller's, as when evaluating, so a body
+    // also sees its callers' parameters
+    fn expand(&self, expr: &Expr, scope: &HashMap<String, Expr>, active: &mut Vec<String>) -> Result<Expr, CalcError> {
+        match expr {
+            Expr::Variable { name, .. } => Ok(scope.get(name).cloned().unwrap_or_else(|| expr.clone())),
+            Expr::Call { function: Callee::User(name), arguments, span } => {
+                let function = user_function(name, arguments.len(), &self.functions, *span)?;
+                
+                // Expanding a recursive function would never end
+                if active.contains(name) {
+                    return Err(CalcError::NotDifferentiable {
+                        what: format!("the recursive function {}", name),
+                        span: *span,
+                    });
+                }
+                
+                let mut inner = scope.clone();
+                for (parameter, argument) in function.parameters.iter().zip(arguments) {
+                    inner.insert(parameter.clone(), self.expand(argument, scope, active)?);
+                }
+                
+                active.push(name.clone());
+                let body = self.expand(&function.body, &inner, active);
+                active.pop();
+                
+                // Spans inside the body refer to the definition, so point at the call instead
+                body.map_err(|error| error.relocated(*span))
+            },
+            _ => expr.try_map_children(|child| self.expand(child, scope, active)),
+        }
     }
     
-    fn visit_variable(&mut self, expr: &VariableExpression) -> Result<(), String> {
-        // If the variable has a known constant value, replace with a number
-        if let Some(value) = self.variables.get(&expr.name) {
-            self.optimized_expression = Some(Box::new(NumberExpression::new(*value)));
-        } else {
-            self.optimized_expression = Some(Box::new(expr.clone()));
+    // Whether `expr` can change with the variable
+    fn depends(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Variable { name, .. } => *name == self.variable,
+            // Unexpanded, a user function could read the variable from its caller
+            Expr::Call { function: Callee::User(_), .. } => true,
+            _ => expr.children().any(|child| self.depends(child)),
         }
-        Ok(())
     }
     
-    fn visit_binary_op(&mut self, expr: &BinaryOperation) -> Result<(), String> {
-        // Optimize left and right subexpressions
-        let left_opt = if let Some(left) = expr.left.as_any().downcast_ref::<dyn Visitable>() {
-            self.optimize_subexpression(left)?
-        } else {
-            expr.left.clone()
-        };
-        
-        let right_opt = if let Some(right) = expr.right.as_any().downcast_ref::<dyn Visitable>() {
-            self.optimize_subexpression(right)?
-        } else {
-            expr.right.clone()
-        };
-        
-        // If both operands are constants, evaluate them
-        if let (Some(left_val), Some(right_val)) = (
-            self.get_constant_value(&*left_opt), 
-            self.get_constant_value(&*right_opt)
-        ) {
-            let result = match expr.operator {
-                Operator::Add => left_val + right_val,
-                Operator::Subtract => left_val - right_val,
-                Operator::Multiply => left_val * right_val,
-                Operator::Divide => {
-                    if right_val == 0.0 {
-                        return Err("Division by zero in optimization".to_string());
-                    }
-                    left_val / right_val
-                },
-                Operator::Power => left_val.powf(right_val),
-            };
-            
-            self.optimized_expression = Some(Box::new(NumberExpression::new(result)));
-        } else {
-            // Some special cases for further optimization
-            match expr.operator {
-                Operator::Multiply => {
-                    // Multiply by 0 = 0
-                    if let Some(0.0) = self.get_constant_value(&*left_opt) {
-                        self.optimized_expression = Some(Box::new(NumberExpression::new(0.0)));
-                        return Ok(());
-                    }
-                    if let Some(0.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(Box::new(NumberExpression::new(0.0)));
-                        return Ok(());
-                    }
-                    
-                    // Multiply by 1 = other operand
-                    if let Some(1.0) = self.get_constant_value(&*left_opt) {
-                        self.optimized_expression = Some(right_opt);
-                        return Ok(());
-                    }
-                    if let Some(1.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(left_opt);
-                        return Ok(());
-                    }
-                },
-                Operator::Add => {
-                    // Add 0 = other operand
-                    if let Some(0.0) = self.get_constant_value(&*left_opt) {
-                        self.optimized_expression = Some(right_opt);
-                        return Ok(());
-                    }
-                    if let Some(0.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(left_opt);
-                        return Ok(());
-                    }
-                },
-                Operator::Subtract => {
-                    // Subtract 0 = left operand
-                    if let Some(0.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(left_opt);
-                        return Ok(());
-                    }
-                },
-                Operator::Divide => {
-                    // Divide by 1 = left operand
-                    if let Some(1.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(left_opt);
-                        return Ok(());
-                    }
-                    // Divide 0 by anything = 0
-                    if let Some(0.0) = self.get_constant_value(&*left_opt) {
-                        self.optimized_expression = Some(Box::new(NumberExpression::new(0.0)));
-                        return Ok(());
-                    }
-                },
-                Operator::Power => {
-                    // Anything^0 = 1
-                    if let Some(0.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(Box::new(NumberExpression::new(1.0)));
-                        return Ok(());
-                    }
-                    // Anything^1 = itself
-                    if let Some(1.0) = self.get_constant_value(&*right_opt) {
-                        self.optimized_expression = Some(left_opt);
-                        return Ok(());
-                    }
-                    // 1^anything = 1
-                    if let Some(1.0) = self.get_constant_value(&*left_opt) {
-                        self.optimized_expression = Some(Box::new(NumberExpression::new(1.0)));
-                        return Ok(());
-                    }
-                },
-            }
-            
-            // Cannot fully optimize, create a new operation with optimized operands
-            self.optimized_expression = Some(Box::new(BinaryOperation::new(
-                left_opt,
-                right_opt,
-                expr.operator.clone(),
-            )));
+    fn differentiate_binary(&mut self, operator: &Operator, left: &Expr, right: &Expr, span: Option<Span>) -> Result<Expr, CalcError> {
+        // a ± b% is a ± a·b%, so the percentage depends on the left operand too
+        if let (Operator::Add | Operator::Subtract, Expr::Postfix { operator: PostfixOperator::Percent, .. }) = (operator, right) {
+            let share = Expr::binary(left.clone(), right.clone(), Operator::Multiply);
+            return Expr::binary(left.clone(), share, operator.clone()).accept(self);
         }
         
-        Ok(())
+        match operator {
+            Operator::Add => Ok(sum(left.accept(self)?, right.accept(self)?)),
+            Operator::Subtract => Ok(difference(left.accept(self)?, right.accept(self)?)),
+            // Product rule: (uv)' = u'v + uv'
+            Operator::Multiply | Operator::ImplicitMultiply => Ok(sum(
+                product(left.accept(self)?, right.clone()),
+                product(left.clone(), right.accept(self)?),
+            )),
+            // Quotient rule: (u/v)' = (u'v - uv') / v²
+            Operator::Divide => Ok(quotient(
+                difference(product(left.accept(self)?, right.clone()), product(left.clone(), right.accept(self)?)),
+                power(right.clone(), Expr::number(2.0)),
+            )),
+            Operator::Power => self.differentiate_power(left, right),
+            // a mod b only jumps where a/b crosses a whole number, so for a fixed b it moves with a
+            Operator::Modulo if !self.depends(right) => left.accept(self),
+            Operator::Modulo => Err(CalcError::NotDifferentiable {
+                what: "a remainder by a varying divisor".to_string(),
+                span,
+            }),
+            // Piecewise constant, so flat wherever the derivative exists
+            Operator::FloorDivide | Operator::Less | Operator::LessEqual | Operator::Equal | Operator::NotEqual
+            | Operator::GreaterEqual | Operator::Greater | Operator::And | Operator::Or => Ok(Expr::number(0.0)),
+        }
     }
     
-    fn visit_function_call(&mut self, expr: &FunctionCall) -> Result<(), String> {
-        // Optimize the argument
-        let arg_opt = if let Some(arg) = expr.argument.as_any().downcast_ref::<dyn Visitable>() {
-            self.optimize_subexpression(arg)?
-        } else {
-            expr.argument.clone()
-        };
+    fn differentiate_power(&mut self, base: &Expr, exponent: &Expr) -> Result<Expr, CalcError> {
+        let base_prime = base.accept(self)?;
+        let exponent_prime = exponent.accept(self)?;
         
-        // If the argument is a constant, evaluate the function
-        if let Some(arg_val) = self.get_constant_value(&*arg_opt) {
-            let result = match expr.function {
-                Function::Sin => arg_val.sin(),
-                Function::Cos => arg_val.cos(),
-                Function::Tan => {
-                    if (arg_val - std::f64::consts::PI/2.0).abs() % std::f64::consts::PI < 1e-10 {
-                        return Err("Tangent undefined at this value".to_string());
-                    }
-                    arg_val.tan()
-                },
-                Function::Sqrt => {
-                    if arg_val < 0.0 {
-                        return Err("Cannot take square root of negative number".to_string());
-                    }
-                    arg_val.sqrt()
-                },
+        if !self.depends(exponent) {
+            // Power rule: (uⁿ)' = n·uⁿ⁻¹·u'
+            let reduced = match exponent {
+                Expr::Number { value, .. } => Expr::number(value - 1.0),
+                _ => difference(exponent.clone(), Expr::number(1.0)),
             };
-            
-            self.optimized_expression = Some(Box::new(NumberExpression::new(result)));
+            Ok(product(product(exponent.clone(), power(base.clone(), reduced)), base_prime))
+        } else if !self.depends(base) {
+            // Exponential rule: (aᵛ)' = aᵛ·ln(a)·v'
+            Ok(product(product(power(base.clone(), exponent.clone()), ln(base.clone())), exponent_prime))
         } else {
-            // Cannot optimize, create a new function call with optimized argument
-            self.optimized_expression = Some(Box::new(FunctionCall::new(
-                expr.function.clone(),
-                arg_opt,
-            )));
+            // (uᵛ)' = uᵛ·(v'·ln(u) + v·u'/u)
+            Ok(product(
+                power(base.clone(), exponent.clone()),
+                sum(
+                    product(exponent_prime, ln(base.clone())),
+                    quotient(product(exponent.clone(), base_prime), base.clone()),
+                ),
+            ))
         }
+    }
+    
+    // Chain rule: f(u)' = f'(u)·u' for each built-in function
+    fn differentiate_call(&mut self, function: &Function, arguments: &[Expr], span: Option<Span>) -> Result<Expr, CalcError> {
+        function.check_arity(arguments.len(), span)?;
         
-        Ok(())
+        let primes = arguments.iter().map(|argument| argument.accept(self)).collect::<Result<Vec<Expr>, CalcError>>()?;
+        let u = arguments[0].clone();
+        let u_prime = primes[0].clone();
+        
+        match function {
+            Function::Sin => Ok(product(builtin(Function::Cos, vec![u]), u_prime)),
+            Function::Cos => Ok(negated(product(builtin(Function::Sin, vec![u]), u_prime))),
+            Function::Tan => Ok(quotient(u_prime, power(builtin(Function::Cos, vec![u]), Expr::number(2.0)))),
+            Function::Sqrt => Ok(quotient(u_prime, product(Expr::number(2.0), builtin(function.clone(), vec![u])))),
+            Function::Log => {
+                // log(u, b) = ln(u) / ln(b)
+                let base = arguments[1].clone();
+                let of_value = quotient(u_prime, product(u, ln(base.clone())));
+                let of_base = quotient(product(builtin(function.clone(), arguments.to_vec()), primes[1].clone()), product(base.clone(), ln(base)));
+                Ok(difference(of_value, of_base))
+            },
+            Function::Atan2 => {
+                // atan2(y, x)' = (x·y' - y·x') / (x² + y²)
+                let (y, x) = (u, arguments[1].clone());
+                Ok(quotient(
+                    difference(product(x.clone(), u_prime), product(y.clone(), primes[1].clone())),
+                    sum(power(x, Expr::number(2.0)), power(y, Expr::number(2.0))),
+                ))
+            },
+            Function::Hypot => {
+                // hypot(a, b)' = (a·a' + b·b') / hypot(a, b)
+                let (a, b) = (u, arguments[1].clone());
+                Ok(quotient(sum(product(a, u_prime), product(b, primes[1].clone())), builtin(function.clone(), arguments.to_vec())))
+            },
+            // Piecewise: the derivative of whichever argument the result is
+            Function::Min | Function::Max => {
+                let result = builtin(function.clone(), arguments.to_vec());
+                let mut chosen = primes[primes.len() - 1].clone();
+                for (argument, prime) in arguments.iter().zip(&primes).rev().skip(1) {
+                    let picked = Expr::binary(argument.clone(), result.clone(), Operator::Equal);
+                    chosen = conditional(picked, prime.clone(), chosen);
+                }
+                Ok(chosen)
+            },
+            Function::Clamp => {
+                let (value, lo, hi) = (u, arguments[1].clone(), arguments[2].clone());
+                let above = conditional(Expr::binary(value.clone(), hi, Operator::Greater), primes[2].clone(), u_prime);
+                Ok(conditional(Expr::binary(value, lo, Operator::Less), primes[1].clone(), above))
+            },
+            Function::If => Ok(conditional(u, primes[1].clone(), primes[2].clone())),
+            // Linear, so each is its own derivative
+            Function::Re | Function::Im | Function::Conj => Ok(match u_prime.as_number() {
+                Some(value) => Expr::number(function.apply(&[value], span)?),
+                None => builtin(function.clone(), vec![u_prime]),
+            }),
+            // abs(u)' = u'·u / abs(u)
+            Function::Abs => Ok(quotient(product(u_prime, u.clone()), builtin(function.clone(), vec![u]))),
+            // Constant on either side of 0
+            Function::Arg => Ok(Expr::number(0.0)),
+        }
+    }
+}
+
+impl ExpressionVisitor for DifferentiationVisitor {
+    type Output = Result<Expr, CalcError>;
+    
+    fn visit(&mut self, expr: &Expr) -> Result<Expr, CalcError> {
+        match expr {
+            Expr::Number { .. } => Ok(Expr::number(0.0)),
+            // Constants and other variables stay fixed
+            Expr::Variable { name, .. } => Ok(Expr::number(if *name == self.variable { 1.0 } else { 0.0 })),
+            Expr::Binary { operator, left, right, span } => self.differentiate_binary(operator, left, right, *span),
+            Expr::Unary { operator, operand, .. } => match operator {
+                UnaryOperator::Negate => Ok(negated(operand.accept(self)?)),
+                UnaryOperator::Plus => operand.accept(self),
+                UnaryOperator::Not => Ok(Expr::number(0.0)),
+            },
+            Expr::Postfix { operator, operand, span } => match operator {
+                PostfixOperator::Percent => Ok(quotient(operand.accept(self)?, Expr::number(100.0))),
+                PostfixOperator::Factorial if !self.depends(operand) => Ok(Expr::number(0.0)),
+                // Needs the digamma function, which the calculator doesn't have
+                PostfixOperator::Factorial => Err(CalcError::NotDifferentiable {
+                    what: expr.to_string(),
+                    span: *span,
+                }),
+            },
+            Expr::Call { function: Callee::Builtin(function), arguments, span } => self.differentiate_call(function, arguments, *span),
+            Expr::Call { .. } => self.expand(expr, &HashMap::new(), &mut Vec::new())?.accept(self),
+            Expr::Error { error, .. } => Err(error.clone()),
+            Expr::Custom(node) if !node.children().iter().any(|child| self.depends(child)) => Ok(Expr::number(0.0)),
+            Expr::Custom(node) => Err(CalcError::NotDifferentiable {
+                what: node.to_string(),
+                span: node.span(),
+            }),
+        }
     }
 }
 
 // Concrete visitor for validating expressions
 pub struct ValidationVisitor {
-    pub errors: Vec<String>,
+    pub errors: Vec<CalcError>,
+    complex_numbers: bool, // Negative numbers then have square roots and logarithms
 }
 
 impl ValidationVisitor {
     pub fn new() -> Self {
         Self {
             errors: Vec::new(),
+            complex_numbers: false,
         }
     }
     
-    pub fn validate(&mut self, expr: &dyn Visitable) -> Result<(), String> {
-        expr.accept(self)?;
+    pub fn with_complex_numbers(mut self, enabled: bool) -> Self {
+        self.complex_numbers = enabled;
+        self
+    }
+    
+    pub fn validate(&mut self, expr: &Expr) -> Result<(), CalcError> {
+        expr.accept(self);
         
         if self.errors.is_empty() {
             Ok(())
         } else {
-            Err(self.errors.join("; "))
+            // Report the first problem; the full list stays in `errors`
+            Err(self.errors[0].clone())
+        }
+    }
+    
+    fn check(&mut self, result: Result<f64, CalcError>) {
+        if let Err(error) = result {
+            self.errors.push(error);
         }
     }
 }
 
 impl ExpressionVisitor for ValidationVisitor {
-    fn visit_number(&mut self, _expr: &NumberExpression) -> Result<(), String> {
-        // Numbers are always valid
-        Ok(())
-    }
-    
-    fn visit_variable(&mut self, _expr: &VariableExpression) -> Result<(), String> {
-        // Variables are assumed to be valid (could add name validation here)
-        Ok(())
-    }
+    type Output = ();
     
-    fn visit_binary_op(&mut self, expr: &BinaryOperation) -> Result<(), String> {
-        // Check for division by zero in constant expressions
-        if let Operator::Divide = expr.operator {
-            if let Some(right) = expr.right.as_any().downcast_ref::<NumberExpression>() {
-                if right.value == 0.0 {
-                    self.errors.push("Division by zero".to_string());
+    fn visit(&mut self, expr: &Expr) {
+        match expr {
+            // Numbers are always valid; variables are only looked up when evaluated
+            Expr::Number { .. } | Expr::Variable { .. } => {},
+            Expr::Binary { operator, left, right, span } => {
+                left.accept(self);
+                
+                // Like a branch of `if`, the right side of `&&` and `||` only matters
+                // if it runs, which is only known for a literal left side
+                let right_runs = match operator {
+                    Operator::And | Operator::Or => literal_value(left)
+                        .is_some_and(|value| is_true(value) == (*operator == Operator::And)),
+                    _ => true,
+                };
+                if right_runs {
+                    right.accept(self);
                 }
-            }
-        }
-        
-        Ok(())
-    }
-    
-    fn visit_function_call(&mut self, expr: &FunctionCall) -> Result<(), String> {
-        // Validate function arguments
-        match expr.function {
-            Function::Sqrt => {
-                if let Some(arg) = expr.argument.as_any().downcast_ref::<NumberExpression>() {
-                    if arg.value < 0.0 {
-                        self.errors.push("Cannot take square root of negative number".to_string());
+                
+                // Check for division by zero in constant expressions
+                if let Operator::Divide | Operator::Modulo | Operator::FloorDivide = operator {
+                    if let Some(0.0) = literal_value(right) {
+                        self.errors.push(CalcError::DivisionByZero { span: *span });
                     }
                 }
             },
-            Function::Tan => {
-                if let Some(arg) = expr.argument.as_any().downcast_ref::<NumberExpression>() {
-                    let value = arg.value;
-                    if (value - std::f64::consts::PI/2.0).abs() % std::f64::consts::PI < 1e-10 {
-                        self.errors.push("Tangent undefined at this value".to_string());
+            // Negation is defined everywhere; signed literals are checked by the parent
+            Expr::Unary { operand, .. } => operand.accept(self),
+            Expr::Postfix { operator, operand, span } => {
+                operand.accept(self);
+                
+                // Factorials of negative integers are undefined
+                if let Some(value) = literal_value(operand) {
+                    self.check(operator.apply(value, *span));
+                }
+            },
+            Expr::Call { function: Callee::Builtin(Function::If), arguments, .. } if arguments.len() == 3 => {
+                arguments[0].accept(self);
+                
+                // With a literal condition only the taken branch can fail; otherwise a
+                // problem in a branch only matters at run time, if that branch is taken
+                if let Some(condition) = literal_value(&arguments[0]) {
+                    branch(arguments, condition).accept(self);
+                }
+            },
+            Expr::Call { function, arguments, span } => {
+                for argument in arguments {
+                    argument.accept(self);
+                }
+                
+                // Validate the argument count, then the domain when every argument is
+                // a literal. A user function is only looked up at evaluation time
+                if let Callee::Builtin(function) = function {
+                    match (function.check_arity(arguments.len(), *span), literal_values(arguments)) {
+                        (Err(error), _) => self.errors.push(error),
+                        (Ok(()), Some(values)) => match function.apply(&values, *span) {
+                            Err(CalcError::DomainError { argument, .. })
+                                if self.complex_numbers && argument < 0.0 && matches!(function, Function::Sqrt | Function::Log) => {},
+                            result => self.check(result),
+                        },
+                        (Ok(()), None) => {},
                     }
                 }
             },
-            _ => {}
+            // Already reported by the parser, but the parts may hide more mistakes
+            Expr::Error { parts, .. } => {
+                for part in parts {
+                    part.accept(self);
+                }
+            },
+            Expr::Custom(node) => {
+                for child in node.children() {
+                    child.accept(self);
+                }
+                
+                // The node's own fold decides which literal operands it rejects
+                if let Some(result) = literal_values(node.children()).and_then(|values| node.fold(&values)) {
+                    self.check(result);
+                }
+            },
         }
-        
-        Ok(())
     }
 }
 
-// Function to optimize an expression
-pub fn optimize_expression(expr: &dyn Expression, variables: &HashMap<String, f64>) -> Result<Box<dyn Expression>, String> {
-    if let Some(visitable) = expr.as_any().downcast_ref::<dyn Visitable>() {
-        let mut visitor = OptimizationVisitor::new(variables.clone());
-        visitor.optimize(visitable)
+// Value of a number literal, looking through any prefix operators (e.g. `-4`)
+fn literal_value(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Number { value, .. } => Some(*value),
+        Expr::Unary { operator, operand, .. } => literal_value(operand).map(|value| operator.apply(value)),
+        _ => None,
+    }
+}
+
+fn literal_values(exprs: &[Expr]) -> Option<Vec<f64>> {
+    exprs.iter().map(literal_value).collect()
+}
+
+// Builders for derivative terms. They drop terms that change nothing (x + 0,
+// x * 1, x * 0) and fold numbers, so the result stays readable
+fn sum(a: Expr, b: Expr) -> Expr {
+    let b = plain(b);
+    match (a.as_number(), b.as_number()) {
+        (Some(x), Some(y)) => Expr::number(x + y),
+        (Some(0.0), _) => b,
+        (_, Some(0.0)) => a,
+        _ => Expr::binary(a, b, Operator::Add),
+    }
+}
+
+fn difference(a: Expr, b: Expr) -> Expr {
+    let b = plain(b);
+    match (a.as_number(), b.as_number()) {
+        (Some(x), Some(y)) => Expr::number(x - y),
+        (Some(0.0), _) => negated(b),
+        (_, Some(0.0)) => a,
+        _ => Expr::binary(a, b, Operator::Subtract),
+    }
+}
+
+fn product(a: Expr, b: Expr) -> Expr {
+    match (a.as_number(), b.as_number()) {
+        (Some(x), Some(y)) => Expr::number(x * y),
+        (Some(0.0), _) | (_, Some(0.0)) => Expr::number(0.0),
+        (Some(1.0), _) => b,
+        (_, Some(1.0)) => a,
+        _ => Expr::binary(a, b, Operator::Multiply),
+    }
+}
+
+fn quotient(a: Expr, b: Expr) -> Expr {
+    match (a.as_number(), b.as_number()) {
+        (Some(x), Some(y)) if y != 0.0 => Expr::number(x / y),
+        (Some(0.0), _) => Expr::number(0.0),
+        (_, Some(1.0)) => a,
+        _ => Expr::binary(a, b, Operator::Divide),
+    }
+}
+
+fn power(a: Expr, b: Expr) -> Expr {
+    match (a.as_number(), b.as_number()) {
+        (Some(x), Some(y)) => Expr::number(x.powf(y)),
+        (_, Some(0.0)) => Expr::number(1.0),
+        (_, Some(1.0)) => a,
+        _ => Expr::binary(a, b, Operator::Power),
+    }
+}
+
+fn negated(a: Expr) -> Expr {
+    match a {
+        Expr::Number { value, .. } => Expr::number(if value == 0.0 { 0.0 } else { -value }),
+        Expr::Unary { operator: UnaryOperator::Negate, operand, .. } => *operand,
+        _ => Expr::unary(UnaryOperator::Negate, a),
+    }
+}
+
+fn conditional(condition: Expr, then: Expr, otherwise: Expr) -> Expr {
+    if then == otherwise {
+        then
     } else {
-        // If not visitable, return as-is
-        Ok(Box::new(expr.clone()))
+        builtin(Function::If, vec![condition, then, otherwise])
+    }
+}
+
+// A percentage as a plain fraction, so `+` and `-` don't take it as a share of
+// their left operand
+fn plain(a: Expr) -> Expr {
+    match a {
+        Expr::Postfix { operator: PostfixOperator::Percent, operand, .. } => quotient(*operand, Expr::number(100.0)),
+        _ => a,
+    }
+}
+
+// Natural logarithm, as the calculator writes it
+fn ln(a: Expr) -> Expr {
+    match a {
+        Expr::Variable { ref name, .. } if name == "e" => Expr::number(1.0),
+        _ => builtin(Function::Log, vec![a, Expr::variable("e")]),
     }
 }
 
+fn builtin(function: Function, arguments: Vec<Expr>) -> Expr {
+    Expr::Call { function: Callee::Builtin(function), arguments, span: None }
+}
+
+// Function to optimize an expression
+pub fn optimize_expression(expr: &Expr, variables: &HashMap<String, f64>) -> Result<Expr, CalcError> {
+    OptimizationVisitor::new(variables.clone()).optimize(expr)
+}
+
+// Derivative of `expr` with respect to `variable`, e.g. for Newton's method
+pub fn derivative(expr: &Expr, variable: &str) -> Result<Expr, CalcError> {
+    DifferentiationVisitor::new(variable).differentiate(expr)
+}
+
 // Function to validate an expression
-pub fn validate_expression(expr: &dyn Expression) -> Result<(), String> {
-    if let Some(visitable) = expr.as_any().downcast_ref::<dyn Visitable>() {
-        let mut visitor = ValidationVisitor::new();
-        visitor.validate(visitable)
-    } else {
-        // If not visitable, assume valid
-        Ok(())
+pub fn validate_expression(expr: &Expr) -> Result<(), CalcError> {
+    ValidationVisitor::new().validate(expr)
+}
+
+// Every syntax and semantic problem in `input` at once, in source order. The
+// parser recovers from syntax errors, and `visitor` runs over the partial tree
+pub fn diagnose(parser: &ExpressionParser, input: &str, mut visitor: ValidationVisitor) -> Vec<CalcError> {
+    let (expr, mut errors) = parser.parse_recovering(input);
+    
+    expr.accept(&mut visitor);
+    errors.extend(visitor.errors);
+    
+    errors.sort_by_key(|error| error.span().map(|span| span.start));
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression::{EvaluationContext, UserFunction};
+    use crate::test_support::variables;
+    
+    fn optimized(input: &str) -> Result<Expr, CalcError> {
+        optimize_expression(&ExpressionParser::new().parse(input)?, &HashMap::new())
+    }
+    
+    #[test]
+    fn diagnose_lists_syntax_and_semantic_errors_in_order() {
+        let errors = diagnose(&ExpressionParser::new(), "sqrt(-1) + 1/0 + * 2", ValidationVisitor::new());
+        assert!(matches!(errors.as_slice(), [
+            CalcError::DomainError { .. },
+            CalcError::DivisionByZero { .. },
+            CalcError::Syntax { .. },
+        ]), "{:?}", errors);
+    }
+    
+    #[test]
+    fn adding_a_percentage_to_zero_is_zero() {
+        let expr = optimized("0 + y%").unwrap();
+        let variables = HashMap::from([("y".to_string(), 50.0)]);
+        assert_eq!(expr.evaluate(&variables), Ok(0.0));
+        assert_eq!(optimized("0 + y").unwrap().to_string(), "y");
+    }
+    
+    #[test]
+    fn zero_only_absorbs_known_finite_operands() {
+        let parser = ExpressionParser::new();
+        let mut functions = HashMap::new();
+        functions.insert("f".to_string(), parser.parse_function_definition("f(n) = f(n - 1)").unwrap());
+        let variables = HashMap::from([("q".to_string(), f64::INFINITY)]);
+        let context = EvaluationContext::new(&variables, &functions, ());
+        
+        for input in ["0 * q", "q * 0", "q ^ 0", "0 / q", "1 ^ q", "0 * f(1)"] {
+            let expr = optimized(input).unwrap();
+            assert_eq!(expr, parser.parse(input).unwrap(), "{}", input);
+            assert_eq!(format!("{:?}", expr.evaluate_in(&context)), format!("{:?}", parser.parse(input).unwrap().evaluate_in(&context)));
+        }
+        assert_eq!(optimized("0 * 5 + 2 ^ 0").unwrap().to_string(), "1");
+        assert_eq!(optimized("x * 1 / 1 ^ 1 - 0").unwrap().to_string(), "x");
+    }
+    
+    #[test]
+    fn short_circuits_fold_without_the_right_side() {
+        assert_eq!(optimized("0 && 1/0").unwrap().to_string(), "0");
+        assert_eq!(optimized("2 || 1/0").unwrap().to_string(), "1");
+        assert!(optimized("1 && 1/0").is_err());
+    }
+    
+    #[test]
+    fn validation_skips_a_right_side_that_may_not_run() {
+        let parser = ExpressionParser::new();
+        for input in ["f(0) && 1 / 0", "x || sqrt(-1)", "0 && 1 / 0"] {
+            assert_eq!(validate_expression(&parser.parse(input).unwrap()), Ok(()), "{}", input);
+        }
+        assert!(validate_expression(&parser.parse("1 && 1 / 0").unwrap()).is_err());
+        assert!(validate_expression(&parser.parse("0 || 1 / 0").unwrap()).is_err());
+    }
+    
+    #[test]
+    fn an_unknown_left_side_keeps_the_right_side_lazy() {
+        let parser = ExpressionParser::new();
+        let mut functions = HashMap::new();
+        functions.insert("f".to_string(), parser.parse_function_definition("f(x) = x").unwrap());
+        let variables = HashMap::new();
+        let context = EvaluationContext::new(&variables, &functions, ());
+        
+        for input in ["f(0) && 1 / 0", "f(1) || 1 / 0"] {
+            let expr = optimized(input).unwrap();
+            assert_eq!(expr.to_string(), input);
+            assert_eq!(expr.evaluate_in(&context), parser.parse(input).unwrap().evaluate_in(&context));
+        }
+    }
+    
+    #[test]
+    fn derivatives_match_finite_differences() {
+        let parser = ExpressionParser::new();
+        let functions: HashMap<String, UserFunction> = ["f(t) = t^2 + y", "h(t) = t * y", "k(y) = h(2) + y"]
+            .iter()
+            .map(|definition| parser.parse_function_definition(definition).unwrap())
+            .map(|function| (function.name.clone(), function))
+            .collect();
+        
+        let inputs = [
+            "3x^2 + 2x + 1", "sin(x^2)", "cos(2x) * tan(x)", "sqrt(x) / (x + 1)", "log(x, 10)", "log(8, x)",
+            "x^x", "2^x", "e^x", "(x^2 + 1)^-3", "atan2(x, 2)", "atan2(1, x)", "hypot(x, 3)", "max(x, 2, x^2)",
+            "clamp(x, 0, 1)", "if(x > 1, x^2, -x)", "x % 3", "200 + x%", "x^2 - 10%", "-x + +y * x", "pi * x",
+            "f(x)", "f(f(x))", "k(x)",
+        ];
+        
+        // x = 1.3 is away from every kink and jump above
+        let at = |x: f64| {
+            let mut variables = variables();
+            variables.insert("x".to_string(), x);
+            variables
+        };
+        let (x, h) = (1.3, 1e-6);
+        
+        for input in inputs {
+            let expr = parser.parse(input).unwrap();
+            let value = |x: f64| expr.evaluate_in(&EvaluationContext::new(&at(x), &functions, ())).unwrap();
+            let numeric = (value(x + h) - value(x - h)) / (2.0 * h);
+            
+            let derivative = DifferentiationVisitor::new("x").with_functions(functions.clone()).differentiate(&expr).unwrap();
+            let symbolic = derivative.evaluate(&at(x)).unwrap();
+            assert!(
+                (symbolic - numeric).abs() < 1e-5 * (1.0 + numeric.abs()),
+                "d/dx {} = {}: {} at x = {}, but finite differences give {}", input, derivative, symbolic, x, numeric,
+            );
+        }
+    }
+    
+    #[test]
+    fn derivatives_are_tidy() {
+        let parser = ExpressionParser::new();
+        let cases = [
+            ("3x^2 + 2x + 1", "3 * (2 * x) + 2"),
+            ("x * 0 + 0 * x + 1 * x", "1"),
+            ("sin(x^2)", "cos(x ^ 2) * (2 * x)"),
+            ("y * x", "y"),
+            ("1 / x", "-1 / x ^ 2"),
+            ("e^x", "e ^ x"),
+            ("if(x > 1, 2, 3)", "0"),
+        ];
+        
+        for (input, expected) in cases {
+            let result = derivative(&parser.parse(input).unwrap(), "x").unwrap();
+            assert_eq!(result.to_string(), expected, "d/dx {}", input);
+        }
+        
+        for input in ["x!", "x % x", "g(x)"] {
+            assert!(derivative(&parser.parse(input).unwrap(), "x").is_err(), "d/dx {} should fail", input);
+        }
+    }
+    
+    #[test]
+    fn validation_allows_negative_roots_in_complex_mode() {
+        let parser = ExpressionParser::new();
+        for input in ["sqrt(-4)", "log(-1, 10)"] {
+            let expr = parser.parse(input).unwrap();
+            assert!(ValidationVisitor::new().validate(&expr).is_err(), "{} should be flagged", input);
+            assert!(ValidationVisitor::new().with_complex_numbers(true).validate(&expr).is_ok(), "{} should pass", input);
+        }
+        let expr = parser.parse("1 / 0").unwrap();
+        assert!(ValidationVisitor::new().with_complex_numbers(true).validate(&expr).is_err());
     }
 }
@@ -0,0 +1,535 @@
+// vm.rs - Bytecode compiler and stack machine for evaluating one expression many times
+
+use std::collections::HashMap;
+use crate::expression::{Expr, Callee, Expression, EvaluationContext, UserFunction, undefined_variable, user_function, is_true};
+use crate::token::{Operator, UnaryOperator, PostfixOperator, Function, Span};
+use crate::error::CalcError;
+use crate::config::{DEFAULT_RECURSION_DEPTH, get_global_config};
+use crate::constants;
+
+// One step of a compiled program. Operands are taken from the top of the stack
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Constant(f64),
+    Load(usize), // Variable slot
+    Binary {
+        operator: Operator,
+        percent: bool, // The right operand was written as a percentage
+    },
+    Unary(UnaryOperator),
+    Postfix(PostfixOperator),
+    Builtin {
+        function: Function,
+        arguments: usize,
+    },
+    // `&&` and `||`: when the left operand decides the result, replace it with
+    // that result and jump past the right operand
+    And(usize),
+    Or(usize),
+    Jump(usize),
+    JumpUnless(usize), // Pops the condition of an `if`
+    // A user function call: `Enter` checks the recursion limit before the
+    // arguments are evaluated, `Call` binds them and jumps to the body
+    Enter(usize),
+    Call(usize),
+    Return,
+    Fold {
+        node: usize,
+        operands: usize,
+    },
+    Fail(usize), // An error known when compiling, e.g. an unknown function
+}
+
+// A user function compiled into the program
+#[derive(Debug, Clone)]
+struct Routine {
+    name: String,
+    parameters: Vec<usize>, // Slots its arguments are bound to
+    entry: usize,
+}
+
+// A compiled expression. Variables are resolved to numbered slots up front,
+// so running it needs no name lookups. User functions are compiled in as they
+// were when compiling; recompile after redefining one
+#[derive(Debug, Clone)]
+pub struct Program {
+    code: Vec<Instruction>,
+    spans: Vec<Option<Span>>, // Source location of each instruction, for errors
+    slots: Vec<String>,       // Variable name of each slot
+    routines: Vec<Routine>,
+    errors: Vec<CalcError>,
+    nodes: Vec<Box<dyn Expression>>, // Custom nodes, see `Instruction::Fold`
+    functions: HashMap<String, UserFunction>,
+    max_depth: usize,
+}
+
+// Compiles `expr` together with the user functions it calls
+pub fn compile(expr: &Expr, functions: &HashMap<String, UserFunction>) -> Program {
+    let mut compiler = Compiler {
+        functions,
+        program: Program {
+            code: Vec::new(),
+            spans: Vec::new(),
+            slots: Vec::new(),
+            routines: Vec::new(),
+            errors: Vec::new(),
+            nodes: Vec::new(),
+            functions: functions.clone(),
+            max_depth: DEFAULT_RECURSION_DEPTH,
+        },
+        routine_index: HashMap::new(),
+    };
+    
+    compiler.expression(expr);
+    compiler.emit(Instruction::Return, None);
+    
+    // Bodies go after the main code; compiling one may queue more
+    let mut next = 0;
+    while next < compiler.program.routines.len() {
+        let body = &functions[&compiler.program.routines[next].name].body;
+        compiler.program.routines[next].entry = compiler.program.code.len();
+        compiler.expression(body);
+        compiler.emit(Instruction::Return, None);
+        next += 1;
+    }
+    
+    compiler.program
+}
+
+impl Program {
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    
+    // The slot holding a variable, for `Vm::set`
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.iter().position(|slot| slot == name)
+    }
+    
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.code
+    }
+    
+    // One-off evaluation; to evaluate repeatedly, keep a `Vm` instead
+    pub fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, CalcError> {
+        Vm::new(self, variables).run()
+    }
+}
+
+struct Compiler<'a> {
+    functions: &'a HashMap<String, UserFunction>,
+    program: Program,
+    routine_index: HashMap<String, usize>,
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, instruction: Instruction, span: Option<Span>) -> usize {
+        self.program.code.push(instruction);
+        self.program.spans.push(span);
+        self.program.code.len() - 1
+    }
+    
+    // Points the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let target = self.program.code.len();
+        match &mut self.program.code[at] {
+            Instruction::And(to) | Instruction::Or(to) | Instruction::Jump(to) | Instruction::JumpUnless(to) => *to = target,
+            other => unreachable!("{:?} is not a jump", other),
+        }
+    }
+    
+    fn slot(&mut self, name: &str) -> usize {
+        self.program.slot(name).unwrap_or_else(|| {
+            self.program.slots.push(name.to_string());
+            self.program.slots.len() - 1
+        })
+    }
+    
+    fn routine(&mut self, function: &'a UserFunction) -> usize {
+        if let Some(&index) = self.routine_index.get(&function.name) {
+            return index;
+        }
+        
+        let parameters = function.parameters.iter().map(|name| self.slot(name)).collect();
+        self.program.routines.push(Routine {
+            name: function.name.clone(),
+            parameters,
+            entry: 0, // Set once the body is compiled
+        });
+        let index = self.program.routines.len() - 1;
+        self.routine_index.insert(function.name.clone(), index);
+        index
+    }
+    
+    fn fail(&mut self, error: CalcError) {
+        self.program.errors.push(error);
+        let index = self.program.errors.len() - 1;
+        self.emit(Instruction::Fail(index), None);
+    }
+    
+    // Emits code that leaves the value of `expr` on the stack, in the order
+    // `Expr::evaluate_in` evaluates it, so errors come out the same
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Number { value, .. } => {
+                self.emit(Instruction::Constant(*value), None);
+            },
+            Expr::Variable { name, span } => {
+                let slot = self.slot(name);
+                self.emit(Instruction::Load(slot), *span);
+            },
+            Expr::Binary { operator, left, right, span } => {
+                self.expression(left);
+                let skip = match operator {
+                    Operator::And => Some(self.emit(Instruction::And(0), None)),
+                    Operator::Or => Some(self.emit(Instruction::Or(0), None)),
+                    _ => None,
+                };
+                self.expression(right);
+                
+                let percent = matches!(**right, Expr::Postfix { operator: PostfixOperator::Percent, .. });
+                self.emit(Instruction::Binary { operator: operator.clone(), percent }, *span);
+                if let Some(skip) = skip {
+                    self.patch(skip);
+                }
+            },
+            Expr::Unary { operator, operand, span } => {
+                self.expression(operand);
+                self.emit(Instruction::Unary(operator.clone()), *span);
+            },
+            Expr::Postfix { operator, operand, span } => {
+                self.expression(operand);
+                self.emit(Instruction::Postfix(operator.clone()), *span);
+            },
+            Expr::Call { function: Callee::Builtin(Function::If), arguments, .. } if arguments.len() == 3 => {
+                self.expression(&arguments[0]);
+                let otherwise = self.emit(Instruction::JumpUnless(0), None);
+                self.expression(&arguments[1]);
+                let end = self.emit(Instruction::Jump(0), None);
+                self.patch(otherwise);
+                self.expression(&arguments[2]);
+                self.patch(end);
+            },
+            Expr::Call { function: Callee::Builtin(function), arguments, span } => {
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.emit(Instruction::Builtin { function: function.clone(), arguments: arguments.len() }, *span);
+            },
+            Expr::Call { function: Callee::User(name), arguments, span } => {
+                match user_function(name, arguments.len(), self.functions, *span) {
+                    Ok(function) => {
+                        let routine = self.routine(function);
+                        self.emit(Instruction::Enter(routine), *span);
+                        for argument in arguments {
+                            self.expression(argument);
+                        }
+                        self.emit(Instruction::Call(routine), *span);
+                    },
+                    Err(error) => self.fail(error),
+                }
+            },
+            Expr::Error { error, .. } => self.fail(error.clone()),
+            Expr::Custom(node) => {
+                for child in node.children() {
+                    self.expression(child);
+                }
+                self.program.nodes.push(node.clone());
+                let index = self.program.nodes.len() - 1;
+                self.emit(Instruction::Fold { node: index, operands: node.children().len() }, node.span());
+            },
+        }
+    }
+}
+
+// A user function call in progress
+struct Frame {
+    routine: usize,
+    call: usize, // The `Call` instruction, whose span errors in the body are moved to
+    saved: usize, // Where the caller's values of the parameters start in `Vm::saved`
+}
+
+// Runs a program against one set of variables. Keep it between runs to
+// reuse its stack, changing variables with `set`
+pub struct Vm<'a> {
+    program: &'a Program,
+    variables: &'a HashMap<String, f64>,
+    values: Vec<Option<f64>>, // Indexed by slot; `None` is undefined
+    pinned: Vec<bool>,        // Constants that variables may not shadow
+    stack: Vec<f64>,
+    frames: Vec<Frame>,
+    saved: Vec<Option<f64>>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a Program, variables: &'a HashMap<String, f64>) -> Self {
+        let shadowing = get_global_config().allow_constant_shadowing;
+        
+        Self {
+            program,
+            variables,
+            values: program.slots.iter().map(|name| constants::resolve(name, variables)).collect(),
+            pinned: program.slots.iter().map(|name| !shadowing && constants::lookup(name).is_some()).collect(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            saved: Vec::new(),
+        }
+    }
+    
+    // Gives a variable a new value, as inserting it into `variables` would
+    pub fn set(&mut self, slot: usize, value: f64) {
+        if !self.pinned[slot] {
+            self.values[slot] = Some(value);
+        }
+    }
+    
+    pub fn run(&mut self) -> Result<f64, CalcError> {
+        self.stack.clear();
+        self.execute().map_err(|error| self.unwind(error))
+    }
+    
+    fn execute(&mut self) -> Result<f64, CalcError> {
+        let program = self.program;
+        let mut pc = 0;
+        
+        loop {
+            match &program.code[pc] {
+                Instruction::Constant(value) => self.stack.push(*value),
+                Instruction::Load(slot) => match self.values[*slot] {
+                    Some(value) => self.stack.push(value),
+                    None => return Err(self.undefined(*slot, program.spans[pc])),
+                },
+                Instruction::Binary { operator, percent } => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    // The commonest operators inline; the rest share the tree's code
+                    let value = match operator {
+                        Operator::Add if !percent => left + right,
+                        Operator::Subtract if !percent => left - right,
+                        Operator::Multiply => left * right,
+                        _ => operator.apply(left, right, *percent, program.spans[pc])?,
+                    };
+                    self.stack.push(value);
+                },
+                Instruction::Unary(operator) => {
+                    let value = self.pop();
+                    self.stack.push(operator.apply(value));
+                },
+                Instruction::Postfix(operator) => {
+                    let value = self.pop();
+                    self.stack.push(operator.apply(value, program.spans[pc])?);
+                },
+                Instruction::Builtin { function, arguments } => {
+                    let start = self.stack.len() - arguments;
+                    let value = function.apply(&self.stack[start..], program.spans[pc])?;
+                    self.stack.truncate(start);
+                    self.stack.push(value);
+                },
+                Instruction::And(target) => {
+                    if !is_true(self.top()) {
+                        self.replace_top(0.0);
+                        pc = *target;
+                        continue;
+                    }
+                },
+                Instruction::Or(target) => {
+                    if is_true(self.top()) {
+                        self.replace_top(1.0);
+                        pc = *target;
+                        continue;
+                    }
+                },
+                Instruction::Jump(target) => {
+                    pc = *target;
+                    continue;
+                },
+                Instruction::JumpUnless(target) => {
+                    if !is_true(self.pop()) {
+                        pc = *target;
+                        continue;
+                    }
+                },
+                Instruction::Enter(routine) => {
+                    if self.frames.len() >= program.max_depth {
+                        return Err(CalcError::RecursionLimit {
+                            function: program.routines[*routine].name.clone(),
+                            limit: program.max_depth,
+                            span: program.spans[pc],
+                        });
+                    }
+                },
+                Instruction::Call(routine) => {
+                    // Parameters are bound over the caller's variables, so the
+                    // body also sees the caller's parameters, as when walking the tree
+                    let parameters = &program.routines[*routine].parameters;
+                    let start = self.stack.len() - parameters.len();
+                    self.frames.push(Frame { routine: *routine, call: pc, saved: self.saved.len() });
+                    for (i, &slot) in parameters.iter().enumerate() {
+                        self.saved.push(self.values[slot]);
+                        let value = self.stack[start + i];
+                        self.set(slot, value);
+                    }
+                    self.stack.truncate(start);
+                    pc = program.routines[*routine].entry;
+                    continue;
+                },
+                Instruction::Return => match self.frames.pop() {
+                    Some(frame) => {
+                        self.restore(&frame);
+                        pc = frame.call + 1;
+                        continue;
+                    },
+                    None => return Ok(self.pop()),
+                },
+                Instruction::Fold { node, operands } => {
+                    let node = &program.nodes[*node];
+                    let start = self.stack.len() - operands;
+                    let value = match node.fold(&self.stack[start..]) {
+                        Some(result) => result?,
+                        // A node that needs more than its operands' values is evaluated as a tree
+                        None => node.evaluate_in(&EvaluationContext {
+                            variables: &self.scope(),
+                            functions: &program.functions,
+                            settings: (),
+                            depth: self.frames.len(),
+                            max_depth: program.max_depth,
+                        })?,
+                    };
+                    self.stack.truncate(start);
+                    self.stack.push(value);
+                },
+                Instruction::Fail(error) => return Err(program.errors[*error].clone()),
+            }
+            pc += 1;
+        }
+    }
+    
+    fn pop(&mut self) -> f64 {
+        self.stack.pop().expect("compiled code keeps the stack balanced")
+    }
+    
+    fn top(&self) -> f64 {
+        self.stack[self.stack.len() - 1]
+    }
+    
+    fn replace_top(&mut self, value: f64) {
+        let top = self.stack.len() - 1;
+        self.stack[top] = value;
+    }
+    
+    // Gives a finished call's parameters back their values in the caller
+    fn restore(&mut self, frame: &Frame) {
+        let parameters = &self.program.routines[frame.routine].parameters;
+        for (i, &slot) in parameters.iter().enumerate() {
+            self.values[slot] = self.saved[frame.saved + i];
+        }
+        self.saved.truncate(frame.saved);
+    }
+    
+    // Abandons the calls in progress, moving the error to each call in turn,
+    // innermost first, as returning through them while walking the tree does
+    fn unwind(&mut self, mut error: CalcError) -> CalcError {
+        while let Some(frame) = self.frames.pop() {
+            self.restore(&frame);
+            error = error.relocated(self.program.spans[frame.call]);
+        }
+        error
+    }
+    
+    // Variables as the tree would see them at this point
+    fn scope(&self) -> HashMap<String, f64> {
+        let mut scope = self.variables.clone();
+        for (name, value) in self.program.slots.iter().zip(&self.values) {
+            if let Some(value) = value {
+                scope.insert(name.clone(), *value);
+            }
+        }
+        scope
+    }
+    
+    fn undefined(&self, slot: usize, span: Option<Span>) -> CalcError {
+        let bound = self.program.slots.iter()
+            .zip(&self.values)
+            .filter(|(_, value)| value.is_some())
+            .map(|(name, _)| name.as_str());
+        undefined_variable(&self.program.slots[slot], self.variables.keys().map(String::as_str).chain(bound), span)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ExpressionParser;
+    use crate::test_support::{check_expressions, mismatch, random_expressions, variables};
+    
+    #[test]
+    fn compiled_programs_match_the_tree() {
+        let variables = variables();
+        check_expressions(random_expressions(), |expr| {
+            mismatch("compiled", &expr.evaluate(&variables), &compile(expr, &HashMap::new()).evaluate(&variables))
+        });
+    }
+    
+    #[test]
+    fn compiled_user_functions_match_the_tree() {
+        let parser = ExpressionParser::new();
+        let mut variables = variables();
+        variables.insert("w".to_string(), 4.0);
+        
+        let functions: HashMap<String, UserFunction> = [
+            "f(x) = x^2 + 1",
+            "g(x, y) = f(x) * y + w",
+            "fact(n) = if(n <= 1, 1, n * fact(n - 1))",
+            "r(n) = r(n - 1)",
+            "h(t) = t * y", // `y` is the innermost caller's, if any has one
+            "k(y) = h(2)",
+            "bad(t) = t / 0 + missing",
+            "outer(t) = 1 + bad(t)",
+        ]
+        .iter()
+        .map(|definition| parser.parse_function_definition(definition).unwrap())
+        .map(|function| (function.name.clone(), function))
+        .collect();
+        
+        let inputs = [
+            "f(3)", "g(2, 1)", "fact(10)", "fact(171)", "r(1)", "h(1)", "k(5)", "k(k(1))", "f(1, 2)", "ff(2)",
+            "f(1/0)", "0 && r(1)", "1 || r(1)", "if(x > 1, fact(5), r(0))", "outer(1)", "outer(missing)",
+            "2 * f(g(1, 1)) - fact(3)!", "max(f(1), f(2), bad(1))", "y + 10% * f(x)", "f(x) / f(x) + fact(f(x))",
+        ];
+        
+        for input in inputs {
+            let expr = parser.parse(input).unwrap();
+            let tree = expr.evaluate_in(&EvaluationContext::new(&variables, &functions, ()).with_max_depth(20));
+            
+            let program = compile(&expr, &functions).with_max_depth(20);
+            let mut vm = Vm::new(&program, &variables);
+            
+            // Twice, since a failed run must leave the machine ready for the next
+            for _ in 0..2 {
+                let compiled = vm.run();
+                assert_eq!(mismatch("compiled", &tree, &compiled), None, "`{}`", input);
+            }
+        }
+    }
+    
+    #[test]
+    fn setting_a_slot_matches_changing_the_variable() {
+        let expr = ExpressionParser::new().parse("if(x < 0, sqrt(x), x^2 / (x - 3)) + pi").unwrap();
+        let mut variables = variables();
+        
+        let program = compile(&expr, &HashMap::new());
+        let x = program.slot("x").unwrap();
+        let initial = variables.clone();
+        let mut vm = Vm::new(&program, &initial);
+        
+        for step in -10..=10 {
+            let value = step as f64 * 0.5;
+            variables.insert("x".to_string(), value);
+            vm.set(x, value);
+            
+            let (tree, compiled) = (expr.evaluate(&variables), vm.run());
+            assert_eq!(mismatch("compiled", &tree, &compiled), None, "x = {}", value);
+        }
+    }
+}